@@ -9,14 +9,18 @@ use std::collections::HashMap;
 /// Admin database.
 use std::sync::atomic::Ordering;
 use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast::Receiver;
 use tokio::time::Instant;
 
-use crate::config::{get_config, reload_config, VERSION};
+use crate::ban_history;
+use crate::client_connections::client_connections;
+use crate::config::{get_config, reload_config, reload_pool_config, VERSION};
 use crate::errors::Error;
+use crate::events::{subscribe, PoolEvent, EVENTS_CHANNEL};
 use crate::messages::*;
-use crate::pool::ClientServerMap;
 use crate::pool::{get_all_pools, get_pool};
-use crate::stats::{get_client_stats, get_server_stats, ClientState, ServerState};
+use crate::pool::{ClientServerMap, PoolMap};
+use crate::stats::{get_client_stats, get_server_stats, ClientState, ServerState, StatementKind};
 
 pub fn generate_server_parameters_for_admin() -> ServerParameters {
     let mut server_parameters = ServerParameters::new();
@@ -35,6 +39,7 @@ pub async fn handle_admin<T>(
     stream: &mut T,
     mut query: BytesMut,
     client_server_map: ClientServerMap,
+    events: &mut Option<Receiver<PoolEvent>>,
 ) -> Result<(), Error>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
@@ -69,14 +74,30 @@ where
             trace!("UNBAN");
             unban(stream, query_parts).await
         }
+        "DRAIN" => {
+            trace!("DRAIN");
+            drain(stream, query_parts, true).await
+        }
+        "UNDRAIN" => {
+            trace!("UNDRAIN");
+            drain(stream, query_parts, false).await
+        }
         "RELOAD" => {
             trace!("RELOAD");
-            reload(stream, client_server_map).await
+            reload(stream, client_server_map, query_parts).await
         }
         "SET" => {
             trace!("SET");
             ignore_set(stream).await
         }
+        "LISTEN" => {
+            trace!("LISTEN");
+            listen(stream, query_parts, events).await
+        }
+        "UNLISTEN" => {
+            trace!("UNLISTEN");
+            unlisten(stream, query_parts, events).await
+        }
         "PAUSE" => {
             trace!("PAUSE");
             pause(stream, query_parts).await
@@ -89,60 +110,186 @@ where
             trace!("SHUTDOWN");
             shutdown(stream).await
         }
-        "SHOW" => match query_parts
-            .get(1)
-            .unwrap_or(&"")
-            .to_ascii_uppercase()
-            .as_str()
+        "KILL" => {
+            trace!("KILL");
+            kill(stream, query_parts).await
+        }
+        "SHOW" => match query_parts.len() > 2
+            && query_parts
+                .last()
+                .is_some_and(|part| part.eq_ignore_ascii_case("JSON"))
         {
-            "HELP" => {
-                trace!("SHOW HELP");
-                show_help(stream).await
-            }
-            "BANS" => {
-                trace!("SHOW BANS");
-                show_bans(stream).await
-            }
-            "CONFIG" => {
-                trace!("SHOW CONFIG");
-                show_config(stream).await
-            }
-            "DATABASES" => {
-                trace!("SHOW DATABASES");
-                show_databases(stream).await
-            }
-            "LISTS" => {
-                trace!("SHOW LISTS");
-                show_lists(stream).await
-            }
-            "POOLS" => {
-                trace!("SHOW POOLS");
-                show_pools(stream).await
+            true => {
+                trace!("SHOW JSON");
+                show_json(stream, &query_parts[..query_parts.len() - 1]).await
             }
-            "CLIENTS" => {
-                trace!("SHOW CLIENTS");
-                show_clients(stream).await
-            }
-            "SERVERS" => {
-                trace!("SHOW SERVERS");
-                show_servers(stream).await
-            }
-            "STATS" => {
-                trace!("SHOW STATS");
-                show_stats(stream).await
+            false => show(stream, &query_parts).await,
+        },
+        _ => error_response(stream, "Unsupported query against the admin database").await,
+    }
+}
+
+/// Run a SHOW command.
+async fn show<T>(stream: &mut T, query_parts: &[&str]) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    match query_parts
+        .get(1)
+        .unwrap_or(&"")
+        .to_ascii_uppercase()
+        .as_str()
+    {
+        "HELP" => {
+            trace!("SHOW HELP");
+            show_help(stream).await
+        }
+        "BANS" => {
+            trace!("SHOW BANS");
+            show_bans(stream).await
+        }
+        "BAN"
+            if query_parts
+                .get(2)
+                .is_some_and(|part| part.eq_ignore_ascii_case("HISTORY")) =>
+        {
+            trace!("SHOW BAN HISTORY");
+            show_ban_history(stream).await
+        }
+        "CONFIG" => {
+            trace!("SHOW CONFIG");
+            show_config(stream).await
+        }
+        "DATABASES" => {
+            trace!("SHOW DATABASES");
+            show_databases(stream).await
+        }
+        "LISTS" => {
+            trace!("SHOW LISTS");
+            show_lists(stream).await
+        }
+        "POOLS" => {
+            trace!("SHOW POOLS");
+            show_pools(stream).await
+        }
+        "CLIENTS" => {
+            trace!("SHOW CLIENTS");
+            show_clients(stream).await
+        }
+        "SERVERS" => {
+            trace!("SHOW SERVERS");
+            show_servers(stream).await
+        }
+        "STATS" => {
+            trace!("SHOW STATS");
+            show_stats(stream).await
+        }
+        "VERSION" => {
+            trace!("SHOW VERSION");
+            show_version(stream).await
+        }
+        "USERS" => {
+            trace!("SHOW USERS");
+            show_users(stream).await
+        }
+        "PREPARED_STATEMENTS" => {
+            trace!("SHOW PREPARED_STATEMENTS");
+            show_prepared_statements(stream).await
+        }
+        _ => error_response(stream, "Unsupported SHOW query against the admin database").await,
+    }
+}
+
+/// Run a SHOW command and return its rows as a JSON array of objects,
+/// in a single `json` column, e.g. for `SHOW POOLS JSON`.
+async fn show_json<T>(stream: &mut T, query_parts: &[&str]) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut response = Vec::new();
+    show(&mut response, query_parts).await?;
+
+    write_all_half(stream, &to_json_result(&response)).await
+}
+
+/// Encode the rows of an admin command result as a single JSON document.
+/// Numbers and booleans keep their type, anything but rows is passed as is.
+fn to_json_result(response: &[u8]) -> BytesMut {
+    let mut response = BytesMut::from(response);
+    let mut columns: Vec<(String, i32)> = Vec::new();
+    let mut rows = Vec::new();
+    let mut res = BytesMut::new();
+
+    while response.len() >= 5 {
+        let code = response[0];
+        let len = i32::from_be_bytes([response[1], response[2], response[3], response[4]]) as usize;
+        let mut message = response.split_to(len + 1);
+
+        match code {
+            // RowDescription
+            b'T' => {
+                message.advance(5);
+                let count = message.get_i16();
+
+                for _ in 0..count {
+                    let name = message.read_string().unwrap_or_default();
+                    message.advance(6);
+                    let data_type = message.get_i32();
+                    message.advance(8);
+
+                    columns.push((name, data_type));
+                }
             }
-            "VERSION" => {
-                trace!("SHOW VERSION");
-                show_version(stream).await
+
+            // DataRow
+            b'D' => {
+                message.advance(5);
+                let count = message.get_i16() as usize;
+                let mut row = serde_json::Map::new();
+
+                for (name, data_type) in columns.iter().take(count) {
+                    let len = message.get_i32();
+
+                    let value = match len {
+                        -1 => serde_json::Value::Null,
+                        len => {
+                            let value = String::from_utf8_lossy(&message.split_to(len as usize))
+                                .to_string();
+
+                            // The OIDs of DataType::Int4, DataType::Numeric and DataType::Bool.
+                            match *data_type {
+                                23 | 1700 => value
+                                    .parse::<serde_json::Number>()
+                                    .map(serde_json::Value::from)
+                                    .unwrap_or(serde_json::Value::String(value)),
+                                16 => serde_json::Value::Bool(value == "t"),
+                                _ => serde_json::Value::String(value),
+                            }
+                        }
+                    };
+
+                    row.insert(name.clone(), value);
+                }
+
+                rows.push(serde_json::Value::Object(row));
             }
-            "USERS" => {
-                trace!("SHOW USERS");
-                show_users(stream).await
+
+            // The result is complete, send it before the rest.
+            b'C' if !columns.is_empty() => {
+                res.put(row_description(&vec![("json", DataType::Text)]));
+                res.put(data_row(&vec![serde_json::Value::Array(std::mem::take(
+                    &mut rows,
+                ))
+                .to_string()]));
+                res.put(message);
+                columns.clear();
             }
-            _ => error_response(stream, "Unsupported SHOW query against the admin database").await,
-        },
-        _ => error_response(stream, "Unsupported query against the admin database").await,
+
+            _ => res.put(message),
+        }
     }
+
+    res
 }
 
 /// Column-oriented statistics.
@@ -203,6 +350,18 @@ where
         "login_clients".to_string(),
         "0".to_string(),
     ]));
+    res.put(data_row(&vec![
+        "client_connections".to_string(),
+        client_connections().to_string(),
+    ]));
+    res.put(data_row(&vec![
+        "max_client_connections".to_string(),
+        get_config()
+            .general
+            .max_client_connections
+            .unwrap_or_default()
+            .to_string(),
+    ]));
     res.put(data_row(&vec![
         "free_servers".to_string(),
         server_stats
@@ -296,18 +455,28 @@ where
     let detail_msg = [
         "",
         "SHOW HELP|CONFIG|DATABASES|POOLS|CLIENTS|SERVERS|USERS|VERSION",
+        "SHOW BANS|BAN HISTORY",
         // "SHOW PEERS|PEER_POOLS", // missing PEERS|PEER_POOLS
         // "SHOW FDS|SOCKETS|ACTIVE_SOCKETS|LISTS|MEM|STATE", // missing FDS|SOCKETS|ACTIVE_SOCKETS|MEM|STATE
         "SHOW LISTS",
+        "SHOW PREPARED_STATEMENTS",
         // "SHOW DNS_HOSTS|DNS_ZONES", // missing DNS_HOSTS|DNS_ZONES
         "SHOW STATS", // missing STATS_TOTALS|STATS_AVERAGES|TOTALS
+        "SHOW <command> JSON",
         "SET key = arg",
-        "RELOAD",
-        "PAUSE [<db>, <user>]",
-        "RESUME [<db>, <user>]",
+        "LISTEN pgcat_events",
+        "UNLISTEN pgcat_events",
+        "RELOAD [<db> [<user>]]",
+        "BAN <host> <port> [<seconds>]",
+        "UNBAN <host> [<port>]",
+        "DRAIN <host> <port>",
+        "UNDRAIN <host> <port>",
+        "PAUSE [<db>[, <user>]]",
+        "RESUME [<db>[, <user>]]",
         // "DISABLE <db>", // missing
         // "ENABLE <db>", // missing
         // "RECONNECT [<db>]", missing
+        "KILL CLIENT <client_id>",
         // "KILL <db>",
         // "SUSPEND",
         "SHUTDOWN",
@@ -405,28 +574,85 @@ where
     custom_protocol_response_ok(stream, "SET").await
 }
 
-/// Bans a host from being used
+/// Channel of a LISTEN or UNLISTEN, unquoted.
+fn listen_channel(tokens: &[&str]) -> Option<String> {
+    match tokens {
+        [_, channel] => Some(channel.trim_matches('"').to_string()),
+        _ => None,
+    }
+}
+
+/// Send the pool events to the client as notifications on the `pgcat_events` channel.
+async fn listen<T>(
+    stream: &mut T,
+    tokens: Vec<&str>,
+    events: &mut Option<Receiver<PoolEvent>>,
+) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    match listen_channel(&tokens) {
+        Some(channel) if channel == EVENTS_CHANNEL => {
+            if events.is_none() {
+                *events = Some(subscribe());
+            }
+            custom_protocol_response_ok(stream, "LISTEN").await
+        }
+        _ => error_response(stream, &format!("usage: LISTEN {}", EVENTS_CHANNEL)).await,
+    }
+}
+
+/// Stop sending the pool events to the client.
+async fn unlisten<T>(
+    stream: &mut T,
+    tokens: Vec<&str>,
+    events: &mut Option<Receiver<PoolEvent>>,
+) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    match listen_channel(&tokens) {
+        Some(channel) if channel == EVENTS_CHANNEL || channel == "*" => {
+            *events = None;
+            custom_protocol_response_ok(stream, "UNLISTEN").await
+        }
+        _ => error_response(stream, &format!("usage: UNLISTEN {}", EVENTS_CHANNEL)).await,
+    }
+}
+
+/// Bans a host and port from being used, for `seconds` or the configured ban time.
 async fn ban<T>(stream: &mut T, tokens: Vec<&str>) -> Result<(), Error>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
 {
+    let usage = "usage: BAN host port [duration_seconds]";
+
     let host = match tokens.get(1) {
         Some(host) => host,
-        None => return error_response(stream, "usage: BAN hostname duration_seconds").await,
+        None => return error_response(stream, usage).await,
     };
 
-    let duration_seconds = match tokens.get(2) {
+    let port = match tokens.get(2) {
+        Some(port) => match port.parse::<u16>() {
+            Ok(port) if port > 0 => port,
+            _ => return error_response(stream, "port must be between 1 and 65535").await,
+        },
+        None => return error_response(stream, usage).await,
+    };
+
+    let duration_seconds = match tokens.get(3) {
         Some(duration_seconds) => match duration_seconds.parse::<i64>() {
-            Ok(duration_seconds) => duration_seconds,
+            Ok(duration_seconds) if duration_seconds > 0 => Some(duration_seconds),
+            Ok(_) => return error_response(stream, "duration_seconds must be > 0").await,
             Err(_) => {
                 return error_response(stream, "duration_seconds must be an integer").await;
             }
         },
-        None => return error_response(stream, "usage: BAN hostname duration_seconds").await,
+        None => None,
     };
 
-    if duration_seconds <= 0 {
-        return error_response(stream, "duration_seconds must be >= 0").await;
+    if tokens.len() > 4 {
+        return error_response(stream, usage).await;
     }
 
     let columns = vec![
@@ -434,12 +660,16 @@ where
         ("user", DataType::Text),
         ("role", DataType::Text),
         ("host", DataType::Text),
+        ("port", DataType::Numeric),
+        ("shard", DataType::Numeric),
     ];
     let mut res = BytesMut::new();
     res.put(row_description(&columns));
 
     for (id, pool) in get_all_pools().iter() {
-        for address in pool.get_addresses_from_host(host) {
+        let duration_seconds = duration_seconds.unwrap_or(pool.settings.ban_time);
+
+        for address in pool.get_addresses_from_host(host, Some(port)) {
             if !pool.is_banned(&address) {
                 pool.ban(&address, BanReason::AdminBan(duration_seconds), None);
                 res.put(data_row(&vec![
@@ -447,6 +677,8 @@ where
                     id.user.clone(),
                     address.role.to_string(),
                     address.host,
+                    address.port.to_string(),
+                    address.shard.to_string(),
                 ]));
             }
         }
@@ -462,7 +694,7 @@ where
     write_all_half(stream, &res).await
 }
 
-/// Clear a host for use
+/// Clear a host for use, only the given port if any.
 async fn unban<T>(stream: &mut T, tokens: Vec<&str>) -> Result<(), Error>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
@@ -472,17 +704,27 @@ where
         None => return error_response(stream, "UNBAN command requires a hostname to unban").await,
     };
 
+    let port = match tokens.get(2) {
+        Some(port) => match port.parse::<u16>() {
+            Ok(port) if port > 0 => Some(port),
+            _ => return error_response(stream, "port must be between 1 and 65535").await,
+        },
+        None => None,
+    };
+
     let columns = vec![
         ("db", DataType::Text),
         ("user", DataType::Text),
         ("role", DataType::Text),
         ("host", DataType::Text),
+        ("port", DataType::Numeric),
+        ("shard", DataType::Numeric),
     ];
     let mut res = BytesMut::new();
     res.put(row_description(&columns));
 
     for (id, pool) in get_all_pools().iter() {
-        for address in pool.get_addresses_from_host(host) {
+        for address in pool.get_addresses_from_host(host, port) {
             if pool.is_banned(&address) {
                 pool.unban(&address);
                 res.put(data_row(&vec![
@@ -490,6 +732,8 @@ where
                     id.user.clone(),
                     address.role.to_string(),
                     address.host,
+                    address.port.to_string(),
+                    address.shard.to_string(),
                 ]));
             }
         }
@@ -505,6 +749,165 @@ where
     write_all_half(stream, &res).await
 }
 
+/// Takes the replicas listening on host and port out of rotation until undrained,
+/// or puts them back. Lists the replicas whose state changed.
+async fn drain<T>(stream: &mut T, tokens: Vec<&str>, drain: bool) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let command = if drain { "DRAIN" } else { "UNDRAIN" };
+    let usage = format!("usage: {} host port", command);
+
+    let host = match tokens.get(1) {
+        Some(host) => host,
+        None => return error_response(stream, &usage).await,
+    };
+
+    let port = match tokens.get(2) {
+        Some(port) => match port.parse::<u16>() {
+            Ok(port) if port > 0 => port,
+            _ => return error_response(stream, "port must be between 1 and 65535").await,
+        },
+        None => return error_response(stream, &usage).await,
+    };
+
+    if tokens.len() > 3 {
+        return error_response(stream, &usage).await;
+    }
+
+    let columns = vec![
+        ("db", DataType::Text),
+        ("user", DataType::Text),
+        ("role", DataType::Text),
+        ("host", DataType::Text),
+        ("port", DataType::Numeric),
+        ("shard", DataType::Numeric),
+    ];
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for (id, pool) in get_all_pools().iter() {
+        for address in pool.get_addresses_from_host(host, Some(port)) {
+            let changed = match drain {
+                true => pool.drain(&address),
+                false => pool.undrain(&address),
+            };
+
+            if changed {
+                res.put(data_row(&vec![
+                    id.db.clone(),
+                    id.user.clone(),
+                    address.role.to_string(),
+                    address.host,
+                    address.port.to_string(),
+                    address.shard.to_string(),
+                ]));
+            }
+        }
+    }
+
+    res.put(command_complete(command));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
+/// Rows of SHOW BANS for the bans that haven't expired yet, sorted by shard then host.
+fn ban_rows(pools: &PoolMap, now: i64) -> Vec<Vec<String>> {
+    let mut bans = Vec::new();
+    for (id, pool) in pools.iter() {
+        for (address, (ban_reason, ban_time, ban_duration)) in pool.get_bans() {
+            let remaining = ban_duration - (now - ban_time.timestamp());
+            if remaining > 0 {
+                bans.push((id, address, ban_reason, ban_time, ban_duration, remaining));
+            }
+        }
+    }
+
+    bans.sort_by(|(a_id, a, ..), (b_id, b, ..)| {
+        (a.shard, &a.host, a.port, &a_id.db, &a_id.user)
+            .cmp(&(b.shard, &b.host, b.port, &b_id.db, &b_id.user))
+    });
+
+    bans.into_iter()
+        .map(
+            |(id, address, ban_reason, ban_time, ban_duration, remaining)| {
+                vec![
+                    id.db.clone(),
+                    id.user.clone(),
+                    address.host,
+                    address.port.to_string(),
+                    address.shard.to_string(),
+                    address.role.to_string(),
+                    format!("{:?}", ban_reason),
+                    ban_time.to_string(),
+                    ban_duration.to_string(),
+                    remaining.to_string(),
+                ]
+            },
+        )
+        .collect()
+}
+
+/// Rows of SHOW PREPARED_STATEMENTS, by pool then the most recently used first.
+fn prepared_statement_rows(pools: &PoolMap) -> Vec<Vec<String>> {
+    let mut pools: Vec<_> = pools.iter().collect();
+    pools.sort_by(|(a, _), (b, _)| (&a.db, &a.user).cmp(&(&b.db, &b.user)));
+
+    let mut rows = Vec::new();
+    for (id, pool) in pools {
+        let statements = match pool.prepared_statement_cache {
+            Some(ref cache) => cache.lock().statements(),
+            None => continue,
+        };
+
+        for (parse, hits) in statements {
+            rows.push(vec![
+                id.db.clone(),
+                id.user.clone(),
+                parse.name.clone(),
+                parse.query().to_string(),
+                hits.to_string(),
+            ]);
+        }
+    }
+
+    rows
+}
+
+/// Shows the statements in the prepared statement caches of the pools.
+async fn show_prepared_statements<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let columns = vec![
+        ("database", DataType::Text),
+        ("user", DataType::Text),
+        ("name", DataType::Text),
+        ("query", DataType::Text),
+        ("hits", DataType::Numeric),
+    ];
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for row in prepared_statement_rows(&get_all_pools()) {
+        res.put(data_row(&row));
+    }
+
+    res.put(command_complete("SHOW"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
 /// Shows all the bans
 async fn show_bans<T>(stream: &mut T) -> Result<(), Error>
 where
@@ -513,12 +916,14 @@ where
     let columns = vec![
         ("db", DataType::Text),
         ("user", DataType::Text),
-        ("role", DataType::Text),
         ("host", DataType::Text),
+        ("port", DataType::Numeric),
+        ("shard", DataType::Numeric),
+        ("role", DataType::Text),
         ("reason", DataType::Text),
-        ("ban_time", DataType::Text),
-        ("ban_duration_seconds", DataType::Text),
-        ("ban_remaining_seconds", DataType::Text),
+        ("banned_at", DataType::Text),
+        ("ban_duration_seconds", DataType::Numeric),
+        ("seconds_remaining", DataType::Numeric),
     ];
     let mut res = BytesMut::new();
     res.put(row_description(&columns));
@@ -529,27 +934,8 @@ where
         .expect("Time went backwards")
         .as_secs() as i64;
 
-    for (id, pool) in get_all_pools().iter() {
-        for (address, (ban_reason, ban_time)) in pool.get_bans().iter() {
-            let ban_duration = match ban_reason {
-                BanReason::AdminBan(duration) => *duration,
-                _ => pool.settings.ban_time,
-            };
-            let remaining = ban_duration - (now - ban_time.timestamp());
-            if remaining <= 0 {
-                continue;
-            }
-            res.put(data_row(&vec![
-                id.db.clone(),
-                id.user.clone(),
-                address.role.to_string(),
-                address.host.clone(),
-                format!("{:?}", ban_reason),
-                ban_time.to_string(),
-                ban_duration.to_string(),
-                remaining.to_string(),
-            ]));
-        }
+    for row in ban_rows(&get_all_pools(), now) {
+        res.put(data_row(&row));
     }
 
     res.put(command_complete("SHOW BANS"));
@@ -562,14 +948,83 @@ where
     write_all_half(stream, &res).await
 }
 
+/// Shows the past bans, the newest first. The ones still going on have no `unbanned_at`.
+async fn show_ban_history<T>(stream: &mut T) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let columns = vec![
+        ("db", DataType::Text),
+        ("user", DataType::Text),
+        ("host", DataType::Text),
+        ("port", DataType::Numeric),
+        ("shard", DataType::Numeric),
+        ("role", DataType::Text),
+        ("reason", DataType::Text),
+        ("banned_at", DataType::Text),
+        ("unbanned_at", DataType::Text),
+        ("ban_duration_seconds", DataType::Numeric),
+    ];
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+
+    for entry in ban_history::history().into_iter().rev() {
+        res.put(data_row_nullable(&vec![
+            Some(entry.pool),
+            Some(entry.user),
+            Some(entry.host),
+            Some(entry.port.to_string()),
+            Some(entry.shard.to_string()),
+            Some(entry.role.to_string()),
+            Some(entry.reason),
+            Some(entry.banned_at.to_string()),
+            entry.unbanned_at.map(|unbanned_at| unbanned_at.to_string()),
+            entry
+                .unbanned_at
+                .map(|unbanned_at| (unbanned_at - entry.banned_at).num_seconds().to_string()),
+        ]));
+    }
+
+    res.put(command_complete("SHOW BAN HISTORY"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
+}
+
 /// Reload the configuration file without restarting the process.
-async fn reload<T>(stream: &mut T, client_server_map: ClientServerMap) -> Result<(), Error>
+async fn reload<T>(
+    stream: &mut T,
+    client_server_map: ClientServerMap,
+    tokens: Vec<&str>,
+) -> Result<(), Error>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
 {
-    info!("Reloading config");
+    match tokens[1..] {
+        [] => {
+            info!("Reloading config");
+
+            reload_config(client_server_map).await?;
+        }
+
+        // Only the given pool, or only one of its users.
+        [pool_name] | [pool_name, _] => {
+            let username = tokens.get(2).copied();
+            info!("Reloading config of pool {}", pool_name);
 
-    reload_config(client_server_map).await?;
+            match reload_pool_config(client_server_map, pool_name, username).await {
+                Ok(_) => (),
+                Err(Error::ClientError(err)) => return error_response(stream, &err).await,
+                Err(err) => return Err(err),
+            }
+        }
+
+        _ => return error_response(stream, "usage: RELOAD [pool_name [user]]").await,
+    };
 
     get_config().show();
 
@@ -656,6 +1111,15 @@ where
         ("avg_xact_time", DataType::Numeric),
         ("avg_query_time", DataType::Numeric),
         ("avg_wait_time", DataType::Numeric),
+        ("p50_query_time", DataType::Numeric),
+        ("p90_query_time", DataType::Numeric),
+        ("p99_query_time", DataType::Numeric),
+        ("total_select_count", DataType::Numeric),
+        ("total_insert_count", DataType::Numeric),
+        ("total_update_count", DataType::Numeric),
+        ("total_delete_count", DataType::Numeric),
+        ("total_ddl_count", DataType::Numeric),
+        ("total_other_count", DataType::Numeric),
     ];
 
     let mut res = BytesMut::new();
@@ -702,6 +1166,16 @@ where
         ("age_seconds", DataType::Numeric),
         ("maxwait", DataType::Numeric),
         ("maxwait_us", DataType::Numeric),
+        ("wait", DataType::Numeric),
+        ("wait_us", DataType::Numeric),
+        ("total_wait_us", DataType::Numeric),
+        ("server_address", DataType::Text),
+        ("select_count", DataType::Numeric),
+        ("insert_count", DataType::Numeric),
+        ("update_count", DataType::Numeric),
+        ("delete_count", DataType::Numeric),
+        ("ddl_count", DataType::Numeric),
+        ("other_count", DataType::Numeric),
     ];
 
     let new_map = get_client_stats();
@@ -710,7 +1184,12 @@ where
 
     for (_, client) in new_map {
         let max_wait = client.max_wait_time.load(Ordering::Relaxed);
-        let row = vec![
+        // How long the client has been waiting for a server, if it is.
+        let wait = match client.state.load(Ordering::Relaxed) {
+            ClientState::Waiting => client.get_current_wait_time_us(),
+            _ => 0,
+        };
+        let mut row = vec![
             format!("{:#010X}", client.client_id()),
             client.pool_name(),
             client.username(),
@@ -725,7 +1204,12 @@ where
                 .to_string(),
             (max_wait / 1_000_000).to_string(),
             (max_wait % 1_000_000).to_string(),
+            (wait / 1_000_000).to_string(),
+            (wait % 1_000_000).to_string(),
+            client.total_wait_time.load(Ordering::Relaxed).to_string(),
+            client.server_address().unwrap_or_default(),
         ];
+        row.extend(StatementKind::ALL.map(|kind| client.statement_counts.get(kind).to_string()));
 
         res.put(data_row(&row));
     }
@@ -815,7 +1299,7 @@ where
     write_all_half(stream, &res).await
 }
 
-/// Pause a pool. It won't pass any more queries to the backends.
+/// Pause a pool, or all the pools of a database. They won't pass any more queries to the backends.
 async fn pause<T>(stream: &mut T, tokens: Vec<&str>) -> Result<(), Error>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
@@ -842,6 +1326,37 @@ where
 
             write_all_half(stream, &res).await
         }
+        1 => {
+            let database = parts[0];
+            let pools: Vec<_> = get_all_pools()
+                .into_iter()
+                .filter(|(id, _)| id.db == database)
+                .map(|(_, pool)| pool)
+                .collect();
+
+            if pools.is_empty() {
+                return error_response(
+                    stream,
+                    &format!("No pool configured for database: {}", database),
+                )
+                .await;
+            }
+
+            for pool in pools {
+                pool.pause();
+            }
+
+            let mut res = BytesMut::new();
+
+            res.put(command_complete(&format!("PAUSE {}", database)));
+
+            // ReadyForQuery
+            res.put_u8(b'Z');
+            res.put_i32(5);
+            res.put_u8(b'I');
+
+            write_all_half(stream, &res).await
+        }
         2 => {
             let database = parts[0];
             let user = parts[1];
@@ -874,11 +1389,11 @@ where
                 }
             }
         }
-        _ => error_response(stream, "usage: PAUSE [db, user]").await,
+        _ => error_response(stream, "usage: PAUSE [db[, user]]").await,
     }
 }
 
-/// Resume a pool. Queries are allowed again.
+/// Resume a pool, or all the pools of a database. Queries are allowed again.
 async fn resume<T>(stream: &mut T, tokens: Vec<&str>) -> Result<(), Error>
 where
     T: tokio::io::AsyncWrite + std::marker::Unpin,
@@ -905,6 +1420,37 @@ where
 
             write_all_half(stream, &res).await
         }
+        1 => {
+            let database = parts[0];
+            let pools: Vec<_> = get_all_pools()
+                .into_iter()
+                .filter(|(id, _)| id.db == database)
+                .map(|(_, pool)| pool)
+                .collect();
+
+            if pools.is_empty() {
+                return error_response(
+                    stream,
+                    &format!("No pool configured for database: {}", database),
+                )
+                .await;
+            }
+
+            for pool in pools {
+                pool.resume();
+            }
+
+            let mut res = BytesMut::new();
+
+            res.put(command_complete(&format!("RESUME {}", database)));
+
+            // ReadyForQuery
+            res.put_u8(b'Z');
+            res.put_i32(5);
+            res.put_u8(b'I');
+
+            write_all_half(stream, &res).await
+        }
         2 => {
             let database = parts[0];
             let user = parts[1];
@@ -937,8 +1483,74 @@ where
                 }
             }
         }
-        _ => error_response(stream, "usage: RESUME [db, user]").await,
+        _ => error_response(stream, "usage: RESUME [db[, user]]").await,
+    }
+}
+
+/// Close the connection of a client, rolling back its transaction if it has one.
+async fn kill<T>(stream: &mut T, tokens: Vec<&str>) -> Result<(), Error>
+where
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let usage = "usage: KILL CLIENT client_id";
+
+    match tokens.get(1) {
+        Some(target) if target.eq_ignore_ascii_case("CLIENT") => (),
+        _ => return error_response(stream, usage).await,
+    };
+
+    // As shown by SHOW CLIENTS, e.g. 0x0000002A, or in decimal.
+    let client_id = match tokens.get(2) {
+        Some(client_id) => {
+            let client_id = match client_id
+                .strip_prefix("0x")
+                .or_else(|| client_id.strip_prefix("0X"))
+            {
+                Some(hex) => u32::from_str_radix(hex, 16).map(|id| id as i32),
+                None => client_id.parse::<i32>(),
+            };
+
+            match client_id {
+                Ok(client_id) => client_id,
+                Err(_) => return error_response(stream, "client_id must be an integer").await,
+            }
+        }
+        None => return error_response(stream, usage).await,
+    };
+
+    if tokens.len() > 3 {
+        return error_response(stream, usage).await;
     }
+
+    let killed = match get_client_stats().get(&client_id) {
+        Some(client) => {
+            info!(
+                "Killing client {:#010X} of pool {}, user {} by admin command",
+                client_id,
+                client.pool_name(),
+                client.username()
+            );
+            client.terminate();
+            true
+        }
+        None => false,
+    };
+
+    let columns = vec![("client_id", DataType::Text), ("killed", DataType::Bool)];
+    let mut res = BytesMut::new();
+    res.put(row_description(&columns));
+    res.put(data_row(&vec![
+        format!("{:#010X}", client_id),
+        if killed { "t" } else { "f" }.to_string(),
+    ]));
+    res.put(command_complete("KILL"));
+
+    // ReadyForQuery
+    res.put_u8(b'Z');
+    res.put_i32(5);
+    res.put_u8(b'I');
+
+    write_all_half(stream, &res).await
 }
 
 /// Send response packets for shutdown.
@@ -997,3 +1609,212 @@ where
 
     write_all_half(stream, &res).await
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Address;
+    use crate::pool::{
+        ConnectionPool, PoolIdentifier, PreparedStatementCache, PreparedStatementCacheStats,
+    };
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_ban_rows() {
+        let pool = ConnectionPool::with_banlist(2);
+        let replica = |host: &str, port: u16, shard: usize| Address {
+            host: host.into(),
+            port,
+            shard,
+            ..Default::default()
+        };
+        pool.ban(
+            &replica("replica-b", 5432, 0),
+            BanReason::AdminBan(60),
+            None,
+        );
+        pool.ban(
+            &replica("replica-a", 6432, 1),
+            BanReason::AdminBan(30),
+            None,
+        );
+        pool.ban(
+            &replica("replica-a", 5432, 0),
+            BanReason::AdminBan(90),
+            None,
+        );
+
+        let mut pools = PoolMap::new();
+        pools.insert(PoolIdentifier::new("db", "user"), pool.clone());
+
+        let now = chrono::offset::Utc::now().naive_utc().timestamp() + 10;
+        let rows = ban_rows(&pools, now);
+
+        let hosts: Vec<_> = rows
+            .iter()
+            .map(|row| (row[4].as_str(), row[2].as_str(), row[3].as_str()))
+            .collect();
+        assert_eq!(
+            hosts,
+            vec![
+                ("0", "replica-a", "5432"),
+                ("0", "replica-b", "5432"),
+                ("1", "replica-a", "6432"),
+            ]
+        );
+
+        let row = &rows[0];
+        assert_eq!(row[0], "db");
+        assert_eq!(row[1], "user");
+        assert_eq!(row[5], "replica");
+        assert_eq!(row[6], "AdminBan(90)");
+        assert_eq!(row[8], "90");
+        // Bans are created within the same second or two of `now - 10`.
+        let remaining: i64 = row[9].parse().unwrap();
+        assert!((79..=80).contains(&remaining), "{}", remaining);
+
+        // Expired bans are not shown.
+        let rows = ban_rows(&pools, now + 60);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][2], "replica-a");
+        assert_eq!(rows[0][3], "5432");
+    }
+
+    #[test]
+    fn test_prepared_statement_rows() {
+        let mut pool = ConnectionPool::with_banlist(1);
+        pool.prepared_statement_cache = Some(Arc::new(Mutex::new(PreparedStatementCache::new(10))));
+        let pools = PoolMap::from([
+            (PoolIdentifier::new("db", "user"), pool.clone()),
+            // Without a cache.
+            (
+                PoolIdentifier::new("other", "user"),
+                ConnectionPool::with_banlist(1),
+            ),
+        ]);
+
+        let parse = |name: &str| {
+            let query = "SELECT $1::int";
+            let mut message = BytesMut::new();
+            message.put_u8(b'P');
+            message.put_i32(4 + name.len() as i32 + 1 + query.len() as i32 + 1 + 2);
+            message.put_slice(name.as_bytes());
+            message.put_u8(0);
+            message.put_slice(query.as_bytes());
+            message.put_u8(0);
+            message.put_i16(0);
+            Parse::try_from(&message).unwrap()
+        };
+
+        // Prepared, then prepared again by another client.
+        let first = parse("s1");
+        let first = pool
+            .register_parse_to_cache(first.get_hash(), &first)
+            .unwrap();
+        let second = parse("s2");
+        pool.register_parse_to_cache(second.get_hash(), &second);
+
+        assert_eq!(
+            pool.prepared_statement_cache_stats(),
+            Some(PreparedStatementCacheStats {
+                hits: 1,
+                misses: 1,
+                evictions: 0,
+                size: 1,
+            })
+        );
+        assert_eq!(
+            prepared_statement_rows(&pools),
+            vec![vec![
+                "db".to_string(),
+                "user".to_string(),
+                first.name.clone(),
+                "SELECT $1::int".to_string(),
+                "1".to_string(),
+            ]]
+        );
+    }
+
+    #[test]
+    fn test_to_json_result() {
+        let mut response = BytesMut::new();
+        response.put(row_description(&vec![
+            ("database", DataType::Text),
+            ("cl_idle", DataType::Numeric),
+            ("paused", DataType::Bool),
+        ]));
+        response.put(data_row(&vec!["db".into(), "2".into(), "t".into()]));
+        response.put(data_row_nullable(&vec![Some("other".into()), None, None]));
+        response.put(command_complete("SHOW"));
+        response.put_u8(b'Z');
+        response.put_i32(5);
+        response.put_u8(b'I');
+
+        let mut expected = BytesMut::new();
+        expected.put(row_description(&vec![("json", DataType::Text)]));
+        expected.put(data_row(&vec![
+            r#"[{"cl_idle":2,"database":"db","paused":true},{"cl_idle":null,"database":"other","paused":null}]"#.into(),
+        ]));
+        expected.put(command_complete("SHOW"));
+        expected.put_u8(b'Z');
+        expected.put_i32(5);
+        expected.put_u8(b'I');
+
+        assert_eq!(to_json_result(&response), expected);
+
+        // Errors are passed as is.
+        let error = BytesMut::from(&b"E\0\0\0\x08SXX\0"[..]);
+        assert_eq!(to_json_result(&error), error);
+    }
+
+    #[tokio::test]
+    async fn test_listen() {
+        let mut events = None;
+        let mut response = Vec::new();
+        handle_admin(
+            &mut response,
+            simple_query("LISTEN pgcat_events"),
+            ClientServerMap::default(),
+            &mut events,
+        )
+        .await
+        .unwrap();
+        assert!(events.is_some());
+        assert!(response.starts_with(&command_complete("LISTEN")));
+
+        let pool = ConnectionPool::with_banlist(1);
+        let replica = Address {
+            host: "listened-replica".into(),
+            pool_name: "listened".into(),
+            username: "user".into(),
+            ..Default::default()
+        };
+        pool.ban(&replica, BanReason::AdminBan(60), None);
+
+        // Other tests publish events too.
+        let event = loop {
+            let event = crate::events::next(&mut events).await;
+            if event.pool.as_deref() == Some("listened") {
+                break event;
+            }
+        };
+        assert_eq!(
+            event.payload(),
+            format!(
+                r#"{{"event":"ban","pool":"listened","user":"user","address":"listened-replica:5432","shard":0,"role":"replica","reason":"AdminBan(60)","timestamp":"{}"}}"#,
+                event.timestamp
+            )
+        );
+
+        handle_admin(
+            &mut response,
+            simple_query("UNLISTEN pgcat_events"),
+            ClientServerMap::default(),
+            &mut events,
+        )
+        .await
+        .unwrap();
+        assert!(events.is_none());
+    }
+}
@@ -1,8 +1,50 @@
-use crate::config::AuthType;
+use crate::config::{get_config, Address, AuthType};
 use crate::errors::Error;
 use crate::pool::ConnectionPool;
+use crate::scram::ScramSecret;
 use crate::server::Server;
 use log::debug;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+/// Hashes (or errors) obtained with auth_query, by server and username.
+type AuthHashCache = RwLock<HashMap<AuthHashKey, Arc<tokio::sync::Mutex<Option<CachedAuthHash>>>>>;
+
+static AUTH_HASH_CACHE: Lazy<AuthHashCache> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// ClientKeys recovered from the clients authenticated with a SCRAM secret, by secret.
+/// The secret can't log in to the server, the key can.
+static SCRAM_CLIENT_KEYS: Lazy<RwLock<HashMap<String, [u8; 32]>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+#[derive(Hash, PartialEq, Eq, Clone, Debug)]
+struct AuthHashKey {
+    host: String,
+    port: u16,
+    database: String,
+    username: String,
+}
+
+impl From<&Address> for AuthHashKey {
+    fn from(address: &Address) -> AuthHashKey {
+        AuthHashKey {
+            host: address.host.clone(),
+            port: address.port,
+            database: address.database.clone(),
+            username: address.username.clone(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct CachedAuthHash {
+    hash: Result<String, String>,
+    fetched_at: Instant,
+}
 
 #[derive(Clone, Debug)]
 pub struct AuthPassthrough {
@@ -50,7 +92,7 @@ impl AuthPassthrough {
 
     /// Connects to server and executes auth_query for the specified address.
     /// If the response is a row with two columns containing the username set in the address.
-    /// and its MD5 hash, the MD5 hash returned.
+    /// and its MD5 hash, the MD5 hash returned. A SCRAM secret is returned as is.
     ///
     /// Note that the query is executed, changing $1 with the name of the user
     /// this is so we only hold in memory (and transfer) the least amount of 'sensitive' data.
@@ -81,8 +123,10 @@ impl AuthPassthrough {
             pool_mode: None,
             server_lifetime: None,
             min_pool_size: None,
+            max_pool_size: None,
             connect_timeout: None,
             idle_timeout: None,
+            server_settings: BTreeMap::new(),
         };
 
         let user = &address.username;
@@ -94,16 +138,16 @@ impl AuthPassthrough {
         match Server::exec_simple_query(address, &auth_user, &auth_query).await {
             Ok(password_data) => {
                 if password_data.len() == 2 && password_data.first().unwrap() == user {
-                    if let Some(stripped_hash) = password_data
-                        .last()
-                        .unwrap()
-                        .to_string()
-                        .strip_prefix("md5") {
+                    let hash = password_data.last().unwrap();
+
+                    if ScramSecret::parse(hash).is_some() {
+                        Ok(hash.to_string())
+                    } else if let Some(stripped_hash) = hash.strip_prefix("md5") {
                             Ok(stripped_hash.to_string())
                         }
                     else {
                         Err(Error::AuthPassthroughError(
-                            "Obtained hash from auth_query does not seem to be in md5 format.".to_string(),
+                            "Obtained hash from auth_query does not seem to be in md5 or SCRAM-SHA-256 format.".to_string(),
                         ))
                     }
                 } else {
@@ -121,12 +165,112 @@ impl AuthPassthrough {
             }
         }
     }
+
+    /// Same as `fetch_hash`, but reuses the hash fetched for the same server and username
+    /// within `auth_query_cache_ttl`, or the error within `auth_query_negative_cache_ttl`.
+    pub async fn fetch_hash_cached(&self, address: &Address) -> Result<String, Error> {
+        let general = get_config().general;
+
+        cached_auth_hash(
+            AuthHashKey::from(address),
+            Duration::from_millis(general.auth_query_cache_ttl),
+            Duration::from_millis(general.auth_query_negative_cache_ttl),
+            || self.fetch_hash(address),
+        )
+        .await
+    }
+}
+
+/// Get the hash from the cache if it's fresh enough, fetch it otherwise.
+/// Concurrent logins for the same key wait for a single fetch.
+async fn cached_auth_hash<F, Fut>(
+    key: AuthHashKey,
+    ttl: Duration,
+    negative_ttl: Duration,
+    fetch: F,
+) -> Result<String, Error>
+where
+    F: FnOnce() -> Fut,
+    Fut: Future<Output = Result<String, Error>>,
+{
+    if ttl.is_zero() && negative_ttl.is_zero() {
+        return fetch().await;
+    }
+
+    let entry = {
+        let guard = AUTH_HASH_CACHE.read();
+        guard.get(&key).cloned()
+    };
+    let entry = match entry {
+        Some(entry) => entry,
+        None => AUTH_HASH_CACHE
+            .write()
+            .entry(key.clone())
+            .or_default()
+            .clone(),
+    };
+
+    let mut entry = entry.lock().await;
+
+    if let Some(cached) = entry.as_ref() {
+        let ttl = match cached.hash {
+            Ok(_) => ttl,
+            Err(_) => negative_ttl,
+        };
+
+        if cached.fetched_at.elapsed() < ttl {
+            debug!("Using cached auth_query result for {:?}", key);
+            return cached.hash.clone().map_err(Error::AuthPassthroughError);
+        }
+    }
+
+    let hash = fetch().await;
+
+    *entry = Some(CachedAuthHash {
+        hash: match &hash {
+            Ok(hash) => Ok(hash.clone()),
+            Err(Error::AuthPassthroughError(err)) => Err(err.clone()),
+            Err(err) => Err(err.to_string()),
+        },
+        fetched_at: Instant::now(),
+    });
+
+    hash
+}
+
+/// Forget all the cached auth_query results.
+pub fn clear_auth_hash_cache() {
+    AUTH_HASH_CACHE.write().clear();
+}
+
+/// Remember the key of a client authenticated with the SCRAM secret, for the server connections.
+pub fn set_scram_client_key(secret: &str, client_key: [u8; 32]) {
+    SCRAM_CLIENT_KEYS
+        .write()
+        .insert(secret.to_string(), client_key);
+}
+
+/// Key to log in to the server with, if a client was authenticated with the SCRAM secret.
+pub fn scram_client_key(secret: &str) -> Option<[u8; 32]> {
+    SCRAM_CLIENT_KEYS.read().get(secret).cloned()
+}
+
+/// The hash obtained when the pool was created, or fetched again if there was none.
+pub async fn pool_auth_hash(pool: &ConnectionPool) -> Result<String, Error> {
+    if let Some(hash) = pool.auth_hash.read().clone() {
+        return Ok(hash);
+    }
+
+    let hash = refetch_auth_hash(pool).await?;
+    *pool.auth_hash.write() = Some(hash.clone());
+
+    Ok(hash)
 }
 
 pub async fn refetch_auth_hash(pool: &ConnectionPool) -> Result<String, Error> {
     let address = pool.address(0, 0);
     if let Some(apt) = AuthPassthrough::from_pool_settings(&pool.settings) {
-        let hash = apt.fetch_hash(address).await?;
+        let hash = apt.fetch_hash_cached(address).await?;
 
         return Ok(hash);
     }
@@ -136,3 +280,82 @@ pub async fn refetch_auth_hash(pool: &ConnectionPool) -> Result<String, Error> {
         address.username, address.database
     )))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn key(username: &str) -> AuthHashKey {
+        AuthHashKey {
+            host: "127.0.0.1".into(),
+            port: 5432,
+            database: "test_auth_hash_cache".into(),
+            username: username.into(),
+        }
+    }
+
+    async fn login(username: &str, queries: &AtomicUsize) -> Result<String, Error> {
+        cached_auth_hash(
+            key(username),
+            Duration::from_millis(200),
+            Duration::from_millis(50),
+            || async {
+                queries.fetch_add(1, Ordering::Relaxed);
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                match username {
+                    "bad_user" => Err(Error::AuthPassthroughError("no such user".into())),
+                    _ => Ok(format!("hash_{}", username)),
+                }
+            },
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_auth_hash_cache() {
+        let queries = AtomicUsize::new(0);
+
+        // Repeated and concurrent logins within the TTL query the server once.
+        assert_eq!(login("user", &queries).await.unwrap(), "hash_user");
+        let logins = futures::future::join_all((0..10).map(|_| login("user", &queries))).await;
+        assert!(logins
+            .iter()
+            .all(|hash| hash.as_ref().unwrap() == "hash_user"));
+        assert_eq!(queries.load(Ordering::Relaxed), 1);
+
+        // Failures are cached for the negative TTL.
+        assert!(login("bad_user", &queries).await.is_err());
+        assert!(login("bad_user", &queries).await.is_err());
+        assert_eq!(queries.load(Ordering::Relaxed), 2);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(login("bad_user", &queries).await.is_err());
+        assert_eq!(queries.load(Ordering::Relaxed), 3);
+
+        // Fetched again once the TTL expired.
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        login("user", &queries).await.unwrap();
+        assert_eq!(queries.load(Ordering::Relaxed), 4);
+
+        // And after a reload.
+        clear_auth_hash_cache();
+        login("user", &queries).await.unwrap();
+        assert_eq!(queries.load(Ordering::Relaxed), 5);
+    }
+
+    #[tokio::test]
+    async fn test_auth_hash_cache_disabled() {
+        let queries = AtomicUsize::new(0);
+
+        for _ in 0..3 {
+            cached_auth_hash(key("disabled"), Duration::ZERO, Duration::ZERO, || async {
+                queries.fetch_add(1, Ordering::Relaxed);
+                Ok(String::from("hash"))
+            })
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(queries.load(Ordering::Relaxed), 3);
+    }
+}
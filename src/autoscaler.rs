@@ -0,0 +1,231 @@
+//! Grows and shrinks the number of server connections a pool can use
+//! based on how long clients wait to check one out.
+use log::{debug, info};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Duration;
+
+use crate::config::Address;
+
+/// Shrink after the average wait was low for this many intervals in a row.
+const SHRINK_AFTER_INTERVALS: u32 = 3;
+
+/// Limits the connections checked out from one server and resizes that limit
+/// between `min_size` and `max_size`.
+#[derive(Debug)]
+pub struct Autoscaler {
+    /// Server the connections are for, used in logs.
+    address: Address,
+
+    /// One permit per connection that can be checked out.
+    semaphore: Arc<Semaphore>,
+
+    /// Current number of permits.
+    size: AtomicU32,
+    min_size: u32,
+    max_size: u32,
+
+    /// Grow when the average checkout wait is above this (microseconds).
+    wait_threshold: u64,
+
+    /// How long to wait for a permit before giving up.
+    checkout_timeout: Duration,
+
+    /// Checkout waits since the last decision.
+    wait_time: AtomicU64,
+    wait_count: AtomicU64,
+
+    /// Consecutive intervals with a low average wait.
+    low_intervals: AtomicU32,
+}
+
+impl Autoscaler {
+    pub fn new(
+        address: Address,
+        size: u32,
+        min_size: u32,
+        max_size: u32,
+        wait_threshold: Duration,
+        checkout_timeout: Duration,
+    ) -> Autoscaler {
+        let min_size = min_size.max(1);
+        let size = size.clamp(min_size, max_size);
+
+        address.stats.set_pool_size(size as u64);
+
+        Autoscaler {
+            address,
+            semaphore: Arc::new(Semaphore::new(size as usize)),
+            size: AtomicU32::new(size),
+            min_size,
+            max_size,
+            wait_threshold: wait_threshold.as_micros() as u64,
+            checkout_timeout,
+            wait_time: AtomicU64::new(0),
+            wait_count: AtomicU64::new(0),
+            low_intervals: AtomicU32::new(0),
+        }
+    }
+
+    /// Start making scaling decisions every `interval`, until the autoscaler is dropped.
+    /// Must be called from within the Tokio runtime.
+    pub fn start(autoscaler: &Arc<Autoscaler>, interval: Duration) {
+        let autoscaler = Arc::downgrade(autoscaler);
+
+        tokio::task::spawn(async move {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+
+            loop {
+                interval.tick().await;
+
+                match Weak::upgrade(&autoscaler) {
+                    Some(autoscaler) => {
+                        autoscaler.scale();
+                    }
+                    None => break,
+                }
+            }
+        });
+    }
+
+    /// Wait for a connection to be available under the current size. Returns None
+    /// if none was available before the timeout, or the checkout timeout if not given.
+    pub async fn acquire(&self, timeout: Option<Duration>) -> Option<OwnedSemaphorePermit> {
+        match tokio::time::timeout(
+            timeout.unwrap_or(self.checkout_timeout),
+            self.semaphore.clone().acquire_owned(),
+        )
+        .await
+        {
+            // The semaphore is never closed.
+            Ok(permit) => permit.ok(),
+            Err(_) => None,
+        }
+    }
+
+    /// Record how long a client waited for a connection (microseconds).
+    pub fn record_wait(&self, microseconds: u64) {
+        self.wait_time.fetch_add(microseconds, Ordering::Relaxed);
+        self.wait_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Current number of connections that can be checked out.
+    pub fn size(&self) -> u32 {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// Grow or shrink based on the waits recorded since the last call, returns the new size.
+    pub fn scale(&self) -> u32 {
+        let wait_time = self.wait_time.swap(0, Ordering::Relaxed);
+        let wait_count = self.wait_count.swap(0, Ordering::Relaxed);
+        let average_wait = match wait_count {
+            0 => 0,
+            count => wait_time / count,
+        };
+        let size = self.size();
+
+        if average_wait > self.wait_threshold {
+            self.low_intervals.store(0, Ordering::Relaxed);
+
+            if size < self.max_size {
+                let new_size = (size + (size / 4).max(1)).min(self.max_size);
+                self.semaphore.add_permits((new_size - size) as usize);
+
+                info!(
+                    "Scaling pool for {:?} up from {} to {}, average checkout wait {}us",
+                    self.address, size, new_size, average_wait
+                );
+
+                return self.resize(new_size);
+            }
+        } else if average_wait < self.wait_threshold / 2 {
+            let low_intervals = self.low_intervals.fetch_add(1, Ordering::Relaxed) + 1;
+
+            if low_intervals >= SHRINK_AFTER_INTERVALS && size > self.min_size {
+                // Only take back a permit that's not in use.
+                if let Ok(permit) = self.semaphore.try_acquire() {
+                    permit.forget();
+                    self.low_intervals.store(0, Ordering::Relaxed);
+
+                    info!(
+                        "Scaling pool for {:?} down from {} to {}, average checkout wait {}us",
+                        self.address,
+                        size,
+                        size - 1,
+                        average_wait
+                    );
+
+                    return self.resize(size - 1);
+                }
+            }
+        } else {
+            self.low_intervals.store(0, Ordering::Relaxed);
+        }
+
+        debug!(
+            "Pool for {:?} stays at {}, average checkout wait {}us",
+            self.address, size, average_wait
+        );
+
+        size
+    }
+
+    fn resize(&self, size: u32) -> u32 {
+        self.size.store(size, Ordering::Relaxed);
+        self.address.stats.set_pool_size(size as u64);
+        size
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_autoscaler() {
+        let autoscaler = Arc::new(Autoscaler::new(
+            Address::default(),
+            2,
+            1,
+            4,
+            Duration::from_millis(10),
+            Duration::from_secs(5),
+        ));
+        let busy = vec![
+            autoscaler.acquire(None).await.unwrap(),
+            autoscaler.acquire(None).await.unwrap(),
+        ];
+
+        // The pool is exhausted, a third client has to wait.
+        let waiter = {
+            let autoscaler = autoscaler.clone();
+            tokio::task::spawn(async move {
+                let started = tokio::time::Instant::now();
+                let permit = autoscaler.acquire(None).await;
+                autoscaler.record_wait(started.elapsed().as_micros() as u64);
+                permit
+            })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+
+        // Contention makes it grow, which lets the waiting client through.
+        autoscaler.record_wait(50_000);
+        assert_eq!(autoscaler.scale(), 3);
+        assert!(waiter.await.unwrap().is_some());
+        assert_eq!(autoscaler.address.stats.pool_size(), 3);
+
+        autoscaler.record_wait(50_000);
+        assert_eq!(autoscaler.scale(), 4);
+        autoscaler.record_wait(50_000);
+        assert_eq!(autoscaler.scale(), 4);
+
+        // Low waits shrink it back one step at a time, down to the minimum.
+        drop(busy);
+        let sizes = (0..15).map(|_| autoscaler.scale()).collect::<Vec<u32>>();
+        assert_eq!(sizes, vec![4, 4, 3, 3, 3, 2, 2, 2, 1, 1, 1, 1, 1, 1, 1]);
+        assert_eq!(autoscaler.address.stats.pool_size(), 1);
+    }
+}
@@ -0,0 +1,132 @@
+//! Past bans of the servers, for `SHOW BAN HISTORY`.
+use chrono::naive::NaiveDateTime;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+
+use crate::config::{get_config, Address, Role};
+use crate::notifications::BanEventKind;
+use crate::pool::BanReason;
+
+static BAN_HISTORY: Lazy<Mutex<BanHistory>> = Lazy::new(|| Mutex::new(BanHistory::default()));
+
+/// A server that was banned, and when it was unbanned if it was.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BanHistoryEntry {
+    pub pool: String,
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    pub shard: usize,
+    pub role: Role,
+    pub reason: String,
+    pub banned_at: NaiveDateTime,
+    pub unbanned_at: Option<NaiveDateTime>,
+}
+
+impl BanHistoryEntry {
+    fn is_of(&self, address: &Address) -> bool {
+        self.pool == address.pool_name
+            && self.user == address.username
+            && self.host == address.host
+            && self.port == address.port
+            && self.shard == address.shard
+            && self.role == address.role
+    }
+}
+
+/// The last bans, the newest at the back.
+#[derive(Debug, Default)]
+pub struct BanHistory(VecDeque<BanHistoryEntry>);
+
+impl BanHistory {
+    /// Add a ban, or mark the last ban of the server as over, keeping at most `size` bans.
+    /// Banning a server that is still banned doesn't start a new entry.
+    fn record(
+        &mut self,
+        size: usize,
+        kind: BanEventKind,
+        address: &Address,
+        reason: Option<&BanReason>,
+    ) {
+        let now = chrono::offset::Utc::now().naive_utc();
+        let open = self
+            .0
+            .iter_mut()
+            .rev()
+            .find(|entry| entry.is_of(address) && entry.unbanned_at.is_none());
+
+        match (kind, open) {
+            (BanEventKind::Ban, None) => self.0.push_back(BanHistoryEntry {
+                pool: address.pool_name.clone(),
+                user: address.username.clone(),
+                host: address.host.clone(),
+                port: address.port,
+                shard: address.shard,
+                role: address.role,
+                reason: reason
+                    .map(|reason| format!("{:?}", reason))
+                    .unwrap_or_default(),
+                banned_at: now,
+                unbanned_at: None,
+            }),
+            (BanEventKind::Unban, Some(entry)) => entry.unbanned_at = Some(now),
+            (BanEventKind::Ban, Some(_)) | (BanEventKind::Unban, None) => (),
+        }
+
+        // The size may have been lowered by a reload.
+        while self.0.len() > size {
+            self.0.pop_front();
+        }
+    }
+}
+
+/// Add a ban or unban of a server to the history.
+pub fn record(kind: BanEventKind, address: &Address, reason: Option<&BanReason>) {
+    let size = get_config().general.ban_history_size;
+    BAN_HISTORY.lock().record(size, kind, address, reason);
+}
+
+/// The bans in the history, oldest first.
+pub fn history() -> Vec<BanHistoryEntry> {
+    BAN_HISTORY.lock().0.iter().cloned().collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ban_history() {
+        let mut history = BanHistory::default();
+        let addresses = (0..3)
+            .map(|port| Address {
+                host: "replica".into(),
+                port,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+
+        // Banned twice before being unbanned is a single ban.
+        history.record(2, BanEventKind::Ban, &addresses[0], None);
+        history.record(2, BanEventKind::Ban, &addresses[0], None);
+        assert_eq!(history.0.len(), 1);
+
+        history.record(2, BanEventKind::Unban, &addresses[0], None);
+        history.record(2, BanEventKind::Ban, &addresses[0], None);
+        assert_eq!(history.0.len(), 2);
+        assert!(history.0[0].unbanned_at.is_some());
+        assert!(history.0[1].unbanned_at.is_none());
+
+        // The oldest bans make room for the new ones.
+        history.record(2, BanEventKind::Ban, &addresses[1], None);
+        history.record(2, BanEventKind::Ban, &addresses[2], None);
+        assert_eq!(
+            history.0.iter().map(|entry| entry.port).collect::<Vec<_>>(),
+            [1, 2]
+        );
+
+        history.record(0, BanEventKind::Unban, &addresses[1], None);
+        assert!(history.0.is_empty());
+    }
+}
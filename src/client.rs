@@ -1,30 +1,46 @@
+use crate::client_connections::ClientConnection;
 use crate::errors::{ClientIdentifier, Error};
+use crate::events::{self, EVENTS_CHANNEL};
+use crate::fan_out;
+use crate::jwt;
+use crate::ldap;
 use crate::pool::BanReason;
 /// Handle clients by pretending to be a PostgreSQL server.
 use bytes::{Buf, BufMut, BytesMut};
 use log::{debug, error, info, trace, warn};
 use once_cell::sync::Lazy;
 use std::collections::{HashMap, VecDeque};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::sync::{atomic::AtomicUsize, Arc};
 use std::time::Instant;
 use tokio::io::{split, AsyncReadExt, BufReader, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
 use tokio::sync::broadcast::Receiver;
 use tokio::sync::mpsc::Sender;
 
 use crate::admin::{generate_server_parameters_for_admin, handle_admin};
-use crate::auth_passthrough::refetch_auth_hash;
+use crate::auth_passthrough::{pool_auth_hash, refetch_auth_hash, set_scram_client_key};
 use crate::config::{
-    get_config, get_idle_client_in_transaction_timeout, Address, AuthType, PoolMode,
+    get_config, get_idle_client_in_transaction_timeout, Address, AuthType, PoolMode, Role,
 };
+use crate::connection_log::{self, ByteCounter, LogLineFields};
 use crate::constants::*;
 use crate::messages::*;
-use crate::plugins::PluginOutput;
+use crate::otel::QuerySpan;
+use crate::plugins::audit_log::AuditLog;
+use crate::plugins::query_cache::{QueryCache, QueryCacheEntry};
+use crate::plugins::{PluginOutput, QueryRewrite};
 use crate::pool::{get_pool, ClientServerMap, ConnectionPool};
+use crate::proxy_protocol;
 use crate::query_router::{Command, QueryRouter};
+use crate::scram::{ScramSecret, ScramSha256Server};
 use crate::server::{Server, ServerParameters};
-use crate::stats::{ClientStats, ServerStats};
-use crate::tls::Tls;
+use crate::slow_query::SlowQuery;
+use crate::stats::{ClientStats, ServerStats, StatementKind};
+use crate::tls::{authenticate_certificate, TlsSession, TLS};
+use sqlparser::ast::Statement;
 
 use tokio_rustls::server::TlsStream;
 
@@ -43,11 +59,11 @@ enum ClientConnectionType {
 /// The client state. One of these is created per client.
 pub struct Client<S, T> {
     /// The reads are buffered (8K by default).
-    read: BufReader<S>,
+    read: BufReader<ByteCounter<S>>,
 
     /// We buffer the writes ourselves because we know the protocol
     /// better than a stock buffer.
-    write: T,
+    write: ByteCounter<T>,
 
     /// Internal buffer, where we place messages until we have to flush
     /// them to the backend.
@@ -87,6 +103,12 @@ pub struct Client<S, T> {
     /// Last address the client talked to.
     last_address_id: Option<usize>,
 
+    /// Servers a sticky session is pinned to, one per shard and role it used.
+    sticky_addresses: Vec<Address>,
+
+    /// The sticky parameter sub-pool picked at login, or the pool itself.
+    sticky_pool: Option<ConnectionPool>,
+
     /// Last server process stats we talked to.
     last_server_stats: Option<Arc<ServerStats>>,
 
@@ -113,6 +135,22 @@ pub struct Client<S, T> {
 
     /// Buffered extended protocol data
     extended_protocol_data_buffer: VecDeque<ExtendedProtocolData>,
+
+    /// The client asked for a replication connection, e.g. `replication=database`.
+    replication: Option<String>,
+
+    /// Result of the query being sent, to store in the query cache.
+    query_cache_entry: Option<QueryCacheEntry>,
+
+    /// Kinds of the statements parsed since the last query was sent, for the stats.
+    statements: Vec<StatementKind>,
+
+    /// Named statements were prepared on the server without our prepared statements
+    /// support, the client can't be given another server connection.
+    server_statements: bool,
+
+    /// Counts the client towards max_client_connections while it's connected.
+    _connection: Option<ClientConnection>,
 }
 
 /// Client entrypoint.
@@ -126,7 +164,7 @@ pub async fn client_entrypoint(
     log_client_connections: bool,
 ) -> Result<(), Error> {
     // Figure out if the client wants TLS or not.
-    let addr = match stream.peer_addr() {
+    let peer_addr = match stream.peer_addr() {
         Ok(addr) => addr,
         Err(err) => {
             return Err(Error::SocketError(format!(
@@ -136,6 +174,25 @@ pub async fn client_entrypoint(
         }
     };
 
+    // The client is behind a load balancer sending its address with the PROXY protocol.
+    let proxy_protocol_networks = get_config().general.proxy_protocol_networks;
+    let addr = match proxy_protocol_networks.is_empty() {
+        true => peer_addr,
+        false => {
+            proxy_protocol::client_addr(&mut stream, peer_addr, &proxy_protocol_networks).await?
+        }
+    };
+
+    if log_client_connections {
+        connection_log::log(
+            &LogLineFields {
+                addr: Some(addr),
+                ..Default::default()
+            },
+            &connection_log::connection_received(&addr),
+        );
+    }
+
     match get_startup::<TcpStream>(&mut stream).await {
         // Client requested a TLS connection.
         Ok((ClientConnectionType::Tls, _)) => {
@@ -148,12 +205,16 @@ pub async fn client_entrypoint(
                 write_all(&mut stream, yes).await?;
 
                 // Negotiate TLS.
-                match startup_tls(stream, client_server_map, shutdown, admin_only).await {
+                match startup_tls(stream, addr, client_server_map, shutdown, admin_only).await {
                     Ok(mut client) => {
                         if log_client_connections {
-                            info!("Client {:?} connected (TLS)", addr);
+                            tracing::info!(client_address = %addr, "Client connected (TLS)");
                         } else {
-                            debug!("Client {:?} connected (TLS)", addr);
+                            tracing::debug!(client_address = %addr, "Client connected (TLS)");
+                        }
+
+                        if log_client_connections {
+                            client.log_connection_authorized();
                         }
 
                         if !client.is_admin() {
@@ -162,6 +223,10 @@ pub async fn client_entrypoint(
 
                         let result = client.handle().await;
 
+                        if get_config().general.log_client_disconnections {
+                            client.log_disconnection();
+                        }
+
                         if !client.is_admin() {
                             let _ = drain.send(-1).await;
                         }
@@ -187,45 +252,17 @@ pub async fn client_entrypoint(
                 match get_startup::<TcpStream>(&mut stream).await {
                     // Client accepted unencrypted connection.
                     Ok((ClientConnectionType::Startup, bytes)) => {
-                        let (read, write) = split(stream);
-
-                        // Continue with regular startup.
-                        match Client::startup(
-                            read,
-                            write,
+                        plain_client(
+                            stream,
                             addr,
                             bytes,
                             client_server_map,
                             shutdown,
+                            drain,
                             admin_only,
+                            log_client_connections,
                         )
                         .await
-                        {
-                            Ok(mut client) => {
-                                if log_client_connections {
-                                    info!("Client {:?} connected (plain)", addr);
-                                } else {
-                                    debug!("Client {:?} connected (plain)", addr);
-                                }
-
-                                if !client.is_admin() {
-                                    let _ = drain.send(1).await;
-                                }
-
-                                let result = client.handle().await;
-
-                                if !client.is_admin() {
-                                    let _ = drain.send(-1).await;
-                                }
-
-                                if result.is_err() {
-                                    client.stats.disconnect();
-                                }
-
-                                result
-                            }
-                            Err(err) => Err(err),
-                        }
                     }
 
                     // Client probably disconnected rejecting our plain text connection.
@@ -241,78 +278,189 @@ pub async fn client_entrypoint(
 
         // Client wants to use plain connection without encryption.
         Ok((ClientConnectionType::Startup, bytes)) => {
-            let (read, write) = split(stream);
-
-            // Continue with regular startup.
-            match Client::startup(
-                read,
-                write,
+            plain_client(
+                stream,
                 addr,
                 bytes,
                 client_server_map,
                 shutdown,
+                drain,
                 admin_only,
+                log_client_connections,
             )
             .await
-            {
-                Ok(mut client) => {
-                    if log_client_connections {
-                        info!("Client {:?} connected (plain)", addr);
-                    } else {
-                        debug!("Client {:?} connected (plain)", addr);
-                    }
+        }
 
-                    if !client.is_admin() {
-                        let _ = drain.send(1).await;
-                    }
+        // Client wants to cancel a query.
+        Ok((ClientConnectionType::CancelQuery, bytes)) => {
+            cancel_client(stream, addr, bytes, client_server_map, shutdown, drain).await
+        }
 
-                    let result = client.handle().await;
+        // Something failed, probably the socket.
+        Err(err) => Err(err),
+    }
+}
 
-                    if !client.is_admin() {
-                        let _ = drain.send(-1).await;
-                    }
+/// Address Unix socket clients are seen as connecting from, e.g. in `SHOW CLIENTS`
+/// and by `allowed_networks`.
+pub const UNIX_SOCKET_CLIENT_ADDR: SocketAddr =
+    SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 0));
 
-                    if result.is_err() {
-                        client.stats.disconnect();
-                    }
+/// Client entrypoint for the connections to the Unix socket, which don't support TLS.
+#[cfg(unix)]
+pub async fn unix_client_entrypoint(
+    mut stream: UnixStream,
+    client_server_map: ClientServerMap,
+    shutdown: Receiver<()>,
+    drain: Sender<i32>,
+    admin_only: bool,
+    log_client_connections: bool,
+) -> Result<(), Error> {
+    let addr = UNIX_SOCKET_CLIENT_ADDR;
 
-                    result
-                }
-                Err(err) => Err(err),
-            }
+    if log_client_connections {
+        connection_log::log(
+            &LogLineFields::default(),
+            "connection received: host=[local]",
+        );
+    }
+
+    let mut startup = get_startup::<UnixStream>(&mut stream).await;
+
+    // Like Postgres, no TLS over the socket, the client can go on without it.
+    if let Ok((ClientConnectionType::Tls, _)) = startup {
+        write_all(&mut stream, BytesMut::from(&b"N"[..])).await?;
+        startup = get_startup::<UnixStream>(&mut stream).await;
+    }
+
+    match startup {
+        Ok((ClientConnectionType::Startup, bytes)) => {
+            plain_client(
+                stream,
+                addr,
+                bytes,
+                client_server_map,
+                shutdown,
+                drain,
+                admin_only,
+                log_client_connections,
+            )
+            .await
         }
 
-        // Client wants to cancel a query.
         Ok((ClientConnectionType::CancelQuery, bytes)) => {
-            let (read, write) = split(stream);
+            cancel_client(stream, addr, bytes, client_server_map, shutdown, drain).await
+        }
 
-            // Continue with cancel query request.
-            match Client::cancel(read, write, addr, bytes, client_server_map, shutdown).await {
-                Ok(mut client) => {
-                    info!("Client {:?} issued a cancel query request", addr);
+        Ok((ClientConnectionType::Tls, _)) => Err(Error::ProtocolSyncError(
+            "Bad postgres client (unix socket)".into(),
+        )),
 
-                    if !client.is_admin() {
-                        let _ = drain.send(1).await;
-                    }
+        Err(err) => Err(err),
+    }
+}
+
+/// Log in a client that doesn't use TLS and serve it until it disconnects.
+#[allow(clippy::too_many_arguments)]
+async fn plain_client<S>(
+    stream: S,
+    addr: SocketAddr,
+    bytes: BytesMut,
+    client_server_map: ClientServerMap,
+    shutdown: Receiver<()>,
+    drain: Sender<i32>,
+    admin_only: bool,
+    log_client_connections: bool,
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let (read, write) = split(stream);
+
+    // Continue with regular startup.
+    match Client::startup(
+        read,
+        write,
+        addr,
+        bytes,
+        client_server_map,
+        shutdown,
+        admin_only,
+        None,
+    )
+    .await
+    {
+        Ok(mut client) => {
+            if log_client_connections {
+                tracing::info!(client_address = %addr, "Client connected (plain)");
+            } else {
+                tracing::debug!(client_address = %addr, "Client connected (plain)");
+            }
 
-                    let result = client.handle().await;
+            if log_client_connections {
+                client.log_connection_authorized();
+            }
 
-                    if !client.is_admin() {
-                        let _ = drain.send(-1).await;
-                    }
+            if !client.is_admin() {
+                let _ = drain.send(1).await;
+            }
 
-                    if result.is_err() {
-                        client.stats.disconnect();
-                    }
+            let result = client.handle().await;
 
-                    result
-                }
+            if get_config().general.log_client_disconnections {
+                client.log_disconnection();
+            }
 
-                Err(err) => Err(err),
+            if !client.is_admin() {
+                let _ = drain.send(-1).await;
             }
+
+            if result.is_err() {
+                client.stats.disconnect();
+            }
+
+            result
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Forward the cancel request of a client to the server running its query.
+async fn cancel_client<S>(
+    stream: S,
+    addr: SocketAddr,
+    bytes: BytesMut,
+    client_server_map: ClientServerMap,
+    shutdown: Receiver<()>,
+    drain: Sender<i32>,
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let (read, write) = split(stream);
+
+    // Continue with cancel query request.
+    match Client::cancel(read, write, addr, bytes, client_server_map, shutdown).await {
+        Ok(mut client) => {
+            tracing::info!(client_address = %addr, "Client issued a cancel query request");
+
+            if !client.is_admin() {
+                let _ = drain.send(1).await;
+            }
+
+            let result = client.handle().await;
+
+            if !client.is_admin() {
+                let _ = drain.send(-1).await;
+            }
+
+            if result.is_err() {
+                client.stats.disconnect();
+            }
+
+            result
         }
 
-        // Something failed, probably the socket.
         Err(err) => Err(err),
     }
 }
@@ -343,7 +491,22 @@ where
         SSL_REQUEST_CODE => Ok((ClientConnectionType::Tls, bytes)),
 
         // Client wants to use plain text, requesting regular startup.
-        PROTOCOL_VERSION_NUMBER => Ok((ClientConnectionType::Startup, bytes)),
+        // Any 3.x version is fine, we tell the client to fall back to 3.0
+        // if it asked for more than that.
+        code if code >> 16 == PROTOCOL_VERSION_NUMBER >> 16 => {
+            let options = unsupported_protocol_options(&bytes);
+
+            if code != PROTOCOL_VERSION_NUMBER || !options.is_empty() {
+                debug!(
+                    "Client requested protocol 3.{} with options {:?}, negotiating 3.0",
+                    code & 0xffff,
+                    options
+                );
+                negotiate_protocol_version(stream, &options).await?;
+            }
+
+            Ok((ClientConnectionType::Startup, bytes))
+        }
 
         // Client is requesting to cancel a running query (plain text connection).
         CANCEL_REQUEST_CODE => Ok((ClientConnectionType::CancelQuery, bytes)),
@@ -357,23 +520,154 @@ where
     }
 }
 
+/// Protocol options in the startup parameters, e.g. `_pq_.compression`. We don't support any.
+fn unsupported_protocol_options(bytes: &BytesMut) -> Vec<String> {
+    let mut options = match parse_params(bytes.clone()) {
+        Ok(parameters) => parameters
+            .into_keys()
+            .filter(|key| key.starts_with("_pq_."))
+            .collect::<Vec<_>>(),
+        // The startup will be rejected later.
+        Err(_) => Vec::new(),
+    };
+
+    options.sort();
+    options
+}
+
+/// Output of the plugins for a query sent with the extended protocol, which can't be
+/// rewritten: the parameters the client describes and binds could no longer match.
+fn extended_protocol_output(output: PluginOutput) -> PluginOutput {
+    match output {
+        PluginOutput::Overwrite(_) => PluginOutput::Deny(
+            "queries sent with the extended protocol can't be rewritten by a plugin".into(),
+        ),
+        output => output,
+    }
+}
+
+/// Error of a plugin routing a query to a shard the transaction isn't on.
+fn route_in_transaction_error(shard: usize, address: &Address) -> Option<String> {
+    (shard != address.shard).then(|| {
+        format!(
+            "a plugin routed the query to shard {} but the transaction is on shard {}",
+            shard, address.shard
+        )
+    })
+}
+
+/// Fill `{application_name}` and `{client_id}` in an `application_name_format`. The id is the
+/// `client_id` of `SHOW CLIENTS`.
+fn format_application_name(format: &str, application_name: &str, client_id: i32) -> String {
+    format
+        .replace("{client_id}", &format!("{:#010X}", client_id))
+        .replace("{application_name}", application_name)
+}
+
+/// Read a cleartext PasswordMessage, sent after `plain_password_challenge`.
+async fn read_password<S>(
+    stream: &mut S,
+    client_identifier: &ClientIdentifier,
+) -> Result<String, Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+{
+    let password_response = read_password_message(stream, client_identifier).await?;
+
+    // The password is a null-terminated string.
+    let password = String::from_utf8_lossy(&password_response);
+    Ok(password.trim_end_matches('\0').to_string())
+}
+
+/// SCRAM-SHA-256 exchange with the client, offering channel binding if it's connected with TLS.
+async fn scram_authenticate<S, T>(
+    read: &mut S,
+    write: &mut T,
+    scram: &mut ScramSha256Server,
+    client_identifier: &ClientIdentifier,
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+    T: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    sasl_challenge(write, &scram.mechanisms()).await?;
+
+    // SASLInitialResponse
+    let response = read_password_message(read, client_identifier).await?;
+    let (mechanism, client_first) = parse_sasl_initial_response(&response)?;
+    let server_first = scram.first(&mechanism, &client_first)?;
+    sasl_message(write, SASL_CONTINUE, server_first.as_bytes()).await?;
+
+    // SASLResponse
+    let client_final = read_password_message(read, client_identifier).await?;
+    let server_final = scram.finish(&client_final)?;
+    sasl_message(write, SASL_FINAL, server_final.as_bytes()).await
+}
+
+/// Read the content of a PasswordMessage, or of a SASL response sent with the same code.
+async fn read_password_message<S>(
+    stream: &mut S,
+    client_identifier: &ClientIdentifier,
+) -> Result<Vec<u8>, Error>
+where
+    S: tokio::io::AsyncRead + std::marker::Unpin,
+{
+    let code = match stream.read_u8().await {
+        Ok(p) => p,
+        Err(_) => {
+            return Err(Error::ClientSocketError(
+                "password code".into(),
+                client_identifier.clone(),
+            ))
+        }
+    };
+
+    // PasswordMessage
+    if code as char != 'p' {
+        return Err(Error::ProtocolSyncError(format!(
+            "Expected p, got {}",
+            code as char
+        )));
+    }
+
+    let len = match stream.read_i32().await {
+        Ok(len) if (4..=MAX_PASSWORD_MESSAGE_LENGTH).contains(&len) => len,
+        _ => {
+            return Err(Error::ClientSocketError(
+                "password message length".into(),
+                client_identifier.clone(),
+            ))
+        }
+    };
+
+    let mut password_response = vec![0u8; (len - 4) as usize];
+
+    match stream.read_exact(&mut password_response).await {
+        Ok(_) => (),
+        Err(_) => {
+            return Err(Error::ClientSocketError(
+                "password message".into(),
+                client_identifier.clone(),
+            ))
+        }
+    };
+
+    Ok(password_response)
+}
+
 /// Handle TLS connection negotiation.
 pub async fn startup_tls(
     stream: TcpStream,
+    addr: SocketAddr,
     client_server_map: ClientServerMap,
     shutdown: Receiver<()>,
     admin_only: bool,
 ) -> Result<Client<ReadHalf<TlsStream<TcpStream>>, WriteHalf<TlsStream<TcpStream>>>, Error> {
     // Negotiate TLS.
-    let tls = Tls::new()?;
-    let addr = match stream.peer_addr() {
-        Ok(addr) => addr,
-        Err(err) => {
-            return Err(Error::SocketError(format!(
-                "Failed to get peer address: {:?}",
-                err
-            )));
-        }
+    let tls = TLS.load_full();
+    let tls = match tls.as_ref() {
+        Some(tls) => tls,
+        None => return Err(Error::TlsError),
     };
 
     let mut stream = match tls.acceptor.accept(stream).await {
@@ -386,6 +680,16 @@ pub async fn startup_tls(
         }
     };
 
+    let session = TlsSession {
+        // Verified by the acceptor if client certificates are enabled.
+        client_certificate: stream
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(|certs| certs.first().cloned()),
+        channel_binding: tls.channel_binding.clone(),
+    };
+
     // TLS negotiation successful.
     // Continue with regular startup using encrypted connection.
     match get_startup::<TlsStream<TcpStream>>(&mut stream).await {
@@ -402,6 +706,7 @@ pub async fn startup_tls(
                 client_server_map,
                 shutdown,
                 admin_only,
+                Some(session),
             )
             .await
         }
@@ -424,18 +729,57 @@ where
         self.admin
     }
 
+    /// What the `log_line_prefix` escapes are replaced with for this client.
+    fn log_line_fields(&self) -> LogLineFields<'_> {
+        LogLineFields {
+            user: &self.username,
+            database: &self.pool_name,
+            application_name: self.server_parameters.get_application_name(),
+            addr: Some(self.addr),
+            process_id: Some(self.process_id),
+        }
+    }
+
+    /// Log that the client logged in, like Postgres with `log_connections`.
+    pub fn log_connection_authorized(&self) {
+        let fields = self.log_line_fields();
+        connection_log::log(&fields, &connection_log::connection_authorized(&fields));
+    }
+
+    /// Log that the client disconnected, with how long it was connected and the bytes
+    /// it sent and received, like Postgres with `log_disconnections`.
+    pub fn log_disconnection(&self) {
+        let fields = self.log_line_fields();
+        connection_log::log(
+            &fields,
+            &connection_log::disconnection(
+                &fields,
+                self.stats.connect_time().elapsed(),
+                self.read.get_ref().count(),
+                self.write.count(),
+            ),
+        );
+    }
+
     /// Handle Postgres client startup after TLS negotiation is complete
     /// or over plain text.
+    #[allow(clippy::too_many_arguments)]
     pub async fn startup(
-        mut read: S,
-        mut write: T,
+        read: S,
+        write: T,
         addr: std::net::SocketAddr,
         bytes: BytesMut, // The rest of the startup message.
         client_server_map: ClientServerMap,
         shutdown: Receiver<()>,
         admin_only: bool,
+        tls: Option<TlsSession>,
     ) -> Result<Client<S, T>, Error> {
-        let parameters = parse_startup(bytes.clone())?;
+        let mut read = ByteCounter::new(read);
+        let mut write = ByteCounter::new(write);
+        let mut parameters = parse_startup(bytes.clone())?;
+
+        // Declined in `negotiate_protocol_version`, they're not server parameters.
+        parameters.retain(|key, _| !key.starts_with("_pq_."));
 
         // This parameter is mandatory by the protocol.
         let username = match parameters.get("user") {
@@ -447,7 +791,24 @@ where
             }
         };
 
+        let replication = match parameters.get("replication") {
+            Some(replication) => match replication.to_lowercase().as_str() {
+                "false" | "off" | "no" | "0" => None,
+                _ => Some(replication.clone()),
+            },
+            None => None,
+        };
+
         let pool_name = match parameters.get("database") {
+            // Physical replication clients, e.g. pg_receivewal, always ask for
+            // the "replication" database, they don't choose one.
+            Some(db)
+                if db == "replication"
+                    && replication.is_some()
+                    && replication.as_deref() != Some("database") =>
+            {
+                username
+            }
             Some(db) => db,
             None => username,
         };
@@ -465,6 +826,17 @@ where
             .count()
             == 1;
 
+        if admin && replication.is_some() {
+            error_response_terminal(
+                &mut write,
+                "replication connections to the admin database are not supported",
+            )
+            .await?;
+            return Err(Error::ClientError(
+                "Replication connection to the admin database".into(),
+            ));
+        }
+
         if !admin && admin_only {
             // Kick any client that's not admin while we're in admin-only mode.
             debug!(
@@ -485,9 +857,33 @@ where
 
         let mut prepared_statements_enabled = false;
 
+        // The admin clients aren't counted.
+        let mut connection = None;
+
         // Authenticate admin user.
-        let (transaction_mode, mut server_parameters) = if admin {
+        let (transaction_mode, mut server_parameters, sticky_pool) = if admin {
             let config = get_config();
+
+            // Reject disallowed networks before asking for a password.
+            if let Some(ref admin_config) = config.admin {
+                if !admin_config.allows(addr.ip()) {
+                    error_response_terminal(
+                        &mut write,
+                        &format!(
+                            "admin database access not allowed from {}",
+                            addr.ip().to_canonical()
+                        ),
+                    )
+                    .await?;
+
+                    return Err(Error::ClientGeneralError(
+                        "Admin access from a disallowed network".into(),
+                        client_identifier,
+                    ));
+                }
+            }
+
+            let (admin_username, admin_password) = config.admin_credentials();
             // TODO: Add SASL support.
             // Perform MD5 authentication.
             match config.general.admin_auth_type {
@@ -536,11 +932,7 @@ where
                     };
 
                     // Compare server and client hashes.
-                    let password_hash = md5_hash_password(
-                        &config.general.admin_username,
-                        &config.general.admin_password,
-                        &salt,
-                    );
+                    let password_hash = md5_hash_password(admin_username, admin_password, &salt);
 
                     if password_hash != password_response {
                         let error =
@@ -552,8 +944,51 @@ where
                         return Err(error);
                     }
                 }
+                AuthType::Scram => {
+                    let mut scram = ScramSha256Server::new(
+                        admin_password,
+                        tls.as_ref().map(|tls| tls.channel_binding.clone()),
+                    );
+
+                    if let Err(err) =
+                        scram_authenticate(&mut read, &mut write, &mut scram, &client_identifier)
+                            .await
+                    {
+                        warn!(
+                            "SCRAM authentication failed for {}: {}",
+                            client_identifier, err
+                        );
+                        wrong_password(&mut write, username).await?;
+                        return Err(Error::ClientGeneralError(
+                            "Invalid password".into(),
+                            client_identifier,
+                        ));
+                    }
+                }
+                AuthType::Cert => {
+                    if let Err(err) = authenticate_certificate(
+                        &config,
+                        tls.as_ref().and_then(|tls| tls.client_certificate.as_ref()),
+                        admin_username,
+                    ) {
+                        warn!(
+                            "Certificate authentication failed for {}: {}",
+                            client_identifier, err
+                        );
+                        wrong_password(&mut write, username).await?;
+                        return Err(Error::ClientGeneralError(
+                            "Invalid password".into(),
+                            client_identifier,
+                        ));
+                    }
+                }
+                // Rejected when validating the config.
+                AuthType::Ldap | AuthType::Jwt => {
+                    wrong_password(&mut write, username).await?;
+                    return Err(Error::ClientAuthImpossible(username.into()));
+                }
             }
-            (false, generate_server_parameters_for_admin())
+            (false, generate_server_parameters_for_admin(), None)
         }
         // Authenticate normal user.
         else {
@@ -576,6 +1011,49 @@ where
                 }
             };
 
+            // Refuse disallowed networks before asking for a password.
+            let config = get_config();
+            if let Some(pool_config) = config.pools.get(pool_name) {
+                if !pool_config.allows(addr.ip()) {
+                    info!(
+                        "Rejecting client from {} to pool {}, its network is not allowed",
+                        addr.ip().to_canonical(),
+                        pool_name
+                    );
+                    error_response_terminal(
+                        &mut write,
+                        &format!(
+                            "access to database \"{}\" not allowed from {}",
+                            pool_name,
+                            addr.ip().to_canonical()
+                        ),
+                    )
+                    .await?;
+                    return Err(Error::ClientGeneralError(
+                        "Connection from a disallowed network".into(),
+                        client_identifier,
+                    ));
+                }
+            }
+
+            // Refuse the client before authenticating it once there are too many.
+            connection = match ClientConnection::new(
+                pool_name,
+                config.general.max_client_connections,
+                config
+                    .pools
+                    .get(pool_name)
+                    .and_then(|pool| pool.max_client_connections),
+            ) {
+                Ok(connection) => Some(connection),
+                Err(Error::ClientError(err)) => {
+                    warn!("Refusing {}: {}", client_identifier, err);
+                    error_response_terminal(&mut write, &err).await?;
+                    return Err(Error::ClientGeneralError(err, client_identifier));
+                }
+                Err(err) => return Err(err),
+            };
+
             // Obtain the hash to compare, we give preference to that written in cleartext in config
             // if there is nothing set in cleartext and auth passthrough (auth_query) is configured, we use the hash obtained
             // when the pool was created. If there is no hash there, we try to fetch it one more time.
@@ -670,7 +1148,17 @@ where
                             }
                         };
 
-                        Some(md5_hash_second_pass(&hash.unwrap(), &salt))
+                        let hash = hash.unwrap();
+
+                        if ScramSecret::parse(&hash).is_some() {
+                            wrong_password(&mut write, username).await?;
+                            return Err(Error::ClientAuthPassthroughError(
+                                "auth_query returned a SCRAM secret, use auth_type scram".into(),
+                                client_identifier,
+                            ));
+                        }
+
+                        Some(md5_hash_second_pass(&hash, &salt))
                     };
 
                     // Once we have the resulting hash, we compare with what the client gave us.
@@ -715,6 +1203,142 @@ where
                         }
                     }
                 }
+                AuthType::Ldap => {
+                    // The LDAP server needs the password in cleartext.
+                    plain_password_challenge(&mut write).await?;
+                    let password = read_password(&mut read, &client_identifier).await?;
+
+                    let ldap = match get_config().ldap {
+                        Some(ldap) => ldap,
+                        None => {
+                            wrong_password(&mut write, username).await?;
+                            return Err(Error::ClientAuthImpossible(username.into()));
+                        }
+                    };
+
+                    if let Err(err) = ldap::authenticate(&ldap, username, &password).await {
+                        warn!(
+                            "LDAP authentication failed for {}: {}",
+                            client_identifier, err
+                        );
+                        wrong_password(&mut write, username).await?;
+                        return Err(Error::ClientGeneralError(
+                            "Invalid password".into(),
+                            client_identifier,
+                        ));
+                    }
+                }
+                AuthType::Scram => {
+                    let channel_binding = tls.as_ref().map(|tls| tls.channel_binding.clone());
+
+                    // Without a password in the config, clients are authenticated with the
+                    // SCRAM secret obtained with auth_query.
+                    let (mut scram, secret) = match &pool.settings.user.password {
+                        Some(password) => (ScramSha256Server::new(password, channel_binding), None),
+                        None => {
+                            if !get_config().is_auth_query_configured() {
+                                wrong_password(&mut write, username).await?;
+                                return Err(Error::ClientAuthImpossible(username.into()));
+                            }
+
+                            let hash = match pool_auth_hash(&pool).await {
+                                Ok(hash) => hash,
+                                Err(err) => {
+                                    wrong_password(&mut write, username).await?;
+                                    return Err(Error::ClientAuthPassthroughError(
+                                        err.to_string(),
+                                        client_identifier,
+                                    ));
+                                }
+                            };
+
+                            match ScramSecret::parse(&hash) {
+                                Some(secret) => (
+                                    ScramSha256Server::from_secret(secret, channel_binding),
+                                    Some(hash),
+                                ),
+                                None => {
+                                    wrong_password(&mut write, username).await?;
+                                    return Err(Error::ClientAuthPassthroughError(
+                                        "auth_query did not return a SCRAM secret".into(),
+                                        client_identifier,
+                                    ));
+                                }
+                            }
+                        }
+                    };
+
+                    if let Err(err) =
+                        scram_authenticate(&mut read, &mut write, &mut scram, &client_identifier)
+                            .await
+                    {
+                        warn!(
+                            "SCRAM authentication failed for {}: {}",
+                            client_identifier, err
+                        );
+
+                        // The password may have changed on the server, the next login will
+                        // use the new secret.
+                        if secret.is_some() {
+                            if let Ok(fetched_hash) = refetch_auth_hash(&pool).await {
+                                *pool.auth_hash.write() = Some(fetched_hash);
+                            }
+                        }
+
+                        wrong_password(&mut write, username).await?;
+                        return Err(Error::ClientGeneralError(
+                            "Invalid password".into(),
+                            client_identifier,
+                        ));
+                    }
+
+                    // The servers are logged in to with the key of the client.
+                    if let (Some(secret), Some(client_key)) = (secret, scram.client_key()) {
+                        set_scram_client_key(&secret, client_key);
+                    }
+                }
+                AuthType::Cert => {
+                    if let Err(err) = authenticate_certificate(
+                        &get_config(),
+                        tls.as_ref().and_then(|tls| tls.client_certificate.as_ref()),
+                        username,
+                    ) {
+                        warn!(
+                            "Certificate authentication failed for {}: {}",
+                            client_identifier, err
+                        );
+                        wrong_password(&mut write, username).await?;
+                        return Err(Error::ClientGeneralError(
+                            "Invalid password".into(),
+                            client_identifier,
+                        ));
+                    }
+                }
+                AuthType::Jwt => {
+                    // The token is sent in place of the password.
+                    plain_password_challenge(&mut write).await?;
+                    let token = read_password(&mut read, &client_identifier).await?;
+
+                    let config = match get_config().jwt {
+                        Some(config) => config,
+                        None => {
+                            wrong_password(&mut write, username).await?;
+                            return Err(Error::ClientAuthImpossible(username.into()));
+                        }
+                    };
+
+                    if let Err(err) = jwt::authenticate(&config, username, &token).await {
+                        warn!(
+                            "JWT authentication failed for {}: {}",
+                            client_identifier, err
+                        );
+                        wrong_password(&mut write, username).await?;
+                        return Err(Error::ClientGeneralError(
+                            "Invalid password".into(),
+                            client_identifier,
+                        ));
+                    }
+                }
             }
             let transaction_mode = pool.settings.pool_mode == PoolMode::Transaction;
             prepared_statements_enabled =
@@ -739,7 +1363,27 @@ where
                 }
             }
 
-            (transaction_mode, pool.server_parameters())
+            // Picked once, the clients already connected keep their sub-pool.
+            let sticky_pool = match pool.sticky_pool(&parameters) {
+                Some(sticky_pool) => sticky_pool,
+                None => {
+                    error_response_terminal(
+                        &mut write,
+                        "too many different sticky parameter values in use, try again later",
+                    )
+                    .await?;
+                    return Err(Error::ClientGeneralError(
+                        "Too many sticky sub-pools".into(),
+                        client_identifier,
+                    ));
+                }
+            };
+
+            (
+                transaction_mode,
+                pool.server_parameters(),
+                Some(sticky_pool),
+            )
         };
 
         // Update the parameters to merge what the application sent and what's originally on the server
@@ -776,6 +1420,8 @@ where
             stats,
             admin,
             last_address_id: None,
+            sticky_addresses: Vec::new(),
+            sticky_pool,
             last_server_stats: None,
             connected_to_server: false,
             pool_name: pool_name.clone(),
@@ -785,6 +1431,11 @@ where
             prepared_statements_enabled,
             prepared_statements: HashMap::new(),
             extended_protocol_data_buffer: VecDeque::new(),
+            replication,
+            query_cache_entry: None,
+            statements: Vec::new(),
+            server_statements: false,
+            _connection: connection,
         })
     }
 
@@ -800,8 +1451,8 @@ where
         let process_id = bytes.get_i32();
         let secret_key = bytes.get_i32();
         Ok(Client {
-            read: BufReader::new(read),
-            write,
+            read: BufReader::new(ByteCounter::new(read)),
+            write: ByteCounter::new(write),
             buffer: BytesMut::with_capacity(8196),
             response_message_queue_buffer: BytesMut::with_capacity(8196),
             addr,
@@ -814,6 +1465,8 @@ where
             stats: Arc::new(ClientStats::default()),
             admin: false,
             last_address_id: None,
+            sticky_addresses: Vec::new(),
+            sticky_pool: None,
             last_server_stats: None,
             connected_to_server: false,
             pool_name: String::from("undefined"),
@@ -823,6 +1476,11 @@ where
             prepared_statements_enabled: false,
             prepared_statements: HashMap::new(),
             extended_protocol_data_buffer: VecDeque::new(),
+            replication: None,
+            query_cache_entry: None,
+            statements: Vec::new(),
+            server_statements: false,
+            _connection: None,
         })
     }
 
@@ -864,6 +1522,9 @@ where
         // Result returned by one of the plugins.
         let mut plugin_output = None;
 
+        // Span of the query being sent, if it's traced.
+        let mut query_span: Option<QuerySpan> = None;
+
         let client_identifier = ClientIdentifier::new(
             self.server_parameters.get_application_name(),
             &self.username,
@@ -880,9 +1541,17 @@ where
             self.get_pool().await?
         };
 
+        if let Some(replication) = self.replication.clone() {
+            return self.replicate(&pool, &replication).await;
+        }
+
         query_router.update_pool_settings(&pool.settings);
+        query_router.set_application_name(self.server_parameters.get_application_name());
         query_router.set_default_role();
 
+        // Pool events of an admin client listening to them.
+        let mut admin_events = None;
+
         // Our custom protocol loop.
         // We expect the client to either start a transaction with regular queries
         // or issue commands for our sharding and server selection protocol.
@@ -898,7 +1567,18 @@ where
             // in case the client is sending some custom protocol messages, e.g.
             // SET SHARDING KEY TO 'bigint';
 
-            let message = tokio::select! {
+            let stats = self.stats.clone();
+
+            let mut message = tokio::select! {
+                _ = stats.terminated() => {
+                    error_response_terminal(
+                        &mut self.write,
+                        "terminating connection due to administrator command"
+                    ).await?;
+
+                    self.stats.disconnect();
+                    return Ok(());
+                },
                 _ = self.shutdown.recv() => {
                     if !self.admin {
                         error_response_terminal(
@@ -915,6 +1595,15 @@ where
                         read_message(&mut self.read).await?
                     }
                 },
+                event = events::next(&mut admin_events) => {
+                    let notification = notification_response(
+                        std::process::id() as i32,
+                        EVENTS_CHANNEL,
+                        &event.payload(),
+                    );
+                    write_all_flush(&mut self.write, &notification).await?;
+                    continue;
+                },
                 message_result = read_message(&mut self.read) => message_result?
             };
 
@@ -929,7 +1618,13 @@ where
             // Handle admin database queries.
             if self.admin {
                 debug!("Handling admin command");
-                handle_admin(&mut self.write, message, self.client_server_map.clone()).await?;
+                handle_admin(
+                    &mut self.write,
+                    message,
+                    self.client_server_map.clone(),
+                    &mut admin_events,
+                )
+                .await?;
                 continue;
             }
 
@@ -942,39 +1637,88 @@ where
             }
 
             let mut initial_parsed_ast = None;
+            let mut query_cache_entry = None;
 
             match message[0] as char {
                 // Query
-                'Q' => {
-                    if query_router.query_parser_enabled() {
-                        match query_router.parse(&message) {
-                            Ok(ast) => {
-                                let plugin_result = query_router.execute_plugins(&ast).await;
+                'Q' if query_router.query_parser_enabled() => {
+                    match query_router.parse(&message) {
+                        Ok(mut ast) => {
+                            let plugin_result = query_router.execute_plugins(&ast).await;
+                            let mut route = None;
+
+                            match plugin_result {
+                                Ok(PluginOutput::Deny(error)) => {
+                                    error_response(&mut self.write, &error).await?;
+                                    continue;
+                                }
 
-                                match plugin_result {
-                                    Ok(PluginOutput::Deny(error)) => {
-                                        error_response(&mut self.write, &error).await?;
-                                        continue;
-                                    }
+                                Ok(PluginOutput::Intercept(result)) => {
+                                    write_all(&mut self.write, result).await?;
+                                    continue;
+                                }
 
-                                    Ok(PluginOutput::Intercept(result)) => {
-                                        write_all(&mut self.write, result).await?;
-                                        continue;
-                                    }
+                                Ok(PluginOutput::Overwrite(rewritten)) => {
+                                    message = simple_query(&QueryRewrite::query(&rewritten));
+                                    ast = rewritten;
+                                }
 
-                                    _ => (),
-                                };
+                                Ok(PluginOutput::Route(shard)) => route = Some(shard),
 
-                                let _ = query_router.infer(&ast);
+                                _ => (),
+                            };
+
+                            match query_router.rewrite_query(&ast).await {
+                                Ok(PluginOutput::Deny(error)) => {
+                                    error_response(&mut self.write, &error).await?;
+                                    continue;
+                                }
+
+                                Ok(PluginOutput::Overwrite(rewritten)) => {
+                                    message = simple_query(&QueryRewrite::query(&rewritten));
+                                    ast = rewritten;
+                                }
+
+                                _ => (),
+                            };
 
-                                initial_parsed_ast = Some(ast);
+                            let _ = query_router.infer(&ast);
+
+                            if route.is_some() {
+                                query_router.set_shard(route);
                             }
-                            Err(error) => {
-                                warn!(
-                                    "Query parsing error: {} (client: {})",
-                                    error, client_identifier
-                                );
+
+                            // The client isn't in a transaction yet, its query can be
+                            // answered from the cache of the shard and role it's routed to.
+                            if let Some(config) = query_router
+                                .pool_settings()
+                                .plugins
+                                .as_ref()
+                                .and_then(|plugins| plugins.query_cache.as_ref())
+                            {
+                                let query_cache = QueryCache {
+                                    enabled: config.enabled,
+                                    config,
+                                };
+
+                                if let Some(key) = query_cache.key(&query_router, &ast) {
+                                    if let Some(result) = query_cache.get(&key) {
+                                        write_all(&mut self.write, result).await?;
+                                        continue;
+                                    }
+
+                                    query_cache_entry = Some(query_cache.entry(key));
+                                }
                             }
+
+                            initial_parsed_ast = Some(ast);
+                        }
+                        Err(error) => {
+                            warn!(
+                                "Query parsing error: {} (client: {})",
+                                error, client_identifier
+                            );
+                            query_router.infer_unparsed();
                         }
                     }
                 }
@@ -988,24 +1732,36 @@ where
                 // to when we get the S message
                 // Parse
                 'P' => {
+                    let mut parsed_ast = None;
+
                     if query_router.query_parser_enabled() {
                         match query_router.parse(&message) {
                             Ok(ast) => {
                                 if let Ok(output) = query_router.execute_plugins(&ast).await {
-                                    plugin_output = Some(output);
+                                    plugin_output = Some(extended_protocol_output(output));
                                 }
 
                                 let _ = query_router.infer(&ast);
+
+                                parsed_ast = Some(ast);
                             }
                             Err(error) => {
                                 warn!(
                                     "Query parsing error: {} (client: {})",
                                     error, client_identifier
                                 );
+                                query_router.infer_unparsed();
                             }
                         };
                     }
 
+                    query_router.filter_mirror(&message, parsed_ast.as_deref());
+                    self.classify(parsed_ast.as_deref());
+
+                    if query_span.is_none() {
+                        query_span = QuerySpan::start(&message);
+                    }
+
                     self.buffer_parse(message, &pool)?;
 
                     continue;
@@ -1046,33 +1802,99 @@ where
                 _ => (),
             }
 
-            // Check on plugin results.
-            if let Some(PluginOutput::Deny(error)) = plugin_output {
-                self.reset_buffered_state();
-                error_response(&mut self.write, &error).await?;
-                plugin_output = None;
-                continue;
+            // Check on plugin results, the shard a plugin routed the query to
+            // wins over the one the binds were routed to.
+            match plugin_output {
+                Some(PluginOutput::Deny(error)) => {
+                    self.reset_buffered_state();
+                    error_response(&mut self.write, &error).await?;
+                    plugin_output = None;
+                    query_span = None;
+                    continue;
+                }
+
+                Some(PluginOutput::Route(shard)) => query_router.set_shard(Some(shard)),
+
+                _ => (),
             };
 
             // Check if the pool is paused and wait until it's resumed.
-            pool.wait_paused().await;
+            if let Err(err) = pool.wait_paused().await {
+                if message[0] as char == 'S' {
+                    self.reset_buffered_state();
+                }
+
+                query_span = None;
+
+                error_response(
+                    &mut self.write,
+                    format!("could not get connection from the pool - {}", err).as_str(),
+                )
+                .await?;
+
+                warn!(
+                    "Pool paused for longer than pause_timeout: \
+                    {{ \
+                        pool_name: {:?}, \
+                        username: {:?} \
+                    }}",
+                    self.pool_name, self.username
+                );
+
+                continue;
+            }
 
             // Refresh pool information, something might have changed.
             pool = self.get_pool().await?;
             query_router.update_pool_settings(&pool.settings);
 
+            // Queries for all shards use one server per shard, only for this query.
+            if fan_out::is_fan_out(&message) {
+                self.classify(initial_parsed_ast.as_deref());
+                self.fan_out(&message, &pool, &query_router).await?;
+                continue;
+            }
+
+            // The span covers the checkout.
+            if message[0] == b'Q' {
+                query_span = QuerySpan::start(&message);
+            }
+
             debug!("Waiting for connection from pool");
             if !self.admin {
                 self.stats.waiting();
             }
 
+            // Sticky sessions go back to the server they used before.
+            let pinned = match query_router.sticky() {
+                true => self.pinned_address(query_router.shard(), query_router.role()),
+                false => None,
+            };
+
             // Grab a server from the pool.
-            let connection = match pool
-                .get(query_router.shard(), query_router.role(), &self.stats)
-                .await
-            {
+            let connection = match &pinned {
+                Some(address) => {
+                    pool.get_pinned(address, &self.stats, query_router.checkout_timeout())
+                        .await
+                }
+                None => {
+                    pool.get(
+                        query_router.shard(),
+                        query_router.role(),
+                        &self.stats,
+                        query_router.checkout_timeout(),
+                    )
+                    .await
+                }
+            };
+
+            let connection = match connection {
                 Ok(conn) => {
                     debug!("Got connection from pool");
+                    if query_router.sticky() && pinned.is_none() {
+                        debug!("Pinning client to {:?}", conn.1);
+                        self.sticky_addresses.push(conn.1.clone());
+                    }
                     conn
                 }
                 Err(err) => {
@@ -1087,9 +1909,17 @@ where
                         self.reset_buffered_state();
                     }
 
-                    error_response(
+                    query_span = None;
+
+                    // Clients can retry when the servers are only overloaded.
+                    let code = match err {
+                        Error::ServerTooManyConnections => TOO_MANY_CONNECTIONS,
+                        _ => "58000",
+                    };
+                    error_response_with_code(
                         &mut self.write,
                         format!("could not get connection from the pool - {}", err).as_str(),
+                        code,
                     )
                     .await?;
 
@@ -1123,7 +1953,7 @@ where
             self.connected_to_server = true;
 
             // Update statistics
-            self.stats.active();
+            self.stats.active(&address);
 
             self.last_address_id = Some(address.id);
             self.last_server_stats = Some(server.stats());
@@ -1134,7 +1964,18 @@ where
                 server.address()
             );
 
-            server.sync_parameters(&self.server_parameters).await?;
+            server
+                .sync_client_parameters(
+                    &self.server_parameters,
+                    self.server_application_name(&pool).as_deref(),
+                )
+                .await?;
+
+            // The server may report different values than the previous one, e.g. a replica.
+            let parameter_status = server.parameter_status_changes(&mut self.server_parameters);
+            if !parameter_status.is_empty() {
+                write_all_flush(&mut self.write, &parameter_status).await?;
+            }
 
             let mut initial_message = Some(message);
 
@@ -1143,26 +1984,85 @@ where
                 timeout => tokio::time::Duration::from_millis(timeout),
             };
 
+            let idle_in_transaction_timeout_duration =
+                match pool.settings.idle_in_transaction_timeout {
+                    Some(timeout) => tokio::time::Duration::from_millis(timeout),
+                    None => tokio::time::Duration::MAX,
+                };
+
+            let client_idle_timeout_duration = match pool.settings.client_idle_timeout {
+                Some(timeout) if !self.transaction_mode => {
+                    tokio::time::Duration::from_millis(timeout)
+                }
+                _ => tokio::time::Duration::MAX,
+            };
+
             // Transaction loop. Multiple queries can be issued by the client here.
             // The connection belongs to the client until the transaction is over,
             // or until the client disconnects if we are in session mode.
             //
             // If the client is in session mode, no more custom protocol
             // commands will be accepted.
+            //
+            // Set when a COPY was started by the extended protocol: the client ends it
+            // with a Sync after CopyDone or CopyFail, the server only answers that Sync.
+            let mut extended_protocol_copy = false;
+
             loop {
-                let message = match initial_message {
+                let mut message = match initial_message {
                     None => {
                         trace!("Waiting for message inside transaction or in session mode");
 
                         // This is not an initial message so discard the initial_parsed_ast
                         initial_parsed_ast.take();
 
-                        match tokio::time::timeout(
-                            idle_client_timeout_duration,
-                            read_message(&mut self.read),
-                        )
-                        .await
-                        {
+                        let stats = self.stats.clone();
+
+                        // Only counts while the client keeps a transaction open, e.g. not between
+                        // the transactions of a session mode client.
+                        let idle_in_transaction = server.in_transaction()
+                            && idle_in_transaction_timeout_duration < idle_client_timeout_duration;
+
+                        // A session mode client can carry on with another server connection
+                        // if nothing it did is left on this one.
+                        let detachable = client_idle_timeout_duration
+                            < idle_client_timeout_duration
+                            && !server.in_transaction()
+                            && !server.in_copy_mode()
+                            && !server.has_session_state()
+                            && !self.server_statements
+                            && !extended_protocol_copy
+                            && self.buffer.is_empty()
+                            && self.extended_protocol_data_buffer.is_empty();
+
+                        let message = tokio::select! {
+                            message = tokio::time::timeout(
+                                if idle_in_transaction {
+                                    idle_in_transaction_timeout_duration
+                                } else if detachable {
+                                    client_idle_timeout_duration
+                                } else {
+                                    idle_client_timeout_duration
+                                },
+                                read_message(&mut self.read),
+                            ) => message,
+                            _ = stats.terminated() => {
+                                // Killed by an admin, roll back the transaction
+                                // and release the server.
+                                error_response_terminal(
+                                    &mut self.write,
+                                    "terminating connection due to administrator command",
+                                )
+                                .await?;
+
+                                self.stats.disconnect();
+                                server.checkin_cleanup().await?;
+
+                                return Ok(());
+                            }
+                        };
+
+                        match message {
                             Ok(Ok(message)) => message,
                             Ok(Err(err)) => {
                                 // Client disconnected inside a transaction.
@@ -1172,6 +2072,46 @@ where
 
                                 return Err(err);
                             }
+                            Err(_) if idle_in_transaction => {
+                                // Roll back the transaction and give the server to someone else,
+                                // the client is gone for good.
+                                error_response_terminal(
+                                    &mut self.write,
+                                    "terminating connection due to idle-in-transaction timeout",
+                                )
+                                .await?;
+                                tracing::warn!(
+                                    client_id = %format_args!("{:#010X}", self.process_id),
+                                    pool = %self.pool_name,
+                                    user = %self.username,
+                                    address = %format_args!(
+                                        "{}:{}",
+                                        server.address().host,
+                                        server.address().port
+                                    ),
+                                    timeout_ms =
+                                        idle_in_transaction_timeout_duration.as_millis() as u64,
+                                    "Client idle in transaction for too long, disconnecting"
+                                );
+
+                                server.checkin_cleanup().await?;
+                                self.stats.disconnect();
+                                self.release();
+
+                                return Ok(());
+                            }
+                            Err(_) if detachable => {
+                                // Give the server to someone else, the client gets one
+                                // again with its next query.
+                                debug!(
+                                    "Client {:?} idle for more than {}ms, releasing server {:?}",
+                                    self.addr,
+                                    client_idle_timeout_duration.as_millis(),
+                                    server.address()
+                                );
+
+                                break;
+                            }
                             Err(_) => {
                                 // Client idle in transaction timeout
                                 error_response(&mut self.write, "idle transaction timeout").await?;
@@ -1210,11 +2150,33 @@ where
                 trace!("Client message: {}", code);
 
                 match code {
+                    // The server is kept until the end of the transaction,
+                    // a new route applies to the next one checked out.
+                    'Q' if QueryRouter::is_route_command(&message) => {
+                        let mut response = BytesMut::new();
+                        match query_router.try_execute_command(&message) {
+                            Some((Command::ShowRoute, value)) => {
+                                response
+                                    .put(row_description(&vec![("pgcat.route", DataType::Text)]));
+                                response.put(data_row(&vec![value]));
+                                response.put(command_complete("SELECT 1"));
+                            }
+                            _ => response.put(command_complete("SET")),
+                        }
+                        response.put(ready_for_query(server.in_transaction()));
+                        write_all_flush(&mut self.write, &response).await?;
+                    }
+
                     // Query
                     'Q' => {
+                        let mut parsed_ast = None;
+
                         if query_router.query_parser_enabled() {
+                            // The plugins already ran on the initial message.
+                            let initial = initial_parsed_ast.is_some();
+
                             // We don't want to parse again if we already parsed it as the initial message
-                            let ast = match initial_parsed_ast {
+                            parsed_ast = match initial_parsed_ast {
                                 Some(_) => Some(initial_parsed_ast.take().unwrap()),
                                 None => match query_router.parse(&message) {
                                     Ok(ast) => Some(ast),
@@ -1228,8 +2190,8 @@ where
                                 },
                             };
 
-                            if let Some(ast) = ast {
-                                let plugin_result = query_router.execute_plugins(&ast).await;
+                            if let Some(ast) = parsed_ast.as_mut().filter(|_| !initial) {
+                                let plugin_result = query_router.execute_plugins(ast).await;
 
                                 match plugin_result {
                                     Ok(PluginOutput::Deny(error)) => {
@@ -1242,13 +2204,51 @@ where
                                         continue;
                                     }
 
+                                    Ok(PluginOutput::Overwrite(rewritten)) => {
+                                        message = simple_query(&QueryRewrite::query(&rewritten));
+                                        *ast = rewritten;
+                                    }
+
+                                    Ok(PluginOutput::Route(shard)) => {
+                                        if let Some(error) =
+                                            route_in_transaction_error(shard, &address)
+                                        {
+                                            error_response(&mut self.write, &error).await?;
+                                            continue;
+                                        }
+                                    }
+
+                                    _ => (),
+                                };
+
+                                match query_router.rewrite_query(ast).await {
+                                    Ok(PluginOutput::Deny(error)) => {
+                                        error_response(&mut self.write, &error).await?;
+                                        continue;
+                                    }
+
+                                    Ok(PluginOutput::Overwrite(rewritten)) => {
+                                        message = simple_query(&QueryRewrite::query(&rewritten));
+                                        parsed_ast = Some(rewritten);
+                                    }
+
                                     _ => (),
                                 };
                             }
                         }
 
+                        query_router.filter_mirror(&message, parsed_ast.as_deref());
+                        self.classify(parsed_ast.as_deref());
+                        server.set_mirroring(query_router.take_mirror());
+
                         debug!("Sending query to server");
 
+                        let span = query_span.take().or_else(|| QuerySpan::start(&message));
+                        server.take_rows();
+
+                        // Only set for the initial message.
+                        self.query_cache_entry = query_cache_entry.take();
+
                         self.send_and_receive_loop(
                             code,
                             Some(&message),
@@ -1259,6 +2259,11 @@ where
                         )
                         .await?;
 
+                        if let Some(mut span) = span {
+                            span.set_address(&address);
+                            span.end(server.take_rows());
+                        }
+
                         if !server.in_transaction() {
                             // Report transaction executed statistics.
                             self.stats.transaction();
@@ -1288,14 +2293,25 @@ where
                     // Parse
                     // The query with placeholders is here, e.g. `SELECT * FROM users WHERE email = $1 AND active = $2`.
                     'P' => {
+                        let mut parsed_ast = None;
+
                         if query_router.query_parser_enabled() {
                             if let Ok(ast) = query_router.parse(&message) {
                                 if let Ok(output) = query_router.execute_plugins(&ast).await {
-                                    plugin_output = Some(output);
+                                    plugin_output = Some(extended_protocol_output(output));
                                 }
+
+                                parsed_ast = Some(ast);
                             }
                         }
 
+                        query_router.filter_mirror(&message, parsed_ast.as_deref());
+                        self.classify(parsed_ast.as_deref());
+
+                        if query_span.is_none() {
+                            query_span = QuerySpan::start(&message);
+                        }
+
                         self.buffer_parse(message, &pool)?;
                     }
 
@@ -1332,24 +2348,35 @@ where
                     'S' => {
                         debug!("Sending query to server");
 
-                        match plugin_output {
-                            Some(PluginOutput::Deny(error)) => {
-                                error_response(&mut self.write, &error).await?;
-                                plugin_output = None;
-                                self.reset_buffered_state();
-                                continue;
+                        // Finishing a COPY, mirrored like the rest of it.
+                        let mirror = query_router.take_mirror();
+                        if !server.in_copy_mode() {
+                            server.set_mirroring(mirror);
+                        }
+
+                        let plugin_error = match plugin_output.take() {
+                            Some(PluginOutput::Deny(error)) => Some(error),
+                            Some(PluginOutput::Route(shard)) => {
+                                route_in_transaction_error(shard, &address)
                             }
 
                             Some(PluginOutput::Intercept(result)) => {
                                 write_all(&mut self.write, result).await?;
-                                plugin_output = None;
+                                query_span = None;
                                 self.reset_buffered_state();
                                 continue;
                             }
 
-                            _ => (),
+                            _ => None,
                         };
 
+                        if let Some(error) = plugin_error {
+                            error_response(&mut self.write, &error).await?;
+                            query_span = None;
+                            self.reset_buffered_state();
+                            continue;
+                        }
+
                         // Prepared statements can arrive like this
                         // 1. Without named describe
                         //      Client: Parse, with name, query and params
@@ -1502,6 +2529,8 @@ where
                             self.response_message_queue_buffer.clear();
                         }
 
+                        server.take_rows();
+
                         if should_send_to_server {
                             self.send_and_receive_loop(
                                 code,
@@ -1513,8 +2542,15 @@ where
                             )
                             .await?;
                         }
+                        server.release_prepared_statements();
+
+                        if let Some(mut span) = query_span.take() {
+                            span.set_address(&address);
+                            span.end(server.take_rows());
+                        }
 
                         self.buffer.clear();
+                        extended_protocol_copy = server.in_copy_mode();
 
                         if !server.in_transaction() {
                             self.stats.transaction();
@@ -1549,6 +2585,12 @@ where
                         // We may already have some copy data in the buffer, add this message to buffer
                         self.buffer.put(&message[..]);
 
+                        // Sent with the Sync that follows, its response is the end of the COPY.
+                        if extended_protocol_copy {
+                            extended_protocol_copy = false;
+                            continue;
+                        }
+
                         self.send_server_message(server, &self.buffer, &address, &pool)
                             .await?;
 
@@ -1596,6 +2638,7 @@ where
 
             server.stats().idle();
             self.connected_to_server = false;
+            self.server_statements = false;
 
             self.release();
             self.stats.idle();
@@ -1606,7 +2649,36 @@ where
     /// Return an error to the client otherwise.
     async fn get_pool(&mut self) -> Result<ConnectionPool, Error> {
         match get_pool(&self.pool_name, &self.username) {
-            Some(pool) => Ok(pool),
+            // Until the pool is reloaded, keep the sub-pool picked at login.
+            Some(pool) => match self.sticky_pool {
+                Some(ref sticky_pool) if sticky_pool.same_sticky_pools(&pool) => {
+                    Ok(sticky_pool.clone())
+                }
+                _ => match pool.sticky_pool(&self.parameters) {
+                    Some(pool) => {
+                        self.sticky_pool = Some(pool.clone());
+                        Ok(pool)
+                    }
+                    // Over the limit of the reloaded pool, stay on the old one.
+                    None => match self.sticky_pool {
+                        Some(ref sticky_pool) => Ok(sticky_pool.clone()),
+                        None => {
+                            error_response_terminal(
+                                &mut self.write,
+                                "too many different sticky parameter values in use, try again later",
+                            )
+                            .await?;
+
+                            Err(Error::ClientError(format!(
+                                "Too many sticky sub-pools {{ username: {}, pool_name: {}, application_name: {} }}",
+                                self.username,
+                                self.pool_name,
+                                self.server_parameters.get_application_name()
+                            )))
+                        }
+                    },
+                },
+            },
             None => {
                 error_response(
                     &mut self.write,
@@ -1680,6 +2752,29 @@ where
                         custom_protocol_response_ok(&mut self.write, "SET SHARDING KEY").await?;
                     }
 
+                    // SET pgcat.sticky TO
+                    (Command::SetSticky, _) => {
+                        if !query_router.sticky() {
+                            self.sticky_addresses.clear();
+                        }
+                        custom_protocol_response_ok(&mut self.write, "SET").await?;
+                    }
+
+                    // SHOW pgcat.sticky
+                    (Command::ShowSticky, value) => {
+                        show_response(&mut self.write, "pgcat.sticky", &value).await?;
+                    }
+
+                    // SET pgcat.route TO
+                    (Command::SetRoute, _) => {
+                        custom_protocol_response_ok(&mut self.write, "SET").await?;
+                    }
+
+                    // SHOW pgcat.route
+                    (Command::ShowRoute, value) => {
+                        show_response(&mut self.write, "pgcat.route", &value).await?;
+                    }
+
                     // SET SERVER ROLE TO
                     (Command::SetServerRole, _) => {
                         custom_protocol_response_ok(&mut self.write, "SET SERVER ROLE").await?;
@@ -1706,6 +2801,17 @@ where
         }
     }
 
+    /// Server a sticky session used before for this shard and role, if any.
+    fn pinned_address(&self, shard: Option<usize>, role: Option<Role>) -> Option<Address> {
+        self.sticky_addresses
+            .iter()
+            .find(|address| {
+                shard.unwrap_or(address.shard) == address.shard
+                    && role.unwrap_or(address.role) == address.role
+            })
+            .cloned()
+    }
+
     /// Makes sure the the checked out server has the prepared statement and sends it to the server if it doesn't
     async fn ensure_prepared_statement_is_on_server(
         &mut self,
@@ -1789,6 +2895,9 @@ where
         // Avoid parsing if prepared statements not enabled
         if !self.prepared_statements_enabled {
             debug!("Anonymous parse message");
+            if !Parse::get_name(&message)?.is_empty() {
+                self.server_statements = true;
+            }
             self.extended_protocol_data_buffer
                 .push_back(ExtendedProtocolData::create_new_parse(message, None));
             return Ok(());
@@ -1947,12 +3056,196 @@ where
         self.response_message_queue_buffer.clear();
     }
 
+    /// Pass a replication connection through to the primary of the first shard
+    /// until either side disconnects. The server is used for the whole session
+    /// and closed afterwards, it's never returned to the pool.
+    async fn replicate(&mut self, pool: &ConnectionPool, replication: &str) -> Result<(), Error> {
+        debug!(
+            "Client {:?} starting a replication connection ({})",
+            self.addr, replication
+        );
+
+        let mut server = match pool
+            .replication_connection(0, replication, self.client_server_map.clone())
+            .await
+        {
+            Ok(server) => server,
+            Err(err) => {
+                error_response_terminal(
+                    &mut self.write,
+                    &format!("could not start replication connection: {:?}", err),
+                )
+                .await?;
+                self.stats.disconnect();
+                return Err(err);
+            }
+        };
+
+        server.claim(self.process_id, self.secret_key);
+        self.connected_to_server = true;
+        self.stats.active(&server.address());
+        server
+            .stats()
+            .active(self.server_parameters.get_application_name().clone());
+
+        let result = server.relay(&mut self.read, &mut self.write).await;
+
+        self.release();
+        self.connected_to_server = false;
+        self.stats.disconnect();
+
+        result
+    }
+
+    /// `application_name` of the servers the client uses, from the `application_name_format`
+    /// of the pool, if it has one.
+    fn server_application_name(&self, pool: &ConnectionPool) -> Option<String> {
+        pool.settings
+            .application_name_format
+            .as_ref()
+            .map(|format| {
+                format_application_name(
+                    format,
+                    self.server_parameters.get_application_name(),
+                    self.process_id,
+                )
+            })
+    }
+
     /// Release the server from the client: it can't cancel its queries anymore.
     pub fn release(&self) {
         let mut guard = self.client_server_map.lock();
         guard.remove(&(self.process_id, self.secret_key));
     }
 
+    /// Send a simple query to all shards in parallel,
+    /// and the rows they return to the client as a single result.
+    async fn fan_out(
+        &mut self,
+        message: &BytesMut,
+        pool: &ConnectionPool,
+        query_router: &QueryRouter,
+    ) -> Result<(), Error> {
+        debug!("Sending query to all {} shards", pool.shards());
+
+        let statement_timeout = match pool.settings.user.statement_timeout {
+            0 => tokio::time::Duration::MAX,
+            timeout => tokio::time::Duration::from_millis(timeout),
+        };
+
+        self.stats.waiting();
+
+        let stats = &self.stats;
+        let server_parameters = &self.server_parameters;
+        let application_name = server_parameters.get_application_name();
+        let server_application_name = &self.server_application_name(pool);
+        let statements = std::mem::take(&mut self.statements);
+        let statements = &statements;
+
+        let shards = (0..pool.shards()).map(|shard| async move {
+            let (mut connection, address) = pool
+                .get(
+                    Some(shard),
+                    query_router.role(),
+                    stats,
+                    query_router.checkout_timeout(),
+                )
+                .await?;
+            let server = &mut *connection;
+
+            server
+                .sync_client_parameters(server_parameters, server_application_name.as_deref())
+                .await?;
+
+            let query_start = Instant::now();
+
+            if let Err(err) = server.send(message).await {
+                pool.ban(&address, BanReason::MessageSendFailed, Some(stats));
+                return Err(err);
+            }
+
+            let mut response = BytesMut::new();
+            let receive = async {
+                loop {
+                    response.put(server.recv(None).await?);
+
+                    if !server.is_data_available() {
+                        return Ok::<(), Error>(());
+                    }
+                }
+            };
+
+            match tokio::time::timeout(statement_timeout, receive).await {
+                Ok(Ok(())) => (),
+                Ok(Err(err)) => {
+                    pool.ban(&address, BanReason::MessageReceiveFailed, Some(stats));
+                    return Err(err);
+                }
+                Err(_) => {
+                    server.mark_bad(
+                        format!(
+                            "Statement timeout while talking to {:?} with user {}",
+                            address, pool.settings.user.username
+                        )
+                        .as_str(),
+                    );
+                    return Err(Error::StatementTimeout);
+                }
+            }
+
+            let query_duration = Instant::now().duration_since(query_start);
+            server.stats().query(query_duration, application_name);
+            pool.record_latency(&address, query_duration);
+            for kind in statements {
+                address.stats.statement_add(*kind);
+            }
+
+            // Nothing stays open on the servers between queries.
+            server.checkin_cleanup().await?;
+
+            Ok(response)
+        });
+
+        let responses = futures::future::join_all(shards)
+            .await
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>();
+
+        self.stats.idle();
+
+        let responses = match responses {
+            Ok(responses) => responses,
+            Err(err) => {
+                error!(
+                    "Could not send query to all shards: {{ pool_name: {:?}, username: {:?}, error: \"{:?}\" }}",
+                    self.pool_name, self.username, err
+                );
+                return error_response(
+                    &mut self.write,
+                    &format!("could not send query to all shards - {}", err),
+                )
+                .await;
+            }
+        };
+
+        self.stats.query();
+        self.stats.transaction();
+        for kind in statements {
+            self.stats.statement(*kind);
+        }
+
+        match fan_out::merge(responses) {
+            Ok(response) => write_all_flush(&mut self.write, &response).await,
+            Err(err) => {
+                warn!(
+                    "Could not merge results from all shards: {} (client: {:?})",
+                    err, self.addr
+                );
+                error_response(&mut self.write, &err).await
+            }
+        }
+    }
+
     async fn send_and_receive_loop(
         &mut self,
         code: char,
@@ -1964,22 +3257,38 @@ where
     ) -> Result<(), Error> {
         debug!("Sending {} to server", code);
 
-        let message = match message {
-            Some(message) => message,
-            None => &self.buffer,
-        };
-
-        self.send_server_message(server, message, address, pool)
+        self.send_server_message(server, message.unwrap_or(&self.buffer), address, pool)
             .await?;
 
         let query_start = Instant::now();
+        let mut query_cache_entry = self.query_cache_entry.take();
+        server.take_error_code();
+
+        let mut query_timeout = match pool.settings.query_timeout {
+            0 => None,
+            timeout => Some((
+                tokio::time::Instant::now() + tokio::time::Duration::from_millis(timeout),
+                false,
+            )),
+        };
+
         // Read all data the server has to offer, which can be multiple messages
         // buffered in 8196 bytes chunks.
         loop {
             let response = self
-                .receive_server_message(server, address, pool, client_stats)
+                .receive_server_message_before_timeout(
+                    server,
+                    address,
+                    pool,
+                    client_stats,
+                    &mut query_timeout,
+                )
                 .await?;
 
+            if let Some(ref mut entry) = query_cache_entry {
+                entry.add(&response);
+            }
+
             match write_all_flush(&mut self.write, &response).await {
                 Ok(_) => (),
                 Err(err) => {
@@ -1994,16 +3303,92 @@ where
             }
         }
 
+        if let Some(entry) = query_cache_entry {
+            entry.store();
+        }
+
         // Report query executed statistics.
+        let query_duration = Instant::now().duration_since(query_start);
         client_stats.query();
         server.stats().query(
-            Instant::now().duration_since(query_start).as_millis() as u64,
+            query_duration,
             self.server_parameters.get_application_name(),
         );
+        for kind in self.statements.drain(..) {
+            client_stats.statement(kind);
+            address.stats.statement_add(kind);
+        }
+        pool.record_latency(address, query_duration);
+
+        if let Some(slow_query) = SlowQuery::new(
+            pool.settings.log_min_duration_ms,
+            query_duration,
+            || self.query_text(message.unwrap_or(&self.buffer)),
+            address,
+        ) {
+            slow_query.log(pool.settings.slow_query_log_format);
+        }
+
+        if let Some(config) = pool
+            .settings
+            .plugins
+            .as_ref()
+            .and_then(|plugins| plugins.audit_log.as_ref())
+        {
+            let audit_log = AuditLog {
+                enabled: config.enabled,
+                config,
+            };
+
+            audit_log.log(
+                &self.username,
+                self.addr,
+                address,
+                self.query_text(message.unwrap_or(&self.buffer)),
+                server.take_error_code(),
+                server.rows(),
+            );
+        }
 
         Ok(())
     }
 
+    /// Count the statements of a parsed query in the stats once it's sent to the server.
+    fn classify(&mut self, ast: Option<&[Statement]>) {
+        self.statements
+            .extend(ast.unwrap_or_default().iter().map(StatementKind::classify));
+    }
+
+    /// The query of the first Query, Parse or Bind message sent to the server.
+    fn query_text(&self, messages: &BytesMut) -> Option<String> {
+        let mut offset = 0;
+
+        while messages.len() >= offset + 5 {
+            let len = i32::from_be_bytes(messages[offset + 1..offset + 5].try_into().unwrap());
+            let end = offset + 1 + len as usize;
+            if len < 4 || messages.len() < end {
+                return None;
+            }
+            let message = BytesMut::from(&messages[offset..end]);
+
+            match message[0] as char {
+                'Q' | 'P' => return QueryRouter::query_text(&message),
+                'B' => {
+                    // The statement was renamed when it was parsed.
+                    let name = Bind::get_name(&message).ok()?;
+                    return self
+                        .prepared_statements
+                        .values()
+                        .find(|(parse, _)| parse.name == name)
+                        .map(|(parse, _)| parse.query().to_string());
+                }
+                _ => offset = end,
+            }
+        }
+
+        None
+    }
+
     async fn send_server_message(
         &self,
         server: &mut Server,
@@ -2020,6 +3405,81 @@ where
         }
     }
 
+    /// Same as `receive_server_message`, canceling the query with a CancelRequest once it runs
+    /// past the `query_timeout` of the pool, `timeout` being its deadline and whether it was
+    /// canceled already. The server gets another `query_timeout` to honor the cancel before
+    /// the connection is closed.
+    async fn receive_server_message_before_timeout(
+        &mut self,
+        server: &mut Server,
+        address: &Address,
+        pool: &ConnectionPool,
+        client_stats: &ClientStats,
+        timeout: &mut Option<(tokio::time::Instant, bool)>,
+    ) -> Result<BytesMut, Error> {
+        let (mut deadline, mut canceled) = match *timeout {
+            Some(timeout) => timeout,
+            None => {
+                return self
+                    .receive_server_message(server, address, pool, client_stats)
+                    .await
+            }
+        };
+
+        let (host, port, process_id, secret_key) = server.cancel_key();
+
+        {
+            // Receiving isn't interrupted so nothing the server sends is lost.
+            let receive = self.receive_server_message(server, address, pool, client_stats);
+            tokio::pin!(receive);
+
+            loop {
+                tokio::select! {
+                    response = &mut receive => {
+                        *timeout = Some((deadline, canceled));
+                        return response;
+                    }
+
+                    _ = tokio::time::sleep_until(deadline) => {
+                        if canceled {
+                            break;
+                        }
+
+                        warn!(
+                            "Query timeout, canceling the query: {{ pool_name: {}, username: {}, address: {}:{} }}",
+                            address.pool_name, address.username, address.host, address.port
+                        );
+
+                        if let Err(err) = Server::cancel(&host, port, process_id, secret_key).await {
+                            warn!("Could not cancel the query: {:?}", err);
+                        }
+
+                        canceled = true;
+                        deadline = tokio::time::Instant::now()
+                            + tokio::time::Duration::from_millis(pool.settings.query_timeout);
+                    }
+                }
+            }
+        }
+
+        server.mark_bad(
+            format!(
+                "Query not canceled after the query timeout while talking to {:?} with user {}",
+                address, pool.settings.user.username
+            )
+            .as_str(),
+        );
+
+        if pool.settings.query_timeout_ban {
+            pool.ban(address, BanReason::StatementTimeout, Some(client_stats));
+        }
+
+        error_response_terminal(&mut self.write, "query timeout, the query was not canceled")
+            .await?;
+
+        Err(Error::StatementTimeout)
+    }
+
     async fn receive_server_message(
         &mut self,
         server: &mut Server,
@@ -2058,7 +3518,12 @@ where
                     )
                     .as_str(),
                 );
-                pool.ban(address, BanReason::StatementTimeout, Some(client_stats));
+                let reason = if pool.settings.statement_timeout_soft_ban {
+                    BanReason::SoftBan
+                } else {
+                    BanReason::StatementTimeout
+                };
+                pool.ban(address, reason, Some(client_stats));
                 error_response_terminal(&mut self.write, "pool statement timeout").await?;
                 Err(Error::StatementTimeout)
             }
@@ -2073,8 +3538,83 @@ impl<S, T> Drop for Client<S, T> {
 
         // Dirty shutdown
         // TODO: refactor, this is not the best way to handle state management.
-        if self.connected_to_server && self.last_server_stats.is_some() {
-            self.last_server_stats.as_ref().unwrap().idle();
+        if self.connected_to_server {
+            if let Some(last_server_stats) = &self.last_server_stats {
+                last_server_stats.idle();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+
+    fn startup_message(version: i32, parameters: &[(&str, &str)]) -> BytesMut {
+        let mut body = BytesMut::new();
+        body.put_i32(version);
+        for (key, value) in parameters {
+            body.put_slice(key.as_bytes());
+            body.put_u8(0);
+            body.put_slice(value.as_bytes());
+            body.put_u8(0);
         }
+        body.put_u8(0);
+
+        let mut message = BytesMut::new();
+        message.put_i32(body.len() as i32 + 4);
+        message.put(body);
+        message
+    }
+
+    #[test]
+    fn test_format_application_name() {
+        assert_eq!(
+            format_application_name("pgcat:{application_name}:{client_id}", "web", 0x1a2b),
+            "pgcat:web:0x00001A2B"
+        );
+        // Only the format is filled.
+        assert_eq!(
+            format_application_name("{application_name}", "{client_id}", 7),
+            "{client_id}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_startup_negotiate_protocol_version() {
+        let (mut client, mut pgcat) = tokio::io::duplex(1024);
+
+        // Protocol 3.2 with an option we don't know about.
+        let startup = startup_message(
+            PROTOCOL_VERSION_NUMBER + 2,
+            &[("user", "postgres"), ("_pq_.compression", "on")],
+        );
+        client.write_all(&startup).await.unwrap();
+
+        let (connection_type, bytes) = get_startup(&mut pgcat).await.unwrap();
+        assert!(matches!(connection_type, ClientConnectionType::Startup));
+        assert!(parse_startup(bytes).is_ok());
+
+        assert_eq!(client.read_u8().await.unwrap(), b'v');
+        let len = client.read_i32().await.unwrap();
+        let mut negotiate = vec![0u8; len as usize - 4];
+        client.read_exact(&mut negotiate).await.unwrap();
+        assert_eq!(&negotiate[..8], &[0, 0, 0, 0, 0, 0, 0, 1]);
+        assert_eq!(&negotiate[8..], b"_pq_.compression\0");
+
+        // Plain 3.0, nothing to negotiate.
+        let startup = startup_message(PROTOCOL_VERSION_NUMBER, &[("user", "postgres")]);
+        client.write_all(&startup).await.unwrap();
+        assert!(get_startup(&mut pgcat).await.is_ok());
+
+        drop(pgcat);
+        assert!(client.read_u8().await.is_err());
+
+        // Not protocol 3.
+        let (mut client, mut pgcat) = tokio::io::duplex(1024);
+        let startup = startup_message(4 << 16, &[("user", "postgres")]);
+        client.write_all(&startup).await.unwrap();
+        assert!(get_startup(&mut pgcat).await.is_err());
     }
 }
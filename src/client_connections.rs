@@ -0,0 +1,116 @@
+//! Clients connected, to refuse new ones past `max_client_connections`.
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::errors::Error;
+
+/// Clients logged in to any pool, the admin ones aren't counted.
+static CLIENT_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Clients logged in to each pool, whatever their user.
+static POOL_CLIENT_CONNECTIONS: Lazy<Mutex<HashMap<String, usize>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A client logged in to a pool, counted until it's dropped.
+#[derive(Debug)]
+pub struct ClientConnection {
+    pool_name: String,
+}
+
+impl ClientConnection {
+    /// Count a new client of the pool, unless there are `max` clients already,
+    /// or `pool_max` clients of the pool.
+    pub fn new(
+        pool_name: &str,
+        max: Option<usize>,
+        pool_max: Option<usize>,
+    ) -> Result<ClientConnection, Error> {
+        if CLIENT_CONNECTIONS
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |count| match max {
+                Some(max) if count >= max => None,
+                _ => Some(count + 1),
+            })
+            .is_err()
+        {
+            return Err(Error::ClientError(format!(
+                "no more connections allowed (max_client_connections = {})",
+                max.unwrap_or_default()
+            )));
+        }
+
+        let mut pools = POOL_CLIENT_CONNECTIONS.lock();
+        let count = pools.entry(pool_name.into()).or_default();
+        if let Some(pool_max) = pool_max {
+            if *count >= pool_max {
+                CLIENT_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+                return Err(Error::ClientError(format!(
+                    "no more connections allowed to database \"{}\" (max_client_connections = {})",
+                    pool_name, pool_max
+                )));
+            }
+        }
+        *count += 1;
+
+        Ok(ClientConnection {
+            pool_name: pool_name.into(),
+        })
+    }
+}
+
+impl Drop for ClientConnection {
+    fn drop(&mut self) {
+        CLIENT_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+
+        let mut pools = POOL_CLIENT_CONNECTIONS.lock();
+        if let Some(count) = pools.get_mut(&self.pool_name) {
+            *count -= 1;
+            if *count == 0 {
+                pools.remove(&self.pool_name);
+            }
+        }
+    }
+}
+
+/// Number of clients logged in to the pools.
+pub fn client_connections() -> usize {
+    CLIENT_CONNECTIONS.load(Ordering::Relaxed)
+}
+
+/// Number of clients logged in to the pool.
+pub fn pool_client_connections(pool_name: &str) -> usize {
+    POOL_CLIENT_CONNECTIONS
+        .lock()
+        .get(pool_name)
+        .copied()
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pool_client_connections() {
+        let first = ClientConnection::new("limited_pool", None, Some(2)).unwrap();
+        let _second = ClientConnection::new("limited_pool", None, Some(2)).unwrap();
+        assert_eq!(pool_client_connections("limited_pool"), 2);
+
+        assert_eq!(
+            ClientConnection::new("limited_pool", None, Some(2)).unwrap_err(),
+            Error::ClientError(
+                "no more connections allowed to database \"limited_pool\" \
+                (max_client_connections = 2)"
+                    .into()
+            )
+        );
+
+        // Another pool has room.
+        let _other = ClientConnection::new("other_pool", None, Some(2)).unwrap();
+
+        drop(first);
+        assert_eq!(pool_client_connections("limited_pool"), 1);
+        let _third = ClientConnection::new("limited_pool", None, Some(2)).unwrap();
+    }
+}
@@ -0,0 +1,234 @@
+//! Connection and disconnection logs in the format of the Postgres `log_connections`
+//! and `log_disconnections` ones, after a `log_line_prefix`.
+use chrono::{DateTime, Utc};
+use log::info;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::config::get_config;
+
+/// The `log_line_prefix` escapes, after a `%`.
+const ESCAPES: &str = "audrhpmt%";
+
+/// What the `log_line_prefix` escapes are replaced with, empty until it's known.
+#[derive(Debug, Default, Clone)]
+pub struct LogLineFields<'a> {
+    pub user: &'a str,
+    pub database: &'a str,
+    pub application_name: &'a str,
+    pub addr: Option<SocketAddr>,
+    pub process_id: Option<i32>,
+}
+
+/// The escape of the prefix that isn't supported, if any.
+pub fn invalid_escape(prefix: &str) -> Option<String> {
+    let mut chars = prefix.chars();
+    while let Some(c) = chars.next() {
+        if c == '%' {
+            match chars.next() {
+                Some(escape) if ESCAPES.contains(escape) => (),
+                Some(escape) => return Some(format!("%{}", escape)),
+                None => return Some("%".into()),
+            }
+        }
+    }
+    None
+}
+
+/// The prefix with its escapes replaced, like Postgres does:
+/// - `%a` application name
+/// - `%u` user
+/// - `%d` database
+/// - `%r` client host and port
+/// - `%h` client host
+/// - `%p` process ID given to the client
+/// - `%m` time with milliseconds
+/// - `%t` time
+/// - `%%` a `%`
+pub fn format_prefix(prefix: &str, fields: &LogLineFields, now: DateTime<Utc>) -> String {
+    let mut line = String::with_capacity(prefix.len());
+    let mut chars = prefix.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            line.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('a') => line.push_str(fields.application_name),
+            Some('u') => line.push_str(fields.user),
+            Some('d') => line.push_str(fields.database),
+            Some('r') => {
+                if let Some(addr) = fields.addr {
+                    line.push_str(&format!("{}({})", addr.ip(), addr.port()));
+                }
+            }
+            Some('h') => {
+                if let Some(addr) = fields.addr {
+                    line.push_str(&addr.ip().to_string());
+                }
+            }
+            Some('p') => {
+                if let Some(process_id) = fields.process_id {
+                    line.push_str(&process_id.to_string());
+                }
+            }
+            Some('m') => line.push_str(&now.format("%Y-%m-%d %H:%M:%S%.3f UTC").to_string()),
+            Some('t') => line.push_str(&now.format("%Y-%m-%d %H:%M:%S UTC").to_string()),
+            Some('%') => line.push('%'),
+            _ => (),
+        }
+    }
+
+    line
+}
+
+/// Log the message after the `log_line_prefix`, if it's set.
+pub fn log(fields: &LogLineFields, message: &str) {
+    if let Some(ref prefix) = get_config().general.log_line_prefix {
+        info!("{}{}", format_prefix(prefix, fields, Utc::now()), message);
+    }
+}
+
+pub fn connection_received(addr: &SocketAddr) -> String {
+    format!(
+        "connection received: host={} port={}",
+        addr.ip(),
+        addr.port()
+    )
+}
+
+pub fn connection_authorized(fields: &LogLineFields) -> String {
+    format!(
+        "connection authorized: user={} database={} application_name={}",
+        fields.user, fields.database, fields.application_name
+    )
+}
+
+pub fn disconnection(
+    fields: &LogLineFields,
+    session_time: Duration,
+    bytes_received: u64,
+    bytes_sent: u64,
+) -> String {
+    let millis = session_time.as_millis();
+    let mut line = format!(
+        "disconnection: session time: {}:{:02}:{:02}.{:03} user={} database={}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1000 % 60,
+        millis % 1000,
+        fields.user,
+        fields.database,
+    );
+
+    if let Some(addr) = fields.addr {
+        line.push_str(&format!(" host={} port={}", addr.ip(), addr.port()));
+    }
+
+    line.push_str(&format!(
+        " bytes_received={} bytes_sent={}",
+        bytes_received, bytes_sent
+    ));
+    line
+}
+
+/// A stream counting the bytes read from or written to it.
+#[derive(Debug)]
+pub struct ByteCounter<S> {
+    inner: S,
+    count: Arc<AtomicU64>,
+}
+
+impl<S> ByteCounter<S> {
+    pub fn new(inner: S) -> ByteCounter<S> {
+        ByteCounter {
+            inner,
+            count: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ByteCounter<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let filled = buf.filled().len();
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.count
+            .fetch_add((buf.filled().len() - filled) as u64, Ordering::Relaxed);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ByteCounter<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = result {
+            self.count.fetch_add(written as u64, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_connection_log_lines() {
+        let fields = LogLineFields {
+            user: "app",
+            database: "shop",
+            application_name: "api",
+            addr: Some("10.1.2.3:54321".parse().unwrap()),
+            process_id: Some(4242),
+        };
+        let now = Utc.with_ymd_and_hms(2024, 3, 1, 12, 30, 5).unwrap();
+
+        assert_eq!(
+            format_prefix("%m [%p] %u@%d %r app=%a 100%% ", &fields, now),
+            "2024-03-01 12:30:05.000 UTC [4242] app@shop 10.1.2.3(54321) app=api 100% "
+        );
+        // Not known before the client logs in.
+        assert_eq!(
+            format_prefix("%t [%p] %u@%d %h ", &LogLineFields::default(), now),
+            "2024-03-01 12:30:05 UTC [] @  "
+        );
+
+        assert_eq!(invalid_escape("%m [%p] %u@%d "), None);
+        assert_eq!(invalid_escape("%x "), Some("%x".into()));
+        assert_eq!(invalid_escape("trailing %"), Some("%".into()));
+
+        assert_eq!(
+            disconnection(&fields, Duration::from_millis(3_723_456), 1200, 34567),
+            "disconnection: session time: 1:02:03.456 user=app database=shop \
+            host=10.1.2.3 port=54321 bytes_received=1200 bytes_sent=34567"
+        );
+    }
+}
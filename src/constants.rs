@@ -1,6 +1,6 @@
-/// Various protocol constants, as defined in
-/// <https://www.postgresql.org/docs/12/protocol-message-formats.html>
-/// and elsewhere in the source code.
+//! Various protocol constants, as defined in
+//! <https://www.postgresql.org/docs/12/protocol-message-formats.html>
+//! and elsewhere in the source code.
 
 // Used in the StartupMessage to indicate regular handshake.
 pub const PROTOCOL_VERSION_NUMBER: i32 = 196608;
@@ -19,14 +19,23 @@ pub const SASL: i32 = 10;
 pub const SASL_CONTINUE: i32 = 11;
 pub const SASL_FINAL: i32 = 12;
 pub const SCRAM_SHA_256: &str = "SCRAM-SHA-256";
+pub const SCRAM_SHA_256_PLUS: &str = "SCRAM-SHA-256-PLUS";
+pub const TLS_SERVER_END_POINT: &str = "tls-server-end-point";
+pub const SCRAM_ITERATIONS: u32 = 4096;
 pub const NONCE_LENGTH: usize = 24;
 
+// Largest PasswordMessage we accept, tokens can be a few kilobytes.
+pub const MAX_PASSWORD_MESSAGE_LENGTH: i32 = 64 * 1024;
+
 // AuthenticationOk
 pub const AUTHENTICATION_SUCCESSFUL: i32 = 0;
 
 // ErrorResponse: A code identifying the field type; if zero, this is the message terminator and no string follows.
 pub const MESSAGE_TERMINATOR: u8 = 0;
 
+// SQLSTATE of the FATAL error of a server that reached max_connections.
+pub const TOO_MANY_CONNECTIONS: &str = "53300";
+
 //
 // Data types
 //
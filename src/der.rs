@@ -0,0 +1,70 @@
+//! DER (and the BER subset LDAP uses) encoding of tag-length-value elements,
+//! for the LDAP messages, JWT keys and TLS certificates.
+
+/// Encode a BER length.
+fn put_length(buffer: &mut Vec<u8>, length: usize) {
+    if length < 0x80 {
+        buffer.push(length as u8);
+    } else {
+        let bytes = length.to_be_bytes();
+        let bytes = &bytes[bytes.iter().position(|b| *b != 0).unwrap_or(0)..];
+        buffer.push(0x80 | bytes.len() as u8);
+        buffer.extend_from_slice(bytes);
+    }
+}
+
+/// Encode a BER element.
+pub(crate) fn put_element(buffer: &mut Vec<u8>, tag: u8, value: &[u8]) {
+    buffer.push(tag);
+    put_length(buffer, value.len());
+    buffer.extend_from_slice(value);
+}
+
+/// Read the tag and length of the element at the start of the buffer,
+/// returns the tag, its content and what comes after.
+pub(crate) fn element(buffer: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = buffer.split_first()?;
+    let (&length, rest) = rest.split_first()?;
+
+    let (length, rest) = if length < 0x80 {
+        (length as usize, rest)
+    } else {
+        let count = (length & 0x7f) as usize;
+        if count > std::mem::size_of::<usize>() || rest.len() < count {
+            return None;
+        }
+        let length = rest[..count]
+            .iter()
+            .fold(0usize, |length, byte| (length << 8) | *byte as usize);
+        (length, &rest[count..])
+    };
+
+    if rest.len() < length {
+        return None;
+    }
+
+    Some((tag, &rest[..length], &rest[length..]))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_element() {
+        let long = vec![7u8; 300];
+        let mut buffer = Vec::new();
+        put_element(&mut buffer, 0x04, b"short");
+        put_element(&mut buffer, 0x04, &long);
+        assert_eq!(&buffer[7..11], &[0x04, 0x82, 0x01, 0x2c]);
+
+        let (tag, value, rest) = element(&buffer).unwrap();
+        assert_eq!((tag, value), (0x04, &b"short"[..]));
+        let (tag, value, rest) = element(rest).unwrap();
+        assert_eq!((tag, value, rest), (0x04, &long[..], &[][..]));
+
+        // Longer than the buffer.
+        assert_eq!(element(&buffer[..buffer.len() - 1][7..]), None);
+        assert_eq!(element(&[0x04, 0x89, 1, 1, 1, 1, 1, 1, 1, 1, 1]), None);
+    }
+}
@@ -1,13 +1,14 @@
 use crate::config::get_config;
 use crate::errors::Error;
 use arc_swap::ArcSwap;
+use async_trait::async_trait;
 use log::{debug, error, info, warn};
 use once_cell::sync::Lazy;
 use std::collections::{HashMap, HashSet};
 use std::io;
 use std::net::IpAddr;
 use std::sync::Arc;
-use std::sync::RwLock;
+use std::sync::{Mutex, RwLock};
 use tokio::time::{sleep, Duration};
 use trust_dns_resolver::error::{ResolveError, ResolveResult};
 use trust_dns_resolver::lookup_ip::LookupIp;
@@ -30,6 +31,25 @@ impl AddrSet {
             set: HashSet::new(),
         }
     }
+
+    pub fn contains(&self, address: &IpAddr) -> bool {
+        self.set.contains(address)
+    }
+
+    // The addresses in a stable order, to go over them in turns.
+    fn addresses(&self) -> Vec<IpAddr> {
+        let mut addresses: Vec<IpAddr> = self.set.iter().cloned().collect();
+        addresses.sort();
+        addresses
+    }
+}
+
+impl From<Vec<IpAddr>> for AddrSet {
+    fn from(addresses: Vec<IpAddr>) -> Self {
+        AddrSet {
+            set: addresses.into_iter().collect(),
+        }
+    }
 }
 
 impl From<LookupIp> for AddrSet {
@@ -42,6 +62,22 @@ impl From<LookupIp> for AddrSet {
     }
 }
 
+/// Resolves hostnames into addresses, the system resolver
+/// unless another one is given, e.g. a stub in tests.
+#[async_trait]
+pub trait Resolve: Send + Sync {
+    async fn lookup_ip(&self, host: &str) -> ResolveResult<AddrSet>;
+}
+
+#[async_trait]
+impl Resolve for TokioAsyncResolver {
+    async fn lookup_ip(&self, host: &str) -> ResolveResult<AddrSet> {
+        Ok(AddrSet::from(
+            TokioAsyncResolver::lookup_ip(self, host).await?,
+        ))
+    }
+}
+
 ///
 /// A CachedResolver is a DNS resolution cache mechanism with customizable expiration time.
 ///
@@ -49,7 +85,8 @@ impl From<LookupIp> for AddrSet {
 ///
 /// When a host is to be resolved, if we have not resolved it before, a new resolution is
 /// executed and stored in the internal cache. Concurrently, every `dns_max_ttl` time, the
-/// cache is refreshed.
+/// cache is refreshed. Server connections are made to the addresses of the host in turns
+/// and closed when they are released if the host no longer resolves to their address.
 ///
 /// # Example:
 ///
@@ -79,7 +116,10 @@ pub struct CachedResolver {
     data: Option<RwLock<HashMap<String, AddrSet>>>,
 
     // The resolver to be used for DNS queries.
-    resolver: Option<TokioAsyncResolver>,
+    resolver: Option<Arc<dyn Resolve>>,
+
+    // The next address of each host to connect to.
+    next_address: Mutex<HashMap<String, usize>>,
 
     // The RefreshLoop
     refresh_loop: RwLock<Option<tokio::task::JoinHandle<()>>>,
@@ -136,7 +176,18 @@ impl CachedResolver {
         data: Option<HashMap<String, AddrSet>>,
     ) -> Result<Arc<Self>, io::Error> {
         // Construct a new Resolver with default configuration options
-        let resolver = Some(TokioAsyncResolver::tokio_from_system_conf()?);
+        let resolver = Arc::new(TokioAsyncResolver::tokio_from_system_conf()?);
+
+        Ok(Self::with_resolver(config, data, resolver))
+    }
+
+    /// Same as `new`, resolving the hostnames with `resolver`.
+    pub fn with_resolver(
+        config: CachedResolverConfig,
+        data: Option<HashMap<String, AddrSet>>,
+        resolver: Arc<dyn Resolve>,
+    ) -> Arc<Self> {
+        let resolver = Some(resolver);
 
         let data = if let Some(hash) = data {
             Some(RwLock::new(hash))
@@ -147,6 +198,7 @@ impl CachedResolver {
         let instance = Arc::new(Self {
             config,
             resolver,
+            next_address: Mutex::new(HashMap::new()),
             data,
             refresh_loop: RwLock::new(None),
         });
@@ -162,7 +214,7 @@ impl CachedResolver {
             *(instance.refresh_loop.write().unwrap()) = Some(refresh_loop);
         }
 
-        Ok(instance)
+        instance
     }
 
     pub fn enabled(&self) -> bool {
@@ -171,53 +223,61 @@ impl CachedResolver {
 
     // Schedules the refresher
     async fn refresh_dns_entries_loop(&self) {
-        let resolver = TokioAsyncResolver::tokio_from_system_conf().unwrap();
         let interval = Duration::from_secs(self.config.dns_max_ttl);
         loop {
-            debug!("Begin refreshing cached DNS addresses.");
-            // To minimize the time we hold the lock, we first create
-            // an array with keys.
-            let mut hostnames: Vec<String> = Vec::new();
-            {
-                if let Some(ref data) = self.data {
-                    for hostname in data.read().unwrap().keys() {
-                        hostnames.push(hostname.clone());
-                    }
+            self.refresh_dns_entries().await;
+            sleep(interval).await;
+        }
+    }
+
+    // Resolves the cached hosts again.
+    async fn refresh_dns_entries(&self) {
+        let resolver = match self.resolver {
+            Some(ref resolver) => resolver,
+            None => return,
+        };
+
+        debug!("Begin refreshing cached DNS addresses.");
+        // To minimize the time we hold the lock, we first create
+        // an array with keys.
+        let mut hostnames: Vec<String> = Vec::new();
+        {
+            if let Some(ref data) = self.data {
+                for hostname in data.read().unwrap().keys() {
+                    hostnames.push(hostname.clone());
                 }
             }
+        }
 
-            for hostname in hostnames.iter() {
-                let addrset = self
-                    .fetch_from_cache(hostname.as_str())
-                    .expect("Could not obtain expected address from cache, this should not happen");
+        for hostname in hostnames.iter() {
+            let addrset = self
+                .fetch_from_cache(hostname.as_str())
+                .expect("Could not obtain expected address from cache, this should not happen");
 
-                match resolver.lookup_ip(hostname).await {
-                    Ok(lookup_ip) => {
-                        let new_addrset = AddrSet::from(lookup_ip);
-                        debug!(
-                            "Obtained address for host ({}) -> ({:?})",
-                            hostname, new_addrset
-                        );
+            match resolver.lookup_ip(hostname).await {
+                Ok(new_addrset) => {
+                    debug!(
+                        "Obtained address for host ({}) -> ({:?})",
+                        hostname, new_addrset
+                    );
 
-                        if addrset != new_addrset {
-                            debug!(
-                                "Addr changed from {:?} to {:?} updating cache.",
-                                addrset, new_addrset
-                            );
-                            self.store_in_cache(hostname, new_addrset);
-                        }
-                    }
-                    Err(err) => {
-                        error!(
-                            "There was an error trying to resolv {}: ({}).",
-                            hostname, err
+                    if addrset != new_addrset {
+                        debug!(
+                            "Addr changed from {:?} to {:?} updating cache.",
+                            addrset, new_addrset
                         );
+                        self.store_in_cache(hostname, new_addrset);
                     }
                 }
+                Err(err) => {
+                    error!(
+                        "There was an error trying to resolv {}: ({}).",
+                        hostname, err
+                    );
+                }
             }
-            debug!("Finished refreshing cached DNS addresses.");
-            sleep(interval).await;
         }
+        debug!("Finished refreshing cached DNS addresses.");
     }
 
     /// Returns a `AddrSet` given the specified hostname.
@@ -251,7 +311,7 @@ impl CachedResolver {
             None => {
                 debug!("Not found, executing a dns query!");
                 if let Some(ref resolver) = self.resolver {
-                    let addr_set = AddrSet::from(resolver.lookup_ip(host).await?);
+                    let addr_set = resolver.lookup_ip(host).await?;
                     debug!("Obtained: {:?}", addr_set);
                     self.store_in_cache(host, addr_set.clone());
                     Ok(addr_set)
@@ -262,6 +322,33 @@ impl CachedResolver {
         }
     }
 
+    /// Returns the addresses to connect to the host, in the order to try them.
+    /// The addresses the host resolves to come first in turns.
+    pub async fn resolve(&self, host: &str) -> ResolveResult<Vec<IpAddr>> {
+        let mut addresses = self.lookup_ip(host).await?.addresses();
+
+        if addresses.is_empty() {
+            return Err(ResolveError::from("No address found"));
+        }
+
+        let mut next_address = self.next_address.lock().unwrap();
+        let next = next_address.entry(host.to_string()).or_insert(0);
+        let first = *next % addresses.len();
+        addresses.rotate_left(first);
+        *next = next.wrapping_add(1);
+
+        Ok(addresses)
+    }
+
+    /// Returns false if the host no longer resolves to the address,
+    /// so the connections made to it are closed when they are released.
+    pub fn resolves_to(&self, host: &str, address: &IpAddr) -> bool {
+        match self.fetch_from_cache(host) {
+            Some(addr_set) => addr_set.contains(address),
+            None => true,
+        }
+    }
+
     //
     // Returns true if the stored host resolution differs from the AddrSet passed.
     pub fn has_changed(&self, host: &str, addr_set: &AddrSet) -> bool {
@@ -387,6 +474,88 @@ mod tests {
         assert!(!resolver.has_changed(hostname, &AddrSet::new()));
     }
 
+    // Resolves every host to the addresses it is given.
+    struct StubResolver {
+        addresses: RwLock<Vec<IpAddr>>,
+    }
+
+    impl StubResolver {
+        fn new(addresses: &[&str]) -> Arc<StubResolver> {
+            let stub = Arc::new(StubResolver {
+                addresses: RwLock::new(Vec::new()),
+            });
+            stub.set(addresses);
+            stub
+        }
+
+        fn set(&self, addresses: &[&str]) {
+            *self.addresses.write().unwrap() = addresses
+                .iter()
+                .map(|address| address.parse().unwrap())
+                .collect();
+        }
+    }
+
+    #[async_trait]
+    impl Resolve for StubResolver {
+        async fn lookup_ip(&self, _host: &str) -> ResolveResult<AddrSet> {
+            Ok(AddrSet::from(self.addresses.read().unwrap().clone()))
+        }
+    }
+
+    #[tokio::test]
+    async fn round_robin() {
+        let config = CachedResolverConfig {
+            dns_max_ttl: 10,
+            enabled: false,
+        };
+        let stub = StubResolver::new(&["10.0.0.2", "10.0.0.1"]);
+        let resolver = CachedResolver::with_resolver(config, None, stub.clone());
+
+        let mut addresses = Vec::new();
+        for _ in 0..4 {
+            addresses.push(resolver.resolve("db.local").await.unwrap()[0].to_string());
+        }
+        assert_eq!(
+            addresses,
+            vec!["10.0.0.1", "10.0.0.2", "10.0.0.1", "10.0.0.2"]
+        );
+
+        stub.set(&[]);
+        assert!(resolver.resolve("empty.local").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn connections_cycle_on_change() {
+        let config = CachedResolverConfig {
+            dns_max_ttl: 10,
+            enabled: false,
+        };
+        let stub = StubResolver::new(&["10.0.0.1", "10.0.0.2"]);
+        let resolver = CachedResolver::with_resolver(config, None, stub.clone());
+        let hostname = "db.local";
+
+        let first = resolver.resolve(hostname).await.unwrap()[0];
+        let second = resolver.resolve(hostname).await.unwrap()[0];
+        assert!(resolver.resolves_to(hostname, &first));
+        assert!(resolver.resolves_to(hostname, &second));
+
+        // The cache is used until it's refreshed.
+        stub.set(&["10.0.0.2", "10.0.0.3"]);
+        assert_eq!(resolver.resolve(hostname).await.unwrap()[0], first);
+        resolver.refresh_dns_entries().await;
+
+        // Only the connections to the address that went away are closed on release,
+        // new connections use the new addresses.
+        assert!(!resolver.resolves_to(hostname, &first));
+        assert!(resolver.resolves_to(hostname, &second));
+        assert_eq!(
+            resolver.resolve(hostname).await.unwrap()[0].to_string(),
+            "10.0.0.3"
+        );
+        assert_eq!(resolver.resolve(hostname).await.unwrap()[0], second);
+    }
+
     #[tokio::test]
     // Ok, this test is based on the fact that google does DNS RR
     // and does not responds with every available ip everytime, so
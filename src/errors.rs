@@ -17,6 +17,8 @@ pub enum Error {
     ServerAuthError(String, ServerIdentifier),
     BadConfig,
     AllServersDown,
+    AllReplicasBanned,
+    CheckoutTimeout,
     ClientError(String),
     TlsError,
     StatementTimeout,
@@ -30,6 +32,9 @@ pub enum Error {
     QueryRouterError(String),
     InvalidShardId(usize),
     PreparedStatementError,
+    PinnedServerUnavailable(String),
+    PoolPaused,
+    ServerTooManyConnections,
 }
 
 #[derive(Clone, PartialEq, Debug)]
@@ -120,6 +125,22 @@ impl std::fmt::Display for Error {
                 write!(f, "{} for {}", error, server_identifier,)
             }
 
+            &Error::PinnedServerUnavailable(reason) => write!(
+                f,
+                "pinned server {}, SET pgcat.sticky TO off to use another server",
+                reason
+            ),
+            &Error::PoolPaused => write!(f, "pool is paused"),
+            &Error::ServerTooManyConnections => write!(
+                f,
+                "the server has too many connections, try again in a moment"
+            ),
+            &Error::AllReplicasBanned => write!(
+                f,
+                "all replicas are banned, reads fail until their bans expire \
+                (on_all_replicas_banned = \"error\")"
+            ),
+
             // The rest can use Debug.
             err => write!(f, "{:?}", err),
         }
@@ -0,0 +1,169 @@
+//! Pool events, sent to the admin clients listening to `pgcat_events`.
+use log::warn;
+use once_cell::sync::Lazy;
+use serde_derive::Serialize;
+use tokio::sync::broadcast::{channel, error::RecvError, Receiver, Sender};
+
+use crate::config::Address;
+use crate::pool::BanReason;
+
+/// The channel admin clients `LISTEN` to.
+pub const EVENTS_CHANNEL: &str = "pgcat_events";
+
+/// How many events a listener can fall behind before it misses some.
+const EVENTS_QUEUE_SIZE: usize = 1024;
+
+static EVENTS: Lazy<Sender<PoolEvent>> = Lazy::new(|| channel(EVENTS_QUEUE_SIZE).0);
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum PoolEventKind {
+    Pause,
+    Resume,
+    Ban,
+    Unban,
+    Reload,
+    AddressAdded,
+    AddressRemoved,
+}
+
+/// Payload of the notifications, as JSON.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct PoolEvent {
+    pub event: PoolEventKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pool: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shard: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+    pub timestamp: String,
+}
+
+impl PoolEvent {
+    pub fn new(event: PoolEventKind) -> PoolEvent {
+        PoolEvent {
+            event,
+            pool: None,
+            user: None,
+            address: None,
+            shard: None,
+            role: None,
+            reason: None,
+            timestamp: chrono::offset::Utc::now().to_rfc3339(),
+        }
+    }
+
+    /// An event of the pool, e.g. it was paused.
+    pub fn for_pool(event: PoolEventKind, pool: &str, user: &str) -> PoolEvent {
+        PoolEvent {
+            pool: Some(pool.into()),
+            user: Some(user.into()),
+            ..PoolEvent::new(event)
+        }
+    }
+
+    /// An event of a server of a pool, e.g. it was banned.
+    pub fn for_address(
+        event: PoolEventKind,
+        address: &Address,
+        reason: Option<&BanReason>,
+    ) -> PoolEvent {
+        PoolEvent {
+            address: Some(format!("{}:{}", address.host, address.port)),
+            shard: Some(address.shard),
+            role: Some(address.role.to_string()),
+            reason: reason.map(|reason| format!("{:?}", reason)),
+            ..PoolEvent::for_pool(event, &address.pool_name, &address.username)
+        }
+    }
+
+    pub fn payload(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+}
+
+/// Send the event to the current listeners, if any.
+pub fn publish(event: PoolEvent) {
+    let _ = EVENTS.send(event);
+}
+
+/// Start receiving the events published from now on.
+pub fn subscribe() -> Receiver<PoolEvent> {
+    EVENTS.subscribe()
+}
+
+/// Wait for the next event, forever when not listening. The events a slow listener
+/// fell too far behind on are dropped.
+pub async fn next(events: &mut Option<Receiver<PoolEvent>>) -> PoolEvent {
+    if let Some(receiver) = events {
+        loop {
+            match receiver.recv().await {
+                Ok(event) => return event,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(
+                        "Admin client listening to pool events missed {} of them",
+                        missed
+                    )
+                }
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    std::future::pending().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_slow_listener() {
+        let mut events = Some(subscribe());
+
+        for _ in 0..EVENTS_QUEUE_SIZE + 1 {
+            publish(PoolEvent::new(PoolEventKind::Reload));
+        }
+        publish(PoolEvent::for_pool(
+            PoolEventKind::Pause,
+            "slow_listener",
+            "user",
+        ));
+
+        // The oldest events are gone, the listener gets the ones still queued.
+        // Other tests publish events too.
+        let mut received = Vec::new();
+        while let Ok(event) =
+            tokio::time::timeout(std::time::Duration::from_millis(10), next(&mut events)).await
+        {
+            received.push(event);
+        }
+        assert!(received.len() <= EVENTS_QUEUE_SIZE);
+
+        let pause = received
+            .iter()
+            .find(|event| event.pool.as_deref() == Some("slow_listener"))
+            .unwrap();
+        assert_eq!(
+            pause.payload(),
+            format!(
+                r#"{{"event":"pause","pool":"slow_listener","user":"user","timestamp":"{}"}}"#,
+                pause.timestamp
+            )
+        );
+
+        // Not listening, nothing ever comes.
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(10), next(&mut None))
+                .await
+                .is_err()
+        );
+    }
+}
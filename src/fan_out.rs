@@ -0,0 +1,327 @@
+//! Queries sent to all shards, e.g. `/* pgcat_shard: all */ SELECT count(*) FROM events`.
+//! The rows of every shard are concatenated into a single result, nothing is aggregated:
+//! that query returns one count per shard.
+use bytes::{BufMut, BytesMut};
+use once_cell::sync::Lazy;
+use regex::Regex;
+
+use crate::messages::{command_complete, ready_for_query};
+
+/// Comment routing a simple query to all shards.
+static ALL_SHARDS: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?i)/\*\s*pgcat_shard\s*:\s*all\s*\*/").unwrap());
+
+/// Whether the message is a simple query that should go to all shards.
+pub fn is_fan_out(message: &BytesMut) -> bool {
+    if message.first() != Some(&b'Q') || message.len() < 5 {
+        return false;
+    }
+
+    ALL_SHARDS.is_match(&String::from_utf8_lossy(&message[5..]))
+}
+
+/// What a shard returned for the query.
+#[derive(Debug, Default)]
+struct ShardResult {
+    row_description: Option<BytesMut>,
+    rows: Vec<BytesMut>,
+    command_complete: Vec<String>,
+    notices: Vec<BytesMut>,
+    error: Option<BytesMut>,
+}
+
+impl ShardResult {
+    /// Split the messages of a response, up to and including ReadyForQuery.
+    fn parse(mut response: BytesMut) -> Result<ShardResult, String> {
+        let mut result = ShardResult::default();
+
+        while response.len() >= 5 {
+            let code = response[0];
+            let len = i32::from_be_bytes(response[1..5].try_into().unwrap());
+            let end = match usize::try_from(len).ok().filter(|len| *len >= 4) {
+                Some(len) => len.checked_add(1).filter(|end| *end <= response.len()),
+                None => None,
+            };
+            let message = match end {
+                Some(end) => response.split_to(end),
+                None => return Err("invalid response from server".into()),
+            };
+
+            match code {
+                b'T' => {
+                    if result.row_description.is_some() {
+                        return Err("only one statement can be sent to all shards".into());
+                    }
+                    result.row_description = Some(message);
+                }
+                b'D' => result.rows.push(message),
+                b'C' => {
+                    let tag = message.get(5..message.len() - 1).unwrap_or_default();
+                    result
+                        .command_complete
+                        .push(String::from_utf8_lossy(tag).to_string());
+                }
+                b'N' => result.notices.push(message),
+                b'E' => result.error = Some(message),
+                b'Z' => break,
+                // ParameterStatus and the like are about the server connections,
+                // not what the client sees.
+                _ => (),
+            }
+        }
+
+        if result.command_complete.len() > 1 {
+            return Err("only one statement can be sent to all shards".into());
+        }
+
+        Ok(result)
+    }
+}
+
+/// Column names, types and formats of a RowDescription. The table OIDs and
+/// column numbers are left out, they're different in every shard's database.
+fn row_columns(row_description: &BytesMut) -> Option<Vec<(&[u8], &[u8])>> {
+    let mut fields = row_description.get(7..)?;
+    let count = i16::from_be_bytes(row_description.get(5..7)?.try_into().ok()?);
+    let mut columns = Vec::new();
+
+    for _ in 0..count {
+        let name_end = fields.iter().position(|byte| *byte == 0)?;
+        let name = &fields[..name_end];
+        // Table OID (4) and column number (2), then type OID (4), size (2), modifier (4), format (2).
+        let field = fields.get(name_end + 1..name_end + 19)?;
+        columns.push((name, &field[6..]));
+        fields = &fields[name_end + 19..];
+    }
+
+    Some(columns)
+}
+
+/// Add up the counts of the command tags, e.g. `SELECT 2` and `SELECT 3` make `SELECT 5`.
+fn merge_tags(tags: &[String]) -> Result<String, String> {
+    let mut command = None;
+    let mut count = 0u64;
+
+    for tag in tags {
+        let (prefix, rows) = match tag.rsplit_once(' ') {
+            Some((prefix, rows)) => match rows.parse::<u64>() {
+                Ok(rows) => (prefix, rows),
+                Err(_) => (tag.as_str(), 0),
+            },
+            None => (tag.as_str(), 0),
+        };
+
+        match command {
+            None => command = Some(prefix),
+            Some(command) if command != prefix => {
+                return Err("shards returned different commands".into());
+            }
+            _ => (),
+        }
+
+        count += rows;
+    }
+
+    match command {
+        // Commands without a row count, e.g. SET.
+        Some(command) if tags.iter().all(|tag| tag == command) => Ok(command.to_string()),
+        Some(command) => Ok(format!("{} {}", command, count)),
+        None => Err("no result from shards".into()),
+    }
+}
+
+/// Merge the responses of all shards to a simple query into one response,
+/// as if a single server returned all the rows. If a shard returned an error,
+/// that's the response instead. Returns the message to send the client
+/// in an ErrorResponse if the results can't be merged.
+pub fn merge(responses: Vec<BytesMut>) -> Result<BytesMut, String> {
+    let results = responses
+        .into_iter()
+        .map(ShardResult::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut merged = BytesMut::new();
+
+    for result in &results {
+        for notice in &result.notices {
+            merged.put(&notice[..]);
+        }
+    }
+
+    if let Some(error) = results.iter().find_map(|result| result.error.as_ref()) {
+        merged.put(&error[..]);
+        merged.put(ready_for_query(false));
+        return Ok(merged);
+    }
+
+    let row_description = match results.first() {
+        Some(result) => result.row_description.as_ref(),
+        None => return Err("no result from shards".into()),
+    };
+
+    let columns = row_description.map(row_columns);
+    if results
+        .iter()
+        .any(|result| result.row_description.as_ref().map(row_columns) != columns)
+    {
+        return Err("shards returned different columns".into());
+    }
+
+    let tags = results
+        .iter()
+        .flat_map(|result| result.command_complete.iter().cloned())
+        .collect::<Vec<_>>();
+
+    // Empty query.
+    if tags.is_empty() && row_description.is_none() {
+        let mut empty = BytesMut::new();
+        empty.put_u8(b'I');
+        empty.put_i32(4);
+        merged.put(empty);
+        merged.put(ready_for_query(false));
+        return Ok(merged);
+    }
+
+    if tags.len() != results.len() {
+        return Err("only one statement can be sent to all shards".into());
+    }
+
+    if let Some(row_description) = row_description {
+        merged.put(&row_description[..]);
+    }
+
+    for result in results {
+        for row in result.rows {
+            merged.put(row);
+        }
+    }
+
+    merged.put(command_complete(&merge_tags(&tags)?));
+    merged.put(ready_for_query(false));
+
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::{data_row, row_description, simple_query, DataType};
+    use bytes::Buf;
+
+    /// Messages in a response, as (code, body).
+    fn message_codes(mut response: BytesMut) -> Vec<(char, BytesMut)> {
+        let mut messages = Vec::new();
+
+        while response.len() >= 5 {
+            let code = response.get_u8() as char;
+            let len = response.get_i32() as usize;
+            messages.push((code, response.split_to(len - 4)));
+        }
+
+        messages
+    }
+
+    fn response(column: &str, rows: &[&str]) -> BytesMut {
+        let mut response = BytesMut::new();
+        response.put(row_description(&vec![(column, DataType::Text)]));
+        for row in rows {
+            response.put(data_row(&vec![row.to_string()]));
+        }
+        response.put(command_complete(&format!("SELECT {}", rows.len())));
+        response.put(ready_for_query(false));
+        response
+    }
+
+    #[test]
+    fn test_is_fan_out() {
+        assert!(is_fan_out(&simple_query(
+            "/* pgcat_shard: all */ SELECT count(*) FROM events"
+        )));
+        assert!(is_fan_out(&simple_query(
+            "SELECT count(*) FROM events /*PGCAT_SHARD:ALL*/"
+        )));
+        assert!(!is_fan_out(&simple_query("/* pgcat_shard: 1 */ SELECT 1")));
+        assert!(!is_fan_out(&simple_query("SELECT 1")));
+    }
+
+    #[test]
+    fn test_merge() {
+        let merged = merge(vec![
+            response("id", &["1", "2"]),
+            response("id", &["3", "4", "5"]),
+        ])
+        .unwrap();
+
+        let messages = message_codes(merged);
+        let codes = messages.iter().map(|(code, _)| *code).collect::<String>();
+        assert_eq!(codes, "TDDDDDCZ");
+        assert_eq!(
+            messages[1..6]
+                .iter()
+                .map(|(_, row)| String::from_utf8_lossy(&row[6..]).to_string())
+                .collect::<Vec<_>>(),
+            vec!["1", "2", "3", "4", "5"]
+        );
+        assert_eq!(&messages[6].1[..], b"SELECT 5\0");
+        assert_eq!(&messages[7].1[..], b"I");
+    }
+
+    #[test]
+    fn test_merge_mismatch() {
+        // Same column from a table with a different OID.
+        let mut other_table = response("id", &["2"]);
+        other_table[10..14].copy_from_slice(&16384i32.to_be_bytes());
+        assert!(merge(vec![response("id", &["1"]), other_table]).is_ok());
+
+        assert_eq!(
+            merge(vec![response("id", &["1"]), response("name", &["a"])]),
+            Err("shards returned different columns".into())
+        );
+    }
+
+    #[test]
+    fn test_merge_error() {
+        let fields = b"SERROR\0C42P01\0Mrelation \"events\" does not exist\0\0";
+        let mut error = BytesMut::new();
+        error.put_u8(b'E');
+        error.put_i32(fields.len() as i32 + 4);
+        error.put_slice(fields);
+        error.put(ready_for_query(false));
+
+        let merged = merge(vec![response("id", &["1"]), error]).unwrap();
+        let codes = message_codes(merged)
+            .iter()
+            .map(|(code, _)| *code)
+            .collect::<String>();
+        assert_eq!(codes, "EZ");
+    }
+
+    #[test]
+    fn test_parse_invalid_length() {
+        for len in [-1, 0, 3, i32::MAX] {
+            let mut response = BytesMut::new();
+            response.put_u8(b'D');
+            response.put_i32(len);
+            response.put(ready_for_query(false));
+
+            assert_eq!(
+                ShardResult::parse(response).err(),
+                Some("invalid response from server".into())
+            );
+        }
+    }
+
+    #[test]
+    fn test_merge_tags() {
+        assert_eq!(
+            merge_tags(&["UPDATE 2".into(), "UPDATE 3".into()]),
+            Ok("UPDATE 5".into())
+        );
+        assert_eq!(
+            merge_tags(&["INSERT 0 1".into(), "INSERT 0 2".into()]),
+            Ok("INSERT 0 3".into())
+        );
+        assert_eq!(merge_tags(&["SET".into(), "SET".into()]), Ok("SET".into()));
+        assert!(merge_tags(&["SELECT 1".into(), "UPDATE 1".into()]).is_err());
+    }
+}
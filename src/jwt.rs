@@ -0,0 +1,622 @@
+//! Client authentication with JSON Web Tokens sent in place of the password.
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use bytes::Bytes;
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper::header::HOST;
+use hyper::{Request, Uri};
+use hyper_util::rt::TokioIo;
+use log::debug;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use ring::signature::{self, UnparsedPublicKey, VerificationAlgorithm};
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use serde_json::Value;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::time::{Duration, Instant};
+
+use crate::config::Jwt;
+use crate::der::{element, put_element};
+use crate::errors::Error;
+
+/// Don't reload the keys more often than this when a token uses an unknown key.
+const MIN_KEYS_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Largest JWKS document we accept.
+const MAX_JWKS_LENGTH: usize = 1024 * 1024;
+
+/// DER tags used by public keys.
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OID: u8 = 0x06;
+
+/// Object identifiers of the supported key types.
+const OID_RSA_ENCRYPTION: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x01];
+const OID_EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const OID_PRIME256V1: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+
+/// Signing keys, cached between logins.
+static KEYS: Lazy<Mutex<Option<CachedKeys>>> = Lazy::new(|| Mutex::new(None));
+
+/// Supported signature algorithms.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Algorithm {
+    RS256,
+    ES256,
+}
+
+impl Algorithm {
+    fn from_name(name: &str) -> Option<Algorithm> {
+        match name {
+            "RS256" => Some(Algorithm::RS256),
+            "ES256" => Some(Algorithm::ES256),
+            _ => None,
+        }
+    }
+
+    fn verification(&self) -> &'static dyn VerificationAlgorithm {
+        match self {
+            Algorithm::RS256 => &signature::RSA_PKCS1_2048_8192_SHA256,
+            Algorithm::ES256 => &signature::ECDSA_P256_SHA256_FIXED,
+        }
+    }
+}
+
+/// A public key tokens can be signed with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Key {
+    kid: Option<String>,
+    algorithm: Algorithm,
+
+    /// PKCS#1 for RSA keys, uncompressed point for EC keys.
+    public_key: Vec<u8>,
+}
+
+impl Key {
+    /// Parse a PEM-encoded public key, either "PUBLIC KEY" or "RSA PUBLIC KEY".
+    pub fn from_pem(pem: &str) -> Result<Key, String> {
+        let rsa = pem.contains("-----BEGIN RSA PUBLIC KEY-----");
+        if !rsa && !pem.contains("-----BEGIN PUBLIC KEY-----") {
+            return Err("expected a PUBLIC KEY PEM block".into());
+        }
+
+        let base64 = pem
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect::<String>();
+        let der = STANDARD
+            .decode(base64.trim())
+            .map_err(|err| format!("invalid PEM: {}", err))?;
+
+        if rsa {
+            return Ok(Key {
+                kid: None,
+                algorithm: Algorithm::RS256,
+                public_key: der,
+            });
+        }
+
+        // SubjectPublicKeyInfo
+        let invalid = || String::from("invalid public key");
+        let (tag, info, _) = element(&der).ok_or_else(invalid)?;
+        if tag != TAG_SEQUENCE {
+            return Err(invalid());
+        }
+        let (_, identifier, rest) = element(info).ok_or_else(invalid)?;
+        let (tag, oid, parameters) = element(identifier).ok_or_else(invalid)?;
+        if tag != TAG_OID {
+            return Err(invalid());
+        }
+        let public_key = match element(rest) {
+            Some((TAG_BIT_STRING, [0, public_key @ ..], _)) => public_key.to_vec(),
+            _ => return Err(invalid()),
+        };
+
+        let algorithm = match oid {
+            OID_RSA_ENCRYPTION => Algorithm::RS256,
+            OID_EC_PUBLIC_KEY => match element(parameters) {
+                Some((TAG_OID, OID_PRIME256V1, _)) => Algorithm::ES256,
+                _ => return Err("only P-256 EC keys are supported".into()),
+            },
+            _ => return Err("only RSA and EC keys are supported".into()),
+        };
+
+        Ok(Key {
+            kid: None,
+            algorithm,
+            public_key,
+        })
+    }
+
+    /// Parse a key from a JWK Set.
+    fn from_jwk(jwk: &Value) -> Result<Key, String> {
+        let field = |name: &str| -> Result<Vec<u8>, String> {
+            jwk.get(name)
+                .and_then(Value::as_str)
+                .and_then(|value| URL_SAFE_NO_PAD.decode(value).ok())
+                .ok_or_else(|| format!("missing or invalid {}", name))
+        };
+
+        if let Some(usage) = jwk.get("use").and_then(Value::as_str) {
+            if usage != "sig" {
+                return Err(format!("key is for {}", usage));
+            }
+        }
+
+        let (algorithm, public_key) = match jwk.get("kty").and_then(Value::as_str) {
+            Some("RSA") => {
+                let mut integers = Vec::new();
+                put_element(&mut integers, TAG_INTEGER, &der_integer(&field("n")?));
+                put_element(&mut integers, TAG_INTEGER, &der_integer(&field("e")?));
+                let mut public_key = Vec::new();
+                put_element(&mut public_key, TAG_SEQUENCE, &integers);
+                (Algorithm::RS256, public_key)
+            }
+            Some("EC") if jwk.get("crv").and_then(Value::as_str) == Some("P-256") => {
+                let mut public_key = vec![0x04];
+                public_key.extend(field("x")?);
+                public_key.extend(field("y")?);
+                (Algorithm::ES256, public_key)
+            }
+            _ => return Err("only RSA and P-256 EC keys are supported".into()),
+        };
+
+        if let Some(alg) = jwk.get("alg").and_then(Value::as_str) {
+            if Algorithm::from_name(alg) != Some(algorithm) {
+                return Err(format!("unsupported algorithm {}", alg));
+            }
+        }
+
+        Ok(Key {
+            kid: jwk.get("kid").and_then(Value::as_str).map(String::from),
+            algorithm,
+            public_key,
+        })
+    }
+
+    /// Can the key have signed a token with this header.
+    fn matches(&self, header: &Header) -> bool {
+        Algorithm::from_name(&header.alg) == Some(self.algorithm)
+            && match (&self.kid, &header.kid) {
+                (Some(kid), Some(header_kid)) => kid == header_kid,
+                _ => true,
+            }
+    }
+
+    fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        UnparsedPublicKey::new(self.algorithm.verification(), &self.public_key)
+            .verify(message, signature)
+            .is_ok()
+    }
+}
+
+struct CachedKeys {
+    source: String,
+    loaded_at: Instant,
+    keys: Arc<Vec<Key>>,
+}
+
+#[derive(Deserialize, Debug)]
+struct Header {
+    alg: String,
+    kid: Option<String>,
+}
+
+/// Check the token sent by the client was signed by one of the configured keys,
+/// is currently valid and was issued for this username.
+pub async fn authenticate(config: &Jwt, username: &str, token: &str) -> Result<(), Error> {
+    let malformed = || Error::AuthError("malformed token".into());
+
+    let (message, signature) = token.rsplit_once('.').ok_or_else(malformed)?;
+    let (header, payload) = message.split_once('.').ok_or_else(malformed)?;
+
+    let header: Header = decode_json(header)?;
+    if Algorithm::from_name(&header.alg).is_none() {
+        return Err(Error::AuthError(format!(
+            "unsupported algorithm {}",
+            header.alg
+        )));
+    }
+    let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| malformed())?;
+
+    let mut keys = load_keys(config, false).await?;
+    if !keys.iter().any(|key| key.matches(&header)) {
+        // The keys could have been rotated.
+        keys = load_keys(config, true).await?;
+    }
+
+    if !keys
+        .iter()
+        .filter(|key| key.matches(&header))
+        .any(|key| key.verify(message.as_bytes(), &signature))
+    {
+        return Err(Error::AuthError("invalid signature".into()));
+    }
+
+    let claims: Value = decode_json(payload)?;
+    validate_claims(config, username, &claims, chrono::Utc::now().timestamp())
+}
+
+/// Check the claims of a token with a valid signature.
+fn validate_claims(config: &Jwt, username: &str, claims: &Value, now: i64) -> Result<(), Error> {
+    let now = now as f64;
+    let leeway = config.leeway as f64;
+
+    match claims.get("exp").and_then(Value::as_f64) {
+        Some(exp) if exp + leeway > now => (),
+        Some(_) => return Err(Error::AuthError("token expired".into())),
+        None => return Err(Error::AuthError("token has no exp claim".into())),
+    }
+
+    if let Some(nbf) = claims.get("nbf") {
+        match nbf.as_f64() {
+            Some(nbf) if nbf - leeway <= now => (),
+            _ => return Err(Error::AuthError("token is not valid yet".into())),
+        }
+    }
+
+    if claims.get("iss").and_then(Value::as_str) != Some(config.issuer.as_str()) {
+        return Err(Error::AuthError(format!(
+            "unexpected issuer {}",
+            claims.get("iss").unwrap_or(&Value::Null)
+        )));
+    }
+
+    let audience = match claims.get("aud") {
+        Some(Value::String(audience)) => audience == &config.audience,
+        Some(Value::Array(audiences)) => audiences
+            .iter()
+            .any(|audience| audience.as_str() == Some(config.audience.as_str())),
+        _ => false,
+    };
+    if !audience {
+        return Err(Error::AuthError(format!(
+            "unexpected audience {}",
+            claims.get("aud").unwrap_or(&Value::Null)
+        )));
+    }
+
+    if claims.get(&config.username_claim).and_then(Value::as_str) != Some(username) {
+        return Err(Error::AuthError(format!(
+            "token {} claim doesn't match username {}",
+            config.username_claim, username
+        )));
+    }
+
+    Ok(())
+}
+
+fn decode_json<T: DeserializeOwned>(segment: &str) -> Result<T, Error> {
+    URL_SAFE_NO_PAD
+        .decode(segment)
+        .ok()
+        .and_then(|json| serde_json::from_slice(&json).ok())
+        .ok_or_else(|| Error::AuthError("malformed token".into()))
+}
+
+/// Minimal DER encoding of an unsigned big-endian integer.
+fn der_integer(bytes: &[u8]) -> Vec<u8> {
+    let bytes = &bytes[bytes.iter().position(|b| *b != 0).unwrap_or(bytes.len())..];
+    let mut integer = Vec::with_capacity(bytes.len() + 1);
+    // Keep the integer positive.
+    match bytes.first() {
+        Some(byte) if byte & 0x80 == 0 => (),
+        _ => integer.push(0),
+    }
+    integer.extend_from_slice(bytes);
+    integer
+}
+
+/// The signing keys, loaded again if the cached ones are older than the refresh interval.
+async fn load_keys(config: &Jwt, refresh: bool) -> Result<Arc<Vec<Key>>, Error> {
+    let max_age = match refresh {
+        true => MIN_KEYS_REFRESH_INTERVAL,
+        false => Duration::from_millis(config.keys_refresh_interval),
+    };
+    let source = config
+        .jwks_url
+        .as_ref()
+        .or(config.public_key_path.as_ref())
+        .cloned()
+        .unwrap_or_default();
+
+    if let Some(cached) = &*KEYS.lock() {
+        if cached.source == source && cached.loaded_at.elapsed() < max_age {
+            return Ok(cached.keys.clone());
+        }
+    }
+
+    let keys = Arc::new(match &config.jwks_url {
+        Some(url) => {
+            let timeout = Duration::from_millis(config.timeout);
+            match tokio::time::timeout(timeout, fetch_jwks(url)).await {
+                Ok(keys) => keys?,
+                Err(_) => return Err(Error::AuthError(format!("JWKS {} timed out", url))),
+            }
+        }
+        None => {
+            let pem = tokio::fs::read_to_string(&source)
+                .await
+                .map_err(|err| Error::AuthError(format!("could not read {}: {}", source, err)))?;
+            vec![Key::from_pem(&pem).map_err(Error::AuthError)?]
+        }
+    });
+
+    debug!("Loaded {} JWT signing keys from {}", keys.len(), source);
+
+    *KEYS.lock() = Some(CachedKeys {
+        source,
+        loaded_at: Instant::now(),
+        keys: keys.clone(),
+    });
+
+    Ok(keys)
+}
+
+async fn fetch_jwks(url: &str) -> Result<Vec<Key>, Error> {
+    let error = |err: String| Error::AuthError(format!("could not fetch JWKS {}: {}", url, err));
+
+    let uri = url.parse::<Uri>().map_err(|err| error(err.to_string()))?;
+    let authority = match uri.authority() {
+        Some(authority) => authority.clone(),
+        None => return Err(error("no host".into())),
+    };
+
+    let stream = TcpStream::connect((authority.host(), authority.port_u16().unwrap_or(80)))
+        .await
+        .map_err(|err| error(err.to_string()))?;
+
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(|err| error(err.to_string()))?;
+
+    tokio::task::spawn(async move {
+        if let Err(err) = connection.await {
+            debug!("JWKS connection error: {:?}", err);
+        }
+    });
+
+    let request = Request::get(uri.path_and_query().map_or("/", |path| path.as_str()))
+        .header(HOST, authority.as_str())
+        .body(Empty::<Bytes>::new())
+        .map_err(|err| error(err.to_string()))?;
+
+    let response = sender
+        .send_request(request)
+        .await
+        .map_err(|err| error(err.to_string()))?;
+
+    if !response.status().is_success() {
+        return Err(error(format!(
+            "endpoint responded with {}",
+            response.status()
+        )));
+    }
+
+    let body = Limited::new(response.into_body(), MAX_JWKS_LENGTH)
+        .collect()
+        .await
+        .map_err(|err| error(err.to_string()))?
+        .to_bytes();
+
+    let jwks: Value = serde_json::from_slice(&body).map_err(|err| error(err.to_string()))?;
+    let keys = jwks
+        .get("keys")
+        .and_then(Value::as_array)
+        .ok_or_else(|| error("no keys".into()))?
+        .iter()
+        .filter_map(|jwk| match Key::from_jwk(jwk) {
+            Ok(key) => Some(key),
+            Err(err) => {
+                debug!("Skipping JWK {}: {}", jwk, err);
+                None
+            }
+        })
+        .collect::<Vec<Key>>();
+
+    match keys.is_empty() {
+        true => Err(error("no supported keys".into())),
+        false => Ok(keys),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use ring::rand::SystemRandom;
+    use ring::signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING};
+    use serde_json::json;
+    use tokio::net::TcpListener;
+
+    fn key_pair() -> EcdsaKeyPair {
+        let pkcs8 =
+            EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &SystemRandom::new())
+                .unwrap();
+        EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref()).unwrap()
+    }
+
+    fn public_key_pem(key_pair: &EcdsaKeyPair) -> String {
+        let mut identifier = Vec::new();
+        put_element(&mut identifier, TAG_OID, OID_EC_PUBLIC_KEY);
+        put_element(&mut identifier, TAG_OID, OID_PRIME256V1);
+        let mut bits = vec![0];
+        bits.extend_from_slice(key_pair.public_key().as_ref());
+        let mut info = Vec::new();
+        put_element(&mut info, TAG_SEQUENCE, &identifier);
+        put_element(&mut info, TAG_BIT_STRING, &bits);
+        let mut der = Vec::new();
+        put_element(&mut der, TAG_SEQUENCE, &info);
+
+        format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----\n",
+            STANDARD.encode(der)
+        )
+    }
+
+    fn sign(key_pair: &EcdsaKeyPair, kid: Option<&str>, claims: Value) -> String {
+        let header = json!({"alg": "ES256", "typ": "JWT", "kid": kid});
+        let message = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(header.to_string()),
+            URL_SAFE_NO_PAD.encode(claims.to_string())
+        );
+        let signature = key_pair
+            .sign(&SystemRandom::new(), message.as_bytes())
+            .unwrap();
+        format!("{}.{}", message, URL_SAFE_NO_PAD.encode(signature))
+    }
+
+    fn claims(exp_in: i64) -> Value {
+        json!({
+            "sub": "alice",
+            "iss": "https://issuer.example.org",
+            "aud": ["other", "pgcat"],
+            "exp": chrono::Utc::now().timestamp() + exp_in,
+        })
+    }
+
+    fn config() -> Jwt {
+        Jwt {
+            issuer: "https://issuer.example.org".into(),
+            audience: "pgcat".into(),
+            username_claim: Jwt::default_username_claim(),
+            public_key_path: None,
+            jwks_url: None,
+            keys_refresh_interval: Jwt::default_keys_refresh_interval(),
+            timeout: Jwt::default_timeout(),
+            leeway: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_jwt_public_key() {
+        let key_pair = key_pair();
+        let path = std::env::temp_dir().join(format!("pgcat_jwt_{}.pem", std::process::id()));
+        std::fs::write(&path, public_key_pem(&key_pair)).unwrap();
+        let config = Jwt {
+            public_key_path: Some(path.to_str().unwrap().into()),
+            ..config()
+        };
+        assert!(config.validate().is_ok());
+
+        let valid = sign(&key_pair, None, claims(60));
+        assert!(authenticate(&config, "alice", &valid).await.is_ok());
+
+        // Issued for someone else.
+        assert!(authenticate(&config, "bob", &valid).await.is_err());
+
+        let expired = sign(&key_pair, None, claims(-60));
+        assert!(authenticate(&config, "alice", &expired).await.is_err());
+
+        let mut wrong_issuer = claims(60);
+        wrong_issuer["iss"] = json!("https://evil.example.org");
+        let wrong_issuer = sign(&key_pair, None, wrong_issuer);
+        assert!(authenticate(&config, "alice", &wrong_issuer).await.is_err());
+
+        let mut wrong_audience = claims(60);
+        wrong_audience["aud"] = json!("other");
+        let wrong_audience = sign(&key_pair, None, wrong_audience);
+        assert!(authenticate(&config, "alice", &wrong_audience)
+            .await
+            .is_err());
+
+        // Signed by another key.
+        let forged = sign(&self::key_pair(), None, claims(60));
+        assert!(authenticate(&config, "alice", &forged).await.is_err());
+
+        // Unsigned.
+        let (unsigned, _) = valid.rsplit_once('.').unwrap();
+        let (_, payload) = unsigned.split_once('.').unwrap();
+        let unsigned = format!(
+            "{}.{}.",
+            URL_SAFE_NO_PAD.encode(json!({"alg": "none"}).to_string()),
+            payload
+        );
+        assert!(authenticate(&config, "alice", &unsigned).await.is_err());
+        assert!(authenticate(&config, "alice", "secret").await.is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_jwt_jwks() {
+        let key_pair = key_pair();
+        let point = key_pair.public_key().as_ref();
+        let jwks = json!({"keys": [
+            {"kty": "oct", "k": "c2VjcmV0"},
+            {
+                "kty": "EC",
+                "crv": "P-256",
+                "kid": "k1",
+                "use": "sig",
+                "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+                "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+            },
+        ]})
+        .to_string();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/jwks.json", listener.local_addr().unwrap());
+        tokio::task::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let jwks = jwks.clone();
+                let service = service_fn(move |_| {
+                    let jwks = jwks.clone();
+                    async move {
+                        Ok::<_, hyper::Error>(Response::new(http_body_util::Full::new(
+                            Bytes::from(jwks),
+                        )))
+                    }
+                });
+                tokio::task::spawn(
+                    http1::Builder::new().serve_connection(TokioIo::new(stream), service),
+                );
+            }
+        });
+
+        let config = Jwt {
+            jwks_url: Some(url),
+            ..config()
+        };
+        assert!(config.validate().is_ok());
+
+        let valid = sign(&key_pair, Some("k1"), claims(60));
+        assert!(authenticate(&config, "alice", &valid).await.is_ok());
+
+        let unknown_kid = sign(&key_pair, Some("k2"), claims(60));
+        assert!(authenticate(&config, "alice", &unknown_kid).await.is_err());
+
+        let expired = sign(&key_pair, Some("k1"), claims(-60));
+        assert!(authenticate(&config, "alice", &expired).await.is_err());
+    }
+
+    #[test]
+    fn test_validate_claims_leeway() {
+        let config = Jwt {
+            leeway: 30,
+            ..config()
+        };
+        let mut claims = claims(0);
+        claims["exp"] = json!(1000);
+        claims["nbf"] = json!(900);
+
+        assert!(validate_claims(&config, "alice", &claims, 1020).is_ok());
+        assert!(validate_claims(&config, "alice", &claims, 1040).is_err());
+        assert!(validate_claims(&config, "alice", &claims, 880).is_ok());
+        assert!(validate_claims(&config, "alice", &claims, 860).is_err());
+    }
+
+    #[test]
+    fn test_der_integer() {
+        assert_eq!(der_integer(&[0x01, 0x00, 0x01]), vec![0x01, 0x00, 0x01]);
+        assert_eq!(der_integer(&[0x00, 0x80]), vec![0x00, 0x80]);
+        assert_eq!(der_integer(&[0xff]), vec![0x00, 0xff]);
+    }
+}
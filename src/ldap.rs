@@ -0,0 +1,269 @@
+//! Client authentication against an LDAP server with a simple bind.
+use log::{debug, warn};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::config::Ldap;
+use crate::der::{element, put_element};
+use crate::errors::Error;
+
+/// BER tags used by the bind exchange.
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_ENUMERATED: u8 = 0x0a;
+const TAG_BIND_REQUEST: u8 = 0x60;
+const TAG_BIND_RESPONSE: u8 = 0x61;
+const TAG_SIMPLE_AUTH: u8 = 0x80;
+
+/// Unbind request with message id 2, we're done with the connection.
+const UNBIND_REQUEST: [u8; 7] = [TAG_SEQUENCE, 0x05, TAG_INTEGER, 0x01, 0x02, 0x42, 0x00];
+
+/// Result code of a successful bind.
+const RESULT_SUCCESS: u8 = 0;
+
+/// Largest bind response we accept.
+const MAX_RESPONSE_LENGTH: usize = 64 * 1024;
+
+/// Check the client's credentials by binding to the LDAP server as the client.
+pub async fn authenticate(config: &Ldap, username: &str, password: &str) -> Result<(), Error> {
+    // A bind with an empty password is an anonymous bind, which most servers accept.
+    if password.is_empty() {
+        return Err(Error::AuthError("empty password".into()));
+    }
+
+    let dn = config
+        .bind_dn
+        .replace("{username}", &escape_dn_value(username));
+
+    let timeout = tokio::time::Duration::from_millis(config.timeout);
+    match tokio::time::timeout(timeout, bind(&config.address()?, &dn, password)).await {
+        Ok(result) => result,
+        Err(_) => Err(Error::AuthError(format!(
+            "LDAP server {} timed out",
+            config.url
+        ))),
+    }
+}
+
+async fn bind(address: &str, dn: &str, password: &str) -> Result<(), Error> {
+    let mut stream = TcpStream::connect(address)
+        .await
+        .map_err(|err| Error::AuthError(format!("could not connect to LDAP server: {}", err)))?;
+
+    stream
+        .write_all(&bind_request(1, dn, password))
+        .await
+        .map_err(|err| Error::AuthError(format!("could not send LDAP bind: {}", err)))?;
+
+    let response = read_message(&mut stream).await?;
+    let result_code = bind_result_code(&response)?;
+
+    // Best effort, the server closes the connection either way.
+    let _ = stream.write_all(&UNBIND_REQUEST).await;
+
+    debug!("LDAP bind for {} returned {}", dn, result_code);
+
+    match result_code {
+        RESULT_SUCCESS => Ok(()),
+        code => {
+            warn!("LDAP bind for {} failed with result code {}", dn, code);
+            Err(Error::AuthError(format!(
+                "LDAP bind failed with result code {}",
+                code
+            )))
+        }
+    }
+}
+
+/// Escape a value used in a distinguished name (RFC 4514).
+fn escape_dn_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    let last = value.chars().count().saturating_sub(1);
+
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=' => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            '#' | ' ' if i == 0 => {
+                escaped.push('\\');
+                escaped.push(c);
+            }
+            ' ' if i == last => escaped.push_str("\\ "),
+            '\0' => escaped.push_str("\\00"),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+/// LDAPv3 simple bind request.
+fn bind_request(message_id: u8, dn: &str, password: &str) -> Vec<u8> {
+    let mut bind = Vec::new();
+    put_element(&mut bind, TAG_INTEGER, &[3]); // version
+    put_element(&mut bind, TAG_OCTET_STRING, dn.as_bytes());
+    put_element(&mut bind, TAG_SIMPLE_AUTH, password.as_bytes());
+
+    let mut message = Vec::new();
+    put_element(&mut message, TAG_INTEGER, &[message_id]);
+    put_element(&mut message, TAG_BIND_REQUEST, &bind);
+
+    let mut request = Vec::new();
+    put_element(&mut request, TAG_SEQUENCE, &message);
+    request
+}
+
+/// Read a whole LDAP message, returns its content.
+async fn read_message(stream: &mut TcpStream) -> Result<Vec<u8>, Error> {
+    let error = |err: std::io::Error| Error::AuthError(format!("invalid LDAP response: {}", err));
+
+    if stream.read_u8().await.map_err(error)? != TAG_SEQUENCE {
+        return Err(Error::AuthError("invalid LDAP response".into()));
+    }
+
+    let length = match stream.read_u8().await.map_err(error)? {
+        length if length < 0x80 => length as usize,
+        length => {
+            let mut bytes = vec![0u8; (length & 0x7f) as usize];
+            if bytes.len() > std::mem::size_of::<usize>() {
+                return Err(Error::AuthError("LDAP response too large".into()));
+            }
+            stream.read_exact(&mut bytes).await.map_err(error)?;
+            bytes
+                .iter()
+                .fold(0usize, |length, byte| (length << 8) | *byte as usize)
+        }
+    };
+
+    if length > MAX_RESPONSE_LENGTH {
+        return Err(Error::AuthError("LDAP response too large".into()));
+    }
+
+    let mut message = vec![0u8; length];
+    stream.read_exact(&mut message).await.map_err(error)?;
+    Ok(message)
+}
+
+/// Result code from the content of a bind response message.
+fn bind_result_code(message: &[u8]) -> Result<u8, Error> {
+    let invalid = || Error::AuthError("invalid LDAP bind response".into());
+
+    let (tag, _message_id, rest) = element(message).ok_or_else(invalid)?;
+    if tag != TAG_INTEGER {
+        return Err(invalid());
+    }
+
+    let (tag, response, _) = element(rest).ok_or_else(invalid)?;
+    if tag != TAG_BIND_RESPONSE {
+        return Err(invalid());
+    }
+
+    match element(response).ok_or_else(invalid)? {
+        (TAG_ENUMERATED, [code], _) => Ok(*code),
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// LDAP server accepting a single bind for the given DN and password.
+    async fn mock_ldap_server(dn: &'static str, password: &'static str) -> Ldap {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+
+        tokio::task::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let message = read_message(&mut stream).await.unwrap();
+
+                let (_, message_id, rest) = element(&message).unwrap();
+                let (tag, bind, _) = element(rest).unwrap();
+                assert_eq!(tag, TAG_BIND_REQUEST);
+                let (_, version, rest) = element(bind).unwrap();
+                assert_eq!(version, [3]);
+                let (_, name, rest) = element(rest).unwrap();
+                let (tag, credentials, _) = element(rest).unwrap();
+                assert_eq!(tag, TAG_SIMPLE_AUTH);
+
+                let code = match name == dn.as_bytes() && credentials == password.as_bytes() {
+                    true => 0,
+                    false => 49, // invalidCredentials
+                };
+
+                let mut result = Vec::new();
+                put_element(&mut result, TAG_ENUMERATED, &[code]);
+                put_element(&mut result, TAG_OCTET_STRING, b"");
+                put_element(&mut result, TAG_OCTET_STRING, b"");
+                let mut response = Vec::new();
+                put_element(&mut response, TAG_INTEGER, message_id);
+                put_element(&mut response, TAG_BIND_RESPONSE, &result);
+                let mut reply = Vec::new();
+                put_element(&mut reply, TAG_SEQUENCE, &response);
+                stream.write_all(&reply).await.unwrap();
+            }
+        });
+
+        Ldap {
+            url,
+            bind_dn: "uid={username},ou=people,dc=example,dc=org".into(),
+            timeout: Ldap::default_timeout(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ldap_authenticate() {
+        let config = mock_ldap_server("uid=alice,ou=people,dc=example,dc=org", "secret").await;
+
+        assert!(authenticate(&config, "alice", "secret").await.is_ok());
+        assert!(authenticate(&config, "alice", "wrong").await.is_err());
+        assert!(authenticate(&config, "bob", "secret").await.is_err());
+
+        // Anonymous binds are never attempted.
+        assert!(authenticate(&config, "alice", "").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_ldap_timeout() {
+        // Accepts connections but never answers.
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let config = Ldap {
+            url: format!("ldap://{}", listener.local_addr().unwrap()),
+            bind_dn: "uid={username}".into(),
+            timeout: 50,
+        };
+
+        assert!(authenticate(&config, "alice", "secret").await.is_err());
+    }
+
+    #[test]
+    fn test_escape_dn_value() {
+        assert_eq!(escape_dn_value("alice"), "alice");
+        assert_eq!(escape_dn_value("alice,ou=admins"), "alice\\,ou\\=admins");
+        assert_eq!(escape_dn_value("#alice "), "\\#alice\\ ");
+        assert_eq!(escape_dn_value(" a\0"), "\\ a\\00");
+    }
+
+    #[test]
+    fn test_bind_request_long_password() {
+        let password = "p".repeat(300);
+        let request = bind_request(1, "uid=alice", &password);
+
+        let (tag, message, rest) = element(&request).unwrap();
+        assert_eq!(tag, TAG_SEQUENCE);
+        assert!(rest.is_empty());
+
+        let (_, _, rest) = element(message).unwrap();
+        let (_, bind, _) = element(rest).unwrap();
+        let (_, _, rest) = element(bind).unwrap();
+        let (_, _, rest) = element(rest).unwrap();
+        let (tag, credentials, _) = element(rest).unwrap();
+        assert_eq!(tag, TAG_SIMPLE_AUTH);
+        assert_eq!(credentials, password.as_bytes());
+    }
+}
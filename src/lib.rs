@@ -1,23 +1,40 @@
 pub mod admin;
 pub mod auth_passthrough;
+pub mod autoscaler;
+pub mod ban_history;
 pub mod client;
+pub mod client_connections;
 pub mod cmd_args;
 pub mod config;
+pub mod connection_log;
 pub mod constants;
+pub mod der;
 pub mod dns_cache;
 pub mod errors;
+pub mod events;
+pub mod fan_out;
+pub mod jwt;
+pub mod ldap;
 pub mod logger;
 pub mod messages;
 pub mod mirrors;
+pub mod notifications;
+pub mod otel;
 pub mod plugins;
 pub mod pool;
+pub mod probes;
 pub mod prometheus;
+pub mod proxy_protocol;
 pub mod query_router;
 pub mod scram;
 pub mod server;
 pub mod sharding;
+pub mod slow_query;
 pub mod stats;
+pub mod statsd;
 pub mod tls;
+#[cfg(unix)]
+pub mod unix_socket;
 
 /// Format chrono::Duration to be more human-friendly.
 ///
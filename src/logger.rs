@@ -1,20 +1,255 @@
 use crate::cmd_args::{Args, LogFormat};
+use crate::config::{get_config, LogFormat as ConfigLogFormat};
+use log::error;
+use once_cell::sync::OnceCell;
+use serde_derive::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
 use tracing_subscriber;
-use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+type FormatLayer = Box<dyn Layer<Registry> + Send + Sync>;
+
+/// Swaps the format of the logs when `log_format` is set in the config.
+struct Formats {
+    handle: reload::Handle<FormatLayer, Registry>,
+    log_format: LogFormat,
+    no_color: bool,
+}
+
+static FORMATS: OnceCell<Formats> = OnceCell::new();
+
+/// Whether the logs are JSON objects, see [`JsonFormat`].
+static JSON: AtomicBool = AtomicBool::new(false);
 
 pub fn init(args: &Args) {
     // Iniitalize a default filter, and then override the builtin default "warning" with our
     // commandline, (default: "info")
     let filter = EnvFilter::from_default_env().add_directive(args.log_level.into());
 
-    let trace_sub = tracing_subscriber::fmt()
-        .with_thread_ids(true)
-        .with_env_filter(filter)
-        .with_ansi(!args.no_color);
+    let (format, handle) = reload::Layer::new(format_layer(None, &args.log_format, args.no_color));
 
-    match args.log_format {
-        LogFormat::Structured => trace_sub.json().init(),
-        LogFormat::Debug => trace_sub.pretty().init(),
-        _ => trace_sub.init(),
+    tracing_subscriber::registry()
+        .with(format)
+        .with(filter)
+        .init();
+
+    let _ = FORMATS.set(Formats {
+        handle,
+        log_format: args.log_format.clone(),
+        no_color: args.no_color,
+    });
+}
+
+/// Use the `log_format` of the config, or the one of the commandline if it's not set.
+pub fn from_config() {
+    let Some(formats) = FORMATS.get() else {
+        return;
     };
+
+    let log_format = get_config().general.log_format;
+
+    match formats.handle.reload(format_layer(
+        log_format,
+        &formats.log_format,
+        formats.no_color,
+    )) {
+        Ok(()) => JSON.store(log_format == Some(ConfigLogFormat::Json), Ordering::Relaxed),
+        Err(err) => error!("Could not change the log format: {}", err),
+    }
+}
+
+/// Whether the logs are JSON objects, so their fields don't need to be in the message too.
+pub fn json() -> bool {
+    JSON.load(Ordering::Relaxed)
+}
+
+fn format_layer(
+    config_format: Option<ConfigLogFormat>,
+    log_format: &LogFormat,
+    no_color: bool,
+) -> FormatLayer {
+    let layer = tracing_subscriber::fmt::layer()
+        .with_thread_ids(true)
+        .with_ansi(!no_color);
+
+    match (config_format, log_format) {
+        (Some(ConfigLogFormat::Json), _) => layer.event_format(JsonFormat).boxed(),
+        (Some(ConfigLogFormat::Text), _) | (None, LogFormat::Text) => layer.boxed(),
+        (None, LogFormat::Structured) => layer.json().boxed(),
+        (None, LogFormat::Debug) => layer.pretty().boxed(),
+    }
+}
+
+/// One JSON object per line, with the fields of the event next to
+/// the level, timestamp, module and message, e.g.
+/// `{"timestamp":"...","level":"ERROR","module":"pgcat::pool","message":"Banning instance ...","pool":"sharded_db", ...}`
+pub struct JsonFormat;
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    timestamp: String,
+    level: &'a str,
+    module: String,
+    message: String,
+    #[serde(flatten)]
+    fields: BTreeMap<&'static str, Value>,
+}
+
+/// Collects the fields of an event. The ones of the events coming from the `log` crate
+/// are named `log.*`: their target is the module, the rest is dropped.
+#[derive(Default)]
+struct JsonFields {
+    message: String,
+    log_target: Option<String>,
+    fields: BTreeMap<&'static str, Value>,
+}
+
+impl JsonFields {
+    fn record(&mut self, field: &Field, value: Value) {
+        match field.name() {
+            "message" => {
+                self.message = match value {
+                    Value::String(message) => message,
+                    value => value.to_string(),
+                }
+            }
+            "log.target" => self.log_target = value.as_str().map(String::from),
+            name if name.starts_with("log.") => (),
+            name => {
+                self.fields.insert(name, value);
+            }
+        }
+    }
+}
+
+impl Visit for JsonFields {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.record(field, value.into())
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.record(field, value.into())
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.record(field, value.into())
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.record(field, value.into())
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.record(field, value.into())
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record(field, format!("{:?}", value).into())
+    }
+}
+
+impl<S, N> FormatEvent<S, N> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let mut fields = JsonFields::default();
+        event.record(&mut fields);
+
+        let metadata = event.metadata();
+        let line = JsonLine {
+            timestamp: chrono::offset::Utc::now().to_rfc3339(),
+            level: metadata.level().as_str(),
+            module: fields
+                .log_target
+                .unwrap_or_else(|| metadata.target().to_string()),
+            message: fields.message,
+            fields: fields.fields,
+        };
+
+        writeln!(
+            writer,
+            "{}",
+            serde_json::to_string(&line).map_err(|_| fmt::Error)?
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Address;
+    use crate::pool::{BanReason, ConnectionPool};
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_json_format() {
+        let output = Output::default();
+        let writer = output.clone();
+        let subscriber = tracing_subscriber::registry().with(
+            tracing_subscriber::fmt::layer()
+                .event_format(JsonFormat)
+                .with_writer(move || writer.clone()),
+        );
+
+        let address = Address {
+            host: "json-replica".into(),
+            pool_name: "json_pool".into(),
+            username: "json_user".into(),
+            ..Default::default()
+        };
+        tracing::subscriber::with_default(subscriber, || {
+            ConnectionPool::with_banlist(1).ban(&address, BanReason::AdminBan(60), None);
+        });
+
+        let output = String::from_utf8(output.0.lock().unwrap().clone()).unwrap();
+        let lines = output
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .collect::<Vec<_>>();
+        let ban = lines
+            .iter()
+            .find(|line| line["message"].as_str().unwrap().starts_with("Banning"))
+            .unwrap();
+
+        assert!(chrono::DateTime::parse_from_rfc3339(ban["timestamp"].as_str().unwrap()).is_ok());
+        assert_eq!(ban["level"], "ERROR");
+        assert_eq!(ban["module"], "pgcat::pool");
+        assert_eq!(ban["pool"], "json_pool");
+        assert_eq!(ban["user"], "json_user");
+        assert_eq!(ban["address"], "json-replica:5432");
+        assert_eq!(ban["role"], "replica");
+        assert_eq!(ban["reason"], "AdminBan(60)");
+        assert_eq!(ban["duration"], 60);
+    }
 }
@@ -44,7 +44,7 @@ use jemallocator::Jemalloc;
 #[global_allocator]
 static GLOBAL: Jemalloc = Jemalloc;
 
-use log::{debug, error, info, warn};
+use log::{debug, error, info};
 use parking_lot::Mutex;
 use pgcat::format_duration;
 use tokio::net::TcpListener;
@@ -65,9 +65,16 @@ use pgcat::config::{get_config, reload_config, VERSION};
 use pgcat::dns_cache;
 use pgcat::logger;
 use pgcat::messages::configure_socket;
-use pgcat::pool::{ClientServerMap, ConnectionPool};
+use pgcat::notifications::Notifier;
+use pgcat::otel::Tracer;
+use pgcat::pool::{restore_bans, start_ban_persistence, ClientServerMap, ConnectionPool};
+use pgcat::probes::start_probe_server;
 use pgcat::prometheus::start_metric_server;
 use pgcat::stats::{Collector, Reporter, REPORTER};
+use pgcat::statsd::start_statsd_exporter;
+use pgcat::tls::Tls;
+#[cfg(unix)]
+use pgcat::unix_socket;
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = cmd_args::parse();
@@ -97,6 +104,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let config = get_config();
 
+    // Switches (if configured) the format of the logs
+    logger::from_config();
+
     // Create the runtime now we know required worker_threads.
     let runtime = Builder::new_multi_thread()
         .worker_threads(config.general.worker_threads)
@@ -124,6 +134,30 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             });
         }
 
+        if let Some(probe_port) = config.general.probe_port {
+            let http_addr = match SocketAddr::from_str(&format!(
+                "{}:{}",
+                config.general.host, probe_port
+            )) {
+                Ok(addr) => addr,
+                Err(err) => {
+                    error!("Invalid http address: {}", err);
+                    std::process::exit(exitcode::CONFIG);
+                }
+            };
+
+            tokio::task::spawn(start_probe_server(http_addr));
+        }
+
+        if let Some(ref statsd_address) = config.general.statsd_address {
+            tokio::task::spawn(start_statsd_exporter(
+                statsd_address.clone(),
+                config.general.statsd_interval,
+                config.general.statsd_prefix.clone(),
+                config.general.statsd_tags,
+            ));
+        }
+
         let addr = format!("{}:{}", config.general.host, config.general.port);
 
         let listener = match TcpListener::bind(&addr).await {
@@ -150,6 +184,15 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 Err(err) => error!("DNS cache initialization error: {:?}", err),
         };
 
+        // Starts (if configured) the ban notifications sender
+        Notifier::from_config();
+
+        // Starts (if configured) the OpenTelemetry span exporter
+        Tracer::from_config();
+
+        // Loads (if configured) the TLS certificate for client connections
+        Tls::from_config();
+
         // Connection pool that allows to query all shards and replicas.
         match ConnectionPool::from_config(client_server_map.clone()).await {
             Ok(_) => (),
@@ -159,6 +202,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
         };
 
+        // Bring back the bans from before the restart and keep saving them.
+        restore_bans().await;
+        start_ban_persistence();
+
         tokio::task::spawn(async move {
             let mut stats_collector = Collector::default();
             stats_collector.collect().await;
@@ -208,9 +255,38 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         let mut admin_only = false;
         let mut total_clients = 0;
 
+        #[cfg(unix)]
+        let unix_socket_path = match config.general.unix_socket_dir {
+            Some(ref unix_socket_dir) => {
+                let path = unix_socket::socket_path(unix_socket_dir, config.general.port);
+
+                let unix_listener = match unix_socket::bind(&path) {
+                    Ok(unix_listener) => unix_listener,
+                    Err(err) => {
+                        error!("Unix socket error: {:?}", err);
+                        std::process::exit(exitcode::CONFIG);
+                    }
+                };
+
+                info!("Running on {}", path.display());
+
+                tokio::task::spawn(unix_socket::accept_clients(
+                    unix_listener,
+                    client_server_map.clone(),
+                    shutdown_tx.clone(),
+                    drain_tx.clone(),
+                ));
+
+                Some(path)
+            }
+            None => None,
+        };
+
         info!("Waiting for clients");
 
         loop {
+            let mut start_drain = false;
+
             tokio::select! {
                 // Reload config:
                 // kill -SIGHUP $(pgrep pgcat)
@@ -225,38 +301,18 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 // Initiate graceful shutdown sequence on sig int
                 _ = interrupt_signal.recv() => {
                     info!("Got SIGINT");
+                    start_drain = true;
+                },
 
-                    // Don't want this to happen more than once
+                // Same on sig term, a second one closes all clients right away.
+                _ = term_signal.recv() => {
                     if admin_only {
-                        continue;
+                        info!("Got SIGTERM again, closing with {} clients active", total_clients);
+                        break;
                     }
 
-                    admin_only = true;
-
-                    // Broadcast that client tasks need to finish
-                    let _ = shutdown_tx.send(());
-                    let exit_tx = exit_tx.clone();
-                    let _ = drain_tx.send(0).await;
-
-                    tokio::task::spawn(async move {
-                        let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(config.general.shutdown_timeout));
-
-                        // First tick fires immediately.
-                        interval.tick().await;
-
-                        // Second one in the interval time.
-                        interval.tick().await;
-
-                        // We're done waiting.
-                        error!("Graceful shutdown timed out. {} active clients being closed", total_clients);
-
-                        let _ = exit_tx.send(()).await;
-                    });
-                },
-
-                _ = term_signal.recv() => {
-                    info!("Got SIGTERM, closing with {} clients active", total_clients);
-                    break;
+                    info!("Got SIGTERM");
+                    start_drain = true;
                 },
 
                 new_client = listener.accept() => {
@@ -294,16 +350,16 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                                 let duration = chrono::offset::Utc::now().naive_utc() - start;
 
                                 if get_config().general.log_client_disconnections {
-                                    info!(
-                                        "Client {:?} disconnected, session duration: {}",
-                                        addr,
-                                        format_duration(&duration)
+                                    tracing::info!(
+                                        client_address = %addr,
+                                        session_duration = %format_duration(&duration),
+                                        "Client disconnected"
                                     );
                                 } else {
-                                    debug!(
-                                        "Client {:?} disconnected, session duration: {}",
-                                        addr,
-                                        format_duration(&duration)
+                                    tracing::debug!(
+                                        client_address = %addr,
+                                        session_duration = %format_duration(&duration),
+                                        "Client disconnected"
                                     );
                                 }
                             }
@@ -311,7 +367,7 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                             Err(err) => {
                                 match err {
                                     pgcat::errors::Error::ClientBadStartup => debug!("Client disconnected with error {:?}", err),
-                                    _ => warn!("Client disconnected with error {:?}", err),
+                                    _ => tracing::warn!(client_address = %addr, error = ?err, "Client disconnected with error"),
                                 }
 
                             }
@@ -332,8 +388,44 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                     }
                 }
             }
+
+            // Don't want this to happen more than once
+            if start_drain && !admin_only {
+                // Only admin clients can connect from now on.
+                admin_only = true;
+
+                info!(
+                    "Waiting up to {}ms for {} clients to finish their transactions",
+                    config.general.shutdown_timeout, total_clients
+                );
+
+                // Broadcast that client tasks need to finish
+                let _ = shutdown_tx.send(());
+                let exit_tx = exit_tx.clone();
+                let _ = drain_tx.send(0).await;
+
+                tokio::task::spawn(async move {
+                    let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(config.general.shutdown_timeout));
+
+                    // First tick fires immediately.
+                    interval.tick().await;
+
+                    // Second one in the interval time.
+                    interval.tick().await;
+
+                    // We're done waiting.
+                    error!("Graceful shutdown timed out, active clients being closed");
+
+                    let _ = exit_tx.send(()).await;
+                });
+            }
         }
 
+    #[cfg(unix)]
+    if let Some(ref path) = unix_socket_path {
+        unix_socket::remove(path);
+    }
+
     info!("Shutting down...");
     });
     Ok(())
@@ -11,9 +11,9 @@ use crate::client::PREPARED_STATEMENT_COUNTER;
 use crate::config::get_config;
 use crate::errors::Error;
 
-use crate::constants::MESSAGE_TERMINATOR;
+use crate::constants::{MESSAGE_TERMINATOR, PROTOCOL_VERSION_NUMBER, SASL};
 use std::collections::hash_map::DefaultHasher;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::ffi::CString;
 use std::fmt::{Display, Formatter};
 use std::hash::{Hash, Hasher};
@@ -87,6 +87,77 @@ where
     Ok(salt)
 }
 
+/// Offer the client SASL authentication with these mechanisms.
+pub async fn sasl_challenge<S>(stream: &mut S, mechanisms: &[&str]) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut res = BytesMut::new();
+    res.put_u8(b'R');
+    res.put_i32(
+        8 + mechanisms
+            .iter()
+            .map(|mechanism| mechanism.len() as i32 + 1)
+            .sum::<i32>()
+            + 1,
+    );
+    res.put_i32(SASL);
+    for mechanism in mechanisms {
+        res.put_slice(mechanism.as_bytes());
+        res.put_u8(0);
+    }
+    res.put_u8(0);
+
+    write_all(stream, res).await
+}
+
+/// Send the client a SASL challenge (AuthenticationSASLContinue or AuthenticationSASLFinal).
+pub async fn sasl_message<S>(stream: &mut S, code: i32, data: &[u8]) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut res = BytesMut::new();
+    res.put_u8(b'R');
+    res.put_i32(8 + data.len() as i32);
+    res.put_i32(code);
+    res.put_slice(data);
+
+    write_all(stream, res).await
+}
+
+/// Mechanism and data of a SASLInitialResponse, without its code and length.
+pub fn parse_sasl_initial_response(message: &[u8]) -> Result<(String, Vec<u8>), Error> {
+    let invalid = || Error::ProtocolSyncError("Invalid SASLInitialResponse".into());
+
+    let end = message.iter().position(|b| *b == 0).ok_or_else(invalid)?;
+    let mechanism = String::from_utf8_lossy(&message[..end]).to_string();
+
+    let mut rest = &message[end + 1..];
+    if rest.len() < 4 {
+        return Err(invalid());
+    }
+    let len = rest.get_i32();
+
+    match len {
+        -1 => Ok((mechanism, Vec::new())),
+        len if len >= 0 && len as usize == rest.len() => Ok((mechanism, rest.to_vec())),
+        _ => Err(invalid()),
+    }
+}
+
+/// Ask the client for its password in cleartext.
+pub async fn plain_password_challenge<S>(stream: &mut S) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut res = BytesMut::new();
+    res.put_u8(b'R');
+    res.put_i32(8);
+    res.put_i32(3); // Cleartext password
+
+    write_all(stream, res).await
+}
+
 /// Give the client the process_id and secret we generated
 /// used in query cancellation.
 pub async fn backend_key_data<S>(
@@ -105,6 +176,27 @@ where
     write_all(stream, key_data).await
 }
 
+/// Tell the client we only speak protocol 3.0, sent when it asked for a newer
+/// minor version or for protocol options (`_pq_.*` startup parameters) we don't know.
+pub async fn negotiate_protocol_version<S>(stream: &mut S, options: &[String]) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    let mut body = BytesMut::new();
+    body.put_i32(PROTOCOL_VERSION_NUMBER & 0xffff); // Newest minor version supported
+    body.put_i32(options.len() as i32);
+    for option in options {
+        body.put_slice(option.as_bytes());
+        body.put_u8(0);
+    }
+
+    let mut message = BytesMut::from(&b"v"[..]);
+    message.put_i32(body.len() as i32 + 4);
+    message.put(body);
+
+    write_all(stream, message).await
+}
+
 /// Construct a `Q`: Query message.
 pub fn simple_query(query: &str) -> BytesMut {
     let mut res = BytesMut::from(&b"Q"[..]);
@@ -125,8 +217,15 @@ where
 }
 
 /// Send the startup packet the server. We're pretending we're a Pg client.
-/// This tells the server which user we are and what database we want.
-pub async fn startup<S>(stream: &mut S, user: &str, database: &str) -> Result<(), Error>
+/// This tells the server which user we are and what database we want,
+/// and if it's a replication connection, e.g. `replication=database`.
+pub async fn startup<S>(
+    stream: &mut S,
+    user: &str,
+    database: &str,
+    replication: Option<&str>,
+    server_settings: &BTreeMap<String, String>,
+) -> Result<(), Error>
 where
     S: tokio::io::AsyncWrite + std::marker::Unpin,
 {
@@ -147,6 +246,21 @@ where
     bytes.put(&b"database\0"[..]);
     bytes.put_slice(database.as_bytes());
     bytes.put_u8(0);
+
+    if let Some(replication) = replication {
+        bytes.put(&b"replication\0"[..]);
+        bytes.put_slice(replication.as_bytes());
+        bytes.put_u8(0);
+    }
+
+    // Session defaults, RESET restores them
+    for (name, value) in server_settings {
+        bytes.put_slice(name.as_bytes());
+        bytes.put_u8(0);
+        bytes.put_slice(value.as_bytes());
+        bytes.put_u8(0);
+    }
+
     bytes.put_u8(0); // Null terminator
 
     let len = bytes.len() as i32 + 4i32;
@@ -336,6 +450,26 @@ pub async fn error_response_terminal<S>(stream: &mut S, message: &str) -> Result
 where
     S: tokio::io::AsyncWrite + std::marker::Unpin,
 {
+    // system_error, see Appendix A.
+    write_all_half(stream, &error_message(message, "58000")).await
+}
+
+/// Send a custom error message with the given SQLSTATE to the client, e.g. one it
+/// knows it can retry on. Tell the client we are ready for the next query.
+pub async fn error_response_with_code<S>(
+    stream: &mut S,
+    message: &str,
+    code: &str,
+) -> Result<(), Error>
+where
+    S: tokio::io::AsyncWrite + std::marker::Unpin,
+{
+    write_all_half(stream, &error_message(message, code)).await?;
+    send_ready_for_query(stream).await
+}
+
+/// FATAL ErrorResponse.
+fn error_message(message: &str, code: &str) -> BytesMut {
     let mut error = BytesMut::new();
 
     // Error level
@@ -348,7 +482,7 @@ where
 
     // Error code: not sure how much this matters.
     error.put_u8(b'C');
-    error.put_slice(&b"58000\0"[..]); // system_error, see Appendix A.
+    error.put_slice(format!("{}\0", code).as_bytes());
 
     // The short error message.
     error.put_u8(b'M');
@@ -364,7 +498,7 @@ where
     res.put_i32(error.len() as i32 + 4);
     res.put(error);
 
-    write_all_half(stream, &res).await
+    res
 }
 
 pub async fn wrong_password<S>(stream: &mut S, user: &str) -> Result<(), Error>
@@ -550,6 +684,23 @@ pub fn notify(message: &str, details: String) -> BytesMut {
     res
 }
 
+/// NotificationResponse, e.g. the payload of a NOTIFY on a channel the client LISTENs to.
+pub fn notification_response(process_id: i32, channel: &str, payload: &str) -> BytesMut {
+    let mut body = BytesMut::new();
+    body.put_i32(process_id);
+    body.put_slice(channel.as_bytes());
+    body.put_u8(0);
+    body.put_slice(payload.as_bytes());
+    body.put_u8(0);
+
+    let mut res = BytesMut::new();
+    res.put_u8(b'A');
+    res.put_i32(body.len() as i32 + 4);
+    res.put(body);
+
+    res
+}
+
 pub fn flush() -> BytesMut {
     let mut bytes = BytesMut::new();
     bytes.put_u8(b'H');
@@ -937,6 +1088,10 @@ impl Parse {
     pub fn anonymous(&self) -> bool {
         self.name.is_empty()
     }
+
+    pub fn query(&self) -> &str {
+        &self.query
+    }
 }
 
 /// Bind (B) message.
@@ -1545,4 +1700,39 @@ mod tests {
             PgErrorMsg::parse(&only_mandatory_msg).unwrap()
         );
     }
+
+    #[tokio::test]
+    async fn test_configure_socket() {
+        use super::*;
+        use tokio::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+
+        configure_socket(&stream);
+
+        let config = get_config();
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(
+            socket.keepalive_time().unwrap(),
+            Duration::from_secs(config.general.tcp_keepalives_idle)
+        );
+        assert_eq!(
+            socket.keepalive_interval().unwrap(),
+            Duration::from_secs(config.general.tcp_keepalives_interval)
+        );
+        assert_eq!(
+            socket.keepalive_retries().unwrap(),
+            config.general.tcp_keepalives_count
+        );
+        #[cfg(target_os = "linux")]
+        assert_eq!(
+            socket.tcp_user_timeout().unwrap(),
+            Some(Duration::from_millis(config.general.tcp_user_timeout))
+        );
+        assert!(socket.nodelay().unwrap());
+    }
 }
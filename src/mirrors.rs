@@ -6,16 +6,21 @@ use bb8::Pool;
 use bytes::{Bytes, BytesMut};
 use parking_lot::RwLock;
 
-use crate::config::{get_config, Address, Role, User};
+use crate::config::{get_config, Address, Mirror, MirrorDropPolicy, Role, User};
 use crate::pool::{ClientServerMap, ServerPool};
+use crate::stats::get_reporter;
 use log::{error, info, trace, warn};
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::Notify;
 
 pub struct MirroredClient {
     address: Address,
     user: User,
     database: String,
-    bytes_rx: Receiver<Bytes>,
+    queue: Arc<MirrorQueue>,
     disconnect_rx: Receiver<()>,
 }
 
@@ -29,7 +34,7 @@ impl MirroredClient {
                     cfg.connect_timeout.unwrap_or(default),
                     cfg.idle_timeout.unwrap_or(default),
                     cfg.clone(),
-                    cfg.prepared_statements_cache_size,
+                    cfg.max_server_prepared_statements(),
                 ),
                 None => (default, default, crate::config::Pool::default(), 0),
             };
@@ -44,6 +49,9 @@ impl MirroredClient {
             true,
             false,
             prepared_statement_cache_size,
+            config.general.server_lifetime,
+            config.general.server_lifetime_jitter,
+            None,
         );
 
         Pool::builder()
@@ -93,7 +101,7 @@ impl MirroredClient {
                     }
 
                     // Messages to send to the server
-                    message = self.bytes_rx.recv() => {
+                    message = self.queue.pop() => {
                         match message {
                             Some(bytes) => {
                                 match server.send(&BytesMut::from(&bytes[..])).await {
@@ -106,7 +114,7 @@ impl MirroredClient {
                                 }
                             }
                             None => {
-                                info!("Mirror channel closed, exiting {:?}", address.clone());
+                                info!("Mirror queue closed, exiting {:?}", address.clone());
                                 break;
                             },
                         }
@@ -116,62 +124,139 @@ impl MirroredClient {
         });
     }
 }
+/// Messages waiting to be sent to a mirror. Adding one never waits,
+/// when the queue is full a message is dropped instead.
+pub struct MirrorQueue {
+    messages: Mutex<VecDeque<Bytes>>,
+    size: usize,
+    drop_policy: MirrorDropPolicy,
+    closed: AtomicBool,
+    notify: Notify,
+
+    /// Reported with the dropped messages (host:port).
+    address: String,
+}
+
+impl MirrorQueue {
+    pub fn new(address: String, size: usize, drop_policy: MirrorDropPolicy) -> MirrorQueue {
+        MirrorQueue {
+            messages: Mutex::new(VecDeque::with_capacity(size)),
+            size,
+            drop_policy,
+            closed: AtomicBool::new(false),
+            notify: Notify::new(),
+            address,
+        }
+    }
+
+    pub fn push(&self, bytes: Bytes) {
+        if self.closed.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let mut messages = self.messages.lock();
+        if messages.len() >= self.size {
+            match self.drop_policy {
+                MirrorDropPolicy::DropNewest => {
+                    drop(messages);
+                    self.dropped();
+                    return;
+                }
+                MirrorDropPolicy::DropOldest => {
+                    messages.pop_front();
+                    self.dropped();
+                }
+            }
+        }
+        messages.push_back(bytes);
+        drop(messages);
+
+        self.notify.notify_one();
+    }
+
+    /// Next message, None once the queue is closed.
+    pub async fn pop(&self) -> Option<Bytes> {
+        loop {
+            if let Some(bytes) = self.messages.lock().pop_front() {
+                return Some(bytes);
+            }
+
+            if self.closed.load(Ordering::Relaxed) {
+                return None;
+            }
+
+            self.notify.notified().await;
+        }
+    }
+
+    pub fn close(&self) {
+        self.closed.store(true, Ordering::Relaxed);
+        self.notify.notify_one();
+    }
+
+    fn dropped(&self) {
+        trace!(
+            "Mirror queue for {} is full, dropping a message",
+            self.address
+        );
+        get_reporter().mirror_drop(self.address.clone());
+    }
+}
+
 pub struct MirroringManager {
-    pub byte_senders: Vec<Sender<Bytes>>,
+    pub queues: Vec<Arc<MirrorQueue>>,
     pub disconnect_senders: Vec<Sender<()>>,
+    pub sample_rates: Vec<f64>,
 }
 impl MirroringManager {
-    pub fn from_addresses(
-        user: User,
-        database: String,
-        addresses: Vec<Address>,
-    ) -> MirroringManager {
-        let mut byte_senders: Vec<Sender<Bytes>> = vec![];
+    pub fn from_addresses(user: User, database: String, mirrors: Vec<Mirror>) -> MirroringManager {
+        let config = get_config();
+        let mut queues: Vec<Arc<MirrorQueue>> = vec![];
         let mut exit_senders: Vec<Sender<()>> = vec![];
+        let mut sample_rates: Vec<f64> = vec![];
 
-        addresses.iter().for_each(|mirror| {
-            let (bytes_tx, bytes_rx) = channel::<Bytes>(10);
+        mirrors.iter().for_each(|mirror| {
+            let queue = Arc::new(MirrorQueue::new(
+                format!("{}:{}", mirror.address.host, mirror.address.port),
+                config.general.mirror_queue_size,
+                config.general.mirror_drop_policy,
+            ));
             let (exit_tx, exit_rx) = channel::<()>(1);
-            let mut addr = mirror.clone();
+            let mut addr = mirror.address.clone();
             addr.role = Role::Mirror;
             let client = MirroredClient {
                 user: user.clone(),
                 database: database.to_owned(),
                 address: addr,
-                bytes_rx,
+                queue: queue.clone(),
                 disconnect_rx: exit_rx,
             };
             exit_senders.push(exit_tx);
-            byte_senders.push(bytes_tx);
+            queues.push(queue);
+            sample_rates.push(mirror.sample_rate);
             client.start();
         });
 
         Self {
-            byte_senders,
+            queues,
             disconnect_senders: exit_senders,
+            sample_rates,
         }
     }
 
     pub fn send(&mut self, bytes: &BytesMut) {
-        // We want to avoid performing an allocation if we won't be able to send the message
-        // There is a possibility of a race here where we check the capacity and then the channel is
-        // closed or the capacity is reduced to 0, but mirroring is best effort anyway
-        if self
-            .byte_senders
-            .iter()
-            .all(|sender| sender.capacity() == 0 || sender.is_closed())
-        {
-            return;
-        }
-        let immutable_bytes = bytes.clone().freeze();
-        self.byte_senders.iter_mut().for_each(|sender| {
-            match sender.try_send(immutable_bytes.clone()) {
-                Ok(_) => {}
-                Err(err) => {
-                    warn!("Failed to send bytes to a mirror channel {}", err);
-                }
+        // Only copy the message if a mirror gets it.
+        let mut immutable_bytes: Option<Bytes> = None;
+
+        for (queue, sample_rate) in self.queues.iter().zip(&self.sample_rates) {
+            // Each mirror gets its own sample of the traffic.
+            if rand::random::<f64>() >= *sample_rate {
+                continue;
             }
-        });
+
+            let bytes = immutable_bytes.get_or_insert_with(|| bytes.clone().freeze());
+            queue.push(bytes.clone());
+        }
     }
 
     pub fn disconnect(&mut self) {
@@ -188,3 +273,114 @@ impl MirroringManager {
             });
     }
 }
+
+impl Drop for MirroringManager {
+    fn drop(&mut self) {
+        for queue in &self.queues {
+            queue.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stats::get_mirror_drop_counts;
+
+    fn message(query: &str) -> BytesMut {
+        crate::messages::simple_query(query)
+    }
+
+    fn queued(queue: &MirrorQueue) -> Vec<BytesMut> {
+        queue
+            .messages
+            .lock()
+            .drain(..)
+            .map(|bytes| BytesMut::from(&bytes[..]))
+            .collect()
+    }
+
+    fn manager(queues: Vec<Arc<MirrorQueue>>, sample_rates: Vec<f64>) -> MirroringManager {
+        MirroringManager {
+            queues,
+            disconnect_senders: vec![],
+            sample_rates,
+        }
+    }
+
+    #[test]
+    fn test_sample_rates() {
+        let sampled = Arc::new(MirrorQueue::new(
+            "sampled:5432".into(),
+            10,
+            MirrorDropPolicy::DropNewest,
+        ));
+        let skipped = Arc::new(MirrorQueue::new(
+            "skipped:5432".into(),
+            10,
+            MirrorDropPolicy::DropNewest,
+        ));
+        let mut manager = manager(vec![sampled.clone(), skipped.clone()], vec![1.0, 0.0]);
+
+        for _ in 0..5 {
+            manager.send(&message("SELECT 1"));
+        }
+
+        assert_eq!(queued(&sampled).len(), 5);
+        assert!(queued(&skipped).is_empty());
+    }
+
+    #[test]
+    fn test_drop_policies() {
+        for (policy, kept) in [
+            (MirrorDropPolicy::DropNewest, ["SELECT 0", "SELECT 1"]),
+            (MirrorDropPolicy::DropOldest, ["SELECT 98", "SELECT 99"]),
+        ] {
+            let address = format!("{}:5432", policy);
+            let queue = Arc::new(MirrorQueue::new(address.clone(), 2, policy));
+            let mut manager = manager(vec![queue.clone()], vec![1.0]);
+
+            // Nothing reads from the queue, sending doesn't wait for it.
+            for i in 0..100 {
+                manager.send(&message(&format!("SELECT {}", i)));
+            }
+
+            assert_eq!(
+                queued(&queue),
+                kept.iter().map(|query| message(query)).collect::<Vec<_>>()
+            );
+            assert_eq!(get_mirror_drop_counts().get(&address), Some(&98));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_queue_closed() {
+        let queue = Arc::new(MirrorQueue::new(
+            "closed:5432".into(),
+            10,
+            MirrorDropPolicy::DropNewest,
+        ));
+        let mirror = tokio::spawn({
+            let queue = queue.clone();
+            async move {
+                let mut received = 0;
+                while queue.pop().await.is_some() {
+                    received += 1;
+                }
+                received
+            }
+        });
+
+        let mut manager = manager(vec![queue.clone()], vec![1.0]);
+        manager.send(&message("SELECT 1"));
+        drop(manager);
+
+        let received = tokio::time::timeout(std::time::Duration::from_secs(1), mirror)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(received, 1);
+        queue.push(Bytes::from_static(b"ignored"));
+        assert!(queued(&queue).is_empty());
+    }
+}
@@ -0,0 +1,223 @@
+//! Ban and unban notifications sent to an HTTP endpoint.
+use arc_swap::ArcSwap;
+use bytes::Bytes;
+use http_body_util::Full;
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::{Request, Uri};
+use hyper_util::rt::TokioIo;
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use serde_derive::Serialize;
+use std::sync::Arc;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+
+use crate::config::{get_config, Address, Notifications};
+use crate::pool::BanReason;
+
+/// How many events can be waiting to be sent before we start dropping them.
+const NOTIFICATION_QUEUE_SIZE: usize = 1024;
+
+/// The notifier, globally available. Not set if notifications are not configured.
+pub static NOTIFIER: Lazy<ArcSwap<Option<Notifier>>> = Lazy::new(|| ArcSwap::from_pointee(None));
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BanEventKind {
+    Ban,
+    Unban,
+}
+
+/// Payload posted to the notifications endpoint.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BanEvent {
+    pub event: BanEventKind,
+    pub address: String,
+    pub shard: usize,
+    pub reason: Option<String>,
+    pub timestamp: String,
+}
+
+impl BanEvent {
+    pub fn new(event: BanEventKind, address: &Address, reason: Option<&BanReason>) -> BanEvent {
+        BanEvent {
+            event,
+            address: format!("{}:{}", address.host, address.port),
+            shard: address.shard,
+            reason: reason.map(|reason| format!("{:?}", reason)),
+            timestamp: chrono::offset::Utc::now().to_rfc3339(),
+        }
+    }
+}
+
+/// Sends events to the configured endpoint from a background task.
+#[derive(Debug)]
+pub struct Notifier {
+    config: Notifications,
+    tx: Sender<BanEvent>,
+}
+
+impl Notifier {
+    /// Create a notifier and start the task sending the events.
+    /// Must be called from within the Tokio runtime.
+    pub fn new(config: Notifications) -> Notifier {
+        let (tx, rx) = channel(NOTIFICATION_QUEUE_SIZE);
+
+        tokio::task::spawn(Self::run(config.clone(), rx));
+
+        Notifier { config, tx }
+    }
+
+    /// (Re)create the global notifier from the current configuration.
+    /// The existing one is kept if its configuration didn't change.
+    pub fn from_config() {
+        let config = get_config();
+
+        if let Some(notifier) = &**NOTIFIER.load() {
+            if Some(&notifier.config) == config.notifications.as_ref() {
+                return;
+            }
+        }
+
+        match config.notifications {
+            Some(notifications) => {
+                info!("Sending ban notifications to {}", notifications.url);
+                NOTIFIER.store(Arc::new(Some(Notifier::new(notifications))));
+            }
+            None => NOTIFIER.store(Arc::new(None)),
+        }
+    }
+
+    /// Queue the event without blocking, drop it if the endpoint can't keep up.
+    pub fn notify(&self, event: BanEvent) {
+        match self.tx.try_send(event) {
+            Ok(()) => (),
+            Err(TrySendError::Full(event)) => {
+                warn!("Notifications queue is full, dropping {:?}", event)
+            }
+            Err(TrySendError::Closed(event)) => {
+                warn!("Notifications task is not running, dropping {:?}", event)
+            }
+        }
+    }
+
+    async fn run(config: Notifications, mut rx: Receiver<BanEvent>) {
+        let timeout = tokio::time::Duration::from_millis(config.timeout);
+
+        while let Some(event) = rx.recv().await {
+            match tokio::time::timeout(timeout, Self::send(&config.url, &event)).await {
+                Ok(Ok(())) => debug!("Sent notification {:?}", event),
+                Ok(Err(err)) => error!("Could not send notification {:?}: {}", event, err),
+                Err(_) => error!("Notification {:?} timed out", event),
+            }
+        }
+
+        debug!("Notifications task for {} exiting", config.url);
+    }
+
+    async fn send(url: &str, event: &BanEvent) -> Result<(), String> {
+        let uri = url.parse::<Uri>().map_err(|err| err.to_string())?;
+        let authority = match uri.authority() {
+            Some(authority) => authority.clone(),
+            None => return Err(format!("{} has no host", url)),
+        };
+        let body = serde_json::to_vec(event).map_err(|err| err.to_string())?;
+
+        let stream = TcpStream::connect((authority.host(), authority.port_u16().unwrap_or(80)))
+            .await
+            .map_err(|err| err.to_string())?;
+
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .map_err(|err| err.to_string())?;
+
+        tokio::task::spawn(async move {
+            if let Err(err) = connection.await {
+                debug!("Notification connection error: {:?}", err);
+            }
+        });
+
+        let request = Request::post(uri.path_and_query().map_or("/", |path| path.as_str()))
+            .header(HOST, authority.as_str())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(body)))
+            .map_err(|err| err.to_string())?;
+
+        let response = sender
+            .send_request(request)
+            .await
+            .map_err(|err| err.to_string())?;
+
+        if !response.status().is_success() {
+            return Err(format!("endpoint responded with {}", response.status()));
+        }
+
+        Ok(())
+    }
+}
+
+/// Send the event to the configured endpoint, if any.
+pub fn notify(event: BanEvent) {
+    if let Some(notifier) = &**NOTIFIER.load() {
+        notifier.notify(event);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use http_body_util::BodyExt;
+    use hyper::body::Incoming;
+    use hyper::server::conn::http1;
+    use hyper::service::service_fn;
+    use hyper::Response;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_notifier_payload() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/bans", listener.local_addr().unwrap());
+        let (body_tx, mut body_rx) = channel::<(String, serde_json::Value)>(1);
+
+        tokio::task::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let service = service_fn(move |request: Request<Incoming>| {
+                let body_tx = body_tx.clone();
+                async move {
+                    let path = request.uri().path().to_string();
+                    let body = request.into_body().collect().await?.to_bytes();
+                    let payload = serde_json::from_slice(&body).unwrap();
+                    body_tx.send((path, payload)).await.unwrap();
+                    Ok::<_, hyper::Error>(Response::new(Full::new(Bytes::new())))
+                }
+            });
+            http1::Builder::new()
+                .serve_connection(TokioIo::new(stream), service)
+                .await
+                .unwrap();
+        });
+
+        let notifier = Notifier::new(Notifications {
+            url,
+            timeout: Notifications::default_timeout(),
+        });
+        let address = Address {
+            shard: 2,
+            ..Default::default()
+        };
+        notifier.notify(BanEvent::new(
+            BanEventKind::Ban,
+            &address,
+            Some(&BanReason::FailedHealthCheck),
+        ));
+
+        let (path, payload) = body_rx.recv().await.unwrap();
+        assert_eq!(path, "/bans");
+        assert_eq!(payload["event"], "ban");
+        assert_eq!(payload["address"], "127.0.0.1:5432");
+        assert_eq!(payload["shard"], 2);
+        assert_eq!(payload["reason"], "FailedHealthCheck");
+        assert!(payload["timestamp"].is_string());
+        assert_eq!(payload.as_object().unwrap().len(), 5);
+    }
+}
@@ -0,0 +1,512 @@
+//! OpenTelemetry spans for queries sent with a W3C trace context in a comment, e.g.
+//! `/*traceparent='00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01'*/ SELECT 1`
+//! as sqlcommenter adds it. The span covers the server checkout and the query, and is
+//! exported to an OTLP/HTTP collector with the JSON encoding. Only sampled traces are exported.
+use arc_swap::ArcSwap;
+use bytes::{Bytes, BytesMut};
+use http_body_util::Full;
+use hyper::header::{CONTENT_TYPE, HOST};
+use hyper::{Request, Uri};
+use hyper_util::rt::TokioIo;
+use log::{debug, error, info, warn};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+
+use crate::config::{get_config, Address, Otel, VERSION};
+use crate::query_router::QueryRouter;
+
+/// How many spans can be waiting to be exported before we start dropping them.
+const SPAN_QUEUE_SIZE: usize = 4096;
+
+/// Most spans sent to the collector in one request.
+const MAX_BATCH_SIZE: usize = 512;
+
+/// OTLP `SPAN_KIND_CLIENT`, we're the client of the server.
+const SPAN_KIND_CLIENT: u8 = 3;
+
+/// The tracer, globally available. Not set if OpenTelemetry is not configured.
+pub static TRACER: Lazy<ArcSwap<Option<Tracer>>> = Lazy::new(|| ArcSwap::from_pointee(None));
+
+static TRACEPARENT: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"traceparent\s*=\s*'([0-9a-f]{2})-([0-9a-f]{32})-([0-9a-f]{16})-([0-9a-f]{2})'")
+        .unwrap()
+});
+
+/// Trace the client's query belongs to, from its `traceparent`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub parent_span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// Parse the `traceparent` in the query of a Query or Parse message, if any.
+    pub fn from_message(message: &BytesMut) -> Option<TraceContext> {
+        let query = QueryRouter::query_text(message)?;
+        let captures = TRACEPARENT.captures(&query)?;
+
+        // Version ff is invalid, an all zeros trace or span ID too.
+        if &captures[1] == "ff" {
+            return None;
+        }
+
+        let mut trace_id = [0u8; 16];
+        let mut parent_span_id = [0u8; 8];
+        decode_hex(&captures[2], &mut trace_id)?;
+        decode_hex(&captures[3], &mut parent_span_id)?;
+        if trace_id == [0; 16] || parent_span_id == [0; 8] {
+            return None;
+        }
+
+        let flags = u8::from_str_radix(&captures[4], 16).ok()?;
+
+        Some(TraceContext {
+            trace_id,
+            parent_span_id,
+            sampled: flags & 1 == 1,
+        })
+    }
+}
+
+fn decode_hex(hex: &str, bytes: &mut [u8]) -> Option<()> {
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(index * 2..index * 2 + 2)?, 16).ok()?;
+    }
+
+    Some(())
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+}
+
+/// A finished span.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanData {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent_span_id: [u8; 8],
+    pub name: String,
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub attributes: Vec<(&'static str, AttributeValue)>,
+}
+
+impl SpanData {
+    /// The span in the OTLP JSON encoding.
+    fn to_otlp(&self) -> Value {
+        let nanos = |time: SystemTime| {
+            time.duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+                .to_string()
+        };
+
+        json!({
+            "traceId": encode_hex(&self.trace_id),
+            "spanId": encode_hex(&self.span_id),
+            "parentSpanId": encode_hex(&self.parent_span_id),
+            "name": self.name,
+            "kind": SPAN_KIND_CLIENT,
+            "startTimeUnixNano": nanos(self.start),
+            "endTimeUnixNano": nanos(self.end),
+            "attributes": self
+                .attributes
+                .iter()
+                .map(|(key, value)| json!({
+                    "key": key,
+                    "value": match value {
+                        AttributeValue::String(value) => json!({ "stringValue": value }),
+                        AttributeValue::Int(value) => json!({ "intValue": value.to_string() }),
+                    },
+                }))
+                .collect::<Vec<_>>(),
+        })
+    }
+}
+
+/// Where the finished spans go.
+pub trait SpanExporter: Send + Sync + std::fmt::Debug {
+    /// Export the span without blocking the query.
+    fn export(&self, span: SpanData);
+}
+
+/// Sends the spans to the collector in batches from a background task.
+#[derive(Debug)]
+pub struct OtlpExporter {
+    tx: Sender<SpanData>,
+}
+
+impl OtlpExporter {
+    /// Create the exporter and start the task sending the spans.
+    /// Must be called from within the Tokio runtime.
+    pub fn new(config: Otel) -> OtlpExporter {
+        let (tx, rx) = channel(SPAN_QUEUE_SIZE);
+
+        tokio::task::spawn(Self::run(config, rx));
+
+        OtlpExporter { tx }
+    }
+
+    async fn run(config: Otel, mut rx: Receiver<SpanData>) {
+        let timeout = tokio::time::Duration::from_millis(config.timeout);
+
+        while let Some(span) = rx.recv().await {
+            let mut batch = vec![span];
+            while batch.len() < MAX_BATCH_SIZE {
+                match rx.try_recv() {
+                    Ok(span) => batch.push(span),
+                    Err(_) => break,
+                }
+            }
+
+            match tokio::time::timeout(timeout, Self::send(&config, &batch)).await {
+                Ok(Ok(())) => debug!("Exported {} spans", batch.len()),
+                Ok(Err(err)) => error!("Could not export {} spans: {}", batch.len(), err),
+                Err(_) => error!("Exporting {} spans timed out", batch.len()),
+            }
+        }
+
+        debug!("Span exporter for {} exiting", config.endpoint);
+    }
+
+    /// The OTLP JSON payload for the spans.
+    fn payload(config: &Otel, spans: &[SpanData]) -> Value {
+        json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": { "stringValue": config.service_name },
+                    }],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "pgcat", "version": VERSION },
+                    "spans": spans.iter().map(SpanData::to_otlp).collect::<Vec<_>>(),
+                }],
+            }],
+        })
+    }
+
+    async fn send(config: &Otel, spans: &[SpanData]) -> Result<(), String> {
+        let uri = config
+            .endpoint
+            .parse::<Uri>()
+            .map_err(|err| err.to_string())?;
+        let authority = match uri.authority() {
+            Some(authority) => authority.clone(),
+            None => return Err(format!("{} has no host", config.endpoint)),
+        };
+        let body =
+            serde_json::to_vec(&Self::payload(config, spans)).map_err(|err| err.to_string())?;
+
+        let stream = TcpStream::connect((authority.host(), authority.port_u16().unwrap_or(80)))
+            .await
+            .map_err(|err| err.to_string())?;
+
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+            .await
+            .map_err(|err| err.to_string())?;
+
+        tokio::task::spawn(async move {
+            if let Err(err) = connection.await {
+                debug!("Span exporter connection error: {:?}", err);
+            }
+        });
+
+        let request = Request::post(uri.path_and_query().map_or("/", |path| path.as_str()))
+            .header(HOST, authority.as_str())
+            .header(CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(body)))
+            .map_err(|err| err.to_string())?;
+
+        let response = sender
+            .send_request(request)
+            .await
+            .map_err(|err| err.to_string())?;
+
+        if !response.status().is_success() {
+            return Err(format!("collector responded with {}", response.status()));
+        }
+
+        Ok(())
+    }
+}
+
+impl SpanExporter for OtlpExporter {
+    /// Queue the span, drop it if the collector can't keep up.
+    fn export(&self, span: SpanData) {
+        match self.tx.try_send(span) {
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => warn!("Span queue is full, dropping a span"),
+            Err(TrySendError::Closed(_)) => warn!("Span exporter is not running, dropping a span"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Tracer {
+    config: Option<Otel>,
+    exporter: Box<dyn SpanExporter>,
+}
+
+impl Tracer {
+    pub fn new(exporter: Box<dyn SpanExporter>) -> Tracer {
+        Tracer {
+            config: None,
+            exporter,
+        }
+    }
+
+    /// (Re)create the global tracer from the current configuration.
+    /// The existing one is kept if its configuration didn't change.
+    pub fn from_config() {
+        let config = get_config();
+
+        if let Some(tracer) = &**TRACER.load() {
+            if tracer.config.is_some() && tracer.config == config.otel {
+                return;
+            }
+        }
+
+        match config.otel {
+            Some(otel) => {
+                info!("Exporting OpenTelemetry spans to {}", otel.endpoint);
+                TRACER.store(Arc::new(Some(Tracer {
+                    config: Some(otel.clone()),
+                    exporter: Box::new(OtlpExporter::new(otel)),
+                })));
+            }
+            None => TRACER.store(Arc::new(None)),
+        }
+    }
+}
+
+/// Span of a query that's not finished yet.
+#[derive(Debug)]
+pub struct QuerySpan {
+    context: TraceContext,
+    span_id: [u8; 8],
+    name: String,
+    start: SystemTime,
+    attributes: Vec<(&'static str, AttributeValue)>,
+}
+
+impl QuerySpan {
+    /// Start the span of the query in the Query or Parse message if it's part of
+    /// a sampled trace and spans are exported.
+    pub fn start(message: &BytesMut) -> Option<QuerySpan> {
+        if TRACER.load().is_none() {
+            return None;
+        }
+
+        let context = TraceContext::from_message(message)?;
+        if !context.sampled {
+            return None;
+        }
+
+        // The command, e.g. SELECT, after the comments.
+        let query = QueryRouter::query_text(message).unwrap_or_default();
+        let mut rest = query.trim_start();
+        while let Some(comment) = rest.strip_prefix("/*") {
+            rest = comment
+                .split_once("*/")
+                .map_or("", |(_, rest)| rest)
+                .trim_start();
+        }
+        let name = match rest.split_whitespace().next() {
+            Some(command) => command.to_uppercase(),
+            None => "query".into(),
+        };
+
+        let mut span_id = [0u8; 8];
+        while span_id == [0; 8] {
+            span_id = rand::random();
+        }
+
+        Some(QuerySpan {
+            context,
+            span_id,
+            name,
+            start: SystemTime::now(),
+            attributes: vec![("db.system", AttributeValue::String("postgresql".into()))],
+        })
+    }
+
+    /// The server the query was sent to.
+    pub fn set_address(&mut self, address: &Address) {
+        self.attributes.extend([
+            ("db.name", AttributeValue::String(address.database.clone())),
+            ("db.user", AttributeValue::String(address.username.clone())),
+            (
+                "pgcat.pool",
+                AttributeValue::String(address.pool_name.clone()),
+            ),
+            ("pgcat.shard", AttributeValue::Int(address.shard as i64)),
+            (
+                "pgcat.role",
+                AttributeValue::String(address.role.to_string()),
+            ),
+            (
+                "server.address",
+                AttributeValue::String(address.host.clone()),
+            ),
+            ("server.port", AttributeValue::Int(address.port as i64)),
+        ]);
+    }
+
+    /// Finish the span with the number of rows the query returned or affected, and export it.
+    pub fn end(mut self, rows: u64) {
+        self.attributes
+            .push(("db.rows", AttributeValue::Int(rows as i64)));
+
+        let span = SpanData {
+            trace_id: self.context.trace_id,
+            span_id: self.span_id,
+            parent_span_id: self.context.parent_span_id,
+            name: self.name,
+            start: self.start,
+            end: SystemTime::now(),
+            attributes: self.attributes,
+        };
+
+        if let Some(tracer) = &**TRACER.load() {
+            tracer.exporter.export(span);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Role;
+    use crate::messages::simple_query;
+    use parking_lot::Mutex;
+
+    /// Keeps the spans for the test to look at.
+    #[derive(Debug, Default, Clone)]
+    struct InMemoryExporter {
+        spans: Arc<Mutex<Vec<SpanData>>>,
+    }
+
+    impl SpanExporter for InMemoryExporter {
+        fn export(&self, span: SpanData) {
+            self.spans.lock().push(span);
+        }
+    }
+
+    const TRACE: &str = "traceparent='00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331";
+
+    #[test]
+    fn test_trace_context() {
+        let context =
+            TraceContext::from_message(&simple_query(&format!("SELECT 1 /*{}-01'*/", TRACE)))
+                .unwrap();
+        assert_eq!(
+            encode_hex(&context.trace_id),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+        assert_eq!(encode_hex(&context.parent_span_id), "b7ad6b7169203331");
+        assert!(context.sampled);
+
+        let context =
+            TraceContext::from_message(&simple_query(&format!("/*{}-00'*/ SELECT 1", TRACE)))
+                .unwrap();
+        assert!(!context.sampled);
+
+        assert_eq!(TraceContext::from_message(&simple_query("SELECT 1")), None);
+        assert_eq!(
+            TraceContext::from_message(&simple_query(
+                "/*traceparent='00-00000000000000000000000000000000-b7ad6b7169203331-01'*/ SELECT 1"
+            )),
+            None
+        );
+    }
+
+    #[test]
+    fn test_query_span() {
+        let exporter = InMemoryExporter::default();
+        TRACER.store(Arc::new(Some(Tracer::new(Box::new(exporter.clone())))));
+
+        // Not sampled.
+        assert!(QuerySpan::start(&simple_query(&format!("/*{}-00'*/ SELECT 1", TRACE))).is_none());
+
+        let mut span = QuerySpan::start(&simple_query(&format!(
+            "/*{}-01'*/ select * from users",
+            TRACE
+        )))
+        .unwrap();
+        span.set_address(&Address {
+            host: "10.0.0.1".into(),
+            database: "shard1".into(),
+            username: "app".into(),
+            pool_name: "users".into(),
+            shard: 1,
+            role: Role::Replica,
+            ..Default::default()
+        });
+        span.end(3);
+
+        TRACER.store(Arc::new(None));
+
+        let spans = exporter.spans.lock();
+        assert_eq!(spans.len(), 1);
+
+        let span = &spans[0];
+        assert_eq!(
+            encode_hex(&span.trace_id),
+            "0af7651916cd43dd8448eb211c80319c"
+        );
+        assert_eq!(encode_hex(&span.parent_span_id), "b7ad6b7169203331");
+        assert_ne!(span.span_id, span.parent_span_id);
+        assert_eq!(span.name, "SELECT");
+        assert!(span.end >= span.start);
+
+        let attribute = |key| {
+            span.attributes
+                .iter()
+                .find(|(name, _)| *name == key)
+                .map(|(_, value)| value.clone())
+        };
+        assert_eq!(
+            attribute("pgcat.pool"),
+            Some(AttributeValue::String("users".into()))
+        );
+        assert_eq!(attribute("pgcat.shard"), Some(AttributeValue::Int(1)));
+        assert_eq!(
+            attribute("server.address"),
+            Some(AttributeValue::String("10.0.0.1".into()))
+        );
+        assert_eq!(attribute("server.port"), Some(AttributeValue::Int(5432)));
+        assert_eq!(attribute("db.rows"), Some(AttributeValue::Int(3)));
+
+        let payload = OtlpExporter::payload(
+            &Otel {
+                endpoint: "http://localhost:4318/v1/traces".into(),
+                service_name: "pgcat".into(),
+                timeout: 1000,
+            },
+            &spans,
+        );
+        let otlp_span = &payload["resourceSpans"][0]["scopeSpans"][0]["spans"][0];
+        assert_eq!(otlp_span["traceId"], "0af7651916cd43dd8448eb211c80319c");
+        assert_eq!(otlp_span["parentSpanId"], "b7ad6b7169203331");
+        assert_eq!(otlp_span["kind"], 3);
+    }
+}
@@ -0,0 +1,294 @@
+//! Audit trail of the statements sent to the servers, as JSON lines
+//! written to a file or stdout.
+//!
+//! Each record ends with the SHA-256 `hash` of the previous record's hash and
+//! the record without its hash, so removing or editing a record breaks the chain.
+
+use log::{debug, error, warn};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde_derive::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc::{channel, error::TrySendError, Receiver, Sender};
+
+use crate::config::{Address, AuditLog as AuditLogConfig};
+use crate::slow_query::normalize;
+
+/// Writers by destination, shared by all the pools logging there.
+static WRITERS: Lazy<Mutex<HashMap<Option<String>, Sender<AuditEvent>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOutcome {
+    Success,
+    Error,
+}
+
+/// A statement sent to a server.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AuditEvent {
+    pub timestamp: String,
+    pub user: String,
+    pub database: String,
+    pub client_address: String,
+    pub server_address: String,
+    pub query: String,
+    pub outcome: AuditOutcome,
+
+    /// SQLSTATE of the error.
+    pub error_code: Option<String>,
+
+    /// Rows returned or affected.
+    pub rows: u64,
+}
+
+/// A record of the log: the event and the hash chaining it to the previous record.
+#[derive(Serialize, Debug)]
+struct AuditRecord<'a> {
+    #[serde(flatten)]
+    event: &'a AuditEvent,
+    hash: String,
+}
+
+/// The audit log plugin.
+pub struct AuditLog<'a> {
+    pub enabled: bool,
+    pub config: &'a AuditLogConfig,
+}
+
+impl<'a> AuditLog<'a> {
+    /// Queue the event of the query without blocking, it's dropped if the writer can't keep up.
+    /// Must be called from within the Tokio runtime.
+    pub fn log(
+        &self,
+        username: &str,
+        client_address: std::net::SocketAddr,
+        address: &Address,
+        query: Option<String>,
+        error_code: Option<String>,
+        rows: u64,
+    ) {
+        if !self.enabled {
+            return;
+        }
+
+        let query = match query {
+            Some(query) if self.config.log_literals => query,
+            Some(query) => normalize(&query),
+            None => "<unknown>".into(),
+        };
+
+        let event = AuditEvent {
+            timestamp: chrono::offset::Utc::now().to_rfc3339(),
+            user: username.into(),
+            database: address.pool_name.clone(),
+            client_address: client_address.ip().to_canonical().to_string(),
+            server_address: format!("{}:{}", address.host, address.port),
+            query,
+            outcome: match error_code {
+                Some(_) => AuditOutcome::Error,
+                None => AuditOutcome::Success,
+            },
+            error_code,
+            rows,
+        };
+
+        let tx = WRITERS
+            .lock()
+            .entry(self.config.path.clone())
+            .or_insert_with(|| {
+                let (tx, rx) = channel(self.config.buffer_size);
+                tokio::task::spawn(run(self.config.clone(), rx));
+                tx
+            })
+            .clone();
+
+        match tx.try_send(event) {
+            Ok(()) => (),
+            Err(TrySendError::Full(event)) => {
+                warn!("Audit log queue is full, dropping {:?}", event)
+            }
+            Err(TrySendError::Closed(event)) => {
+                // The writer couldn't open the file, it's retried with the next event.
+                WRITERS.lock().remove(&self.config.path);
+                warn!("Audit log writer is not running, dropping {:?}", event)
+            }
+        }
+    }
+}
+
+/// The line of the event, chained to the previous one by `hash`.
+fn record(event: &AuditEvent, previous_hash: &str) -> (String, String) {
+    let fields = serde_json::to_string(event).unwrap();
+
+    let mut hasher = Sha256::new();
+    hasher.update(previous_hash.as_bytes());
+    hasher.update(fields.as_bytes());
+    let hash = format!("{:x}", hasher.finalize());
+
+    let record = AuditRecord {
+        event,
+        hash: hash.clone(),
+    };
+
+    (serde_json::to_string(&record).unwrap() + "\n", hash)
+}
+
+async fn open(path: &str) -> std::io::Result<(File, u64)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    let size = file.metadata().await?.len();
+
+    Ok((file, size))
+}
+
+/// Write the events, rotating the file to `<path>.1` when it's larger than `max_file_size`.
+async fn run(config: AuditLogConfig, mut rx: Receiver<AuditEvent>) {
+    let mut previous_hash = String::new();
+
+    let (mut writer, mut size): (Box<dyn AsyncWrite + Send + Unpin>, u64) = match config.path {
+        Some(ref path) => match open(path).await {
+            Ok((file, size)) => (Box::new(file), size),
+            Err(err) => {
+                error!("Could not open the audit log {}: {}", path, err);
+                return;
+            }
+        },
+        None => (Box::new(tokio::io::stdout()), 0),
+    };
+
+    while let Some(event) = rx.recv().await {
+        let (line, hash) = record(&event, &previous_hash);
+        previous_hash = hash;
+
+        if let Some(ref path) = config.path {
+            if config.max_file_size > 0
+                && size > 0
+                && size + line.len() as u64 > config.max_file_size
+            {
+                let _ = writer.flush().await;
+
+                let rotated = format!("{}.1", path);
+                if let Err(err) = tokio::fs::rename(path, &rotated).await {
+                    error!("Could not rotate the audit log {}: {}", path, err);
+                }
+
+                match open(path).await {
+                    Ok((file, new_size)) => {
+                        writer = Box::new(file);
+                        size = new_size;
+                    }
+                    Err(err) => {
+                        error!("Could not open the audit log {}: {}", path, err);
+                        return;
+                    }
+                }
+            }
+        }
+
+        if let Err(err) = writer.write_all(line.as_bytes()).await {
+            error!("Could not write to the audit log: {}", err);
+            continue;
+        }
+
+        size += line.len() as u64;
+
+        let _ = writer.flush().await;
+    }
+
+    debug!("Audit log writer exiting");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let path = std::env::temp_dir().join(format!("pgcat_audit_{}.log", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let config = AuditLogConfig {
+            enabled: true,
+            path: Some(path.to_str().unwrap().into()),
+            max_file_size: 0,
+            buffer_size: 10,
+            log_literals: false,
+        };
+        let audit_log = AuditLog {
+            enabled: true,
+            config: &config,
+        };
+        let address = Address {
+            host: "127.0.0.1".into(),
+            port: 5432,
+            pool_name: "pool".into(),
+            ..Default::default()
+        };
+        let client_address = "[::ffff:10.0.0.1]:40000".parse().unwrap();
+
+        audit_log.log(
+            "user",
+            client_address,
+            &address,
+            Some("SELECT * FROM users WHERE email = 'a@b.c'".into()),
+            None,
+            1,
+        );
+        audit_log.log(
+            "user",
+            client_address,
+            &address,
+            Some("SELECT 1/0".into()),
+            Some("22012".into()),
+            0,
+        );
+
+        let mut log = String::new();
+        for _ in 0..50 {
+            tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            log = std::fs::read_to_string(&path).unwrap_or_default();
+            if log.lines().count() == 2 {
+                break;
+            }
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        let lines: Vec<serde_json::Value> = log
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        let record = &lines[0];
+        assert_eq!(record["user"], "user");
+        assert_eq!(record["database"], "pool");
+        assert_eq!(record["client_address"], "10.0.0.1");
+        assert_eq!(record["server_address"], "127.0.0.1:5432");
+        assert_eq!(record["query"], "SELECT * FROM users WHERE email = ?");
+        assert_eq!(record["outcome"], "success");
+        assert_eq!(record["error_code"], serde_json::Value::Null);
+        assert_eq!(record["rows"], 1);
+        assert!(
+            chrono::DateTime::parse_from_rfc3339(record["timestamp"].as_str().unwrap()).is_ok()
+        );
+
+        assert_eq!(lines[1]["outcome"], "error");
+        assert_eq!(lines[1]["error_code"], "22012");
+
+        // Each hash covers the previous one and the record without its hash.
+        let line = log.lines().nth(1).unwrap();
+        let fields = format!("{}}}", &line[..line.rfind(",\"hash\":").unwrap()]);
+        let mut hasher = Sha256::new();
+        hasher.update(lines[0]["hash"].as_str().unwrap().as_bytes());
+        hasher.update(fields.as_bytes());
+        assert_eq!(lines[1]["hash"], format!("{:x}", hasher.finalize()));
+    }
+}
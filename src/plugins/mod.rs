@@ -5,13 +5,24 @@
 //!   - block known bad queries
 //!   - block access to system catalogs
 //!   - block dangerous modifications like `DROP TABLE`
+//!   - limit the queries per second of a user
+//!   - cache the results of read-only queries
+//!   - keep an audit trail of the queries
+//!   - rewrite the statements sent to the servers
+//!   - run custom policies compiled to WebAssembly
 //!   - etc
 //!
 
+pub mod audit_log;
 pub mod intercept;
 pub mod prewarmer;
+pub mod query_cache;
+pub mod query_filter;
 pub mod query_logger;
+pub mod query_rewrite;
+pub mod rate_limit;
 pub mod table_access;
+pub mod wasm;
 
 use crate::{errors::Error, query_router::QueryRouter};
 use async_trait::async_trait;
@@ -19,8 +30,12 @@ use bytes::BytesMut;
 use sqlparser::ast::Statement;
 
 pub use intercept::Intercept;
+pub use query_filter::QueryFilter;
 pub use query_logger::QueryLogger;
+pub use query_rewrite::QueryRewrite;
+pub use rate_limit::RateLimit;
 pub use table_access::TableAccess;
+pub use wasm::Wasm;
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum PluginOutput {
@@ -28,6 +43,8 @@ pub enum PluginOutput {
     Deny(String),
     Overwrite(Vec<Statement>),
     Intercept(BytesMut),
+    /// Send the query to this shard.
+    Route(usize),
 }
 
 #[async_trait]
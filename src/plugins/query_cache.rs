@@ -0,0 +1,356 @@
+//! Cache the results of read-only queries, e.g. the same dashboard queries
+//! sent by many clients, and answer them without a server.
+//!
+//! Only simple queries sent outside of a transaction are cached, for `ttl` ms,
+//! by pool, user, the shard and role the query is routed to, and query text.
+//! Queries sent with the extended protocol always go to a server.
+
+use bytes::{BufMut, BytesMut};
+use log::debug;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use sqlparser::ast::Statement;
+use std::collections::HashMap;
+use tokio::time::{Duration, Instant};
+
+use crate::{
+    config::{QueryCache as QueryCacheConfig, Role, StatementType},
+    query_router::QueryRouter,
+};
+
+/// Cached results of each pool, up to its `max_entries`.
+static CACHE: Lazy<Mutex<HashMap<String, HashMap<CacheKey, CachedResult>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    db: String,
+    username: String,
+    shard: Option<usize>,
+    role: Option<Role>,
+    query: String,
+}
+
+#[derive(Debug, Clone)]
+struct CachedResult {
+    response: BytesMut,
+    expires_at: Instant,
+}
+
+/// The query cache plugin.
+pub struct QueryCache<'a> {
+    pub enabled: bool,
+    pub config: &'a QueryCacheConfig,
+}
+
+impl<'a> QueryCache<'a> {
+    /// The key of the query, if its result can be cached: it only reads
+    /// and matches one of the configured patterns. Called once the query
+    /// router inferred where the query goes: the formatted query doesn't have
+    /// the shard selection comments, and shards don't have the same rows.
+    pub fn key(&self, query_router: &QueryRouter, ast: &[Statement]) -> Option<CacheKey> {
+        if !self.enabled || ast.is_empty() {
+            return None;
+        }
+
+        // Sent to whichever shard the pool picks.
+        let shard = query_router.shard();
+        if shard.is_none() && query_router.pool_settings().shards > 1 {
+            return None;
+        }
+
+        if ast
+            .iter()
+            .any(|statement| QueryRouter::statement_type(statement) != StatementType::Read)
+        {
+            return None;
+        }
+
+        // Formatted by the parser, so whitespace and keyword case don't matter.
+        let query = ast
+            .iter()
+            .map(|statement| statement.to_string())
+            .collect::<Vec<String>>()
+            .join("; ");
+
+        if !query_router
+            .pool_settings()
+            .query_cache_patterns
+            .iter()
+            .any(|pattern| pattern.is_match(&query))
+        {
+            return None;
+        }
+
+        Some(CacheKey {
+            db: query_router.pool_settings().db.clone(),
+            username: query_router.pool_settings().user.username.clone(),
+            shard,
+            role: query_router.role(),
+            query,
+        })
+    }
+
+    /// The cached result of the query, if it hasn't expired.
+    pub fn get(&self, key: &CacheKey) -> Option<BytesMut> {
+        let mut cache = CACHE.lock();
+        let cache = cache.get_mut(&key.db)?;
+
+        match cache.get(key) {
+            Some(result) if result.expires_at > Instant::now() => {
+                debug!("Query cache hit: {}", key.query);
+                Some(result.response.clone())
+            }
+            Some(_) => {
+                cache.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Start collecting the result of the query sent to the server.
+    pub fn entry(&self, key: CacheKey) -> QueryCacheEntry {
+        QueryCacheEntry {
+            key,
+            response: Some(BytesMut::new()),
+            max_entry_size: self.config.max_entry_size,
+            max_entries: self.config.max_entries,
+            ttl: Duration::from_millis(self.config.ttl),
+        }
+    }
+}
+
+/// Result of a query being received from the server.
+#[derive(Debug)]
+pub struct QueryCacheEntry {
+    key: CacheKey,
+
+    /// None once the result is too large.
+    response: Option<BytesMut>,
+
+    max_entry_size: usize,
+    max_entries: usize,
+    ttl: Duration,
+}
+
+impl QueryCacheEntry {
+    pub fn add(&mut self, response: &[u8]) {
+        if let Some(ref mut result) = self.response {
+            if result.len() + response.len() > self.max_entry_size {
+                self.response = None;
+            } else {
+                result.put_slice(response);
+            }
+        }
+    }
+
+    /// Cache the result if the query succeeded and left no transaction open.
+    pub fn store(self) {
+        let response = match self.response {
+            Some(response) if is_cacheable(&response) => response,
+            _ => return,
+        };
+
+        let now = Instant::now();
+        let mut cache = CACHE.lock();
+        let cache = cache.entry(self.key.db.clone()).or_default();
+
+        if cache.len() >= self.max_entries {
+            cache.retain(|_, result| result.expires_at > now);
+
+            if cache.len() >= self.max_entries {
+                return;
+            }
+        }
+
+        cache.insert(
+            self.key,
+            CachedResult {
+                response,
+                expires_at: now + self.ttl,
+            },
+        );
+    }
+}
+
+/// Whether the response has no ErrorResponse or CopyInResponse
+/// and ends with an idle ReadyForQuery.
+fn is_cacheable(response: &[u8]) -> bool {
+    let mut offset = 0;
+    let mut idle = false;
+
+    while response.len() >= offset + 5 {
+        let code = response[offset];
+        let len = i32::from_be_bytes(response[offset + 1..offset + 5].try_into().unwrap());
+        let end = match usize::try_from(len) {
+            Ok(len) if len >= 4 => offset + 1 + len,
+            _ => return false,
+        };
+
+        if response.len() < end {
+            return false;
+        }
+
+        match code {
+            b'E' | b'G' | b'H' | b'W' => return false,
+            b'Z' => idle = response[offset + 5] == b'I',
+            _ => (),
+        }
+
+        offset = end;
+    }
+
+    offset == response.len() && idle
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::{command_complete, data_row, row_description, simple_query, DataType};
+    use crate::pool::PoolSettings;
+    use regex::Regex;
+
+    fn ready_for_query(status: u8) -> BytesMut {
+        let mut res = BytesMut::new();
+        res.put_u8(b'Z');
+        res.put_i32(5);
+        res.put_u8(status);
+        res
+    }
+
+    #[test]
+    fn test_query_cache_entry() {
+        let config = QueryCacheConfig {
+            enabled: true,
+            queries: vec![".*".into()],
+            ttl: 60_000,
+            max_entry_size: 100,
+            max_entries: 10,
+        };
+        let query_cache = QueryCache {
+            enabled: true,
+            config: &config,
+        };
+        let key = |query: &str| CacheKey {
+            db: "query_cache_db".into(),
+            username: "user".into(),
+            shard: None,
+            role: None,
+            query: query.into(),
+        };
+
+        let mut response = row_description(&vec![("a", DataType::Text)]);
+        response.put(data_row(&vec!["1".into()]));
+        response.put(command_complete("SELECT 1"));
+
+        let mut entry = query_cache.entry(key("SELECT 1"));
+        entry.add(&response);
+        entry.add(&ready_for_query(b'I'));
+        entry.store();
+
+        response.put(ready_for_query(b'I'));
+        assert_eq!(query_cache.get(&key("SELECT 1")), Some(response.clone()));
+
+        // Too large.
+        let mut entry = query_cache.entry(key("SELECT 2"));
+        entry.add(&response);
+        entry.add(&response);
+        entry.store();
+        assert_eq!(query_cache.get(&key("SELECT 2")), None);
+
+        // In a transaction.
+        let mut entry = query_cache.entry(key("SELECT 3"));
+        entry.add(&ready_for_query(b'T'));
+        entry.store();
+        assert_eq!(query_cache.get(&key("SELECT 3")), None);
+    }
+
+    #[test]
+    fn test_query_cache_max_entries() {
+        let config = QueryCacheConfig {
+            enabled: true,
+            queries: vec![".*".into()],
+            ttl: 60_000,
+            max_entry_size: 100,
+            max_entries: 1,
+        };
+        let query_cache = QueryCache {
+            enabled: true,
+            config: &config,
+        };
+        let key = |db: &str, query: &str| CacheKey {
+            db: db.into(),
+            username: "user".into(),
+            shard: None,
+            role: None,
+            query: query.into(),
+        };
+        let store = |key: CacheKey| {
+            let mut entry = query_cache.entry(key);
+            entry.add(&ready_for_query(b'I'));
+            entry.store();
+        };
+
+        store(key("max_entries_db_a", "SELECT 1"));
+        store(key("max_entries_db_a", "SELECT 2"));
+        store(key("max_entries_db_b", "SELECT 1"));
+
+        // The first pool is full, the second one has its own room.
+        assert!(query_cache
+            .get(&key("max_entries_db_a", "SELECT 1"))
+            .is_some());
+        assert_eq!(query_cache.get(&key("max_entries_db_a", "SELECT 2")), None);
+        assert!(query_cache
+            .get(&key("max_entries_db_b", "SELECT 1"))
+            .is_some());
+    }
+
+    #[test]
+    fn test_query_cache_key() {
+        QueryRouter::setup();
+        let config = QueryCacheConfig {
+            enabled: true,
+            queries: vec![".*".into()],
+            ttl: 60_000,
+            max_entry_size: 100,
+            max_entries: 10,
+        };
+        let query_cache = QueryCache {
+            enabled: true,
+            config: &config,
+        };
+
+        let mut query_router = QueryRouter::new();
+        query_router.update_pool_settings(&PoolSettings {
+            shards: 2,
+            query_parser_enabled: true,
+            query_cache_patterns: vec![Regex::new(".*").unwrap()],
+            ..Default::default()
+        });
+        let ast = query_router
+            .parse(&simple_query("/* shard_id: 1 */ SELECT 1"))
+            .unwrap();
+
+        // Sent to any shard.
+        assert_eq!(query_cache.key(&query_router, &ast), None);
+
+        query_router.set_shard(Some(0));
+        let first = query_cache.key(&query_router, &ast).unwrap();
+        query_router.set_shard(Some(1));
+        let second = query_cache.key(&query_router, &ast).unwrap();
+        assert_eq!(first.query, second.query);
+        assert_ne!(first, second);
+    }
+
+    #[test]
+    fn test_is_cacheable_invalid_length() {
+        let mut response = BytesMut::new();
+        response.put_u8(b'D');
+        response.put_i32(-1);
+        response.put(ready_for_query(b'I'));
+        assert!(!is_cacheable(&response));
+
+        assert!(is_cacheable(&ready_for_query(b'I')));
+    }
+}
@@ -0,0 +1,74 @@
+//! Allow or deny statements with ordered rules, e.g. only allow `SELECT`s
+//! on a read-only endpoint or block `DROP` and `TRUNCATE`.
+
+use async_trait::async_trait;
+use log::debug;
+use sqlparser::ast::Statement;
+
+use crate::{
+    config::{QueryFilter as QueryFilterConfig, QueryFilterAction},
+    errors::Error,
+    plugins::{Plugin, PluginOutput},
+    query_router::QueryRouter,
+};
+
+/// The query filter plugin.
+pub struct QueryFilter<'a> {
+    pub enabled: bool,
+    pub config: &'a QueryFilterConfig,
+}
+
+#[async_trait]
+impl<'a> Plugin for QueryFilter<'a> {
+    async fn run(
+        &mut self,
+        query_router: &QueryRouter,
+        ast: &Vec<Statement>,
+    ) -> Result<PluginOutput, Error> {
+        if !self.enabled {
+            return Ok(PluginOutput::Allow);
+        }
+
+        for statement in ast {
+            let query = statement.to_string();
+            let statement_type = QueryRouter::statement_type(statement);
+
+            // The first rule matching the statement decides.
+            let rule = query_router
+                .pool_settings()
+                .query_filter_rules
+                .iter()
+                .find(|(pattern, rule)| {
+                    let pattern_matches = match pattern {
+                        Some(pattern) => pattern.is_match(&query),
+                        None => true,
+                    };
+                    let type_matches = match rule.statement_types {
+                        Some(ref statement_types) => statement_types.contains(&statement_type),
+                        None => true,
+                    };
+
+                    pattern_matches && type_matches
+                })
+                .map(|(_, rule)| rule);
+
+            let action = match rule {
+                Some(rule) => rule.action,
+                None => self.config.default_action,
+            };
+
+            if action == QueryFilterAction::Deny {
+                debug!("Denying statement \"{}\"", query);
+
+                return Ok(PluginOutput::Deny(
+                    match rule.and_then(|rule| rule.message.clone()) {
+                        Some(message) => message,
+                        None => format!("statement not allowed: {}", query),
+                    },
+                ));
+            }
+        }
+
+        Ok(PluginOutput::Allow)
+    }
+}
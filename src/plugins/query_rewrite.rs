@@ -0,0 +1,94 @@
+//! Rewrite the statements matching a pattern before they are sent to the server,
+//! e.g. to query a view instead of a deprecated table during a migration.
+//!
+//! Only simple queries are rewritten: the statements of the extended protocol
+//! are sent as the client prepared them, the parameters it describes and binds
+//! could otherwise no longer match.
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use sqlparser::ast::Statement;
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::Parser;
+
+use crate::{
+    errors::Error,
+    plugins::{Plugin, PluginOutput},
+    query_router::QueryRouter,
+};
+
+/// The query rewrite plugin.
+pub struct QueryRewrite {
+    pub enabled: bool,
+}
+
+impl QueryRewrite {
+    /// The simple query sent to the server instead.
+    pub fn query(ast: &[Statement]) -> String {
+        ast.iter()
+            .map(|statement| statement.to_string())
+            .collect::<Vec<_>>()
+            .join("; ")
+    }
+}
+
+#[async_trait]
+impl Plugin for QueryRewrite {
+    async fn run(
+        &mut self,
+        query_router: &QueryRouter,
+        ast: &Vec<Statement>,
+    ) -> Result<PluginOutput, Error> {
+        let rules = &query_router.pool_settings().query_rewrite_rules;
+
+        if !self.enabled || rules.is_empty() {
+            return Ok(PluginOutput::Allow);
+        }
+
+        let mut rewritten = false;
+        let mut statements = Vec::with_capacity(ast.len());
+
+        for statement in ast {
+            let query = statement.to_string();
+            let mut rewrite = query.clone();
+
+            for (pattern, rule) in rules {
+                if pattern.is_match(&rewrite) {
+                    rewrite = pattern.replace_all(&rewrite, &rule.rewrite).into_owned();
+                }
+            }
+
+            if rewrite == query {
+                statements.push(statement.clone());
+                continue;
+            }
+
+            // A rule can't send the server something we can't route.
+            match Parser::parse_sql(&PostgreSqlDialect {}, &rewrite) {
+                Ok(ast) => {
+                    debug!("Rewrote statement \"{}\" to \"{}\"", query, rewrite);
+
+                    rewritten = true;
+                    statements.extend(ast);
+                }
+                Err(err) => {
+                    warn!(
+                        "Rewrite of \"{}\" is not valid SQL: {}: {}",
+                        query, err, rewrite
+                    );
+
+                    return Ok(PluginOutput::Deny(format!(
+                        "rewritten query is not valid SQL: {}",
+                        err
+                    )));
+                }
+            }
+        }
+
+        if rewritten {
+            Ok(PluginOutput::Overwrite(statements))
+        } else {
+            Ok(PluginOutput::Allow)
+        }
+    }
+}
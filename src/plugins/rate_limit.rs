@@ -0,0 +1,119 @@
+//! Cap the queries per second of each user of a pool with a token bucket.
+//! The bucket is shared by all the connections of the user.
+
+use async_trait::async_trait;
+use log::debug;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use sqlparser::ast::Statement;
+use std::collections::HashMap;
+use tokio::time::Instant;
+
+use crate::{
+    config::RateLimit as RateLimitConfig,
+    errors::Error,
+    plugins::{Plugin, PluginOutput},
+    query_router::QueryRouter,
+};
+
+/// Buckets of the users, by pool and username.
+static BUCKETS: Lazy<Mutex<HashMap<(String, String), TokenBucket>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Refills `rate` tokens per second, up to `burst`. A query takes one.
+#[derive(Debug, Clone, Copy)]
+struct TokenBucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(burst: u64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: burst as f64,
+            updated_at: now,
+        }
+    }
+
+    fn take(&mut self, rate: u64, burst: u64, now: Instant) -> bool {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * rate as f64).min(burst as f64);
+        self.updated_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The rate limit plugin.
+pub struct RateLimit<'a> {
+    pub enabled: bool,
+    pub config: &'a RateLimitConfig,
+}
+
+#[async_trait]
+impl<'a> Plugin for RateLimit<'a> {
+    async fn run(
+        &mut self,
+        query_router: &QueryRouter,
+        _ast: &Vec<Statement>,
+    ) -> Result<PluginOutput, Error> {
+        if !self.enabled {
+            return Ok(PluginOutput::Allow);
+        }
+
+        let db = &query_router.pool_settings().db;
+        let username = &query_router.pool_settings().user.username;
+        let rate = self.config.queries_per_second(username);
+        let burst = self.config.burst(username);
+        let now = Instant::now();
+
+        let allowed = BUCKETS
+            .lock()
+            .entry((db.clone(), username.clone()))
+            .or_insert_with(|| TokenBucket::new(burst, now))
+            .take(rate, burst, now);
+
+        if allowed {
+            Ok(PluginOutput::Allow)
+        } else {
+            debug!("Rate limiting user \"{}\" of pool \"{}\"", username, db);
+
+            Ok(PluginOutput::Deny(format!(
+                "rate limit exceeded: user \"{}\" is limited to {} queries per second",
+                username, rate
+            )))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_token_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(2, now);
+
+        assert!(bucket.take(10, 2, now));
+        assert!(bucket.take(10, 2, now));
+        assert!(!bucket.take(10, 2, now));
+
+        // One token every 100ms.
+        assert!(bucket.take(10, 2, now + Duration::from_millis(100)));
+        assert!(!bucket.take(10, 2, now + Duration::from_millis(150)));
+
+        // Never more than the burst.
+        let later = now + Duration::from_secs(10);
+        assert!(bucket.take(10, 2, later));
+        assert!(bucket.take(10, 2, later));
+        assert!(!bucket.take(10, 2, later));
+    }
+}
@@ -0,0 +1,491 @@
+//! Intercept the queries with a WebAssembly module, for the policies the other
+//! plugins can't express.
+//!
+//! The module is given the query and its metadata as JSON and can let it through,
+//! rewrite it, reject it or route it to a shard:
+//!
+//! - it exports its `memory`, `alloc(len: i32) -> i32` which returns where to write
+//!   `len` bytes, and `intercept(ptr: i32, len: i32) -> i32` which is called with the JSON;
+//! - `intercept` returns 0 to let the query through, 1 to rewrite it, 2 to reject it
+//!   and 3 to route it;
+//! - it can import from `pgcat`: `set_output(ptr: i32, len: i32)` to give the rewritten
+//!   query or the rejection message, `set_shard(shard: i32)` to give the shard the query
+//!   is routed to and `log(ptr: i32, len: i32)` to log a debug message.
+//!
+//! Modules run on wasmtime. Each query gets a fresh instance, with a limited fuel, time
+//! and memory. A module which runs out of them or traps rejects the query.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use serde_derive::Serialize;
+use sqlparser::ast::Statement;
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::parser::Parser;
+use wasmtime::{
+    Caller, Engine, Extern, ExternType, InstancePre, Linker, Store, StoreLimits,
+    StoreLimitsBuilder, Trap,
+};
+
+use crate::{
+    config,
+    errors::Error,
+    plugins::{Plugin, PluginOutput, QueryRewrite},
+    query_router::QueryRouter,
+};
+
+/// Size of a memory page.
+pub const PAGE_SIZE: usize = 65536;
+
+/// How often the epoch advances, the timeouts are counted in epochs.
+const EPOCH_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Largest table a module can have.
+const MAX_TABLE_ELEMENTS: usize = 100_000;
+
+const PASS: i32 = 0;
+const REWRITE: i32 = 1;
+const REJECT: i32 = 2;
+const ROUTE: i32 = 3;
+
+/// Shared by the modules, its epoch is advanced by a thread started with it.
+static ENGINE: Lazy<Engine> = Lazy::new(|| {
+    let mut config = wasmtime::Config::new();
+    config.consume_fuel(true);
+    config.epoch_interruption(true);
+
+    let engine = Engine::new(&config).expect("WASM engine configuration is invalid");
+
+    let ticker = engine.clone();
+    std::thread::Builder::new()
+        .name("wasm-epoch".into())
+        .spawn(move || loop {
+            std::thread::sleep(EPOCH_INTERVAL);
+            ticker.increment_epoch();
+        })
+        .expect("failed to start the WASM epoch thread");
+
+    engine
+});
+
+/// What the module is given.
+#[derive(Serialize)]
+struct Input<'a> {
+    query: &'a str,
+    user: &'a str,
+    database: &'a str,
+    application_name: &'a str,
+    shard: Option<usize>,
+    shards: usize,
+    role: Option<String>,
+}
+
+/// State of an instance: its limits and what it gave through its imports.
+struct State {
+    limits: StoreLimits,
+    output: Option<String>,
+    shard: Option<i32>,
+}
+
+/// String the module gave to an import.
+fn string(caller: &mut Caller<'_, State>, ptr: i32, len: i32) -> wasmtime::Result<String> {
+    let memory = match caller.get_export("memory") {
+        Some(Extern::Memory(memory)) => memory,
+        _ => return Err(wasmtime::Error::msg("the module doesn't export its memory")),
+    };
+
+    let mut bytes = vec![0; len as u32 as usize];
+    memory.read(&caller, ptr as u32 as usize, &mut bytes)?;
+
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Functions the module can import.
+fn linker() -> wasmtime::Result<Linker<State>> {
+    let mut linker = Linker::new(&ENGINE);
+
+    linker.func_wrap(
+        "pgcat",
+        "set_output",
+        |mut caller: Caller<'_, State>, ptr: i32, len: i32| {
+            caller.data_mut().output = Some(string(&mut caller, ptr, len)?);
+            Ok(())
+        },
+    )?;
+    linker.func_wrap(
+        "pgcat",
+        "set_shard",
+        |mut caller: Caller<'_, State>, shard: i32| {
+            caller.data_mut().shard = Some(shard);
+        },
+    )?;
+    linker.func_wrap(
+        "pgcat",
+        "log",
+        |mut caller: Caller<'_, State>, ptr: i32, len: i32| {
+            debug!("WASM plugin: {}", string(&mut caller, ptr, len)?);
+            Ok(())
+        },
+    )?;
+
+    Ok(linker)
+}
+
+/// Parameters and results of a function, e.g. `(i32, i32) -> (i32)`.
+fn signature(ty: &ExternType) -> Option<String> {
+    match ty {
+        ExternType::Func(ty) => Some(format!(
+            "({}) -> ({})",
+            ty.params()
+                .map(|ty| ty.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            ty.results()
+                .map(|ty| ty.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+        _ => None,
+    }
+}
+
+/// Message of a failure for the client.
+fn failure(err: &wasmtime::Error) -> String {
+    match err.downcast_ref::<Trap>() {
+        Some(Trap::Interrupt) => "timed out".into(),
+        Some(Trap::OutOfFuel) => "out of fuel".into(),
+        Some(trap) => trap.to_string(),
+        None => err.root_cause().to_string(),
+    }
+}
+
+/// A compiled module, with its imports resolved.
+pub struct Module {
+    instance: InstancePre<State>,
+}
+
+impl std::fmt::Debug for Module {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Module").finish_non_exhaustive()
+    }
+}
+
+impl Module {
+    /// Compiles a module, `.wasm` or `.wat`, and checks it has what pgcat expects.
+    pub fn new(bytes: &[u8], max_memory: usize) -> wasmtime::Result<Module> {
+        let module = wasmtime::Module::new(&ENGINE, bytes)?;
+
+        match module.get_export("memory") {
+            Some(ExternType::Memory(memory)) => {
+                if memory.minimum().saturating_mul(PAGE_SIZE as u64) > max_memory as u64 {
+                    return Err(wasmtime::Error::msg(format!(
+                        "the module needs {} pages of memory, more than max_memory",
+                        memory.minimum()
+                    )));
+                }
+            }
+            _ => return Err(wasmtime::Error::msg("the module must export its memory")),
+        }
+
+        for (name, expected) in [
+            ("alloc", "(i32) -> (i32)"),
+            ("intercept", "(i32, i32) -> (i32)"),
+        ] {
+            match module.get_export(name).as_ref().map(signature) {
+                Some(Some(signature)) if signature == expected => (),
+                Some(_) => {
+                    return Err(wasmtime::Error::msg(format!(
+                        "export {} must be a function {}",
+                        name, expected
+                    )))
+                }
+                None => {
+                    return Err(wasmtime::Error::msg(format!(
+                        "the module must export {}",
+                        name
+                    )))
+                }
+            }
+        }
+
+        // Fails on the imports pgcat doesn't have.
+        let instance = linker()?.instantiate_pre(&module)?;
+
+        Ok(Module { instance })
+    }
+
+    /// Lets the module intercept the input, returns its result and outputs.
+    fn intercept(&self, config: &config::Wasm, input: &[u8]) -> wasmtime::Result<(i32, State)> {
+        let limits = StoreLimitsBuilder::new()
+            .memory_size(config.max_memory)
+            .table_elements(MAX_TABLE_ELEMENTS)
+            .instances(1)
+            .memories(1)
+            .tables(1)
+            .build();
+        let mut store = Store::new(
+            &ENGINE,
+            State {
+                limits,
+                output: None,
+                shard: None,
+            },
+        );
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(config.fuel)?;
+        store.set_epoch_deadline(config.timeout.div_ceil(EPOCH_INTERVAL.as_millis() as u64));
+
+        let instance = self.instance.instantiate(&mut store)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("the module doesn't export its memory"))?;
+        let alloc = instance.get_typed_func::<i32, i32>(&mut store, "alloc")?;
+        let intercept = instance.get_typed_func::<(i32, i32), i32>(&mut store, "intercept")?;
+
+        let len = i32::try_from(input.len())?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as u32 as usize, input)?;
+
+        let result = intercept.call(&mut store, (ptr, len))?;
+
+        Ok((result, store.into_data()))
+    }
+}
+
+/// The WASM plugin.
+pub struct Wasm<'a> {
+    pub enabled: bool,
+    pub config: &'a config::Wasm,
+}
+
+#[async_trait]
+impl<'a> Plugin for Wasm<'a> {
+    async fn run(
+        &mut self,
+        query_router: &QueryRouter,
+        ast: &Vec<Statement>,
+    ) -> Result<PluginOutput, Error> {
+        let pool_settings = query_router.pool_settings();
+
+        let module: &Arc<Module> = match pool_settings.wasm_module {
+            Some(ref module) if self.enabled && !ast.is_empty() => module,
+            _ => return Ok(PluginOutput::Allow),
+        };
+
+        let query = QueryRewrite::query(ast);
+        let input = Input {
+            query: &query,
+            user: &pool_settings.user.username,
+            database: &pool_settings.db,
+            application_name: query_router.application_name(),
+            shard: query_router.shard(),
+            shards: pool_settings.shards,
+            role: query_router.role().map(|role| role.to_string()),
+        };
+        let input = match serde_json::to_vec(&input) {
+            Ok(input) => input,
+            Err(err) => {
+                warn!("WASM plugin input can't be serialized: {}", err);
+                return Ok(PluginOutput::Deny("query plugin failed".into()));
+            }
+        };
+
+        // The module runs on the blocking threads, not to hold up the other clients.
+        let (module, config) = (module.clone(), self.config.clone());
+        let (result, output) =
+            match tokio::task::spawn_blocking(move || module.intercept(&config, &input)).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(err)) => {
+                    warn!("WASM plugin {} failed: {:?}", self.config.path, err);
+                    return Ok(PluginOutput::Deny(format!(
+                        "query plugin failed: {}",
+                        failure(&err)
+                    )));
+                }
+                Err(err) => {
+                    warn!("WASM plugin {} failed: {}", self.config.path, err);
+                    return Ok(PluginOutput::Deny("query plugin failed".into()));
+                }
+            };
+
+        match result {
+            PASS => Ok(PluginOutput::Allow),
+
+            REWRITE => {
+                let rewrite = match output.output {
+                    Some(rewrite) => rewrite,
+                    None => {
+                        warn!(
+                            "WASM plugin {} rewrote a query without setting it",
+                            self.config.path
+                        );
+                        return Ok(PluginOutput::Deny(
+                            "query plugin failed to rewrite the query".into(),
+                        ));
+                    }
+                };
+
+                match Parser::parse_sql(&PostgreSqlDialect {}, &rewrite) {
+                    Ok(ast) if !ast.is_empty() => {
+                        debug!("WASM plugin rewrote \"{}\" to \"{}\"", query, rewrite);
+                        Ok(PluginOutput::Overwrite(ast))
+                    }
+                    Ok(_) => Ok(PluginOutput::Deny("rewritten query is empty".into())),
+                    Err(err) => {
+                        warn!(
+                            "WASM plugin {} rewrite is not valid SQL: {}: {}",
+                            self.config.path, err, rewrite
+                        );
+                        Ok(PluginOutput::Deny(format!(
+                            "rewritten query is not valid SQL: {}",
+                            err
+                        )))
+                    }
+                }
+            }
+
+            REJECT => Ok(PluginOutput::Deny(
+                output
+                    .output
+                    .unwrap_or_else(|| "query rejected by a plugin".into()),
+            )),
+
+            ROUTE => match output.shard {
+                Some(shard) if shard >= 0 && (shard as usize) < pool_settings.shards => {
+                    Ok(PluginOutput::Route(shard as usize))
+                }
+                shard => {
+                    warn!(
+                        "WASM plugin {} routed a query to shard {:?}, out of the {} shards",
+                        self.config.path, shard, pool_settings.shards
+                    );
+                    Ok(PluginOutput::Deny(
+                        "query plugin routed the query to an unknown shard".into(),
+                    ))
+                }
+            },
+
+            result => {
+                warn!("WASM plugin {} returned {}", self.config.path, result);
+                Ok(PluginOutput::Deny("query plugin failed".into()))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::messages::simple_query;
+    use crate::pool::PoolSettings;
+
+    const FIXTURE: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/wasm/plugin.wat");
+
+    fn config() -> config::Wasm {
+        config::Wasm {
+            enabled: true,
+            path: FIXTURE.into(),
+            fuel: 1_000_000,
+            timeout: 1000,
+            max_memory: 1024 * 1024,
+        }
+    }
+
+    async fn run(config: &config::Wasm, query: &str) -> PluginOutput {
+        let module = Module::new(&std::fs::read(FIXTURE).unwrap(), config.max_memory).unwrap();
+        let mut query_router = QueryRouter::new();
+        query_router.update_pool_settings(&PoolSettings {
+            shards: 2,
+            query_parser_enabled: true,
+            wasm_module: Some(Arc::new(module)),
+            ..Default::default()
+        });
+        let ast = query_router.parse(&simple_query(query)).unwrap();
+
+        let mut wasm = Wasm {
+            enabled: true,
+            config,
+        };
+        wasm.run(&query_router, &ast).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_wasm() {
+        QueryRouter::setup();
+        let config = config();
+
+        assert_eq!(
+            run(&config, "DROP TABLE users").await,
+            PluginOutput::Deny("dropping tables is not allowed".into())
+        );
+        assert_eq!(run(&config, "SELECT 1").await, PluginOutput::Allow);
+
+        match run(&config, "SELECT * FROM legacy_users").await {
+            PluginOutput::Overwrite(ast) => {
+                assert_eq!(QueryRewrite::query(&ast), "SELECT * FROM users")
+            }
+            output => panic!("not rewritten: {:?}", output),
+        }
+
+        assert_eq!(
+            run(&config, "SELECT 'shard_me'").await,
+            PluginOutput::Route(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wasm_limits() {
+        QueryRouter::setup();
+
+        assert_eq!(
+            run(&config(), "SELECT 'spin'").await,
+            PluginOutput::Deny("query plugin failed: out of fuel".into())
+        );
+
+        let config = config::Wasm {
+            fuel: u64::MAX,
+            timeout: 20,
+            ..config()
+        };
+        assert_eq!(
+            run(&config, "SELECT 'spin'").await,
+            PluginOutput::Deny("query plugin failed: timed out".into())
+        );
+
+        // Can't grow its memory past max_memory.
+        assert_eq!(
+            run(&config, "SELECT 'grow'").await,
+            PluginOutput::Deny(
+                "query plugin failed: wasm trap: wasm `unreachable` instruction executed".into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_module_new() {
+        let fixture = std::fs::read(FIXTURE).unwrap();
+        assert!(Module::new(&fixture, PAGE_SIZE).is_ok());
+
+        // Needs one page.
+        assert!(Module::new(&fixture, PAGE_SIZE - 1).is_err());
+
+        assert!(Module::new(b"not wasm", PAGE_SIZE).is_err());
+        assert!(Module::new(b"(module (memory (export \"memory\") 1))", PAGE_SIZE).is_err());
+
+        let unknown_import = r#"(module
+            (import "env" "abort" (func))
+            (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32) (i32.const 0))
+            (func (export "intercept") (param i32 i32) (result i32) (i32.const 0)))"#;
+        assert!(Module::new(unknown_import.as_bytes(), PAGE_SIZE).is_err());
+
+        let wrong_type = r#"(module
+            (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32) (i32.const 0))
+            (func (export "intercept") (param i32) (result i32) (i32.const 0)))"#;
+        assert!(Module::new(wrong_type.as_bytes(), PAGE_SIZE).is_err());
+    }
+}
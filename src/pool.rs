@@ -1,43 +1,52 @@
 use arc_swap::ArcSwap;
 use async_trait::async_trait;
-use bb8::{ManageConnection, Pool, PooledConnection, QueueStrategy};
+use bb8::{ManageConnection, Pool, PooledConnection, QueueStrategy, RunError};
 use chrono::naive::NaiveDateTime;
 use log::{debug, error, info, warn};
 use lru::LruCache;
 use once_cell::sync::Lazy;
 use parking_lot::{Mutex, RwLock};
 use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rand::{thread_rng, Rng};
 use regex::Regex;
-use std::collections::HashMap;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt::{Display, Formatter};
 use std::num::NonZeroUsize;
-use std::sync::atomic::AtomicU64;
+use std::sync::atomic::{AtomicI64, AtomicU64};
 use std::sync::{
     atomic::{AtomicBool, Ordering},
     Arc,
 };
 use std::time::Instant;
-use tokio::sync::Notify;
+use tokio::sync::{Notify, Semaphore};
 
 use crate::config::{
-    get_config, Address, DefaultShard, General, LoadBalancingMode, Plugins, PoolMode, Role, User,
+    get_config, Address, DefaultShard, General, LoadBalancingMode, Mirror, OnAllReplicasBanned,
+    Plugins, Pool as PoolConfig, PoolMode, QueryFilterRule, QueryRewriteRule, Role,
+    SlowQueryLogFormat, StatementType, TableShardRule, User,
 };
 use crate::errors::Error;
 
 use crate::auth_passthrough::AuthPassthrough;
+use crate::autoscaler::Autoscaler;
+use crate::ban_history;
+use crate::events::{publish, PoolEvent, PoolEventKind};
 use crate::messages::Parse;
+use crate::notifications::{notify, BanEvent, BanEventKind};
 use crate::plugins::prewarmer;
+use crate::plugins::wasm::Module as WasmModule;
 use crate::server::{Server, ServerParameters};
 use crate::sharding::ShardingFunction;
-use crate::stats::{AddressStats, ClientStats, ServerStats};
+use crate::stats::{get_reporter, AddressStats, ClientStats, Histogram, ServerStats};
 
 pub type ProcessId = i32;
 pub type SecretKey = i32;
 pub type ServerHost = String;
 pub type ServerPort = u16;
 
-pub type BanList = Arc<RwLock<Vec<HashMap<Address, (BanReason, NaiveDateTime)>>>>;
+/// Banned addresses per shard, with the reason, the time of the ban and its duration in seconds.
+pub type BanList = Arc<RwLock<Vec<HashMap<Address, (BanReason, NaiveDateTime, i64)>>>>;
 pub type ClientServerMap =
     Arc<Mutex<HashMap<(ProcessId, SecretKey), (ProcessId, SecretKey, ServerHost, ServerPort)>>>;
 pub type PoolMap = HashMap<PoolIdentifier, ConnectionPool>;
@@ -46,8 +55,14 @@ pub type PoolMap = HashMap<PoolIdentifier, ConnectionPool>;
 /// The pool is recreated dynamically when the config is reloaded.
 pub static POOLS: Lazy<ArcSwap<PoolMap>> = Lazy::new(|| ArcSwap::from_pointee(HashMap::default()));
 
+/// Signals the ban persistence task that a banlist changed.
+static BANS_CHANGED: Lazy<Notify> = Lazy::new(Notify::new);
+
+/// Load balancing weight of an address that just got soft banned.
+const SOFT_BAN_MIN_WEIGHT: f64 = 0.1;
+
 // Reasons for banning a server.
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum BanReason {
     FailedHealthCheck,
     MessageSendFailed,
@@ -55,16 +70,63 @@ pub enum BanReason {
     FailedCheckout,
     StatementTimeout,
     AdminBan(i64),
+    /// The address stays in rotation with a reduced weight, see `ConnectionPool::effective_weight`.
+    SoftBan,
+}
+
+impl BanReason {
+    /// Reasons whose ban time can be set with `ban_times`. Admin bans come with their own duration.
+    pub const CONFIGURABLE: &'static [&'static str] = &[
+        "FailedHealthCheck",
+        "MessageSendFailed",
+        "MessageReceiveFailed",
+        "FailedCheckout",
+        "StatementTimeout",
+        "SoftBan",
+    ];
+
+    /// Name of the reason without its parameters, e.g. used as a metric label.
+    pub fn name(&self) -> &'static str {
+        match self {
+            BanReason::FailedHealthCheck => "FailedHealthCheck",
+            BanReason::MessageSendFailed => "MessageSendFailed",
+            BanReason::MessageReceiveFailed => "MessageReceiveFailed",
+            BanReason::FailedCheckout => "FailedCheckout",
+            BanReason::StatementTimeout => "StatementTimeout",
+            BanReason::AdminBan(_) => "AdminBan",
+            BanReason::SoftBan => "SoftBan",
+        }
+    }
 }
 
 pub type PreparedStatementCacheType = Arc<Mutex<PreparedStatementCache>>;
 
-// TODO: Add stats the this cache
 // TODO: Add application name to the cache value to help identify which application is using the cache
-// TODO: Create admin command to show which statements are in the cache
 #[derive(Debug)]
 pub struct PreparedStatementCache {
-    cache: LruCache<u64, Arc<Parse>>,
+    cache: LruCache<u64, CachedStatement>,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+#[derive(Debug)]
+struct CachedStatement {
+    parse: Arc<Parse>,
+    hits: u64,
+}
+
+/// Counters of a prepared statement cache, since the pool was created.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PreparedStatementCacheStats {
+    /// Parses of a query already in the cache.
+    pub hits: u64,
+    /// Parses of a query that wasn't, added to the cache.
+    pub misses: u64,
+    /// Statements removed to make room for others.
+    pub evictions: u64,
+    /// Statements in the cache.
+    pub size: u64,
 }
 
 impl PreparedStatementCache {
@@ -76,6 +138,9 @@ impl PreparedStatementCache {
 
         PreparedStatementCache {
             cache: LruCache::new(NonZeroUsize::new(size).unwrap()),
+            hits: 0,
+            misses: 0,
+            evictions: 0,
         }
     }
 
@@ -84,16 +149,28 @@ impl PreparedStatementCache {
     ///
     /// Pass the hash to this so that we can do the compute before acquiring the lock
     pub fn get_or_insert(&mut self, parse: &Parse, hash: u64) -> Arc<Parse> {
-        match self.cache.get(&hash) {
-            Some(rewritten_parse) => rewritten_parse.clone(),
+        match self.cache.get_mut(&hash) {
+            Some(statement) => {
+                self.hits += 1;
+                statement.hits += 1;
+                statement.parse.clone()
+            }
             None => {
+                self.misses += 1;
                 let new_parse = Arc::new(parse.clone().rewrite());
-                let evicted = self.cache.push(hash, new_parse.clone());
+                let evicted = self.cache.push(
+                    hash,
+                    CachedStatement {
+                        parse: new_parse.clone(),
+                        hits: 0,
+                    },
+                );
 
-                if let Some((_, evicted_parse)) = evicted {
+                if let Some((_, evicted)) = evicted {
+                    self.evictions += 1;
                     debug!(
                         "Evicted prepared statement {} from cache",
-                        evicted_parse.name
+                        evicted.parse.name
                     );
                 }
 
@@ -106,6 +183,50 @@ impl PreparedStatementCache {
     pub fn promote(&mut self, hash: &u64) {
         self.cache.promote(hash);
     }
+
+    pub fn stats(&self) -> PreparedStatementCacheStats {
+        PreparedStatementCacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            size: self.cache.len() as u64,
+        }
+    }
+
+    /// The cached statements with their hits, the most recently used first.
+    pub fn statements(&self) -> Vec<(Arc<Parse>, u64)> {
+        self.cache
+            .iter()
+            .map(|(_, statement)| (statement.parse.clone(), statement.hits))
+            .collect()
+    }
+}
+
+/// A ban that can be saved and restored across restarts and config reloads.
+/// Addresses are matched by pool, user, shard, host and port since
+/// address ids are not stable across config changes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PersistedBan {
+    pub pool_name: String,
+    pub username: String,
+    pub shard: usize,
+    pub host: String,
+    pub port: u16,
+    pub reason: BanReason,
+    /// Unix timestamp (seconds) of the ban.
+    pub timestamp: i64,
+    /// Ban duration (seconds).
+    pub duration: i64,
+}
+
+impl PersistedBan {
+    fn matches(&self, address: &Address) -> bool {
+        self.pool_name == address.pool_name
+            && self.username == address.username
+            && self.shard == address.shard
+            && self.host == address.host
+            && self.port == address.port
+    }
 }
 
 /// An identifier for a PgCat pool,
@@ -121,6 +242,31 @@ pub struct PoolIdentifier {
 
 static POOL_REAPER_RATE: u64 = 30_000; // 30 seconds by default
 
+/// Share of checkouts ignoring latencies with the `latency` load balancing mode.
+const LATENCY_PROBE_RATE: f64 = 0.05;
+
+/// How often expired bans are verified when `verify_before_unban` is enabled,
+/// and the replication lag is checked when `max_replica_lag_seconds` is set.
+static HEALTH_CHECK_INTERVAL: u64 = 1_000; // 1 second
+
+/// How many server connections are opened at the same time when prewarming pools.
+const PREWARM_CONCURRENCY: usize = 16;
+
+/// Limit on the server connections being established at once, with its `max_concurrent_connects`.
+static CONNECT_LIMIT: Mutex<Option<(usize, Arc<Semaphore>)>> = Mutex::new(None);
+
+/// How many times opening a connection is attempted when prewarming pools.
+const PREWARM_ATTEMPTS: u32 = 3;
+
+/// Cheap query as it skips the query planner.
+pub const DEFAULT_HEALTHCHECK_QUERY: &str = ";";
+
+/// A standby gets the writes of its banned primary once this returns true, i.e. it was promoted.
+const STANDBY_PROMOTED_QUERY: &str = "SELECT NOT pg_is_in_recovery()";
+
+/// Delay before the first retry when prewarming pools, doubled for each next one.
+static PREWARM_RETRY_DELAY: u64 = 100; // 100 milliseconds
+
 impl PoolIdentifier {
     /// Create a new user/pool identifier.
     pub fn new(db: &str, user: &str) -> PoolIdentifier {
@@ -174,9 +320,15 @@ pub struct PoolSettings {
     // Read from the primary as well or not.
     pub primary_reads_enabled: bool,
 
+    // Clients start pinned to the first server they use.
+    pub sticky_sessions: bool,
+
     // Sharding function.
     pub sharding_function: ShardingFunction,
 
+    // Points per shard on the consistent hashing ring.
+    pub virtual_nodes: usize,
+
     // Sharding key
     pub automatic_sharding_key: Option<String>,
 
@@ -186,15 +338,114 @@ pub struct PoolSettings {
     // Health check delay
     pub healthcheck_delay: u64,
 
+    // Health check query
+    pub healthcheck_query: String,
+
+    // Replicas lagging more than this (seconds) are out of rotation
+    pub max_replica_lag: Option<u64>,
+
+    // Replication lag query
+    pub replica_lag_query: String,
+
+    // How long to wait for a server connection (ms)
+    pub connect_timeout: u64,
+
+    // How long clients can be idle in a transaction (ms)
+    pub idle_in_transaction_timeout: Option<u64>,
+
+    // How long session mode clients can keep their server while idle (ms)
+    pub client_idle_timeout: Option<u64>,
+
+    // Connections in use at once to each server, across the users of the pool
+    pub max_backend_connections: Option<u32>,
+
+    // Queries run on new connections on startup
+    pub warmup_queries: Vec<String>,
+
+    // Connections to each server running the warmup queries
+    pub warmup_connections: u32,
+
+    // Client startup parameters splitting the server connections in sub-pools
+    pub sticky_parameters: Vec<String>,
+
+    // Most sub-pools of the sticky parameters
+    pub max_sticky_pools: usize,
+
     // Ban time
     pub ban_time: i64,
 
+    // Maximum random time added to the ban time
+    pub ban_time_jitter: i64,
+
+    // Ban time per ban reason, overrides ban_time
+    pub ban_times: BTreeMap<String, i64>,
+
+    // Ban time multiplier for consecutive bans of the same address
+    pub ban_backoff_base: u64,
+
+    // Upper bound for the ban time computed with the backoff
+    pub max_ban_time: Option<i64>,
+
+    // Soft ban servers hitting the statement timeout instead of banning them
+    pub statement_timeout_soft_ban: bool,
+
+    // Cancel the queries running longer than this (ms)
+    pub query_timeout: u64,
+
+    // Ban the servers not canceling a timed out query
+    pub query_timeout_ban: bool,
+
+    // Health check banned servers in the background before unbanning them
+    pub verify_before_unban: bool,
+
+    // Send reads to the primary while all replicas of a shard are banned
+    pub on_all_replicas_banned: OnAllReplicasBanned,
+
+    // Other replicas a read is tried on after a failed checkout, all of them if not set
+    pub checkout_retries: Option<usize>,
+
+    // Number of errors within the window required to ban an address
+    pub ban_error_threshold: u64,
+
+    // Sliding window for counting errors towards the ban threshold
+    pub ban_error_window: u64,
+
+    // Percentage of the latency average kept for each new sample
+    pub latency_ewma_decay: u64,
+
+    // How long clients wait for the pool to be resumed (ms), 0 for no limit
+    pub pause_timeout: u64,
+
+    // Queries taking at least this long (ms) are logged
+    pub log_min_duration_ms: Option<u64>,
+
+    // Format of the slow query log lines
+    pub slow_query_log_format: SlowQueryLogFormat,
+
+    // application_name of the servers used by a client, composed with its name and id
+    pub application_name_format: Option<String>,
+
     // Regex for searching for the sharding key in SQL statements
     pub sharding_key_regex: Option<Regex>,
 
     // Regex for searching for the shard id in SQL statements
     pub shard_id_regex: Option<Regex>,
 
+    // Rules routing queries by the name of their table
+    pub table_shard_rules: Vec<(Regex, TableShardRule)>,
+
+    // Server roles of clients by their application_name
+    pub application_name_rules: Vec<(Regex, Option<Role>)>,
+
+    // Statement types sent to the mirrors, all if None
+    pub mirror_statement_types: Option<Vec<StatementType>>,
+
+    // Queries sent to the mirrors must match one of these, if any
+    pub mirror_include: Vec<Regex>,
+
+    // Queries not sent to the mirrors
+    pub mirror_exclude: Vec<Regex>,
+
     // What to do when no shard is selected in a sharded system
     pub default_shard: DefaultShard,
 
@@ -208,6 +459,18 @@ pub struct PoolSettings {
 
     /// Plugins
     pub plugins: Option<Plugins>,
+
+    // Rules of the query_filter plugin with their compiled pattern
+    pub query_filter_rules: Vec<(Option<Regex>, QueryFilterRule)>,
+
+    // Queries cached by the query_cache plugin
+    pub query_cache_patterns: Vec<Regex>,
+
+    // Rules of the query_rewrite plugin with their compiled pattern
+    pub query_rewrite_rules: Vec<(Regex, QueryRewriteRule)>,
+
+    // Module of the wasm plugin
+    pub wasm_module: Option<Arc<WasmModule>>,
 }
 
 impl Default for PoolSettings {
@@ -223,12 +486,47 @@ impl Default for PoolSettings {
             query_parser_max_length: None,
             query_parser_read_write_splitting: false,
             primary_reads_enabled: true,
+            sticky_sessions: false,
             sharding_function: ShardingFunction::PgBigintHash,
+            virtual_nodes: PoolConfig::default_virtual_nodes(),
             automatic_sharding_key: None,
             healthcheck_delay: General::default_healthcheck_delay(),
             healthcheck_timeout: General::default_healthcheck_timeout(),
+            healthcheck_query: DEFAULT_HEALTHCHECK_QUERY.into(),
+            max_replica_lag: None,
+            replica_lag_query: PoolConfig::default_replica_lag_query(),
+            connect_timeout: General::default_connect_timeout(),
+            idle_in_transaction_timeout: None,
+            client_idle_timeout: None,
+            max_backend_connections: None,
+            warmup_queries: Vec::new(),
+            warmup_connections: PoolConfig::default_warmup_connections(),
+            sticky_parameters: Vec::new(),
+            max_sticky_pools: PoolConfig::default_max_sticky_pools(),
             ban_time: General::default_ban_time(),
+            ban_time_jitter: 0,
+            ban_times: BTreeMap::new(),
+            ban_backoff_base: PoolConfig::default_ban_backoff_base(),
+            max_ban_time: None,
+            statement_timeout_soft_ban: false,
+            query_timeout: 0,
+            query_timeout_ban: false,
+            verify_before_unban: false,
+            on_all_replicas_banned: OnAllReplicasBanned::UnbanAll,
+            checkout_retries: None,
+            ban_error_threshold: PoolConfig::default_ban_error_threshold(),
+            ban_error_window: PoolConfig::default_ban_error_window(),
+            latency_ewma_decay: PoolConfig::default_latency_ewma_decay(),
+            pause_timeout: 0,
+            log_min_duration_ms: None,
+            slow_query_log_format: SlowQueryLogFormat::default(),
+            application_name_format: None,
             sharding_key_regex: None,
+            table_shard_rules: Vec::new(),
+            application_name_rules: Vec::new(),
+            mirror_statement_types: None,
+            mirror_include: Vec::new(),
+            mirror_exclude: Vec::new(),
             shard_id_regex: None,
             regex_search_limit: 1000,
             default_shard: DefaultShard::Shard(0),
@@ -236,6 +534,10 @@ impl Default for PoolSettings {
             auth_query_user: None,
             auth_query_password: None,
             plugins: None,
+            query_filter_rules: Vec::new(),
+            query_cache_patterns: Vec::new(),
+            query_rewrite_rules: Vec::new(),
+            wasm_module: None,
         }
     }
 }
@@ -246,6 +548,19 @@ pub struct ConnectionPool {
     /// The pools handled internally by bb8.
     databases: Arc<Vec<Vec<Pool<ServerPool>>>>,
 
+    /// How to build the bb8 pools of `databases` again, for the sticky parameter sub-pools.
+    templates: Arc<Vec<Vec<ServerPoolTemplate>>>,
+
+    /// Sub-pools of the clients that set `sticky_parameters`, shared by the pool and its sub-pools.
+    sticky_pools: Arc<Mutex<StickyPools>>,
+
+    /// The bb8 pools of the pool itself, for its sticky sub-pools.
+    pool_databases: Arc<Vec<Vec<Pool<ServerPool>>>>,
+
+    /// Connections to each server the pool and its sticky sub-pools can have open together,
+    /// with `sticky_parameters`. The connections hold a permit until they are closed.
+    connection_budgets: Arc<Vec<Vec<Arc<Semaphore>>>>,
+
     /// The addresses (host, port, role) to handle
     /// failover and load balancing deterministically.
     addresses: Arc<Vec<Vec<Address>>>,
@@ -254,6 +569,31 @@ pub struct ConnectionPool {
     /// that should not be queried.
     banlist: BanList,
 
+    /// Limits on the connections checked out from each server, if the pool is autoscaled.
+    autoscalers: Arc<Vec<Vec<Arc<Autoscaler>>>>,
+
+    /// Limits on the connections checked out from each server across the users of the pool,
+    /// with `max_backend_connections`.
+    backend_limits: Arc<Vec<Vec<Arc<Semaphore>>>>,
+
+    /// Number of clients waiting to check out a connection.
+    waiting_clients: Arc<AtomicU64>,
+
+    /// How long clients waited to check out a connection.
+    checkout_wait_histogram: Arc<Histogram>,
+
+    /// Replicas (host, port) taken out of rotation with the admin DRAIN command.
+    drained: Arc<RwLock<HashSet<(String, u16)>>>,
+
+    /// Standbys (shard, index) that passed their last promotion check.
+    promoted_standbys: Arc<RwLock<HashSet<(usize, usize)>>>,
+
+    /// Replicas out of rotation until they catch up, with their last replication lag (seconds).
+    lagging: Arc<RwLock<HashMap<Address, f64>>>,
+
+    /// Recent errors per address that have not resulted in a ban yet.
+    ban_errors: Arc<Mutex<HashMap<Address, VecDeque<NaiveDateTime>>>>,
+
     /// The server information has to be passed to the
     /// clients on startup. We pre-connect to all shards and replicas
     /// on pool creation and save the startup parameters here.
@@ -285,20 +625,95 @@ pub struct ConnectionPool {
 impl ConnectionPool {
     /// Construct the connection pool from the configuration.
     pub async fn from_config(client_server_map: ClientServerMap) -> Result<(), Error> {
+        Self::from_config_for(client_server_map, None, None).await
+    }
+
+    /// Construct the connection pools of `pool_name` only, and only the one of `username`
+    /// if set. The other pools are kept as they are, even if their config changed.
+    pub async fn from_config_for(
+        client_server_map: ClientServerMap,
+        pool_name: Option<&str>,
+        username: Option<&str>,
+    ) -> Result<(), Error> {
         let config = get_config();
+        let (only_pool, only_user) = (pool_name, username);
 
         let mut new_pools = HashMap::new();
+        let mut created_pools = Vec::new();
         let mut address_id: usize = 0;
+        let connect_limit = global_connect_limit(config.general.max_concurrent_connects);
 
         for (pool_name, pool_config) in &config.pools {
             let new_pool_hash_value = pool_config.hash_value();
 
+            // Shared by the users of the pool, the unchanged pools keep theirs.
+            let backend_limits = match pool_config
+                .users
+                .values()
+                .filter_map(|user| get_pool(pool_name, &user.username))
+                .find(|pool| pool.config_hash == new_pool_hash_value)
+            {
+                Some(pool) => pool.backend_limits.clone(),
+                None => Arc::new(match pool_config.max_backend_connections {
+                    Some(max_backend_connections) => {
+                        let mut shard_ids = pool_config.shards.keys().collect::<Vec<_>>();
+                        shard_ids.sort_by_key(|k| k.parse::<i64>().unwrap());
+                        shard_ids
+                            .iter()
+                            .map(|shard_idx| {
+                                pool_config.shards[*shard_idx]
+                                    .servers
+                                    .iter()
+                                    .map(|_| {
+                                        Arc::new(Semaphore::new(max_backend_connections as usize))
+                                    })
+                                    .collect()
+                            })
+                            .collect()
+                    }
+                    None => Vec::new(),
+                }),
+            };
+
+            // Compiled once for all the users of the pool.
+            let wasm_module = match pool_config
+                .users
+                .values()
+                .filter_map(|user| get_pool(pool_name, &user.username))
+                .find(|pool| pool.config_hash == new_pool_hash_value)
+            {
+                Some(pool) => pool.settings.wasm_module.clone(),
+                None => match pool_config
+                    .plugins
+                    .as_ref()
+                    .or(config.plugins.as_ref())
+                    .and_then(|plugins| plugins.wasm.as_ref())
+                    .filter(|wasm| wasm.enabled)
+                {
+                    Some(wasm) => Some(Arc::new(wasm.module().await?)),
+                    None => None,
+                },
+            };
+
             // There is one pool per database/user pair.
             for user in pool_config.users.values() {
+                // The connections of the user get the server settings of the pool too.
+                let user = &User {
+                    server_settings: pool_config.server_settings(user),
+                    ..user.clone()
+                };
                 let old_pool_ref = get_pool(pool_name, &user.username);
                 let identifier = PoolIdentifier::new(pool_name, &user.username);
 
+                let reloaded = (only_pool.is_none() || only_pool == Some(pool_name.as_str()))
+                    && (only_user.is_none() || only_user == Some(user.username.as_str()));
+
                 if let Some(pool) = old_pool_ref {
+                    if !reloaded {
+                        new_pools.insert(identifier.clone(), pool.clone());
+                        continue;
+                    }
+
                     // If the pool hasn't changed, get existing reference and insert it into the new_pools.
                     // We replace all pools at the end, but if the reference is kept, the pool won't get re-created (bb8).
                     if pool.config_hash == new_pool_hash_value {
@@ -317,8 +732,11 @@ impl ConnectionPool {
                 );
 
                 let mut shards = Vec::new();
+                let mut templates = Vec::new();
                 let mut addresses = Vec::new();
                 let mut banlist = Vec::new();
+                let mut autoscalers = Vec::new();
+                let mut connection_budgets = Vec::new();
                 let mut shard_ids = pool_config
                     .shards
                     .clone()
@@ -332,8 +750,12 @@ impl ConnectionPool {
                 for shard_idx in &shard_ids {
                     let shard = &pool_config.shards[shard_idx];
                     let mut pools = Vec::new();
+                    let mut shard_templates = Vec::new();
                     let mut servers = Vec::new();
+                    let mut shard_autoscalers = Vec::new();
+                    let mut shard_connection_budgets = Vec::new();
                     let mut replica_number = 0;
+                    let mut primary_number = 0;
 
                     // Load Mirror settings
                     for (address_index, server) in shard.servers.iter().enumerate() {
@@ -345,20 +767,27 @@ impl ConnectionPool {
                                 if mirror_settings.mirroring_target_index != address_index {
                                     continue;
                                 }
-                                mirror_addresses.push(Address {
-                                    id: address_id,
-                                    database: shard.database.clone(),
-                                    host: mirror_settings.host.clone(),
-                                    port: mirror_settings.port,
-                                    role: server.role,
-                                    address_index: mirror_idx,
-                                    replica_number,
-                                    shard: shard_idx.parse::<usize>().unwrap(),
-                                    username: user.username.clone(),
-                                    pool_name: pool_name.clone(),
-                                    mirrors: vec![],
-                                    stats: Arc::new(AddressStats::default()),
-                                    error_count: Arc::new(AtomicU64::new(0)),
+                                mirror_addresses.push(Mirror {
+                                    address: Address {
+                                        id: address_id,
+                                        database: shard.database.clone(),
+                                        host: mirror_settings.host.clone(),
+                                        port: mirror_settings.port,
+                                        role: server.role,
+                                        weight: server.weight,
+                                        address_index: mirror_idx,
+                                        replica_number,
+                                        shard: shard_idx.parse::<usize>().unwrap(),
+                                        username: user.username.clone(),
+                                        pool_name: pool_name.clone(),
+                                        mirrors: vec![],
+                                        stats: Arc::new(AddressStats::default()),
+                                        error_count: Arc::new(AtomicU64::new(0)),
+                                        consecutive_bans: Arc::new(AtomicU64::new(0)),
+                                        last_ban_expiry: Arc::new(AtomicI64::new(0)),
+                                        too_many_connections_at: Arc::new(AtomicI64::new(0)),
+                                    },
+                                    sample_rate: mirror_settings.sample_rate,
                                 });
                                 address_id += 1;
                             }
@@ -370,27 +799,36 @@ impl ConnectionPool {
                             host: server.host.clone(),
                             port: server.port,
                             role: server.role,
+                            weight: server.weight,
                             address_index,
-                            replica_number,
+                            replica_number: match server.role {
+                                Role::Primary => primary_number,
+                                _ => replica_number,
+                            },
                             shard: shard_idx.parse::<usize>().unwrap(),
                             username: user.username.clone(),
                             pool_name: pool_name.clone(),
                             mirrors: mirror_addresses,
-                            stats: Arc::new(AddressStats::default()),
+                            stats: Arc::new(AddressStats::new(&pool_config.query_time_buckets)),
                             error_count: Arc::new(AtomicU64::new(0)),
+                            consecutive_bans: Arc::new(AtomicU64::new(0)),
+                            last_ban_expiry: Arc::new(AtomicI64::new(0)),
+                            too_many_connections_at: Arc::new(AtomicI64::new(0)),
                         };
 
                         address_id += 1;
 
-                        if server.role == Role::Replica {
-                            replica_number += 1;
+                        match server.role {
+                            Role::Replica => replica_number += 1,
+                            Role::Primary => primary_number += 1,
+                            Role::Mirror => (),
                         }
 
                         // We assume every server in the pool share user/passwords
                         let auth_passthrough = AuthPassthrough::from_pool_config(pool_config);
 
                         if let Some(apt) = &auth_passthrough {
-                            match apt.fetch_hash(&address).await {
+                            match apt.fetch_hash_cached(&address).await {
                                 Ok(ok) => {
                                     if let Some(ref pool_auth_hash_value) = *(pool_auth_hash.read())
                                     {
@@ -421,21 +859,6 @@ impl ConnectionPool {
                             }
                         }
 
-                        let manager = ServerPool::new(
-                            address.clone(),
-                            user.clone(),
-                            &shard.database,
-                            client_server_map.clone(),
-                            pool_auth_hash.clone(),
-                            match pool_config.plugins {
-                                Some(ref plugins) => Some(plugins.clone()),
-                                None => config.plugins.clone(),
-                            },
-                            pool_config.cleanup_server_connections,
-                            pool_config.log_client_parameter_status_changes,
-                            pool_config.prepared_statements_cache_size,
-                        );
-
                         let connect_timeout = match user.connect_timeout {
                             Some(connect_timeout) => connect_timeout,
                             None => match pool_config.connect_timeout {
@@ -460,6 +883,60 @@ impl ConnectionPool {
                             },
                         };
 
+                        // Like PgBouncer, transaction mode clients only get it when asked for explicitly.
+                        let server_reset_query =
+                            match user.pool_mode.unwrap_or(pool_config.pool_mode) {
+                                PoolMode::Session => pool_config.server_reset_query.clone(),
+                                _ if pool_config.server_reset_query_always => {
+                                    pool_config.server_reset_query.clone()
+                                }
+                                _ => None,
+                            };
+
+                        let mut manager = ServerPool::new(
+                            address.clone(),
+                            User {
+                                server_settings: pool_config
+                                    .role_server_settings(&user.server_settings, address.role),
+                                ..user.clone()
+                            },
+                            &shard.database,
+                            client_server_map.clone(),
+                            pool_auth_hash.clone(),
+                            match pool_config.plugins {
+                                Some(ref plugins) => Some(plugins.clone()),
+                                None => config.plugins.clone(),
+                            },
+                            pool_config.cleanup_server_connections,
+                            pool_config.log_client_parameter_status_changes,
+                            pool_config.max_server_prepared_statements(),
+                            server_lifetime,
+                            config.general.server_lifetime_jitter,
+                            server_reset_query.clone(),
+                        );
+                        manager.set_connect_limit(connect_limit.clone());
+
+                        // The sticky sub-pools share one pool size of connections to the server.
+                        if !pool_config.sticky_parameters.is_empty() {
+                            let connection_budget = Arc::new(Semaphore::new(
+                                user.max_pool_size.unwrap_or(user.pool_size) as usize,
+                            ));
+                            manager.set_connection_budget(Some(connection_budget.clone()));
+                            shard_connection_budgets.push(connection_budget);
+                        }
+
+                        if pool_config.test_on_checkout {
+                            manager.set_checkout_validation(
+                                pool_config
+                                    .healthcheck_query
+                                    .as_deref()
+                                    .unwrap_or(DEFAULT_HEALTHCHECK_QUERY),
+                                pool_config
+                                    .healthcheck_timeout
+                                    .unwrap_or(config.general.healthcheck_timeout),
+                            );
+                        }
+
                         let reaper_rate = *[idle_timeout, server_lifetime, POOL_REAPER_RATE]
                             .iter()
                             .min()
@@ -475,29 +952,62 @@ impl ConnectionPool {
                             pool_name, user.username, reaper_rate
                         );
 
-                        let pool = Pool::builder()
-                            .max_size(user.pool_size)
-                            .min_idle(user.min_pool_size)
-                            .connection_timeout(std::time::Duration::from_millis(connect_timeout))
-                            .idle_timeout(Some(std::time::Duration::from_millis(idle_timeout)))
-                            .max_lifetime(Some(std::time::Duration::from_millis(server_lifetime)))
-                            .reaper_rate(std::time::Duration::from_millis(reaper_rate))
-                            .queue_strategy(queue_strategy)
-                            .test_on_check_out(false);
-
-                        let pool = if config.general.validate_config {
-                            pool.build(manager).await?
+                        match user.max_pool_size {
+                            Some(max_pool_size) => {
+                                let autoscaler = Arc::new(Autoscaler::new(
+                                    address.clone(),
+                                    user.pool_size,
+                                    user.min_pool_size.unwrap_or(0),
+                                    max_pool_size,
+                                    std::time::Duration::from_millis(
+                                        pool_config.autoscale_wait_threshold,
+                                    ),
+                                    std::time::Duration::from_millis(connect_timeout),
+                                ));
+                                Autoscaler::start(
+                                    &autoscaler,
+                                    std::time::Duration::from_millis(
+                                        pool_config.autoscale_interval,
+                                    ),
+                                );
+                                shard_autoscalers.push(autoscaler);
+                            }
+                            None => address.stats.set_pool_size(user.pool_size as u64),
+                        }
+
+                        let template = ServerPoolTemplate {
+                            manager,
+                            max_size: user.max_pool_size.unwrap_or(user.pool_size),
+                            min_idle: user.min_pool_size,
+                            connect_timeout,
+                            idle_timeout,
+                            server_lifetime,
+                            reaper_rate,
+                            queue_strategy,
+                        };
+
+                        // Prewarming logs connection errors instead of refusing to start.
+                        let pool = if config.general.validate_config && !config.general.prewarm {
+                            template.builder().build(template.manager.clone()).await?
                         } else {
-                            pool.build_unchecked(manager)
+                            template.builder().build_unchecked(template.manager.clone())
                         };
 
                         pools.push(pool);
+                        shard_templates.push(template);
                         servers.push(address);
                     }
 
                     shards.push(pools);
+                    templates.push(shard_templates);
                     addresses.push(servers);
                     banlist.push(HashMap::new());
+                    if !shard_autoscalers.is_empty() {
+                        autoscalers.push(shard_autoscalers);
+                    }
+                    if !shard_connection_budgets.is_empty() {
+                        connection_budgets.push(shard_connection_budgets);
+                    }
                 }
 
                 assert_eq!(shards.len(), addresses.len());
@@ -508,10 +1018,23 @@ impl ConnectionPool {
                     );
                 }
 
+                let databases = Arc::new(shards);
                 let pool = ConnectionPool {
-                    databases: Arc::new(shards),
+                    databases: databases.clone(),
+                    pool_databases: databases,
+                    templates: Arc::new(templates),
+                    sticky_pools: Arc::default(),
+                    connection_budgets: Arc::new(connection_budgets),
                     addresses: Arc::new(addresses),
                     banlist: Arc::new(RwLock::new(banlist)),
+                    drained: Arc::new(RwLock::new(HashSet::new())),
+                    promoted_standbys: Arc::new(RwLock::new(HashSet::new())),
+                    lagging: Arc::new(RwLock::new(HashMap::new())),
+                    ban_errors: Arc::new(Mutex::new(HashMap::new())),
+                    autoscalers: Arc::new(autoscalers),
+                    backend_limits: backend_limits.clone(),
+                    waiting_clients: Arc::new(AtomicU64::new(0)),
+                    checkout_wait_histogram: Arc::new(Histogram::default()),
                     config_hash: new_pool_hash_value,
                     original_server_parameters: Arc::new(RwLock::new(ServerParameters::new())),
                     auth_hash: pool_auth_hash,
@@ -536,11 +1059,54 @@ impl ConnectionPool {
                         query_parser_read_write_splitting: pool_config
                             .query_parser_read_write_splitting,
                         primary_reads_enabled: pool_config.primary_reads_enabled,
-                        sharding_function: pool_config.sharding_function,
+                        sticky_sessions: pool_config.sticky_sessions,
+                        sharding_function: pool_config.sharding_function.clone(),
+                        virtual_nodes: pool_config.virtual_nodes,
                         automatic_sharding_key: pool_config.automatic_sharding_key.clone(),
-                        healthcheck_delay: config.general.healthcheck_delay,
-                        healthcheck_timeout: config.general.healthcheck_timeout,
+                        healthcheck_delay: pool_config
+                            .healthcheck_interval
+                            .unwrap_or(config.general.healthcheck_delay),
+                        healthcheck_timeout: pool_config
+                            .healthcheck_timeout
+                            .unwrap_or(config.general.healthcheck_timeout),
+                        healthcheck_query: pool_config
+                            .healthcheck_query
+                            .clone()
+                            .unwrap_or_else(|| DEFAULT_HEALTHCHECK_QUERY.into()),
+                        max_replica_lag: pool_config.max_replica_lag_seconds,
+                        replica_lag_query: pool_config.replica_lag_query.clone(),
+                        max_backend_connections: pool_config.max_backend_connections,
+                        warmup_queries: pool_config.warmup_queries.clone(),
+                        warmup_connections: pool_config.warmup_connections,
+                        sticky_parameters: pool_config.sticky_parameters.clone(),
+                        max_sticky_pools: pool_config.max_sticky_pools,
+                        idle_in_transaction_timeout: pool_config.idle_in_transaction_timeout,
+                        client_idle_timeout: pool_config.client_idle_timeout,
+                        connect_timeout: match user.connect_timeout {
+                            Some(connect_timeout) => connect_timeout,
+                            None => match pool_config.connect_timeout {
+                                Some(connect_timeout) => connect_timeout,
+                                None => config.general.connect_timeout,
+                            },
+                        },
                         ban_time: config.general.ban_time,
+                        ban_time_jitter: config.general.ban_time_jitter,
+                        ban_times: config.general.ban_times.clone(),
+                        ban_backoff_base: pool_config.ban_backoff_base,
+                        max_ban_time: pool_config.max_ban_time,
+                        statement_timeout_soft_ban: pool_config.statement_timeout_soft_ban,
+                        query_timeout: pool_config.query_timeout,
+                        query_timeout_ban: pool_config.query_timeout_ban,
+                        verify_before_unban: pool_config.verify_before_unban,
+                        on_all_replicas_banned: pool_config.all_replicas_banned_policy(),
+                        checkout_retries: pool_config.checkout_retries,
+                        ban_error_threshold: pool_config.ban_error_threshold,
+                        ban_error_window: pool_config.ban_error_window,
+                        latency_ewma_decay: pool_config.latency_ewma_decay,
+                        pause_timeout: config.general.pause_timeout,
+                        log_min_duration_ms: config.general.log_min_duration_ms,
+                        slow_query_log_format: config.general.slow_query_log_format,
+                        application_name_format: pool_config.application_name_format.clone(),
                         sharding_key_regex: pool_config
                             .sharding_key_regex
                             .clone()
@@ -549,6 +1115,32 @@ impl ConnectionPool {
                             .shard_id_regex
                             .clone()
                             .map(|regex| Regex::new(regex.as_str()).unwrap()),
+                        table_shard_rules: pool_config
+                            .table_shard_rules
+                            .iter()
+                            .map(|rule| (Regex::new(&rule.pattern).unwrap(), rule.clone()))
+                            .collect(),
+                        application_name_rules: pool_config
+                            .application_name_rules
+                            .iter()
+                            .map(|rule| {
+                                (
+                                    Regex::new(&rule.pattern).unwrap(),
+                                    rule.server_role().unwrap(),
+                                )
+                            })
+                            .collect(),
+                        mirror_statement_types: pool_config.mirror_statement_types.clone(),
+                        mirror_include: pool_config
+                            .mirror_include
+                            .iter()
+                            .map(|regex| Regex::new(regex).unwrap())
+                            .collect(),
+                        mirror_exclude: pool_config
+                            .mirror_exclude
+                            .iter()
+                            .map(|regex| Regex::new(regex).unwrap())
+                            .collect(),
                         regex_search_limit: pool_config.regex_search_limit.unwrap_or(1000),
                         default_shard: pool_config.default_shard,
                         auth_query: pool_config.auth_query.clone(),
@@ -558,6 +1150,38 @@ impl ConnectionPool {
                             Some(ref plugins) => Some(plugins.clone()),
                             None => config.plugins.clone(),
                         },
+                        query_filter_rules: match pool_config
+                            .plugins
+                            .as_ref()
+                            .or(config.plugins.as_ref())
+                            .and_then(|plugins| plugins.query_filter.as_ref())
+                        {
+                            Some(query_filter) => query_filter.compiled_rules(),
+                            None => Vec::new(),
+                        },
+                        query_cache_patterns: match pool_config
+                            .plugins
+                            .as_ref()
+                            .or(config.plugins.as_ref())
+                            .and_then(|plugins| plugins.query_cache.as_ref())
+                        {
+                            Some(query_cache) => query_cache
+                                .queries
+                                .iter()
+                                .map(|regex| Regex::new(regex).unwrap())
+                                .collect(),
+                            None => Vec::new(),
+                        },
+                        query_rewrite_rules: match pool_config
+                            .plugins
+                            .as_ref()
+                            .or(config.plugins.as_ref())
+                            .and_then(|plugins| plugins.query_rewrite.as_ref())
+                        {
+                            Some(query_rewrite) => query_rewrite.compiled_rules(),
+                            None => Vec::new(),
+                        },
+                        wasm_module: wasm_module.clone(),
                     }),
                     validated: Arc::new(AtomicBool::new(false)),
                     paused: Arc::new(AtomicBool::new(false)),
@@ -580,15 +1204,291 @@ impl ConnectionPool {
                     });
                 }
 
+                // Keep the bans and drained replicas of the pool we are replacing.
+                if let Some(old_pool) = get_pool(pool_name, &user.username) {
+                    pool.restore(&old_pool.persist());
+                    pool.drained
+                        .write()
+                        .extend(old_pool.drained.read().iter().cloned());
+                }
+
                 // There is one pool per database/user pair.
+                created_pools.push(pool.clone());
                 new_pools.insert(PoolIdentifier::new(pool_name, &user.username), pool);
             }
         }
 
+        if config.general.prewarm {
+            let semaphore = Arc::new(Semaphore::new(PREWARM_CONCURRENCY));
+            futures::future::join_all(
+                created_pools
+                    .iter()
+                    .map(|pool| pool.prewarm(semaphore.clone())),
+            )
+            .await;
+        }
+
+        futures::future::join_all(created_pools.iter().map(|pool| pool.warmup())).await;
+
+        publish_address_changes(&POOLS.load(), &new_pools);
         POOLS.store(Arc::new(new_pools.clone()));
+
+        for pool in created_pools {
+            if pool.settings.verify_before_unban || pool.settings.max_replica_lag.is_some() {
+                pool.start_health_checks();
+            }
+        }
+
         Ok(())
     }
 
+    /// Run the warmup queries on `warmup_connections` connections to every server of the pool,
+    /// opening them if needed. Failures are logged, the pool is used anyway.
+    async fn warmup(&self) {
+        if self.settings.warmup_queries.is_empty() {
+            return;
+        }
+
+        let wanted = self
+            .settings
+            .warmup_connections
+            .min(self.settings.user.pool_size);
+        let mut tasks = Vec::new();
+
+        for shard in 0..self.shards() {
+            for server in 0..self.servers(shard) {
+                for _ in 0..wanted {
+                    let pool = self.databases[shard][server].clone();
+                    let address = self.address(shard, server).clone();
+                    let queries = self.settings.warmup_queries.clone();
+
+                    tasks.push(tokio::task::spawn(async move {
+                        let mut conn = match pool.get_owned().await {
+                            Ok(conn) => conn,
+                            Err(err) => {
+                                warn!("Could not warm up connection to {:?}: {:?}", address, err);
+                                return None;
+                            }
+                        };
+
+                        for query in &queries {
+                            if conn.checked_query(query).await.is_err() {
+                                warn!("Warmup query `{}` failed on {:?}", query, address);
+                            }
+
+                            if conn.is_bad() {
+                                return None;
+                            }
+                        }
+
+                        Some(conn)
+                    }));
+                }
+            }
+        }
+
+        // Hold the connections until all of them are warm, so each server gets `wanted` different ones.
+        let connections = futures::future::join_all(tasks).await;
+        let warmed = connections
+            .iter()
+            .filter(|conn| matches!(conn, Ok(Some(_))))
+            .count();
+
+        info!(
+            "[pool: {}][user: {}] Warmed up {} of {} server connections",
+            self.settings.db,
+            self.settings.user.username,
+            warmed,
+            connections.len()
+        );
+    }
+
+    /// Open `min_pool_size` connections to every server of the pool,
+    /// at most as many at once as the semaphore allows across pools.
+    async fn prewarm(&self, semaphore: Arc<Semaphore>) {
+        let wanted = self.settings.user.min_pool_size.unwrap_or(0);
+        if wanted == 0 {
+            return;
+        }
+
+        let mut tasks = Vec::new();
+
+        for shard in 0..self.shards() {
+            for server in 0..self.servers(shard) {
+                for _ in 0..wanted {
+                    let pool = self.databases[shard][server].clone();
+                    let address = self.address(shard, server).clone();
+                    let semaphore = semaphore.clone();
+
+                    tasks.push(tokio::task::spawn(async move {
+                        let _permit = semaphore.acquire_owned().await.ok()?;
+
+                        for attempt in 0..PREWARM_ATTEMPTS {
+                            match pool.get_owned().await {
+                                Ok(conn) => return Some(conn),
+                                Err(err) => {
+                                    let delay = PREWARM_RETRY_DELAY * 2_u64.pow(attempt);
+                                    warn!(
+                                        "Could not prewarm connection to {:?}, retrying in {}ms: {:?}",
+                                        address, delay, err
+                                    );
+                                    tokio::time::sleep(tokio::time::Duration::from_millis(delay))
+                                        .await;
+                                }
+                            }
+                        }
+
+                        error!(
+                            "Giving up prewarming connection to {:?} after {} attempts",
+                            address, PREWARM_ATTEMPTS
+                        );
+                        None
+                    }));
+                }
+            }
+        }
+
+        // Hold all the connections until the end so they are all opened,
+        // they go back to the pool as idle connections when dropped.
+        let connections = futures::future::join_all(tasks).await;
+        let opened = connections
+            .iter()
+            .filter(|conn| matches!(conn, Ok(Some(_))))
+            .count();
+
+        info!(
+            "[pool: {}][user: {}] Prewarmed {} of {} server connections",
+            self.settings.db,
+            self.settings.user.username,
+            opened,
+            connections.len()
+        );
+    }
+
+    /// Periodically health check the addresses with an expired ban and unban them if they are
+    /// healthy, and check the replication lag of the replicas, see `verify_expired_ban` and
+    /// `check_replica_lag`. Stops once the pool gets replaced after a config reload.
+    fn start_health_checks(&self) {
+        let pool = self.clone();
+
+        tokio::task::spawn(async move {
+            let mut interval =
+                tokio::time::interval(tokio::time::Duration::from_millis(HEALTH_CHECK_INTERVAL));
+
+            loop {
+                interval.tick().await;
+
+                match get_pool(&pool.settings.db, &pool.settings.user.username) {
+                    Some(current) if Arc::ptr_eq(&current.banlist, &pool.banlist) => (),
+                    _ => break,
+                };
+
+                if pool.settings.verify_before_unban {
+                    for address in pool.expired_bans() {
+                        pool.verify_expired_ban(&address).await;
+                    }
+                }
+
+                if pool.settings.max_replica_lag.is_some() {
+                    let replicas = pool
+                        .addresses
+                        .iter()
+                        .flatten()
+                        .filter(|address| address.role == Role::Replica && !pool.is_banned(address))
+                        .cloned()
+                        .collect::<Vec<Address>>();
+
+                    futures::future::join_all(
+                        replicas
+                            .iter()
+                            .map(|address| pool.check_replica_lag(address)),
+                    )
+                    .await;
+                }
+            }
+
+            debug!(
+                "[pool: {}][user: {}] Health checks exiting",
+                pool.settings.db, pool.settings.user.username
+            );
+        });
+    }
+
+    /// Take the replica out of rotation if it lags more than `max_replica_lag`, put it
+    /// back once it caught up. The replica keeps its state if the lag can't be measured.
+    pub async fn check_replica_lag(&self, address: &Address) {
+        let max_replica_lag = match self.settings.max_replica_lag {
+            Some(max_replica_lag) => max_replica_lag as f64,
+            None => return,
+        };
+
+        let mut conn = match self.databases[address.shard][address.address_index]
+            .get()
+            .await
+        {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!(
+                    "Replication lag check checkout error for instance {:?}, error: {:?}",
+                    address, err
+                );
+                return;
+            }
+        };
+
+        let lag = match tokio::time::timeout(
+            tokio::time::Duration::from_millis(self.settings.healthcheck_timeout),
+            conn.query_value(&self.settings.replica_lag_query),
+        )
+        .await
+        {
+            // NULL before the replica replayed anything.
+            Ok(Ok(lag)) => match lag.map(|lag| lag.parse::<f64>()) {
+                Some(Ok(lag)) => lag,
+                Some(Err(_)) => {
+                    warn!(
+                        "Replication lag query of instance {:?} didn't return a number",
+                        address
+                    );
+                    return;
+                }
+                None => 0.0,
+            },
+            Ok(Err(err)) => {
+                warn!(
+                    "Replication lag check failed on instance {:?}, error: {:?}",
+                    address, err
+                );
+                return;
+            }
+            Err(_) => {
+                warn!("Replication lag check timeout on instance {:?}", address);
+                conn.mark_bad("replication lag check timeout");
+                return;
+            }
+        };
+
+        let mut lagging = self.lagging.write();
+        if lag > max_replica_lag {
+            if lagging.insert(address.clone(), lag).is_none() {
+                warn!(
+                    "Replica {:?} lags {:.1}s behind, more than {}s, taking it out of rotation",
+                    address, lag, max_replica_lag
+                );
+            }
+        } else if lagging.remove(address).is_some() {
+            info!(
+                "Replica {:?} caught up ({:.1}s behind), putting it back in rotation",
+                address, lag
+            );
+        }
+    }
+
+    /// Replication lag (seconds) of the replica if it's out of rotation because of it.
+    pub fn replica_lag(&self, address: &Address) -> Option<f64> {
+        self.lagging.read().get(address).copied()
+    }
+
     /// Connect to all shards, grab server information, and possibly
     /// passwords to use in client auth.
     /// Return server information we will pass to the clients
@@ -650,12 +1550,22 @@ impl ConnectionPool {
     /// Pause the pool, allowing no more queries and make clients wait.
     pub fn pause(&self) {
         self.paused.store(true, Ordering::Relaxed);
+        publish(PoolEvent::for_pool(
+            PoolEventKind::Pause,
+            &self.settings.db,
+            &self.settings.user.username,
+        ));
     }
 
     /// Resume the pool, allowing queries and resuming any pending queries.
     pub fn resume(&self) {
         self.paused.store(false, Ordering::Relaxed);
         self.paused_waiter.notify_waiters();
+        publish(PoolEvent::for_pool(
+            PoolEventKind::Resume,
+            &self.settings.db,
+            &self.settings.user.username,
+        ));
     }
 
     /// Check if the pool is paused.
@@ -663,47 +1573,46 @@ impl ConnectionPool {
         self.paused.load(Ordering::Relaxed)
     }
 
-    /// Check if the pool is paused and wait until it's resumed.
-    pub async fn wait_paused(&self) -> bool {
+    /// Check if the pool is paused and wait until it's resumed,
+    /// for at most `pause_timeout` if set.
+    pub async fn wait_paused(&self) -> Result<bool, Error> {
         let waiter = self.paused_waiter.notified();
         let paused = self.paused.load(Ordering::Relaxed);
 
         if paused {
-            waiter.await;
+            match self.settings.pause_timeout {
+                0 => waiter.await,
+                timeout => {
+                    if tokio::time::timeout(tokio::time::Duration::from_millis(timeout), waiter)
+                        .await
+                        .is_err()
+                    {
+                        return Err(Error::PoolPaused);
+                    }
+                }
+            }
         }
 
-        paused
+        Ok(paused)
     }
 
     /// Get a connection from the pool.
-    pub async fn get(
-        &self,
-        shard: Option<usize>,       // shard number
-        role: Option<Role>,         // primary or replica
-        client_stats: &ClientStats, // client id
-    ) -> Result<(PooledConnection<'_, ServerPool>, Address), Error> {
-        let effective_shard_id = if self.shards() == 1 {
-            // The base, unsharded case
-            Some(0)
-        } else {
-            if !self.valid_shard_id(shard) {
-                // None is valid shard ID so it is safe to unwrap here
-                return Err(Error::InvalidShardId(shard.unwrap()));
-            }
-            shard
-        };
-
+    /// Addresses to try for a checkout, in reverse order: the last one is tried first.
+    fn candidates(&self, effective_shard_id: Option<usize>, role: Option<Role>) -> Vec<&Address> {
         let mut candidates = self
             .addresses
             .iter()
             .flatten()
-            .filter(|address| address.role == role)
+            .filter(|address| {
+                address.role == role && !address.is_standby() && self.in_rotation(address)
+            })
             .collect::<Vec<&Address>>();
 
         // We start with a shuffled list of addresses even if we end up resorting
         // this is meant to avoid hitting instance 0 everytime if the sorting metric
         // ends up being the same for all instances
-        candidates.shuffle(&mut thread_rng());
+        self.unban_expired_soft_bans();
+        self.weighted_shuffle(&mut candidates, &mut thread_rng());
 
         match effective_shard_id {
             Some(shard_id) => candidates.retain(|address| address.shard == shard_id),
@@ -723,17 +1632,307 @@ impl ConnectionPool {
             },
         };
 
-        if self.settings.load_balancing_mode == LoadBalancingMode::LeastOutstandingConnections {
-            candidates.sort_by(|a, b| {
-                self.busy_connection_count(b)
-                    .partial_cmp(&self.busy_connection_count(a))
-                    .unwrap()
-            });
+        match self.settings.load_balancing_mode {
+            LoadBalancingMode::Random => (),
+            LoadBalancingMode::LeastOutstandingConnections => {
+                least_busy_last(&mut candidates, |address| {
+                    self.busy_connection_count(address)
+                });
+            }
+            LoadBalancingMode::Latency => {
+                fastest_last(&mut candidates, &mut thread_rng());
+            }
+        }
+
+        // Fall back to the primary of the shards where all replicas are banned.
+        // The replicas are still tried first, their bans may have expired.
+        if role == Some(Role::Replica)
+            && self.settings.on_all_replicas_banned == OnAllReplicasBanned::FailoverPrimary
+        {
+            let primaries = self
+                .addresses
+                .iter()
+                .flatten()
+                .filter(|address| {
+                    address.role == Role::Primary
+                        && !address.is_standby()
+                        && candidates
+                            .iter()
+                            .any(|candidate| candidate.shard == address.shard)
+                        && self.all_replicas_banned(address.shard)
+                })
+                .collect::<Vec<&Address>>();
+
+            if !primaries.is_empty() {
+                warn!("All replicas banned, sending reads to the primary");
+                candidates.splice(0..0, primaries);
+            }
+        }
+
+        // The standbys come after their primary, they only get the writes once it's banned,
+        // e.g. when checking it out just failed, and they've been promoted, see `get_from`.
+        if role == Some(Role::Primary) || role.is_none() {
+            let standbys = self
+                .addresses
+                .iter()
+                .flatten()
+                .filter(|address| {
+                    address.is_standby()
+                        && self.in_rotation(address)
+                        && candidates.iter().any(|candidate| {
+                            candidate.shard == address.shard && candidate.role == Role::Primary
+                        })
+                })
+                .collect::<Vec<&Address>>();
+
+            candidates.splice(0..0, standbys);
+        }
+
+        candidates
+    }
+
+    /// The sub-pool of the clients connecting with these values of the `sticky_parameters`, so they only
+    /// share the server connections started with the same ones. Clients that set none of them use this pool.
+    /// None if there are already `max_sticky_pools` sub-pools and clients are connected to all of them.
+    /// Clients keep the sub-pool they got until they disconnect.
+    pub fn sticky_pool(&self, parameters: &HashMap<String, String>) -> Option<ConnectionPool> {
+        let values = self
+            .settings
+            .sticky_parameters
+            .iter()
+            .map(|name| parameters.get(name).cloned())
+            .collect::<Vec<_>>();
+
+        if values.iter().all(Option::is_none) {
+            return Some(self.clone());
+        }
+
+        let now = Instant::now();
+        let mut sticky_pools = self.sticky_pools.lock();
+
+        // The bb8 pools close their idle connections, the sub-pools are closed here.
+        let idle_timeout = self
+            .templates
+            .iter()
+            .flatten()
+            .map(|template| template.idle_timeout)
+            .max()
+            .unwrap_or_default();
+        sticky_pools.pools.retain(|_, pool| {
+            pool.in_use()
+                || now.duration_since(pool.last_used)
+                    < std::time::Duration::from_millis(idle_timeout)
+        });
+
+        if !sticky_pools.pools.contains_key(&values) {
+            if sticky_pools.pools.len() >= self.settings.max_sticky_pools
+                && !sticky_pools.close_unused(None)
+            {
+                warn!(
+                    "[pool: {}][user: {}] All {} sticky sub-pools are in use",
+                    self.settings.db, self.settings.user.username, self.settings.max_sticky_pools
+                );
+                return None;
+            }
+
+            let settings = self
+                .settings
+                .sticky_parameters
+                .iter()
+                .zip(&values)
+                .filter_map(|(name, value)| Some((name.clone(), value.clone()?)))
+                .collect::<BTreeMap<_, _>>();
+
+            debug!(
+                "[pool: {}][user: {}] Creating the sub-pool of {:?}",
+                self.settings.db, self.settings.user.username, settings
+            );
+
+            let databases = self
+                .templates
+                .iter()
+                .map(|shard| {
+                    shard
+                        .iter()
+                        .map(|template| template.build_with_settings(&settings))
+                        .collect()
+                })
+                .collect();
+
+            sticky_pools.pools.insert(
+                values.clone(),
+                StickyPool {
+                    databases: Arc::new(databases),
+                    last_used: now,
+                },
+            );
+        }
+
+        let pool = sticky_pools.pools.get_mut(&values)?;
+        pool.last_used = now;
+
+        // Shares the rest with this pool, the addresses, bans and limits are the same.
+        Some(ConnectionPool {
+            databases: pool.databases.clone(),
+            ..self.clone()
+        })
+    }
+
+    /// This is the pool or one of its sticky sub-pools, and it wasn't reloaded since.
+    pub fn same_sticky_pools(&self, pool: &ConnectionPool) -> bool {
+        Arc::ptr_eq(&self.sticky_pools, &pool.sticky_pools)
+    }
+
+    /// Budget of the connections to the server, with `sticky_parameters`.
+    fn connection_budget(&self, address: &Address) -> Option<&Arc<Semaphore>> {
+        self.connection_budgets
+            .get(address.shard)
+            .and_then(|connection_budgets| connection_budgets.get(address.address_index))
+    }
+
+    /// When this pool has to open a connection to the server and all the connections to it are open,
+    /// close the least recently used sub-pool no client is connected to, or else an idle connection
+    /// of the pool or another sub-pool. The connections give their permits back as they are closed.
+    async fn make_room_for_connection(&self, address: &Address) {
+        let server = (address.shard, address.address_index);
+        let connection_budget = match self.connection_budget(address) {
+            Some(connection_budget) => connection_budget,
+            None => return,
+        };
+
+        if connection_budget.available_permits() > 0
+            || self.databases[server.0][server.1].state().idle_connections > 0
+        {
+            return;
+        }
+
+        let idle = {
+            let mut sticky_pools = self.sticky_pools.lock();
+            if sticky_pools.close_unused(Some(server)) {
+                return;
+            }
+
+            sticky_pools
+                .pools
+                .values()
+                .map(|pool| pool.databases.clone())
+                .chain(std::iter::once(self.pool_databases.clone()))
+                .filter(|databases| !Arc::ptr_eq(databases, &self.databases))
+                .find_map(|databases| {
+                    let pool = databases.get(server.0)?.get(server.1)?;
+                    (pool.state().idle_connections > 0).then(|| pool.clone())
+                })
+        };
+
+        // Idle, the checkout doesn't wait unless another client got it first.
+        if let Some(pool) = idle {
+            let timeout = std::time::Duration::from_millis(self.settings.healthcheck_timeout);
+            if let Ok(Ok(mut conn)) = tokio::time::timeout(timeout, pool.get()).await {
+                conn.close_on_checkin();
+            }
+        }
+    }
+
+    /// All the connections to the server are open, to the pool or its sticky sub-pools.
+    fn connection_budget_used(&self, address: &Address) -> bool {
+        self.connection_budget(address)
+            .is_some_and(|connection_budget| connection_budget.available_permits() == 0)
+    }
+
+    pub async fn get(
+        &self,
+        shard: Option<usize>,          // shard number
+        role: Option<Role>,            // primary or replica
+        client_stats: &ClientStats,    // client id
+        checkout_timeout: Option<u64>, // overrides connect_timeout (ms)
+    ) -> Result<(PooledConnection<'_, ServerPool>, Address), Error> {
+        let effective_shard_id = if self.shards() == 1 {
+            // The base, unsharded case
+            Some(0)
+        } else {
+            if !self.valid_shard_id(shard) {
+                // None is valid shard ID so it is safe to unwrap here
+                return Err(Error::InvalidShardId(shard.unwrap()));
+            }
+            shard
+        };
+
+        let candidates = self.candidates(effective_shard_id, role);
+
+        match self
+            .get_from(candidates, client_stats, checkout_timeout)
+            .await
+        {
+            // The replicas are known to be bad, say so instead of using them.
+            Err(Error::AllServersDown)
+                if role == Some(Role::Replica)
+                    && self.settings.on_all_replicas_banned == OnAllReplicasBanned::Error
+                    && effective_shard_id.map_or(true, |shard| self.all_replicas_banned(shard)) =>
+            {
+                Err(Error::AllReplicasBanned)
+            }
+            result => result,
+        }
+    }
+
+    /// Get a connection to this server only, for sessions pinned to it.
+    /// Unlike `get`, there is no failing over to another server.
+    pub async fn get_pinned(
+        &self,
+        address: &Address,
+        client_stats: &ClientStats,
+        checkout_timeout: Option<u64>,
+    ) -> Result<(PooledConnection<'_, ServerPool>, Address), Error> {
+        // The pool was reloaded and the server is gone.
+        let address = match self
+            .addresses
+            .get(address.shard)
+            .and_then(|addresses| addresses.get(address.address_index))
+        {
+            Some(current) if current == address => current,
+            _ => {
+                return Err(Error::PinnedServerUnavailable(format!(
+                    "{} is not in the pool anymore",
+                    address.name()
+                )))
+            }
+        };
+
+        match self
+            .get_from(vec![address], client_stats, checkout_timeout)
+            .await
+        {
+            Err(Error::AllServersDown) => Err(Error::PinnedServerUnavailable(format!(
+                "{} is banned or down",
+                address.name()
+            ))),
+            result => result,
         }
+    }
 
+    /// Check out a connection from the first healthy candidate, starting at the end.
+    async fn get_from(
+        &self,
+        mut candidates: Vec<&Address>,
+        client_stats: &ClientStats,
+        checkout_timeout: Option<u64>,
+    ) -> Result<(PooledConnection<'_, ServerPool>, Address), Error> {
         // Indicate we're waiting on a server connection from a pool.
         let now = Instant::now();
+        let deadline =
+            checkout_timeout.map(|timeout| now + std::time::Duration::from_millis(timeout));
         client_stats.waiting();
+        let _waiting = CheckoutWait::new(self, now);
+
+        // Servers refusing connections because they reached max_connections are not banned.
+        let started_at = chrono::offset::Utc::now().timestamp_millis();
+        let mut overloaded = false;
+
+        // Replicas tried so far, all but the last one failed.
+        let mut replicas_tried = 0;
+
+        // The primary of the standbys, tried before them, so it's banned or it failed.
+        let mut failed_primary = None;
 
         while !candidates.is_empty() {
             // Get the next candidate
@@ -744,6 +1943,15 @@ impl ConnectionPool {
 
             let mut force_healthcheck = false;
 
+            if address.is_standby() {
+                if failed_primary.is_none() && !self.primary_banned(address.shard) {
+                    continue;
+                }
+                warn!("Primary down, failing writes over to {:?}", address);
+            } else if address.role == Role::Primary && self.has_standby(address.shard) {
+                failed_primary = Some(address);
+            }
+
             if self.is_banned(address) {
                 if self.try_unban(address).await {
                     force_healthcheck = true;
@@ -753,15 +1961,118 @@ impl ConnectionPool {
                 }
             }
 
+            // Reads are retried on the next replica, up to checkout_retries times.
+            // Writes only go to the primary, or a promoted standby once it's banned.
+            if address.role == Role::Replica {
+                if let Some(checkout_retries) = self.settings.checkout_retries {
+                    if replicas_tried > checkout_retries {
+                        warn!(
+                            "Connection checkout failed on {} replica(s) of shard {}, not retrying (checkout_retries = {})",
+                            replicas_tried, address.shard, checkout_retries
+                        );
+                        break;
+                    }
+                }
+                replicas_tried += 1;
+            }
+
+            // Wait for the autoscaled pool to have room for one more connection
+            let autoscaler = self.autoscaler(address);
+            let permit = match autoscaler {
+                Some(autoscaler) => match autoscaler
+                    .acquire(
+                        deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())),
+                    )
+                    .await
+                {
+                    Some(permit) => Some(permit),
+                    None if deadline.is_some() => {
+                        return Err(self.checkout_timed_out(
+                            address,
+                            client_stats,
+                            checkout_timeout,
+                        ))
+                    }
+                    None => {
+                        error!(
+                            "Connection checkout timeout for instance {:?}, all {} connections are in use",
+                            address,
+                            autoscaler.size()
+                        );
+                        self.ban(address, BanReason::FailedCheckout, Some(client_stats));
+                        address.stats.error();
+                        client_stats.checkout_error();
+                        continue;
+                    }
+                },
+                None => None,
+            };
+
+            // Wait for the server to have room for one more connection of the pool.
+            // The server is not blamed when it doesn't, the pool is just busy.
+            let backend_permit = match self.backend_limit(address) {
+                Some(backend_limit) => {
+                    let timeout = match deadline {
+                        Some(deadline) => deadline.saturating_duration_since(Instant::now()),
+                        None => std::time::Duration::from_millis(self.settings.connect_timeout),
+                    };
+                    match tokio::time::timeout(timeout, backend_limit.clone().acquire_owned()).await
+                    {
+                        // The semaphore is never closed.
+                        Ok(permit) => permit.ok(),
+                        Err(_) => {
+                            warn!(
+                                "Connection checkout for instance {:?} timed out, all {} backend connections are in use",
+                                address,
+                                self.settings.max_backend_connections.unwrap_or_default()
+                            );
+                            client_stats.checkout_error();
+                            return Err(Error::CheckoutTimeout);
+                        }
+                    }
+                }
+                None => None,
+            };
+
+            self.make_room_for_connection(address).await;
+
             // Check if we can connect
-            let mut conn = match self.databases[address.shard][address.address_index]
-                .get()
-                .await
-            {
-                Ok(conn) => {
+            let mut conn = match self.checkout(address, deadline).await {
+                Ok(mut conn) => {
                     address.reset_error_count();
+                    self.decay_ban_errors(address);
+                    if let (Some(autoscaler), Some(permit)) = (autoscaler, permit) {
+                        autoscaler.record_wait(now.elapsed().as_micros() as u64);
+                        conn.add_checkout_permit(permit);
+                    }
+                    if let Some(permit) = backend_permit {
+                        conn.add_checkout_permit(permit);
+                    }
                     conn
                 }
+                // The client picked the timeout, the server may be fine.
+                Err(RunError::TimedOut) if deadline.is_some() => {
+                    return Err(self.checkout_timed_out(address, client_stats, checkout_timeout));
+                }
+                // The pool is busy, the server may be fine.
+                Err(RunError::TimedOut) if self.connection_budget_used(address) => {
+                    warn!(
+                        "Connection checkout for instance {:?} timed out, all its connections are used by the sticky sub-pools",
+                        address
+                    );
+                    client_stats.checkout_error();
+                    return Err(Error::CheckoutTimeout);
+                }
+                Err(err) if address.too_many_connections_since(started_at) => {
+                    warn!(
+                        "Connection checkout error for instance {:?}, error: {:?}, \
+                        the server has too many connections, not banning it",
+                        address, err
+                    );
+                    address.stats.error();
+                    overloaded = true;
+                    continue;
+                }
                 Err(err) => {
                     error!(
                         "Connection checkout error for instance {:?}, error: {:?}",
@@ -777,10 +2088,27 @@ impl ConnectionPool {
             // // Check if this server is alive with a health check.
             let server = &mut *conn;
 
+            // The writes only go to a standby once it's been promoted,
+            // checking it is a health check too.
+            let standby = address.is_standby();
+            if standby {
+                if !self.standby_promoted(address, server, client_stats).await {
+                    continue;
+                }
+
+                // The writes can fail over, the primary is banned until its ban expires.
+                if let Some(primary) = failed_primary {
+                    if !self.is_banned(primary) {
+                        self.ban(primary, BanReason::FailedCheckout, Some(client_stats));
+                    }
+                }
+            }
+
             // Will return error if timestamp is greater than current system time, which it should never be set to
-            let require_healthcheck = force_healthcheck
-                || server.last_activity().elapsed().unwrap().as_millis()
-                    > self.settings.healthcheck_delay as u128;
+            let require_healthcheck = !standby
+                && (force_healthcheck
+                    || server.last_activity().elapsed().unwrap().as_millis()
+                        > self.settings.healthcheck_delay as u128);
 
             // Do not issue a health check unless it's been a little while
             // since we last checked the server is ok.
@@ -792,7 +2120,7 @@ impl ConnectionPool {
                     .stats()
                     .checkout_time(checkout_time, client_stats.application_name());
                 server.stats().active(client_stats.application_name());
-                client_stats.active();
+                client_stats.active(address);
                 return Ok((conn, address.clone()));
             }
 
@@ -800,13 +2128,18 @@ impl ConnectionPool {
                 .run_health_check(address, server, now, client_stats)
                 .await
             {
+                // Still banned, a primary whose writes didn't fail over.
+                if self.is_banned(address) {
+                    self.unban(address);
+                }
+
                 let checkout_time = now.elapsed().as_micros() as u64;
                 client_stats.checkout_success();
                 server
                     .stats()
                     .checkout_time(checkout_time, client_stats.application_name());
                 server.stats().active(client_stats.application_name());
-                client_stats.active();
+                client_stats.active(address);
                 return Ok((conn, address.clone()));
             } else {
                 continue;
@@ -815,7 +2148,103 @@ impl ConnectionPool {
 
         client_stats.checkout_error();
 
-        Err(Error::AllServersDown)
+        match overloaded {
+            true => Err(Error::ServerTooManyConnections),
+            false => Err(Error::AllServersDown),
+        }
+    }
+
+    /// Check out a connection from the server's pool. With a deadline, wait until then
+    /// instead of the pool's connect_timeout.
+    async fn checkout(
+        &self,
+        address: &Address,
+        deadline: Option<Instant>,
+    ) -> Result<PooledConnection<'_, ServerPool>, RunError<Error>> {
+        let pool = &self.databases[address.shard][address.address_index];
+        let deadline = match deadline {
+            Some(deadline) => deadline,
+            None => return pool.get().await,
+        };
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match tokio::time::timeout(remaining, pool.get()).await {
+                // The pool's connect_timeout is shorter, keep waiting.
+                Ok(Err(RunError::TimedOut)) if Instant::now() < deadline => continue,
+                Ok(result) => return result,
+                Err(_) => return Err(RunError::TimedOut),
+            }
+        }
+    }
+
+    /// No connection within the checkout timeout requested by the query. Unlike
+    /// with connect_timeout, the server is not banned since clients pick that timeout.
+    fn checkout_timed_out(
+        &self,
+        address: &Address,
+        client_stats: &ClientStats,
+        checkout_timeout: Option<u64>,
+    ) -> Error {
+        warn!(
+            "Connection checkout for instance {:?} timed out after the {}ms requested by the query",
+            address,
+            checkout_timeout.unwrap_or(0)
+        );
+        client_stats.checkout_error();
+        Error::CheckoutTimeout
+    }
+
+    /// Check the standby accepts writes. Until it's promoted, it's skipped but not banned.
+    async fn standby_promoted(
+        &self,
+        address: &Address,
+        server: &mut Server,
+        client_info: &ClientStats,
+    ) -> bool {
+        server.stats().tested();
+
+        match tokio::time::timeout(
+            tokio::time::Duration::from_millis(self.settings.healthcheck_timeout),
+            server.query_value(STANDBY_PROMOTED_QUERY),
+        )
+        .await
+        {
+            Ok(Ok(Some(promoted))) if promoted == "t" => {
+                debug!("Standby {:?} was promoted, sending it the writes", address);
+                self.promoted_standbys
+                    .write()
+                    .insert((address.shard, address.address_index));
+                return true;
+            }
+            Ok(Ok(_)) => {
+                warn!("Standby {:?} is still in recovery, skipping it", address);
+                self.promoted_standbys
+                    .write()
+                    .remove(&(address.shard, address.address_index));
+                return false;
+            }
+            Ok(Err(err)) => {
+                error!(
+                    "Failed promotion check on standby {:?}, error: {:?}",
+                    address, err
+                );
+            }
+            Err(err) => {
+                error!(
+                    "Promotion check timeout on standby {:?}, error: {:?}",
+                    address, err
+                );
+            }
+        }
+
+        server.mark_bad("failed promotion check");
+        self.promoted_standbys
+            .write()
+            .remove(&(address.shard, address.address_index));
+
+        self.ban(address, BanReason::FailedHealthCheck, Some(client_info));
+        false
     }
 
     async fn run_health_check(
@@ -831,7 +2260,7 @@ impl ConnectionPool {
 
         match tokio::time::timeout(
             tokio::time::Duration::from_millis(self.settings.healthcheck_timeout),
-            server.query(";"), // Cheap query as it skips the query planner
+            server.checked_query(&self.settings.healthcheck_query),
         )
         .await
         {
@@ -877,6 +2306,17 @@ impl ConnectionPool {
     /// traffic for any new transactions. Existing transactions on that replica
     /// will finish successfully or error out to the clients.
     pub fn ban(&self, address: &Address, reason: BanReason, client_info: Option<&ClientStats>) {
+        self.ban_with_rng(address, reason, client_info, &mut thread_rng())
+    }
+
+    /// Same as `ban`, with the random number generator used for the ban time jitter.
+    fn ban_with_rng<R: Rng>(
+        &self,
+        address: &Address,
+        reason: BanReason,
+        client_info: Option<&ClientStats>,
+        rng: &mut R,
+    ) {
         // Count the number of errors since the last successful checkout
         // This is used to determine if the shard is down
         match reason {
@@ -889,124 +2329,711 @@ impl ConnectionPool {
             _ => (),
         };
 
-        // Primary can never be banned
-        if address.role == Role::Primary {
+        // Primary can never be banned, unless the writes can fail over to a standby:
+        // one of them passed the promotion check, or an admin bans it to fail them over.
+        if address.role == Role::Primary
+            && !address.is_standby()
+            && !match reason {
+                BanReason::AdminBan(_) => self.has_standby(address.shard),
+                _ => self.standby_promoted_in(address.shard),
+            }
+        {
             return;
         }
 
-        error!("Banning instance {:?}, reason: {:?}", address, reason);
-
         let now = chrono::offset::Utc::now().naive_utc();
+
+        if !matches!(reason, BanReason::AdminBan(_))
+            && !self.ban_error_threshold_reached(address, now)
+        {
+            tracing::warn!(
+                pool = %address.pool_name,
+                user = %address.username,
+                address = %format_args!("{}:{}", address.host, address.port),
+                role = %address.role,
+                reason = ?reason,
+                "Not banning instance yet, ban error threshold not reached"
+            );
+            return;
+        }
+
         let mut guard = self.banlist.write();
+        let existing_reason = guard[address.shard]
+            .get(address)
+            .map(|(reason, _, _)| reason.clone());
+
+        // Don't turn a ban into a soft ban.
+        if reason == BanReason::SoftBan
+            && existing_reason.is_some()
+            && existing_reason != Some(BanReason::SoftBan)
+        {
+            return;
+        }
+
+        let duration = match reason {
+            BanReason::AdminBan(duration) => duration,
+            _ => match guard[address.shard].get(address) {
+                // Already banned, e.g. by another client using the same server,
+                // this is not a new consecutive ban.
+                Some((existing_reason, _, duration))
+                    if *existing_reason != BanReason::SoftBan || reason == BanReason::SoftBan =>
+                {
+                    *duration
+                }
+                _ => {
+                    self.backoff_ban_time(address, &reason, now.timestamp())
+                        + self.ban_time_jitter(rng)
+                }
+            },
+        };
 
         if let Some(client_info) = client_info {
             client_info.ban_error();
             address.stats.error();
         }
 
-        guard[address.shard].insert(address.clone(), (reason, now));
-    }
-
-    /// Clear the replica to receive traffic again. Takes effect immediately
-    /// for all new transactions.
-    pub fn unban(&self, address: &Address) {
-        let mut guard = self.banlist.write();
-        guard[address.shard].remove(address);
+        Self::insert_ban(&mut guard[address.shard], address, reason, now, duration);
     }
 
-    /// Check if address is banned
-    /// true if banned, false otherwise
-    pub fn is_banned(&self, address: &Address) -> bool {
-        let guard = self.banlist.read();
-
-        match guard[address.shard].get(address) {
-            Some(_) => true,
-            None => {
-                debug!("{:?} is ok", address);
-                false
-            }
-        }
+    /// Add the address to the banlist of its shard, and tell the admin clients, the reporter
+    /// and the ban persistence about it.
+    // The stats of the addresses are not part of their hash.
+    #[allow(clippy::mutable_key_type)]
+    fn insert_ban(
+        banlist: &mut HashMap<Address, (BanReason, NaiveDateTime, i64)>,
+        address: &Address,
+        reason: BanReason,
+        now: NaiveDateTime,
+        duration: i64,
+    ) {
+        tracing::error!(
+            pool = %address.pool_name,
+            user = %address.username,
+            address = %format_args!("{}:{}", address.host, address.port),
+            role = %address.role,
+            reason = ?reason,
+            duration,
+            "Banning instance"
+        );
+
+        ban_event(BanEventKind::Ban, address, Some(&reason));
+        get_reporter().ban(reason.name(), format!("{}:{}", address.host, address.port));
+        banlist.insert(address.clone(), (reason, now, duration));
+        BANS_CHANGED.notify_one();
     }
 
-    /// Determines trying to unban this server was successful
-    pub async fn try_unban(&self, address: &Address) -> bool {
-        // If somehow primary ends up being banned we should return true here
-        if address.role == Role::Primary {
+    /// Record an error for the address and check if enough errors happened
+    /// within the sliding window to ban it.
+    fn ban_error_threshold_reached(&self, address: &Address, now: NaiveDateTime) -> bool {
+        if self.settings.ban_error_threshold <= 1 {
             return true;
         }
 
-        // Check if all replicas are banned, in that case unban all of them
-        let replicas_available = self.addresses[address.shard]
-            .iter()
-            .filter(|addr| addr.role == Role::Replica)
-            .count();
-
-        debug!("Available targets: {}", replicas_available);
-
-        let read_guard = self.banlist.read();
-        let all_replicas_banned = read_guard[address.shard].len() == replicas_available;
-        drop(read_guard);
-
-        if all_replicas_banned {
-            let mut write_guard = self.banlist.write();
-            warn!("Unbanning all replicas.");
-            write_guard[address.shard].clear();
-
-            return true;
-        }
+        let window = chrono::Duration::seconds(self.settings.ban_error_window as i64);
+        let mut guard = self.ban_errors.lock();
+        let errors = guard.entry(address.clone()).or_default();
 
-        // Check if ban time is expired
-        let read_guard = self.banlist.read();
-        let exceeded_ban_time = match read_guard[address.shard].get(address) {
-            Some((ban_reason, timestamp)) => {
-                let now = chrono::offset::Utc::now().naive_utc();
-                match ban_reason {
-                    BanReason::AdminBan(duration) => {
-                        now.timestamp() - timestamp.timestamp() > *duration
-                    }
-                    _ => now.timestamp() - timestamp.timestamp() > self.settings.ban_time,
-                }
+        while let Some(oldest) = errors.front() {
+            if now - *oldest > window {
+                errors.pop_front();
+            } else {
+                break;
             }
-            None => return true,
-        };
-        drop(read_guard);
+        }
 
-        if exceeded_ban_time {
-            warn!("Unbanning {:?}", address);
-            let mut write_guard = self.banlist.write();
-            write_guard[address.shard].remove(address);
-            drop(write_guard);
+        errors.push_back(now);
 
+        if errors.len() as u64 >= self.settings.ban_error_threshold {
+            errors.clear();
             true
         } else {
-            debug!("{:?} is banned", address);
             false
         }
     }
 
+    /// Forget the oldest recorded error of the address after a successful checkout.
+    fn decay_ban_errors(&self, address: &Address) {
+        if self.settings.ban_error_threshold <= 1 {
+            return;
+        }
+
+        if let Some(errors) = self.ban_errors.lock().get_mut(address) {
+            errors.pop_front();
+        }
+    }
+
+    /// Register a new consecutive ban of the address and compute its duration:
+    /// `ban_time * ban_backoff_base ^ (consecutive_bans - 1)`, capped at `max_ban_time`.
+    /// The streak is reset if the address stayed healthy for longer than the last ban.
+    fn backoff_ban_time(&self, address: &Address, reason: &BanReason, now: i64) -> i64 {
+        let consecutive_bans = address.consecutive_bans();
+
+        if consecutive_bans > 0 {
+            let last_ban_expiry = address.last_ban_expiry.load(Ordering::Relaxed);
+            let last_ban_time = self.ban_time_for(reason, consecutive_bans);
+
+            if now - last_ban_expiry > last_ban_time {
+                address.consecutive_bans.store(0, Ordering::Relaxed);
+            }
+        }
+
+        let consecutive_bans = address.consecutive_bans.fetch_add(1, Ordering::Relaxed) + 1;
+        let ban_time = self.ban_time_for(reason, consecutive_bans);
+
+        address
+            .last_ban_expiry
+            .store(now.saturating_add(ban_time), Ordering::Relaxed);
+
+        ban_time
+    }
+
+    /// Random extra ban time so addresses banned together don't all get unbanned at once.
+    fn ban_time_jitter<R: Rng>(&self, rng: &mut R) -> i64 {
+        match self.settings.ban_time_jitter {
+            0 => 0,
+            jitter => rng.gen_range(0..=jitter),
+        }
+    }
+
+    /// Ban duration for the n-th consecutive ban of an address.
+    fn ban_time_for(&self, reason: &BanReason, consecutive_bans: u64) -> i64 {
+        let exponent = u32::try_from(consecutive_bans.saturating_sub(1)).unwrap_or(u32::MAX);
+        let multiplier = self.settings.ban_backoff_base.saturating_pow(exponent);
+        let ban_time = self
+            .settings
+            .ban_times
+            .get(reason.name())
+            .copied()
+            .unwrap_or(self.settings.ban_time)
+            .saturating_mul(i64::try_from(multiplier).unwrap_or(i64::MAX));
+
+        match self.settings.max_ban_time {
+            Some(max_ban_time) => ban_time.min(max_ban_time),
+            None => ban_time,
+        }
+    }
+
+    /// Clear the replica to receive traffic again. Takes effect immediately
+    /// for all new transactions.
+    pub fn unban(&self, address: &Address) {
+        let mut guard = self.banlist.write();
+        if guard[address.shard].remove(address).is_some() {
+            ban_event(BanEventKind::Unban, address, None);
+            BANS_CHANGED.notify_one();
+        }
+    }
+
+    /// Add a query latency sample to the average latency of the address.
+    pub fn record_latency(&self, address: &Address, latency: std::time::Duration) {
+        let decay = self.settings.latency_ewma_decay as f64 / 100.0;
+        address
+            .stats
+            .latency_ewma_add(latency.as_micros() as f64, decay);
+    }
+
+    /// Check if all the replicas of the shard are banned, soft bans excluded.
+    /// The writes of the shard fail over to the standbys.
+    fn primary_banned(&self, shard: usize) -> bool {
+        self.addresses[shard].iter().any(|address| {
+            address.role == Role::Primary && !address.is_standby() && self.is_banned(address)
+        })
+    }
+
+    /// The main primary of the shard has standbys to fail the writes over to.
+    fn has_standby(&self, shard: usize) -> bool {
+        self.addresses
+            .get(shard)
+            .is_some_and(|addresses| addresses.iter().any(|address| address.is_standby()))
+    }
+
+    /// One of the standbys of the shard passed its last promotion check.
+    fn standby_promoted_in(&self, shard: usize) -> bool {
+        self.promoted_standbys
+            .read()
+            .iter()
+            .any(|(standby_shard, _)| *standby_shard == shard)
+    }
+
+    fn all_replicas_banned(&self, shard: usize) -> bool {
+        // Drained replicas are out of rotation whether they are banned or not.
+        let replicas_available = self.addresses[shard]
+            .iter()
+            .filter(|addr| addr.role == Role::Replica && self.in_rotation(addr))
+            .count();
+
+        debug!("Available targets: {}", replicas_available);
+
+        self.banlist.read()[shard]
+            .iter()
+            .filter(|(address, (reason, _, _))| {
+                address.role == Role::Replica
+                    && self.in_rotation(address)
+                    && *reason != BanReason::SoftBan
+            })
+            .count()
+            == replicas_available
+    }
+
+    /// Take the replica out of rotation: it gets no new transactions, the ones
+    /// in progress finish. Unlike a ban, it lasts until `undrain` and isn't an error.
+    /// Returns false if the replica was already drained.
+    pub fn drain(&self, address: &Address) -> bool {
+        if address.role == Role::Primary {
+            return false;
+        }
+
+        let drained = self
+            .drained
+            .write()
+            .insert((address.host.clone(), address.port));
+        if drained {
+            info!("Draining {:?}", address);
+        }
+        drained
+    }
+
+    /// Put the drained replica back in rotation.
+    /// Returns false if the replica wasn't drained.
+    pub fn undrain(&self, address: &Address) -> bool {
+        let undrained = self
+            .drained
+            .write()
+            .remove(&(address.host.clone(), address.port));
+        if undrained {
+            info!("Undraining {:?}", address);
+        }
+        undrained
+    }
+
+    pub fn is_drained(&self, address: &Address) -> bool {
+        self.drained
+            .read()
+            .contains(&(address.host.clone(), address.port))
+    }
+
+    /// Drained servers, with the admin command or a weight of 0, get no new traffic.
+    /// Neither do the replicas lagging too far behind.
+    fn in_rotation(&self, address: &Address) -> bool {
+        address.weight > 0 && !self.is_drained(address) && self.replica_lag(address).is_none()
+    }
+
+    /// Check if address is banned
+    /// true if banned, false otherwise
+    /// Soft banned addresses are not considered banned.
+    pub fn is_banned(&self, address: &Address) -> bool {
+        let guard = self.banlist.read();
+
+        match guard[address.shard].get(address) {
+            Some((BanReason::SoftBan, _, _)) => false,
+            Some(_) => true,
+            None => {
+                debug!("{:?} is ok", address);
+                false
+            }
+        }
+    }
+
+    /// Load balancing weight of the address, between 0 and 1.
+    /// Soft banned addresses start at `SOFT_BAN_MIN_WEIGHT` and get back
+    /// to full weight as the soft ban comes to an end. Banned addresses have no weight.
+    pub fn effective_weight(&self, address: &Address) -> f64 {
+        let guard = self.banlist.read();
+
+        match guard[address.shard].get(address) {
+            Some((BanReason::SoftBan, timestamp, duration)) => {
+                if *duration <= 0 {
+                    return 1.0;
+                }
+
+                let now = chrono::offset::Utc::now().naive_utc();
+                let elapsed = (now - *timestamp).num_milliseconds() as f64 / 1000.0;
+
+                (elapsed / *duration as f64).clamp(SOFT_BAN_MIN_WEIGHT, 1.0)
+            }
+            Some(_) => 0.0,
+            None => 1.0,
+        }
+    }
+
+    /// Soft banned addresses are never skipped, so they don't go through `try_unban`.
+    fn unban_expired_soft_bans(&self) {
+        let now = chrono::offset::Utc::now().naive_utc().timestamp();
+        let expired = self
+            .banlist
+            .read()
+            .iter()
+            .flatten()
+            .filter(|(_, (reason, timestamp, duration))| {
+                *reason == BanReason::SoftBan && now - timestamp.timestamp() > *duration
+            })
+            .map(|(address, _)| address.clone())
+            .collect::<Vec<Address>>();
+
+        for address in expired {
+            tracing::info!(
+                pool = %address.pool_name,
+                user = %address.username,
+                address = %format_args!("{}:{}", address.host, address.port),
+                role = %address.role,
+                "Soft ban expired"
+            );
+            self.unban(&address);
+        }
+    }
+
+    /// Shuffle the candidates so that addresses with a lower weight are less likely
+    /// to be tried first (i.e. to be at the end of the list). The configured weights
+    /// are scaled down by `effective_weight`.
+    fn weighted_shuffle<R: Rng>(&self, candidates: &mut Vec<&Address>, rng: &mut R) {
+        let weights = candidates
+            .iter()
+            .map(|address| self.effective_weight(address) * address.weight as f64)
+            .collect::<Vec<f64>>();
+
+        if weights
+            .iter()
+            .all(|weight| *weight > 0.0 && *weight == weights[0])
+        {
+            candidates.shuffle(rng);
+            return;
+        }
+
+        // Weighted random sampling (Efraimidis-Spirakis): the higher the key, the earlier it's picked.
+        let mut keyed = candidates
+            .drain(..)
+            .zip(weights)
+            .map(|(address, weight)| (rng.gen::<f64>().powf(1.0 / weight), address))
+            .collect::<Vec<(f64, &Address)>>();
+
+        keyed.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+        candidates.extend(keyed.into_iter().map(|(_, address)| address));
+    }
+
+    /// Determines trying to unban this server was successful
+    pub async fn try_unban(&self, address: &Address) -> bool {
+        // If somehow primary ends up being banned we should return true here,
+        // or if no standby is promoted anymore. It's unbanned once its health check succeeds.
+        if address.role == Role::Primary
+            && !address.is_standby()
+            && !self.standby_promoted_in(address.shard)
+            && !matches!(
+                self.banlist.read()[address.shard].get(address),
+                Some((BanReason::AdminBan(_), _, _))
+            )
+        {
+            return true;
+        }
+
+        // Check if all replicas are banned, in that case unban all of them,
+        // unless reads fail or are sent to the primary until the bans expire.
+        if address.role == Role::Replica
+            && self.settings.on_all_replicas_banned == OnAllReplicasBanned::UnbanAll
+            && self.all_replicas_banned(address.shard)
+        {
+            let mut write_guard = self.banlist.write();
+            tracing::warn!(
+                pool = %address.pool_name,
+                user = %address.username,
+                shard = address.shard,
+                "Unbanning all replicas."
+            );
+            for (unbanned, _) in write_guard[address.shard].drain() {
+                ban_event(BanEventKind::Unban, &unbanned, None);
+            }
+            BANS_CHANGED.notify_one();
+
+            return true;
+        }
+
+        // Check if ban time is expired
+        let read_guard = self.banlist.read();
+        let exceeded_ban_time = match read_guard[address.shard].get(address) {
+            Some((_, timestamp, duration)) => {
+                let now = chrono::offset::Utc::now().naive_utc();
+                now.timestamp() - timestamp.timestamp() > *duration
+            }
+            None => return true,
+        };
+        drop(read_guard);
+
+        if exceeded_ban_time && self.settings.verify_before_unban {
+            debug!("{:?} ban expired, waiting for verification", address);
+            false
+        } else if exceeded_ban_time {
+            tracing::warn!(
+                pool = %address.pool_name,
+                user = %address.username,
+                address = %format_args!("{}:{}", address.host, address.port),
+                role = %address.role,
+                "Unbanning instance"
+            );
+            self.unban(address);
+
+            true
+        } else {
+            debug!("{:?} is banned", address);
+            false
+        }
+    }
+
+    /// Addresses banned for longer than their ban duration. Soft bans are not included.
+    fn expired_bans(&self) -> Vec<Address> {
+        let now = chrono::offset::Utc::now().naive_utc().timestamp();
+
+        self.banlist
+            .read()
+            .iter()
+            .flatten()
+            .filter(|(_, (reason, timestamp, duration))| {
+                *reason != BanReason::SoftBan && now - timestamp.timestamp() > *duration
+            })
+            .map(|(address, _)| address.clone())
+            .collect()
+    }
+
+    /// Health check an address whose ban expired. Unban it if it's healthy,
+    /// ban it again (with backoff) otherwise. Returns true if it was unbanned.
+    pub async fn verify_expired_ban(&self, address: &Address) -> bool {
+        debug!("Verifying {:?} before unbanning", address);
+
+        let healthy = match self.databases[address.shard][address.address_index]
+            .get()
+            .await
+        {
+            Ok(mut conn) => {
+                let server = &mut *conn;
+                server.stats().tested();
+
+                match tokio::time::timeout(
+                    tokio::time::Duration::from_millis(self.settings.healthcheck_timeout),
+                    server.checked_query(&self.settings.healthcheck_query),
+                )
+                .await
+                {
+                    Ok(Ok(_)) => true,
+                    Ok(Err(err)) => {
+                        error!(
+                            "Failed ban verification on instance {:?}, error: {:?}",
+                            address, err
+                        );
+                        server.mark_bad("failed ban verification");
+                        false
+                    }
+                    Err(_) => {
+                        error!("Ban verification timeout on instance {:?}", address);
+                        server.mark_bad("ban verification timeout");
+                        false
+                    }
+                }
+            }
+            Err(err) => {
+                error!(
+                    "Ban verification checkout error for instance {:?}, error: {:?}",
+                    address, err
+                );
+                false
+            }
+        };
+
+        if healthy {
+            tracing::warn!(
+                pool = %address.pool_name,
+                user = %address.username,
+                address = %format_args!("{}:{}", address.host, address.port),
+                role = %address.role,
+                "Unbanning instance, verification succeeded"
+            );
+            self.unban(address);
+            return true;
+        }
+
+        address.increment_error_count();
+
+        let now = chrono::offset::Utc::now().naive_utc();
+        let duration =
+            self.backoff_ban_time(address, &BanReason::FailedHealthCheck, now.timestamp())
+                + self.ban_time_jitter(&mut thread_rng());
+
+        Self::insert_ban(
+            &mut self.banlist.write()[address.shard],
+            address,
+            BanReason::FailedHealthCheck,
+            now,
+            duration,
+        );
+
+        false
+    }
+
     /// Get the number of configured shards.
     pub fn shards(&self) -> usize {
         self.databases.len()
     }
 
-    pub fn get_bans(&self) -> Vec<(Address, (BanReason, NaiveDateTime))> {
-        let mut bans: Vec<(Address, (BanReason, NaiveDateTime))> = Vec::new();
+    /// Limit on the connections of the pool checked out from this address, if any.
+    fn backend_limit(&self, address: &Address) -> Option<&Arc<Semaphore>> {
+        self.backend_limits
+            .get(address.shard)
+            .and_then(|backend_limits| backend_limits.get(address.address_index))
+    }
+
+    /// Number of clients waiting to check out a connection.
+    pub fn waiting_clients(&self) -> u64 {
+        self.waiting_clients.load(Ordering::Relaxed)
+    }
+
+    /// How long clients waited to check out a connection, whether they got one or not.
+    pub fn checkout_wait_histogram(&self) -> &Histogram {
+        &self.checkout_wait_histogram
+    }
+
+    /// Autoscaler limiting the connections checked out from this address, if any.
+    fn autoscaler(&self, address: &Address) -> Option<&Arc<Autoscaler>> {
+        self.autoscalers
+            .get(address.shard)
+            .and_then(|autoscalers| autoscalers.get(address.address_index))
+    }
+
+    /// Pool without any servers but with a banlist for the given number of shards.
+    #[cfg(test)]
+    pub fn with_banlist(shards: usize) -> ConnectionPool {
+        ConnectionPool {
+            banlist: Arc::new(RwLock::new(vec![HashMap::new(); shards])),
+            ..Default::default()
+        }
+    }
+
+    /// Pool with these servers and a banlist for their shards, but no connections to them.
+    #[cfg(test)]
+    pub fn with_addresses(addresses: Vec<Vec<Address>>) -> ConnectionPool {
+        let databases = addresses
+            .iter()
+            .map(|shard| {
+                shard
+                    .iter()
+                    .map(|address| {
+                        Pool::builder().build_unchecked(ServerPool::new(
+                            address.clone(),
+                            User::default(),
+                            &address.database,
+                            ClientServerMap::default(),
+                            Arc::new(RwLock::new(None)),
+                            None,
+                            true,
+                            false,
+                            0,
+                            General::default_server_lifetime(),
+                            0,
+                            None,
+                        ))
+                    })
+                    .collect()
+            })
+            .collect();
+
+        ConnectionPool {
+            databases: Arc::new(databases),
+            addresses: Arc::new(addresses.clone()),
+            ..ConnectionPool::with_banlist(addresses.len())
+        }
+    }
+
+    pub fn get_bans(&self) -> Vec<(Address, (BanReason, NaiveDateTime, i64))> {
+        let mut bans: Vec<(Address, (BanReason, NaiveDateTime, i64))> = Vec::new();
         let guard = self.banlist.read();
         for banlist in guard.iter() {
-            for (address, (reason, timestamp)) in banlist.iter() {
-                bans.push((address.clone(), (reason.clone(), *timestamp)));
+            for (address, (reason, timestamp, duration)) in banlist.iter() {
+                bans.push((address.clone(), (reason.clone(), *timestamp, *duration)));
             }
         }
         bans
     }
 
+    /// Number of banned addresses in each shard, soft bans excluded since those
+    /// addresses still serve traffic.
+    pub fn banned_count_per_shard(&self) -> Vec<u64> {
+        self.banlist
+            .read()
+            .iter()
+            .map(|banlist| {
+                banlist
+                    .values()
+                    .filter(|(reason, _, _)| *reason != BanReason::SoftBan)
+                    .count() as u64
+            })
+            .collect()
+    }
+
+    /// Current bans of this pool in a form that survives restarts and config reloads.
+    pub fn persist(&self) -> Vec<PersistedBan> {
+        self.get_bans()
+            .into_iter()
+            .map(|(address, (reason, timestamp, duration))| PersistedBan {
+                pool_name: address.pool_name,
+                username: address.username,
+                shard: address.shard,
+                host: address.host,
+                port: address.port,
+                reason,
+                timestamp: timestamp.timestamp(),
+                duration,
+            })
+            .collect()
+    }
+
+    /// Ban again the addresses of this pool found in the list, keeping the original
+    /// ban time. Expired bans and addresses that are not configured anymore are skipped.
+    /// Returns the number of restored bans.
+    pub fn restore(&self, bans: &[PersistedBan]) -> usize {
+        let now = chrono::offset::Utc::now().naive_utc().timestamp();
+        let mut guard = self.banlist.write();
+        let mut restored = 0;
+
+        for ban in bans {
+            if now - ban.timestamp > ban.duration {
+                continue;
+            }
+
+            let address = match self
+                .addresses
+                .iter()
+                .flatten()
+                .find(|address| address.role == Role::Replica && ban.matches(address))
+            {
+                Some(address) => address,
+                None => continue,
+            };
+
+            let timestamp = match NaiveDateTime::from_timestamp_opt(ban.timestamp, 0) {
+                Some(timestamp) => timestamp,
+                None => continue,
+            };
+
+            info!(
+                "Restoring ban of {:?}, reason: {:?}, remaining: {}s",
+                address,
+                ban.reason,
+                ban.duration - (now - ban.timestamp)
+            );
+
+            guard[address.shard].insert(
+                address.clone(),
+                (ban.reason.clone(), timestamp, ban.duration),
+            );
+            restored += 1;
+        }
+
+        restored
+    }
+
     /// Get the address from the host url
-    pub fn get_addresses_from_host(&self, host: &str) -> Vec<Address> {
+    /// Get the addresses of the host, only those listening on `port` if given.
+    pub fn get_addresses_from_host(&self, host: &str, port: Option<u16>) -> Vec<Address> {
         let mut addresses = Vec::new();
         for shard in 0..self.shards() {
             for server in 0..self.servers(shard) {
                 let address = self.address(shard, server);
-                if address.host == host {
+                if address.host == host && (port.is_none() || port == Some(address.port)) {
                     addresses.push(address.clone());
                 }
             }
@@ -1039,6 +3066,64 @@ impl ConnectionPool {
         &self.addresses[shard][server]
     }
 
+    /// Open a replication connection, e.g. `replication=database`, to the primary
+    /// of the shard. These connections stream WAL and can't be shared,
+    /// so they're not part of the pool.
+    pub async fn replication_connection(
+        &self,
+        shard: usize,
+        replication: &str,
+        client_server_map: ClientServerMap,
+    ) -> Result<Server, Error> {
+        let address = match self.addresses.get(shard).and_then(|addresses| {
+            addresses
+                .iter()
+                .find(|address| address.role == Role::Primary)
+        }) {
+            // The replication stream isn't made of queries, there is nothing to mirror.
+            Some(address) => Address {
+                mirrors: Vec::new(),
+                ..address.clone()
+            },
+            None => {
+                return Err(Error::ClientError(format!(
+                    "no primary configured for shard {}, can't replicate",
+                    shard
+                )))
+            }
+        };
+
+        info!("Creating a replication connection {:?}", address);
+
+        let stats = Arc::new(ServerStats::new(
+            address.clone(),
+            tokio::time::Instant::now(),
+        ));
+
+        stats.register(stats.clone());
+
+        match Server::startup(
+            &address,
+            &self.settings.user,
+            &address.database,
+            client_server_map,
+            stats.clone(),
+            self.auth_hash.clone(),
+            false,
+            false,
+            0,
+            Some(replication),
+        )
+        .await
+        {
+            Ok(server) => Ok(server),
+            Err(err) => {
+                stats.disconnect();
+                Err(err)
+            }
+        }
+    }
+
     pub fn server_parameters(&self) -> ServerParameters {
         self.original_server_parameters.read().clone()
     }
@@ -1087,36 +3172,168 @@ impl ConnectionPool {
             cache.promote(hash);
         }
     }
+
+    /// Counters of the prepared statement cache, if it's enabled.
+    pub fn prepared_statement_cache_stats(&self) -> Option<PreparedStatementCacheStats> {
+        self.prepared_statement_cache
+            .as_ref()
+            .map(|cache| cache.lock().stats())
+    }
 }
 
-/// Wrapper for the bb8 connection pool.
-pub struct ServerPool {
-    /// Server address.
-    address: Address,
+/// Counts a client as waiting for a connection until dropped, then records how long it waited.
+struct CheckoutWait<'a> {
+    pool: &'a ConnectionPool,
+    start: Instant,
+}
 
-    /// Server Postgres user.
-    user: User,
+impl<'a> CheckoutWait<'a> {
+    fn new(pool: &'a ConnectionPool, start: Instant) -> CheckoutWait<'a> {
+        pool.waiting_clients.fetch_add(1, Ordering::Relaxed);
+        CheckoutWait { pool, start }
+    }
+}
 
-    /// Server database.
-    database: String,
+impl Drop for CheckoutWait<'_> {
+    fn drop(&mut self) {
+        self.pool.waiting_clients.fetch_sub(1, Ordering::Relaxed);
+        self.pool
+            .checkout_wait_histogram
+            .record(self.start.elapsed());
+    }
+}
 
-    /// Client/server mapping.
-    client_server_map: ClientServerMap,
+/// The sticky parameter sub-pools of a pool, by the values of the parameters.
+#[derive(Debug, Default)]
+struct StickyPools {
+    pools: HashMap<Vec<Option<String>>, StickyPool>,
+}
 
-    /// Server auth hash (for auth passthrough).
-    auth_hash: Arc<RwLock<Option<String>>>,
+#[derive(Debug)]
+struct StickyPool {
+    databases: Arc<Vec<Vec<Pool<ServerPool>>>>,
+    last_used: Instant,
+}
 
-    /// Server plugins.
-    plugins: Option<Plugins>,
+impl StickyPool {
+    /// Clients connected to the sub-pool hold it.
+    fn in_use(&self) -> bool {
+        Arc::strong_count(&self.databases) > 1
+    }
+}
 
-    /// Should we clean up dirty connections before putting them into the pool?
-    cleanup_connections: bool,
+impl StickyPools {
+    /// Close the least recently used sub-pool no client is connected to, with its connections, one
+    /// with connections to the server (shard, index) if given. False if there is none.
+    fn close_unused(&mut self, server: Option<(usize, usize)>) -> bool {
+        let values = self
+            .pools
+            .iter()
+            .filter(|(_, pool)| !pool.in_use())
+            .filter(|(_, pool)| {
+                server.map_or(true, |server| {
+                    pool.databases
+                        .get(server.0)
+                        .and_then(|shard| shard.get(server.1))
+                        .is_some_and(|pool| pool.state().connections > 0)
+                })
+            })
+            .min_by_key(|(_, pool)| pool.last_used)
+            .map(|(values, _)| values.clone());
+
+        match values {
+            Some(values) => {
+                debug!("Closing the unused sticky sub-pool of {:?}", values);
+                self.pools.remove(&values);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The manager and settings of a bb8 pool of connections to a server, to build more of them.
+#[derive(Clone, Debug)]
+struct ServerPoolTemplate {
+    manager: ServerPool,
+    max_size: u32,
+    min_idle: Option<u32>,
+    connect_timeout: u64,
+    idle_timeout: u64,
+    server_lifetime: u64,
+    reaper_rate: u64,
+    queue_strategy: QueueStrategy,
+}
+
+impl ServerPoolTemplate {
+    fn builder(&self) -> bb8::Builder<ServerPool> {
+        Pool::builder()
+            .max_size(self.max_size)
+            .min_idle(self.min_idle)
+            .connection_timeout(std::time::Duration::from_millis(self.connect_timeout))
+            .idle_timeout(Some(std::time::Duration::from_millis(self.idle_timeout)))
+            .max_lifetime(Some(std::time::Duration::from_millis(self.server_lifetime)))
+            .reaper_rate(std::time::Duration::from_millis(self.reaper_rate))
+            .queue_strategy(self.queue_strategy)
+            .test_on_check_out(self.manager.checkout_validation.is_some())
+    }
+
+    /// A pool of connections started with these settings on top of the ones of the user.
+    /// The connections are only opened on checkout, there can be many of these pools.
+    fn build_with_settings(&self, settings: &BTreeMap<String, String>) -> Pool<ServerPool> {
+        let mut manager = self.manager.clone();
+        manager.user.server_settings.extend(settings.clone());
+        self.builder().min_idle(None).build_unchecked(manager)
+    }
+}
+
+/// Wrapper for the bb8 connection pool.
+#[derive(Clone, Debug)]
+pub struct ServerPool {
+    /// Server address.
+    address: Address,
+
+    /// Server Postgres user.
+    user: User,
+
+    /// Server database.
+    database: String,
+
+    /// Client/server mapping.
+    client_server_map: ClientServerMap,
+
+    /// Server auth hash (for auth passthrough).
+    auth_hash: Arc<RwLock<Option<String>>>,
+
+    /// Server plugins.
+    plugins: Option<Plugins>,
+
+    /// Should we clean up dirty connections before putting them into the pool?
+    cleanup_connections: bool,
 
     /// Log client parameter status changes
     log_client_parameter_status_changes: bool,
 
     /// Prepared statement cache size
     prepared_statement_cache_size: usize,
+
+    /// Max server connection lifetime (ms).
+    server_lifetime: u64,
+
+    /// Maximum random time taken off the lifetime of each connection (ms).
+    server_lifetime_jitter: u64,
+
+    /// Query run on the connections returned to the pool.
+    reset_query: Option<String>,
+
+    /// Limit on the connections being established at once, shared with the other pools.
+    connect_limit: Option<Arc<Semaphore>>,
+
+    /// Limit on the connections open to the server, shared with the sticky sub-pools.
+    connection_budget: Option<Arc<Semaphore>>,
+
+    /// Query validating the idle connections on checkout, and its timeout (ms), with `test_on_checkout`.
+    checkout_validation: Option<(String, u64)>,
 }
 
 impl ServerPool {
@@ -1131,6 +3348,9 @@ impl ServerPool {
         cleanup_connections: bool,
         log_client_parameter_status_changes: bool,
         prepared_statement_cache_size: usize,
+        server_lifetime: u64,
+        server_lifetime_jitter: u64,
+        reset_query: Option<String>,
     ) -> ServerPool {
         ServerPool {
             address,
@@ -1142,8 +3362,30 @@ impl ServerPool {
             cleanup_connections,
             log_client_parameter_status_changes,
             prepared_statement_cache_size,
+            server_lifetime,
+            server_lifetime_jitter,
+            reset_query,
+            connect_limit: None,
+            connection_budget: None,
+            checkout_validation: None,
         }
     }
+
+    /// Run the query on the idle connections before they are checked out,
+    /// the ones failing it within the timeout (ms) are replaced.
+    pub fn set_checkout_validation(&mut self, query: &str, timeout: u64) {
+        self.checkout_validation = Some((query.into(), timeout));
+    }
+
+    /// Wait for a permit of the limit before establishing a connection.
+    pub fn set_connect_limit(&mut self, connect_limit: Option<Arc<Semaphore>>) {
+        self.connect_limit = connect_limit;
+    }
+
+    /// Hold a permit of the budget while a connection is open.
+    pub fn set_connection_budget(&mut self, connection_budget: Option<Arc<Semaphore>>) {
+        self.connection_budget = connection_budget;
+    }
 }
 
 #[async_trait]
@@ -1153,7 +3395,38 @@ impl ManageConnection for ServerPool {
 
     /// Attempts to create a new connection.
     async fn connect(&self) -> Result<Self::Connection, Self::Error> {
-        info!("Creating a new server connection {:?}", self.address);
+        // Give an overloaded server a moment instead of adding to its load.
+        let backoff = get_config().general.too_many_connections_backoff as i64;
+        if self
+            .address
+            .too_many_connections_since(chrono::offset::Utc::now().timestamp_millis() - backoff)
+        {
+            debug!(
+                "Not connecting to {:?}, it had too many connections less than {}ms ago",
+                self.address, backoff
+            );
+            return Err(Error::ServerTooManyConnections);
+        }
+
+        // Wait for a connection of the sticky sub-pools to the server to be closed.
+        let connection_permit = match self.connection_budget {
+            Some(ref connection_budget) => connection_budget.clone().acquire_owned().await.ok(),
+            None => None,
+        };
+
+        // Queue behind the other connections being established, not to overwhelm the servers.
+        let _permit = match self.connect_limit {
+            Some(ref connect_limit) => connect_limit.acquire().await.ok(),
+            None => None,
+        };
+
+        tracing::info!(
+            pool = %self.address.pool_name,
+            user = %self.address.username,
+            address = %format_args!("{}:{}", self.address.host, self.address.port),
+            role = %self.address.role,
+            "Creating a new server connection"
+        );
 
         let stats = Arc::new(ServerStats::new(
             self.address.clone(),
@@ -1173,6 +3446,7 @@ impl ManageConnection for ServerPool {
             self.cleanup_connections,
             self.log_client_parameter_status_changes,
             self.prepared_statement_cache_size,
+            None,
         )
         .await
         {
@@ -1189,27 +3463,177 @@ impl ManageConnection for ServerPool {
                     }
                 }
 
+                if let Some(permit) = connection_permit {
+                    conn.set_connection_permit(permit);
+                }
+                conn.set_reset_query(self.reset_query.clone());
+                conn.set_max_lifetime(jittered_lifetime(
+                    self.server_lifetime,
+                    self.server_lifetime_jitter,
+                    &mut thread_rng(),
+                ));
+
                 stats.idle();
                 Ok(conn)
             }
             Err(err) => {
                 stats.disconnect();
+                if err == Error::ServerTooManyConnections {
+                    warn!(
+                        "Server {:?} has too many connections, not connecting to it for {}ms",
+                        self.address, backoff
+                    );
+                    self.address.too_many_connections();
+                }
                 Err(err)
             }
         }
     }
 
     /// Determines if the connection is still connected to the database.
-    async fn is_valid(&self, _conn: &mut Self::Connection) -> Result<(), Self::Error> {
-        Ok(())
+    /// Only called on checkout with `test_on_checkout`, bb8 replaces the connection if it's not.
+    async fn is_valid(&self, conn: &mut Self::Connection) -> Result<(), Self::Error> {
+        let (query, timeout) = match self.checkout_validation {
+            Some((ref query, timeout)) => (query, timeout),
+            None => return Ok(()),
+        };
+
+        conn.stats().tested();
+
+        let err = match tokio::time::timeout(
+            tokio::time::Duration::from_millis(timeout),
+            conn.checked_query(query),
+        )
+        .await
+        {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(err)) => err,
+            Err(_) => Error::ServerError,
+        };
+
+        info!(
+            "Server connection {:?} failed validation on checkout, replacing it: {:?}",
+            self.address, err
+        );
+        conn.mark_bad("failed validation on checkout");
+        Err(err)
     }
 
     /// Synchronously determine if the connection is no longer usable, if possible.
     fn has_broken(&self, conn: &mut Self::Connection) -> bool {
+        // Called every time the connection is returned to the pool.
+        conn.release_checkout_permits();
+
+        if conn.is_expired() {
+            info!(
+                "Server connection {:?} reached its max lifetime, replacing it",
+                self.address
+            );
+            return true;
+        }
+
+        if conn.closing_on_checkin() {
+            debug!(
+                "Closing the idle server connection {:?} for another sticky sub-pool",
+                self.address
+            );
+            return true;
+        }
+
         conn.is_bad()
     }
 }
 
+/// Tell the admin clients listening to pool events about the servers added to or removed from the pools.
+fn publish_address_changes(old_pools: &PoolMap, new_pools: &PoolMap) {
+    let addresses = |pools: &PoolMap| -> HashMap<String, Address> {
+        pools
+            .values()
+            .flat_map(|pool| pool.addresses.iter().flatten())
+            .map(|address| {
+                (
+                    format!(
+                        "{}/{}/{}/{}:{}/{}",
+                        address.pool_name,
+                        address.username,
+                        address.shard,
+                        address.host,
+                        address.port,
+                        address.role
+                    ),
+                    address.clone(),
+                )
+            })
+            .collect()
+    };
+    let old_addresses = addresses(old_pools);
+    let new_addresses = addresses(new_pools);
+
+    for (key, address) in &new_addresses {
+        if !old_addresses.contains_key(key) {
+            publish(PoolEvent::for_address(
+                PoolEventKind::AddressAdded,
+                address,
+                None,
+            ));
+        }
+    }
+
+    for (key, address) in &old_addresses {
+        if !new_addresses.contains_key(key) {
+            publish(PoolEvent::for_address(
+                PoolEventKind::AddressRemoved,
+                address,
+                None,
+            ));
+        }
+    }
+}
+
+/// Tell the notifications endpoint and the admin clients listening to pool events about a ban or unban,
+/// and keep it in the ban history.
+fn ban_event(kind: BanEventKind, address: &Address, reason: Option<&BanReason>) {
+    ban_history::record(kind, address, reason);
+    notify(BanEvent::new(kind, address, reason));
+    publish(PoolEvent::for_address(
+        match kind {
+            BanEventKind::Ban => PoolEventKind::Ban,
+            BanEventKind::Unban => PoolEventKind::Unban,
+        },
+        address,
+        reason,
+    ));
+}
+
+/// How long a new server connection may live for. The jitter is taken off the lifetime,
+/// so connections created together are not all recycled at the same time.
+fn jittered_lifetime<R: Rng>(lifetime: u64, jitter: u64, rng: &mut R) -> std::time::Duration {
+    let jitter = match jitter.min(lifetime.saturating_sub(1)) {
+        0 => 0,
+        jitter => rng.gen_range(0..=jitter),
+    };
+
+    std::time::Duration::from_millis(lifetime - jitter)
+}
+
+/// The limit on the server connections being established at once, shared by the pools.
+/// It's replaced when `max_concurrent_connects` changes, the pools keep the one they were created with.
+fn global_connect_limit(max_concurrent_connects: Option<usize>) -> Option<Arc<Semaphore>> {
+    let mut limit = CONNECT_LIMIT.lock();
+    match (max_concurrent_connects, limit.as_ref()) {
+        (None, _) => {
+            *limit = None;
+            None
+        }
+        (Some(max), Some((current, semaphore))) if max == *current => Some(semaphore.clone()),
+        (Some(max), _) => {
+            let semaphore = Arc::new(Semaphore::new(max));
+            *limit = Some((max, semaphore.clone()));
+            Some(semaphore)
+        }
+    }
+}
+
 /// Get the connection pool
 pub fn get_pool(db: &str, user: &str) -> Option<ConnectionPool> {
     (*(*POOLS.load()))
@@ -1221,3 +3645,1923 @@ pub fn get_pool(db: &str, user: &str) -> Option<ConnectionPool> {
 pub fn get_all_pools() -> HashMap<PoolIdentifier, ConnectionPool> {
     (*(*POOLS.load())).clone()
 }
+
+/// Sort the candidates so the address with the fewest busy connections comes last, i.e. is tried first.
+/// The sort is stable, so addresses equally busy keep their (shuffled) order.
+fn least_busy_last<F: Fn(&Address) -> u32>(candidates: &mut [&Address], busy_connection_count: F) {
+    candidates.sort_by_cached_key(|address| std::cmp::Reverse(busy_connection_count(address)));
+}
+
+/// Sort the candidates so the address with the lowest query latency comes last, i.e. is tried first.
+/// Addresses without latency samples yet come first. Once in a while, the shuffled order is kept
+/// instead so slower addresses still get some queries and their latency stays up to date.
+fn fastest_last<R: Rng>(candidates: &mut [&Address], rng: &mut R) {
+    if rng.gen_bool(LATENCY_PROBE_RATE) {
+        return;
+    }
+
+    candidates.sort_by(|a, b| {
+        let a = a.stats.latency_ewma().unwrap_or(0.0);
+        let b = b.stats.latency_ewma().unwrap_or(0.0);
+        b.total_cmp(&a)
+    });
+}
+
+/// Restore the bans saved in `ban_persistence_path` into the current pools.
+pub async fn restore_bans() {
+    let path = match get_config().general.ban_persistence_path {
+        Some(path) => path,
+        None => return,
+    };
+
+    let contents = match tokio::fs::read_to_string(&path).await {
+        Ok(contents) => contents,
+        Err(err) => {
+            if err.kind() != std::io::ErrorKind::NotFound {
+                warn!("Could not read bans from {}: {}", path, err);
+            }
+            return;
+        }
+    };
+
+    let bans: Vec<PersistedBan> = match serde_json::from_str(&contents) {
+        Ok(bans) => bans,
+        Err(err) => {
+            warn!("Could not parse bans from {}: {}", path, err);
+            return;
+        }
+    };
+
+    let restored: usize = get_all_pools()
+        .values()
+        .map(|pool| pool.restore(&bans))
+        .sum();
+
+    info!("Restored {} bans from {}", restored, path);
+}
+
+/// Save the bans of all pools to `ban_persistence_path` every time a banlist changes.
+pub fn start_ban_persistence() {
+    tokio::task::spawn(async move {
+        loop {
+            BANS_CHANGED.notified().await;
+
+            let path = match get_config().general.ban_persistence_path {
+                Some(path) => path,
+                None => continue,
+            };
+
+            let bans = get_all_pools()
+                .values()
+                .flat_map(|pool| pool.persist())
+                .collect::<Vec<PersistedBan>>();
+
+            let contents = match serde_json::to_string(&bans) {
+                Ok(contents) => contents,
+                Err(err) => {
+                    error!("Could not serialize bans: {}", err);
+                    continue;
+                }
+            };
+
+            // Write to a temporary file first so a crash never leaves a truncated file behind.
+            let tmp_path = format!("{}.tmp", path);
+            let result = match tokio::fs::write(&tmp_path, contents).await {
+                Ok(()) => tokio::fs::rename(&tmp_path, &path).await,
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(()) => debug!("Saved {} bans to {}", bans.len(), path),
+                Err(err) => error!("Could not save bans to {}: {}", path, err),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::{BufMut, BytesMut};
+
+    fn pool_with_settings(settings: PoolSettings) -> ConnectionPool {
+        ConnectionPool {
+            settings: Arc::new(settings),
+            ..ConnectionPool::with_banlist(1)
+        }
+    }
+
+    fn ban_duration(pool: &ConnectionPool, address: &Address) -> i64 {
+        pool.get_bans()
+            .iter()
+            .find(|(banned, _)| banned == address)
+            .map(|(_, (_, _, duration))| *duration)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_ban_backoff() {
+        let pool = pool_with_settings(PoolSettings {
+            ban_time: 10,
+            ban_backoff_base: 2,
+            max_ban_time: Some(30),
+            ..Default::default()
+        });
+        let address = Address::default();
+
+        pool.ban(&address, BanReason::FailedHealthCheck, None);
+        assert_eq!(ban_duration(&pool, &address), 10);
+        pool.unban(&address);
+
+        pool.ban(&address, BanReason::FailedCheckout, None);
+        assert_eq!(ban_duration(&pool, &address), 20);
+
+        // Banning an address that is already banned doesn't escalate.
+        pool.ban(&address, BanReason::MessageSendFailed, None);
+        assert_eq!(ban_duration(&pool, &address), 20);
+        pool.unban(&address);
+
+        // 10 * 2^2 = 40, capped at 30.
+        pool.ban(&address, BanReason::StatementTimeout, None);
+        assert_eq!(ban_duration(&pool, &address), 30);
+        pool.unban(&address);
+        assert_eq!(address.consecutive_bans(), 3);
+
+        // Admin bans use their own duration and don't count towards the backoff.
+        pool.ban(&address, BanReason::AdminBan(5), None);
+        assert_eq!(ban_duration(&pool, &address), 5);
+        assert_eq!(address.consecutive_bans(), 3);
+    }
+
+    #[test]
+    fn test_ban_history() {
+        let pool = ConnectionPool::with_banlist(1);
+        let address = Address {
+            host: "ban-history-replica".into(),
+            ..Default::default()
+        };
+
+        pool.ban(&address, BanReason::FailedHealthCheck, None);
+        pool.unban(&address);
+
+        let entry = ban_history::history()
+            .into_iter()
+            .rfind(|entry| entry.host == address.host)
+            .unwrap();
+        assert_eq!(entry.reason, "FailedHealthCheck");
+        assert!(entry.unbanned_at.unwrap() >= entry.banned_at);
+    }
+
+    #[test]
+    fn test_ban_time_jitter() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+
+        let settings = PoolSettings {
+            ban_time: 10,
+            ban_time_jitter: 5,
+            ..Default::default()
+        };
+        let addresses = (0..10)
+            .map(|id| Address {
+                id,
+                ..Default::default()
+            })
+            .collect::<Vec<Address>>();
+
+        let ban_all = |seed| {
+            let pool = pool_with_settings(settings.clone());
+            let mut rng = StdRng::seed_from_u64(seed);
+            for address in &addresses {
+                pool.ban_with_rng(address, BanReason::FailedHealthCheck, None, &mut rng);
+            }
+            addresses
+                .iter()
+                .map(|address| ban_duration(&pool, address))
+                .collect::<Vec<i64>>()
+        };
+
+        let durations = ban_all(42);
+        assert!(durations
+            .iter()
+            .all(|duration| (10..=15).contains(duration)));
+        assert!(durations.iter().any(|duration| *duration != durations[0]));
+        assert_eq!(durations, ban_all(42));
+
+        // Admin bans are exact.
+        let pool = pool_with_settings(settings.clone());
+        pool.ban_with_rng(
+            &addresses[0],
+            BanReason::AdminBan(30),
+            None,
+            &mut StdRng::seed_from_u64(42),
+        );
+        assert_eq!(ban_duration(&pool, &addresses[0]), 30);
+    }
+
+    #[test]
+    fn test_jittered_lifetime() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+        use std::time::Duration;
+
+        let mut rng = StdRng::seed_from_u64(42);
+        assert_eq!(
+            jittered_lifetime(1000, 0, &mut rng),
+            Duration::from_millis(1000)
+        );
+
+        let lifetimes = (0..10)
+            .map(|_| jittered_lifetime(1000, 200, &mut rng))
+            .collect::<Vec<Duration>>();
+        assert!(lifetimes.iter().all(|lifetime| {
+            (Duration::from_millis(800)..=Duration::from_millis(1000)).contains(lifetime)
+        }));
+        assert!(lifetimes.iter().any(|lifetime| *lifetime != lifetimes[0]));
+
+        // Jitter larger than the lifetime never makes it 0.
+        assert!((0..10).all(|_| jittered_lifetime(10, 100, &mut rng) >= Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn test_soft_ban() {
+        let healthy = Address::default();
+        let degraded = Address {
+            id: 1,
+            ..Default::default()
+        };
+        let pool = ConnectionPool {
+            addresses: Arc::new(vec![vec![healthy.clone(), degraded.clone()]]),
+            ..pool_with_settings(PoolSettings::default())
+        };
+
+        pool.ban(&degraded, BanReason::SoftBan, None);
+        assert!(!pool.is_banned(&degraded));
+        assert_eq!(pool.effective_weight(&healthy), 1.0);
+        assert_eq!(pool.effective_weight(&degraded), SOFT_BAN_MIN_WEIGHT);
+
+        let mut rng = thread_rng();
+        let mut degraded_picks = 0;
+        for _ in 0..10_000 {
+            let mut candidates = vec![&healthy, &degraded];
+            pool.weighted_shuffle(&mut candidates, &mut rng);
+            if *candidates.pop().unwrap() == degraded {
+                degraded_picks += 1;
+            }
+        }
+        // Expected to be picked first ~9% of the time.
+        assert!(degraded_picks < 1_500, "picked {} times", degraded_picks);
+        assert!(degraded_picks > 0);
+
+        // Expired soft bans are cleared.
+        pool.banlist.write()[0].get_mut(&degraded).unwrap().1 -= chrono::Duration::seconds(120);
+        pool.unban_expired_soft_bans();
+        assert!(pool.get_bans().is_empty());
+
+        // A soft ban can become a ban, but not the other way around.
+        pool.ban(&degraded, BanReason::FailedHealthCheck, None);
+        assert!(pool.is_banned(&degraded));
+        pool.ban(&degraded, BanReason::SoftBan, None);
+        assert!(pool.is_banned(&degraded));
+        assert_eq!(pool.effective_weight(&degraded), 0.0);
+    }
+
+    #[tokio::test]
+    async fn test_verify_before_unban() {
+        // Nothing listens on this port anymore, connections are refused.
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let address = Address {
+            port,
+            ..Default::default()
+        };
+        let manager = ServerPool::new(
+            address.clone(),
+            User::default(),
+            &address.database,
+            ClientServerMap::default(),
+            Arc::new(RwLock::new(None)),
+            None,
+            true,
+            false,
+            0,
+            General::default_server_lifetime(),
+            0,
+            None,
+        );
+        let server_pool = Pool::builder()
+            .max_size(1)
+            .connection_timeout(std::time::Duration::from_millis(100))
+            .build_unchecked(manager);
+        let pool = ConnectionPool {
+            databases: Arc::new(vec![vec![server_pool]]),
+            // The second replica keeps the pool from unbanning everything at once.
+            addresses: Arc::new(vec![vec![
+                address.clone(),
+                Address {
+                    id: 1,
+                    address_index: 1,
+                    ..Default::default()
+                },
+            ]]),
+            ..pool_with_settings(PoolSettings {
+                ban_time: 10,
+                ban_backoff_base: 2,
+                verify_before_unban: true,
+                ..Default::default()
+            })
+        };
+
+        let bans = || {
+            crate::stats::get_ban_counts()
+                .get(&(
+                    BanReason::FailedHealthCheck.name().to_string(),
+                    format!("{}:{}", address.host, address.port),
+                ))
+                .copied()
+                .unwrap_or_default()
+        };
+
+        pool.ban(&address, BanReason::FailedHealthCheck, None);
+        pool.banlist.write()[0].get_mut(&address).unwrap().1 -= chrono::Duration::seconds(60);
+        assert_eq!(bans(), 1);
+
+        // The ban expired but the address is only unbanned after verification.
+        assert!(!pool.try_unban(&address).await);
+        assert_eq!(pool.expired_bans(), vec![address.clone()]);
+
+        assert!(!pool.verify_expired_ban(&address).await);
+        assert!(pool.is_banned(&address));
+        assert!(pool.expired_bans().is_empty());
+        assert_eq!(ban_duration(&pool, &address), 20);
+        assert_eq!(bans(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_checkout_timeout_override() {
+        // Accepts connections but never answers, so checkouts can't complete.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = Address {
+            port: listener.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+        let manager = ServerPool::new(
+            address.clone(),
+            User::default(),
+            &address.database,
+            ClientServerMap::default(),
+            Arc::new(RwLock::new(None)),
+            None,
+            true,
+            false,
+            0,
+            General::default_server_lifetime(),
+            0,
+            None,
+        );
+        let server_pool = Pool::builder()
+            .max_size(1)
+            .connection_timeout(std::time::Duration::from_secs(5))
+            .build_unchecked(manager);
+        let pool = ConnectionPool {
+            databases: Arc::new(vec![vec![server_pool]]),
+            addresses: Arc::new(vec![vec![address.clone()]]),
+            ..pool_with_settings(PoolSettings::default())
+        };
+
+        let started = Instant::now();
+        let result = pool
+            .get(Some(0), None, &ClientStats::default(), Some(50))
+            .await;
+        assert_eq!(result.err(), Some(Error::CheckoutTimeout));
+        assert!(started.elapsed() < std::time::Duration::from_secs(1));
+
+        // The client picked the timeout, the server is not blamed for it.
+        assert!(!pool.is_banned(&address));
+    }
+
+    /// Server answering the startup, then every query with the messages `respond` returns
+    /// for it, followed by ReadyForQuery.
+    async fn mock_server<F>(respond: F) -> Address
+    where
+        F: Fn(&str) -> BytesMut + Send + Sync + 'static,
+    {
+        mock_server_with_startups(respond).await.0
+    }
+
+    /// Like `mock_server`, with the startup messages of the connections it got.
+    async fn mock_server_with_startups<F>(respond: F) -> (Address, Arc<Mutex<Vec<String>>>)
+    where
+        F: Fn(&str) -> BytesMut + Send + Sync + 'static,
+    {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = Address {
+            port: listener.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+        let respond = Arc::new(respond);
+        let startups = Arc::new(Mutex::new(Vec::new()));
+
+        tokio::task::spawn({
+            let startups = startups.clone();
+            async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let respond = respond.clone();
+                    let startups = startups.clone();
+                    tokio::task::spawn(async move {
+                        let len = stream.read_i32().await.unwrap();
+                        let mut startup = vec![0u8; len as usize - 4];
+                        stream.read_exact(&mut startup).await.unwrap();
+                        startups
+                            .lock()
+                            .push(String::from_utf8_lossy(&startup).into_owned());
+
+                        let mut response = BytesMut::new();
+                        response.put_u8(b'R');
+                        response.put_i32(8);
+                        response.put_i32(0);
+                        response.put_u8(b'Z');
+                        response.put_i32(5);
+                        response.put_u8(b'I');
+                        stream.write_all(&response).await.unwrap();
+
+                        while let Ok(code) = stream.read_u8().await {
+                            let len = stream.read_i32().await.unwrap();
+                            let mut query = vec![0u8; len as usize - 4];
+                            stream.read_exact(&mut query).await.unwrap();
+                            if code != b'Q' {
+                                continue;
+                            }
+
+                            let mut response = respond(&String::from_utf8_lossy(&query));
+                            response.put_u8(b'Z');
+                            response.put_i32(5);
+                            response.put_u8(b'I');
+                            stream.write_all(&response).await.unwrap();
+                        }
+                    });
+                }
+            }
+        });
+
+        (address, startups)
+    }
+
+    /// Pool of connections to the address, opened on checkout.
+    fn server_pool(address: &Address) -> Pool<ServerPool> {
+        server_pool_with_reset_query(address, None)
+    }
+
+    fn server_pool_with_reset_query(
+        address: &Address,
+        reset_query: Option<&str>,
+    ) -> Pool<ServerPool> {
+        Pool::builder().build_unchecked(server_manager(address, reset_query))
+    }
+
+    fn server_manager(address: &Address, reset_query: Option<&str>) -> ServerPool {
+        ServerPool::new(
+            address.clone(),
+            User::default(),
+            &address.database,
+            ClientServerMap::default(),
+            Arc::new(RwLock::new(None)),
+            None,
+            true,
+            false,
+            0,
+            General::default_server_lifetime(),
+            0,
+            reset_query.map(String::from),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_custom_health_check_query() {
+        // Fails the queries reading the recovery status, as if it wasn't a replica anymore.
+        let address = mock_server(|query| {
+            let mut response = BytesMut::new();
+            if query.contains("pg_is_in_recovery") {
+                let error = b"SERROR\0C55000\0Mnot in recovery\0\0";
+                response.put_u8(b'E');
+                response.put_i32(error.len() as i32 + 4);
+                response.put_slice(error);
+            } else {
+                response.put_u8(b'I');
+                response.put_i32(4);
+            }
+            response
+        })
+        .await;
+
+        let pool = |healthcheck_query: &str| ConnectionPool {
+            databases: Arc::new(vec![vec![server_pool(&address)]]),
+            addresses: Arc::new(vec![vec![address.clone()]]),
+            ..pool_with_settings(PoolSettings {
+                healthcheck_query: healthcheck_query.into(),
+                ..Default::default()
+            })
+        };
+
+        let healthy = pool(DEFAULT_HEALTHCHECK_QUERY);
+        let mut conn = healthy.databases[0][0].get().await.unwrap();
+        assert!(
+            healthy
+                .run_health_check(&address, &mut conn, Instant::now(), &ClientStats::default())
+                .await
+        );
+        assert!(!healthy.is_banned(&address));
+
+        let failing = pool("SELECT 1 / pg_is_in_recovery()::int");
+        let mut conn = failing.databases[0][0].get().await.unwrap();
+        assert!(
+            !failing
+                .run_health_check(&address, &mut conn, Instant::now(), &ClientStats::default())
+                .await
+        );
+        assert!(conn.is_bad());
+        assert_eq!(failing.get_bans()[0].1 .0, BanReason::FailedHealthCheck);
+    }
+
+    #[tokio::test]
+    async fn test_on_checkout() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Closes the first connection on its next query once it's gone stale.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = Address {
+            port: listener.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+        let stale = Arc::new(AtomicBool::new(false));
+        let connections = Arc::new(AtomicU64::new(0));
+        tokio::task::spawn({
+            let (stale, connections) = (stale.clone(), connections.clone());
+            async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let first = connections.fetch_add(1, Ordering::SeqCst) == 0;
+                    let stale = stale.clone();
+                    tokio::task::spawn(async move {
+                        let len = stream.read_i32().await.unwrap();
+                        let mut startup = vec![0u8; len as usize - 4];
+                        stream.read_exact(&mut startup).await.unwrap();
+
+                        let mut response = BytesMut::new();
+                        response.put_u8(b'R');
+                        response.put_i32(8);
+                        response.put_i32(0);
+                        response.put_u8(b'Z');
+                        response.put_i32(5);
+                        response.put_u8(b'I');
+                        stream.write_all(&response).await.unwrap();
+
+                        while let Ok(_code) = stream.read_u8().await {
+                            let len = stream.read_i32().await.unwrap();
+                            let mut query = vec![0u8; len as usize - 4];
+                            stream.read_exact(&mut query).await.unwrap();
+                            if first && stale.load(Ordering::SeqCst) {
+                                return;
+                            }
+
+                            let mut response = BytesMut::new();
+                            response.put_u8(b'I');
+                            response.put_i32(4);
+                            response.put_u8(b'Z');
+                            response.put_i32(5);
+                            response.put_u8(b'I');
+                            stream.write_all(&response).await.unwrap();
+                        }
+                    });
+                }
+            }
+        });
+
+        let mut manager = server_manager(&address, None);
+        manager.set_checkout_validation(DEFAULT_HEALTHCHECK_QUERY, 1000);
+        let template = ServerPoolTemplate {
+            manager,
+            max_size: 1,
+            min_idle: None,
+            connect_timeout: 1000,
+            idle_timeout: General::default_idle_timeout(),
+            server_lifetime: General::default_server_lifetime(),
+            reaper_rate: POOL_REAPER_RATE,
+            queue_strategy: QueueStrategy::Lifo,
+        };
+        let pool = ConnectionPool {
+            databases: Arc::new(vec![vec![template
+                .builder()
+                .build_unchecked(template.manager.clone())]]),
+            addresses: Arc::new(vec![vec![address.clone()]]),
+            ..pool_with_settings(PoolSettings::default())
+        };
+        let stats = ClientStats::default();
+
+        drop(pool.get(None, None, &stats, None).await.unwrap());
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        // The idle connection was closed by the server, the client gets a new one.
+        stale.store(true, Ordering::SeqCst);
+        let (mut conn, _) = pool.get(None, None, &stats, None).await.unwrap();
+        assert!(conn.checked_query(";").await.is_ok());
+        assert_eq!(connections.load(Ordering::SeqCst), 2);
+        assert!(!pool.is_banned(&address));
+    }
+
+    #[tokio::test]
+    async fn test_checkout_retries() {
+        // Nothing listens on the first replica's port anymore, as if it just died.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dead = Address {
+            port: listener.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+        drop(listener);
+        let healthy = Address {
+            id: 1,
+            address_index: 1,
+            ..mock_server(|_| {
+                let mut response = BytesMut::new();
+                response.put_u8(b'D');
+                response.put_i32(4 + 2 + 4 + 2);
+                response.put_i16(1);
+                response.put_i32(2);
+                response.put_slice(b"42");
+                response
+            })
+            .await
+        };
+
+        let pool = |checkout_retries| ConnectionPool {
+            databases: Arc::new(vec![vec![
+                Pool::builder()
+                    .connection_timeout(std::time::Duration::from_millis(100))
+                    .build_unchecked(server_manager(&dead, None)),
+                server_pool(&healthy),
+            ]]),
+            addresses: Arc::new(vec![vec![dead.clone(), healthy.clone()]]),
+            ..pool_with_settings(PoolSettings {
+                checkout_retries,
+                ..Default::default()
+            })
+        };
+        let stats = ClientStats::default();
+
+        // The dead replica is tried first, the read goes to the next one.
+        let retrying = pool(Some(1));
+        let (mut conn, address) = retrying
+            .get_from(vec![&healthy, &dead], &stats, None)
+            .await
+            .unwrap();
+        assert_eq!(address, healthy);
+        assert_eq!(
+            conn.query_value("SELECT 42").await.unwrap(),
+            Some("42".into())
+        );
+        assert!(retrying.is_banned(&dead));
+        assert!(!retrying.is_banned(&healthy));
+
+        // No retries left.
+        let not_retrying = pool(Some(0));
+        assert_eq!(
+            not_retrying
+                .get_from(vec![&healthy, &dead], &stats, None)
+                .await
+                .err(),
+            Some(Error::AllServersDown)
+        );
+        assert!(not_retrying.is_banned(&dead));
+    }
+
+    #[tokio::test]
+    async fn test_max_concurrent_connects() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Takes a while to let the connections in, counting how many are being established at once.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = Address {
+            port: listener.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+        let connecting = Arc::new(AtomicU64::new(0));
+        let max_connecting = Arc::new(AtomicU64::new(0));
+        tokio::task::spawn({
+            let (connecting, max_connecting) = (connecting.clone(), max_connecting.clone());
+            async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    let (connecting, max_connecting) = (connecting.clone(), max_connecting.clone());
+                    tokio::task::spawn(async move {
+                        let now = connecting.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_connecting.fetch_max(now, Ordering::SeqCst);
+
+                        let len = stream.read_i32().await.unwrap();
+                        let mut startup = vec![0u8; len as usize - 4];
+                        stream.read_exact(&mut startup).await.unwrap();
+                        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+                        connecting.fetch_sub(1, Ordering::SeqCst);
+                        let mut response = BytesMut::new();
+                        response.put_u8(b'R');
+                        response.put_i32(8);
+                        response.put_i32(0);
+                        response.put_u8(b'Z');
+                        response.put_i32(5);
+                        response.put_u8(b'I');
+                        stream.write_all(&response).await.unwrap();
+
+                        // Keep the connection open until the pool closes it.
+                        let mut buf = [0u8; 1024];
+                        while let Ok(n) = stream.read(&mut buf).await {
+                            if n == 0 {
+                                break;
+                            }
+                        }
+                    });
+                }
+            }
+        });
+
+        let mut manager = server_manager(&address, None);
+        manager.set_connect_limit(Some(Arc::new(Semaphore::new(2))));
+        let pool = Pool::builder().max_size(10).build_unchecked(manager);
+
+        // Ten clients at once, the connections are established two by two.
+        let conns = futures::future::join_all((0..10).map(|_| pool.get())).await;
+        assert!(conns.iter().all(|conn| conn.is_ok()));
+        assert_eq!(pool.state().connections, 10);
+        assert_eq!(max_connecting.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_global_connect_limit() {
+        let limit = global_connect_limit(Some(5)).unwrap();
+        assert_eq!(limit.available_permits(), 5);
+        assert!(Arc::ptr_eq(&limit, &global_connect_limit(Some(5)).unwrap()));
+        assert_eq!(
+            global_connect_limit(Some(3)).unwrap().available_permits(),
+            3
+        );
+        assert!(global_connect_limit(None).is_none());
+    }
+
+    /// Pool splitting its connections to the address by `search_path`, with `max_size`
+    /// connections to it.
+    fn sticky_pool(address: &Address, max_size: u32, max_sticky_pools: usize) -> ConnectionPool {
+        let connection_budget = Arc::new(Semaphore::new(max_size as usize));
+        let mut manager = server_manager(address, None);
+        manager.set_connection_budget(Some(connection_budget.clone()));
+
+        let databases = Arc::new(vec![vec![Pool::builder()
+            .max_size(max_size)
+            .connection_timeout(std::time::Duration::from_millis(1000))
+            .build_unchecked(manager.clone())]]);
+
+        ConnectionPool {
+            databases: databases.clone(),
+            pool_databases: databases,
+            templates: Arc::new(vec![vec![ServerPoolTemplate {
+                manager,
+                max_size,
+                min_idle: None,
+                connect_timeout: 1000,
+                idle_timeout: General::default_idle_timeout(),
+                server_lifetime: General::default_server_lifetime(),
+                reaper_rate: POOL_REAPER_RATE,
+                queue_strategy: QueueStrategy::Lifo,
+            }]]),
+            connection_budgets: Arc::new(vec![vec![connection_budget]]),
+            addresses: Arc::new(vec![vec![address.clone()]]),
+            ..pool_with_settings(PoolSettings {
+                sticky_parameters: vec!["search_path".into()],
+                max_sticky_pools,
+                ..Default::default()
+            })
+        }
+    }
+
+    fn search_path(value: &str) -> HashMap<String, String> {
+        HashMap::from([("search_path".to_string(), value.to_string())])
+    }
+
+    #[tokio::test]
+    async fn test_sticky_pools() {
+        let (address, startups) = mock_server_with_startups(|_| BytesMut::new()).await;
+        let pool = sticky_pool(&address, 2, 10);
+        let checkout = |pool: ConnectionPool| async move {
+            pool.get(None, None, &ClientStats::default(), None)
+                .await
+                .unwrap();
+        };
+
+        let app = pool.sticky_pool(&search_path("app")).unwrap();
+        let audit = pool.sticky_pool(&search_path("audit")).unwrap();
+        assert!(!Arc::ptr_eq(&app.databases, &audit.databases));
+        assert!(!Arc::ptr_eq(&app.databases, &pool.databases));
+
+        // Each sub-pool opens its own connection, started with its search_path.
+        checkout(app.clone()).await;
+        checkout(audit.clone()).await;
+        {
+            let startups = startups.lock();
+            assert_eq!(startups.len(), 2);
+            assert!(startups[0].contains("search_path\0app\0"));
+            assert!(startups[1].contains("search_path\0audit\0"));
+        }
+
+        // Another client with the same search_path reuses the connection of the sub-pool.
+        let same = pool.sticky_pool(&search_path("app")).unwrap();
+        assert!(Arc::ptr_eq(&app.databases, &same.databases));
+        checkout(same).await;
+        assert_eq!(startups.lock().len(), 2);
+
+        // Clients without it use the pool itself.
+        let default = pool.sticky_pool(&HashMap::new()).unwrap();
+        assert!(Arc::ptr_eq(&default.databases, &pool.databases));
+    }
+
+    #[tokio::test]
+    async fn test_sticky_pools_budget() {
+        let (address, startups) = mock_server_with_startups(|_| BytesMut::new()).await;
+        let pool = sticky_pool(&address, 1, 10);
+        let stats = ClientStats::default();
+
+        let app = pool.sticky_pool(&search_path("app")).unwrap();
+        let audit = pool.sticky_pool(&search_path("audit")).unwrap();
+
+        // The pool and its sub-pools share the one connection to the server.
+        let conn = app.get(None, None, &stats, None).await.unwrap();
+        assert_eq!(
+            audit.get(None, None, &stats, Some(100)).await.err(),
+            Some(Error::CheckoutTimeout)
+        );
+        assert_eq!(
+            pool.get(None, None, &stats, None).await.err(),
+            Some(Error::CheckoutTimeout)
+        );
+        assert!(!pool.is_banned(&address));
+
+        // Once it's idle, the connection of app is closed for one of audit.
+        drop(conn);
+        audit.get(None, None, &stats, None).await.unwrap();
+        assert_eq!(startups.lock().len(), 2);
+
+        // The sub-pools no client is connected to are closed first, with their connections.
+        drop(audit);
+        pool.get(None, None, &stats, None).await.unwrap();
+        assert_eq!(startups.lock().len(), 3);
+        assert_eq!(pool.sticky_pools.lock().pools.len(), 1);
+        drop(app);
+    }
+
+    #[tokio::test]
+    async fn test_max_sticky_pools() {
+        let address = mock_server(|_| BytesMut::new()).await;
+        let pool = sticky_pool(&address, 1, 1);
+        let stats = ClientStats::default();
+
+        let app = pool.sticky_pool(&search_path("app")).unwrap();
+        let conn = app.get(None, None, &stats, None).await.unwrap();
+
+        // A client is connected to the only sub-pool, even once it's idle.
+        assert!(pool.sticky_pool(&search_path("audit")).is_none());
+        assert!(pool.sticky_pool(&search_path("app")).is_some());
+        drop(conn);
+        assert!(pool.sticky_pool(&search_path("audit")).is_none());
+
+        // Once it disconnects, it's replaced.
+        let databases = Arc::downgrade(&app.databases);
+        drop(app);
+        let audit = pool.sticky_pool(&search_path("audit")).unwrap();
+        assert!(databases.upgrade().is_none());
+        assert!(!Arc::ptr_eq(&pool.databases, &audit.databases));
+        assert_eq!(pool.sticky_pools.lock().pools.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_too_many_connections() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // Refuses every connection, like a server that reached max_connections.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = Address {
+            port: listener.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+        let connections = Arc::new(AtomicU64::new(0));
+        tokio::task::spawn({
+            let connections = connections.clone();
+            async move {
+                while let Ok((mut stream, _)) = listener.accept().await {
+                    connections.fetch_add(1, Ordering::Relaxed);
+                    let len = stream.read_i32().await.unwrap();
+                    let mut startup = vec![0u8; len as usize - 4];
+                    stream.read_exact(&mut startup).await.unwrap();
+
+                    let error = b"SFATAL\0VFATAL\0C53300\0Msorry, too many clients already\0\0";
+                    let mut response = BytesMut::new();
+                    response.put_u8(b'E');
+                    response.put_i32(error.len() as i32 + 4);
+                    response.put_slice(error);
+                    stream.write_all(&response).await.unwrap();
+                }
+            }
+        });
+
+        let pool = ConnectionPool {
+            databases: Arc::new(vec![vec![Pool::builder()
+                .connection_timeout(std::time::Duration::from_millis(300))
+                .build_unchecked(server_manager(&address, None))]]),
+            addresses: Arc::new(vec![vec![address.clone()]]),
+            ..pool_with_settings(PoolSettings::default())
+        };
+
+        let err = match pool.get(None, None, &ClientStats::default(), None).await {
+            Ok(_) => panic!("the server refuses connections"),
+            Err(err) => err,
+        };
+        assert_eq!(err, Error::ServerTooManyConnections);
+
+        // Still in rotation, but not connected to again until the backoff is over.
+        assert!(!pool.is_banned(&address));
+        assert!(address.too_many_connections_since(0));
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn test_write_failover_to_standby() {
+        // Answers the promotion check, in recovery until it's promoted.
+        let promoted = Arc::new(AtomicBool::new(false));
+        let standby = mock_server({
+            let promoted = promoted.clone();
+            move |_| {
+                let mut response = BytesMut::new();
+                response.put_u8(b'D');
+                response.put_i32(4 + 2 + 4 + 1);
+                response.put_i16(1);
+                response.put_i32(1);
+                response.put_u8(match promoted.load(Ordering::Relaxed) {
+                    true => b't',
+                    false => b'f',
+                });
+                response
+            }
+        })
+        .await;
+        let standby = Address {
+            role: Role::Primary,
+            replica_number: 1,
+            address_index: 1,
+            ..standby
+        };
+
+        // Nothing listens on the primary's port anymore.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let primary = Address {
+            id: 1,
+            port: listener.local_addr().unwrap().port(),
+            role: Role::Primary,
+            ..Default::default()
+        };
+        drop(listener);
+        let primary_pool = Pool::builder()
+            .connection_timeout(std::time::Duration::from_millis(100))
+            .build_unchecked(ServerPool::new(
+                primary.clone(),
+                User::default(),
+                &primary.database,
+                ClientServerMap::default(),
+                Arc::new(RwLock::new(None)),
+                None,
+                true,
+                false,
+                0,
+                General::default_server_lifetime(),
+                0,
+                None,
+            ));
+
+        let pool = ConnectionPool {
+            databases: Arc::new(vec![vec![primary_pool, server_pool(&standby)]]),
+            addresses: Arc::new(vec![vec![primary.clone(), standby.clone()]]),
+            ..pool_with_settings(PoolSettings {
+                ban_time: 60,
+                ..Default::default()
+            })
+        };
+        let stats = ClientStats::default();
+        assert_eq!(standby.name(), "pool_name_shard_0_standby_1");
+
+        // Tried after the primary.
+        assert_eq!(
+            pool.candidates(Some(0), Some(Role::Primary)),
+            vec![&standby, &primary]
+        );
+
+        // The primary is down, and the standby wasn't promoted yet: the primary
+        // is tried again next time.
+        assert_eq!(
+            pool.get(Some(0), Some(Role::Primary), &stats, None)
+                .await
+                .err(),
+            Some(Error::AllServersDown)
+        );
+        assert!(!pool.is_banned(&primary));
+        assert!(!pool.is_banned(&standby));
+
+        // Once it's promoted, the writes fail over to it and the primary is banned.
+        promoted.store(true, Ordering::Relaxed);
+        let (conn, address) = pool
+            .get(Some(0), Some(Role::Primary), &stats, None)
+            .await
+            .unwrap();
+        assert_eq!(address, standby);
+        assert!(pool.is_banned(&primary));
+        assert!(!pool.try_unban(&primary).await);
+        drop(conn);
+
+        // Back in recovery, the primary is tried again before its ban expires.
+        promoted.store(false, Ordering::Relaxed);
+        assert_eq!(
+            pool.get_from(vec![&standby], &stats, None).await.err(),
+            Some(Error::AllServersDown)
+        );
+        assert!(pool.try_unban(&primary).await);
+
+        // Not while the primary is fine.
+        pool.unban(&primary);
+        assert_eq!(
+            pool.get_from(vec![&standby], &stats, None).await.err(),
+            Some(Error::AllServersDown)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_primary_unbanned_without_promoted_standby() {
+        let primary = mock_server(|_| {
+            let mut response = BytesMut::new();
+            response.put_u8(b'I');
+            response.put_i32(4);
+            response
+        })
+        .await;
+        let primary = Address {
+            role: Role::Primary,
+            ..primary
+        };
+        let standby = Address {
+            role: Role::Primary,
+            replica_number: 1,
+            address_index: 1,
+            ..primary.clone()
+        };
+        let pool = ConnectionPool {
+            databases: Arc::new(vec![vec![server_pool(&primary), server_pool(&standby)]]),
+            addresses: Arc::new(vec![vec![primary.clone(), standby.clone()]]),
+            ..pool_with_settings(PoolSettings {
+                ban_time: 60,
+                ..Default::default()
+            })
+        };
+
+        // Not banned until a standby is promoted.
+        pool.ban(&primary, BanReason::FailedCheckout, None);
+        assert!(!pool.is_banned(&primary));
+
+        pool.promoted_standbys.write().insert((0, 1));
+        pool.ban(&primary, BanReason::FailedCheckout, None);
+        assert!(pool.is_banned(&primary));
+
+        // The standby is back in recovery, the primary is unbanned once it's healthy.
+        pool.promoted_standbys.write().clear();
+        let (_conn, address) = pool
+            .get_from(vec![&primary], &ClientStats::default(), None)
+            .await
+            .unwrap();
+        assert_eq!(address, primary);
+        assert!(!pool.is_banned(&primary));
+    }
+
+    #[tokio::test]
+    async fn test_server_reset_query() {
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let address = mock_server({
+            let queries = queries.clone();
+            move |query| {
+                let query = query.trim_end_matches('\0').to_string();
+                let mut response = BytesMut::new();
+                if query == "DISCARD PLANS; RAISE" {
+                    let error = b"SERROR\0C42601\0Msyntax error\0\0";
+                    response.put_u8(b'E');
+                    response.put_i32(error.len() as i32 + 4);
+                    response.put_slice(error);
+                } else {
+                    response.put_u8(b'I');
+                    response.put_i32(4);
+                }
+                queries.lock().push(query);
+                response
+            }
+        })
+        .await;
+
+        // Runs every time the connection is returned.
+        let pool = server_pool_with_reset_query(&address, Some("DISCARD PLANS"));
+        let mut conn = pool.get().await.unwrap();
+        conn.checkin_cleanup().await.unwrap();
+        conn.checkin_cleanup().await.unwrap();
+        assert!(!conn.is_bad());
+        assert_eq!(*queries.lock(), vec!["DISCARD PLANS", "DISCARD PLANS"]);
+        drop(conn);
+        assert_eq!(pool.state().idle_connections, 1);
+
+        // The connection is closed if it fails.
+        let pool = server_pool_with_reset_query(&address, Some("DISCARD PLANS; RAISE"));
+        let mut conn = pool.get().await.unwrap();
+        conn.checkin_cleanup().await.unwrap();
+        assert!(conn.is_bad());
+        drop(conn);
+        assert_eq!(pool.state().connections, 0);
+    }
+
+    #[tokio::test]
+    async fn test_warmup_queries() {
+        let queries = Arc::new(Mutex::new(Vec::new()));
+        let address = mock_server({
+            let queries = queries.clone();
+            move |query| {
+                let query = query.trim_end_matches('\0').to_string();
+                let mut response = BytesMut::new();
+                if query.contains("missing_table") {
+                    let error = b"SERROR\0C42P01\0Mrelation does not exist\0\0";
+                    response.put_u8(b'E');
+                    response.put_i32(error.len() as i32 + 4);
+                    response.put_slice(error);
+                } else {
+                    response.put_u8(b'I');
+                    response.put_i32(4);
+                }
+                queries.lock().push(query);
+                response
+            }
+        })
+        .await;
+
+        let pool = ConnectionPool {
+            databases: Arc::new(vec![vec![server_pool(&address)]]),
+            addresses: Arc::new(vec![vec![address.clone()]]),
+            ..pool_with_settings(PoolSettings {
+                warmup_queries: vec![
+                    "SELECT pg_prewarm('missing_table')".into(),
+                    "SELECT pg_prewarm('accounts')".into(),
+                ],
+                warmup_connections: 2,
+                ..Default::default()
+            })
+        };
+
+        pool.warmup().await;
+
+        // Both connections ran both queries, the failing one didn't stop the warmup.
+        let mut queries = queries.lock().clone();
+        queries.sort();
+        assert_eq!(
+            queries,
+            vec![
+                "SELECT pg_prewarm('accounts')",
+                "SELECT pg_prewarm('accounts')",
+                "SELECT pg_prewarm('missing_table')",
+                "SELECT pg_prewarm('missing_table')",
+            ]
+        );
+        assert_eq!(pool.databases[0][0].state().idle_connections, 2);
+    }
+
+    #[tokio::test]
+    async fn test_max_backend_connections() {
+        let address = mock_server(|_| {
+            let mut response = BytesMut::new();
+            response.put_u8(b'I');
+            response.put_i32(4);
+            response
+        })
+        .await;
+        let pool = ConnectionPool {
+            databases: Arc::new(vec![vec![server_pool(&address)]]),
+            addresses: Arc::new(vec![vec![address.clone()]]),
+            backend_limits: Arc::new(vec![vec![Arc::new(Semaphore::new(1))]]),
+            ..pool_with_settings(PoolSettings {
+                connect_timeout: 300,
+                max_backend_connections: Some(1),
+                ..Default::default()
+            })
+        };
+
+        let client_stats = ClientStats::default();
+        let (conn, _) = pool.get(Some(0), None, &client_stats, None).await.unwrap();
+        assert_eq!(pool.waiting_clients(), 0);
+
+        let waiting = (0..2)
+            .map(|_| {
+                let pool = pool.clone();
+                tokio::task::spawn(async move {
+                    pool.get(Some(0), None, &ClientStats::default(), None)
+                        .await
+                        .err()
+                })
+            })
+            .collect::<Vec<_>>();
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert_eq!(pool.waiting_clients(), 2);
+
+        // They give up after the connect timeout, without banning the server.
+        for client in waiting {
+            assert_eq!(client.await.unwrap(), Some(Error::CheckoutTimeout));
+        }
+        assert_eq!(pool.waiting_clients(), 0);
+        assert!(!pool.is_banned(&address));
+        assert_eq!(pool.checkout_wait_histogram().count(), 3);
+
+        // Returning the connection makes room for another one.
+        drop(conn);
+        assert!(pool.get(Some(0), None, &client_stats, None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_replica_lag() {
+        // Replication lag (seconds) reported by the replica, NULL when None.
+        let lag = Arc::new(Mutex::new(Some("0.5")));
+        let reported_lag = lag.clone();
+        let lagging = Address {
+            id: 1,
+            ..mock_server(move |_| {
+                let mut row = BytesMut::new();
+                row.put_i16(1);
+                match *reported_lag.lock() {
+                    Some(lag) => {
+                        row.put_i32(lag.len() as i32);
+                        row.put_slice(lag.as_bytes());
+                    }
+                    None => row.put_i32(-1),
+                }
+
+                let mut response = BytesMut::new();
+                response.put_u8(b'D');
+                response.put_i32(row.len() as i32 + 4);
+                response.put(row);
+                response.put_u8(b'C');
+                response.put_i32(13);
+                response.put_slice(b"SELECT 1\0");
+                response
+            })
+            .await
+        };
+        let healthy = Address {
+            address_index: 1,
+            ..Default::default()
+        };
+        let pool = ConnectionPool {
+            databases: Arc::new(vec![vec![server_pool(&lagging), server_pool(&healthy)]]),
+            addresses: Arc::new(vec![vec![lagging.clone(), healthy.clone()]]),
+            ..pool_with_settings(PoolSettings {
+                max_replica_lag: Some(10),
+                ..Default::default()
+            })
+        };
+
+        pool.check_replica_lag(&lagging).await;
+        assert_eq!(pool.replica_lag(&lagging), None);
+        assert_eq!(pool.candidates(None, Some(Role::Replica)).len(), 2);
+
+        *lag.lock() = Some("42.25");
+        pool.check_replica_lag(&lagging).await;
+        assert_eq!(pool.replica_lag(&lagging), Some(42.25));
+        for _ in 0..100 {
+            assert_eq!(pool.candidates(None, Some(Role::Replica)), vec![&healthy]);
+        }
+        // Not a ban.
+        assert!(pool.get_bans().is_empty());
+
+        // Not replaying anything yet.
+        *lag.lock() = None;
+        pool.check_replica_lag(&lagging).await;
+        assert_eq!(pool.replica_lag(&lagging), None);
+        assert_eq!(pool.candidates(None, Some(Role::Replica)).len(), 2);
+    }
+
+    /// Address the pool would check out, without connecting to it.
+    async fn next_candidate(pool: &ConnectionPool, role: Option<Role>) -> Option<Address> {
+        for address in pool.candidates(Some(0), role).into_iter().rev() {
+            if !pool.is_banned(address) || pool.try_unban(address).await {
+                return Some(address.clone());
+            }
+        }
+        None
+    }
+
+    #[tokio::test]
+    async fn test_on_all_replicas_banned() {
+        let primary = Address {
+            role: Role::Primary,
+            ..Default::default()
+        };
+        let replicas: Vec<_> = (1..3)
+            .map(|index| Address {
+                id: index,
+                address_index: index,
+                ..Default::default()
+            })
+            .collect();
+        let addresses = Arc::new(vec![vec![
+            primary.clone(),
+            replicas[0].clone(),
+            replicas[1].clone(),
+        ]]);
+
+        let pool = ConnectionPool {
+            addresses: addresses.clone(),
+            ..pool_with_settings(PoolSettings {
+                on_all_replicas_banned: OnAllReplicasBanned::FailoverPrimary,
+                ..Default::default()
+            })
+        };
+
+        // Replicas are preferred while they are healthy.
+        pool.ban(&replicas[0], BanReason::FailedHealthCheck, None);
+        assert_eq!(
+            next_candidate(&pool, Some(Role::Replica)).await,
+            Some(replicas[1].clone())
+        );
+
+        // All replicas banned: a SELECT lands on the primary and the replicas stay banned.
+        pool.ban(&replicas[1], BanReason::FailedHealthCheck, None);
+        assert_eq!(
+            next_candidate(&pool, Some(Role::Replica)).await,
+            Some(primary.clone())
+        );
+        assert!(pool.is_banned(&replicas[0]));
+        assert!(pool.is_banned(&replicas[1]));
+
+        // Replicas are used again once their ban expires.
+        for (_, timestamp, _) in pool.banlist.write()[0].values_mut() {
+            *timestamp -= chrono::Duration::seconds(120);
+        }
+        assert_ne!(
+            next_candidate(&pool, Some(Role::Replica)).await,
+            Some(primary.clone())
+        );
+
+        // By default, all the replicas are unbanned instead.
+        let pool = ConnectionPool {
+            addresses: addresses.clone(),
+            ..pool_with_settings(PoolSettings::default())
+        };
+        pool.ban(&replicas[0], BanReason::FailedHealthCheck, None);
+        pool.ban(&replicas[1], BanReason::FailedHealthCheck, None);
+        assert_ne!(
+            next_candidate(&pool, Some(Role::Replica)).await,
+            Some(primary.clone())
+        );
+        assert!(pool.get_bans().is_empty());
+
+        // Or the reads fail, and the replicas stay banned.
+        let pool = ConnectionPool {
+            addresses,
+            ..pool_with_settings(PoolSettings {
+                on_all_replicas_banned: OnAllReplicasBanned::Error,
+                ..Default::default()
+            })
+        };
+        let stats = ClientStats::default();
+        pool.ban(&replicas[0], BanReason::FailedHealthCheck, None);
+        pool.ban(&replicas[1], BanReason::FailedHealthCheck, None);
+        assert_eq!(next_candidate(&pool, Some(Role::Replica)).await, None);
+        assert_eq!(
+            pool.get(None, Some(Role::Replica), &stats, None)
+                .await
+                .err(),
+            Some(Error::AllReplicasBanned)
+        );
+        assert_eq!(pool.get_bans().len(), 2);
+
+        // Until a ban expires.
+        pool.banlist.write()[0].get_mut(&replicas[0]).unwrap().1 -= chrono::Duration::seconds(120);
+        assert_eq!(
+            next_candidate(&pool, Some(Role::Replica)).await,
+            Some(replicas[0].clone())
+        );
+        assert!(pool.is_banned(&replicas[1]));
+    }
+
+    #[test]
+    fn test_weighted_replicas() {
+        let replicas: Vec<_> = [3, 1, 0, 5]
+            .into_iter()
+            .enumerate()
+            .map(|(index, weight)| Address {
+                id: index,
+                address_index: index,
+                weight,
+                ..Default::default()
+            })
+            .collect();
+        let pool = ConnectionPool {
+            addresses: Arc::new(vec![replicas.clone()]),
+            ..pool_with_settings(PoolSettings::default())
+        };
+
+        // Banned replicas get no traffic, whatever their weight.
+        pool.ban(&replicas[3], BanReason::FailedHealthCheck, None);
+
+        let mut picks = [0; 4];
+        for _ in 0..10_000 {
+            let candidates = pool.candidates(None, Some(Role::Replica));
+            // The drained replica isn't even tried.
+            assert_eq!(candidates.len(), 3);
+            picks[candidates.last().unwrap().id] += 1;
+        }
+
+        // Expected 7500 and 2500.
+        assert!((7_000..8_000).contains(&picks[0]), "{:?}", picks);
+        assert!((2_000..3_000).contains(&picks[1]), "{:?}", picks);
+        assert_eq!(picks[2], 0);
+        assert_eq!(picks[3], 0);
+
+        // Drained replicas don't count towards all replicas being banned.
+        assert!(!pool.all_replicas_banned(0));
+        pool.ban(&replicas[0], BanReason::FailedHealthCheck, None);
+        pool.ban(&replicas[1], BanReason::FailedHealthCheck, None);
+        assert!(pool.all_replicas_banned(0));
+    }
+
+    #[test]
+    fn test_drain() {
+        let primary = Address {
+            role: Role::Primary,
+            ..Default::default()
+        };
+        let replicas: Vec<_> = (1..3)
+            .map(|index| Address {
+                id: index,
+                address_index: index,
+                host: format!("replica-{}", index),
+                ..Default::default()
+            })
+            .collect();
+        let pool = ConnectionPool {
+            addresses: Arc::new(vec![vec![
+                primary.clone(),
+                replicas[0].clone(),
+                replicas[1].clone(),
+            ]]),
+            ..pool_with_settings(PoolSettings {
+                on_all_replicas_banned: OnAllReplicasBanned::FailoverPrimary,
+                ..Default::default()
+            })
+        };
+
+        assert!(!pool.drain(&primary));
+        assert!(pool.drain(&replicas[0]));
+        assert!(!pool.drain(&replicas[0]));
+        assert!(pool.is_drained(&replicas[0]));
+
+        for _ in 0..100 {
+            let candidates = pool.candidates(None, Some(Role::Replica));
+            assert_eq!(candidates, vec![&replicas[1]]);
+        }
+
+        // Not a ban, nor an error.
+        assert!(pool.get_bans().is_empty());
+        assert_eq!(replicas[0].error_count.load(Ordering::Relaxed), 0);
+        assert_eq!(replicas[0].stats.connection_errors(), 0);
+
+        // The remaining replica being banned is enough to fail over to the primary.
+        pool.ban(&replicas[1], BanReason::FailedHealthCheck, None);
+        assert!(pool.all_replicas_banned(0));
+        pool.unban(&replicas[1]);
+
+        assert!(pool.undrain(&replicas[0]));
+        assert!(!pool.undrain(&replicas[0]));
+        assert_eq!(pool.candidates(None, Some(Role::Replica)).len(), 2);
+    }
+
+    #[test]
+    fn test_least_busy_last() {
+        use std::collections::HashSet;
+
+        let replicas: Vec<_> = (0..3)
+            .map(|index| Address {
+                id: index,
+                address_index: index,
+                ..Default::default()
+            })
+            .collect();
+        // Slow queries stacked on the first replica.
+        let busy = |address: &Address| if address.id == 0 { 3 } else { 0 };
+        let pool = pool_with_settings(PoolSettings::default());
+        let mut picked = HashSet::new();
+
+        for _ in 0..100 {
+            let mut candidates: Vec<_> = replicas.iter().collect();
+            pool.weighted_shuffle(&mut candidates, &mut thread_rng());
+            least_busy_last(&mut candidates, busy);
+
+            assert_eq!(candidates[0].id, 0);
+            picked.insert(candidates.last().unwrap().id);
+        }
+
+        // Ties are broken randomly.
+        assert_eq!(picked, HashSet::from([1, 2]));
+    }
+
+    #[test]
+    fn test_latency_load_balancing() {
+        use rand::rngs::StdRng;
+        use rand::SeedableRng;
+        use std::time::Duration;
+
+        let pool = pool_with_settings(PoolSettings {
+            load_balancing_mode: LoadBalancingMode::Latency,
+            latency_ewma_decay: 50,
+            ..Default::default()
+        });
+        let replicas: Vec<_> = (0..3)
+            .map(|index| Address {
+                id: index,
+                address_index: index,
+                ..Default::default()
+            })
+            .collect();
+
+        // Nothing measured yet.
+        assert_eq!(replicas[0].stats.latency_ewma(), None);
+
+        pool.record_latency(&replicas[0], Duration::from_millis(20));
+        pool.record_latency(&replicas[0], Duration::from_millis(10));
+        assert_eq!(replicas[0].stats.latency_ewma(), Some(15_000.0));
+
+        pool.record_latency(&replicas[1], Duration::from_millis(1));
+        pool.record_latency(&replicas[2], Duration::from_millis(5));
+
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut picks = [0; 3];
+        for _ in 0..1000 {
+            let mut candidates: Vec<_> = replicas.iter().collect();
+            pool.weighted_shuffle(&mut candidates, &mut rng);
+            fastest_last(&mut candidates, &mut rng);
+            picks[candidates.last().unwrap().id] += 1;
+        }
+
+        // The fastest replica gets most queries, the others are still probed once in a while.
+        assert!(picks[1] > 900, "{:?}", picks);
+        assert!(picks[0] > 0, "{:?}", picks);
+        assert!(picks[2] > 0, "{:?}", picks);
+
+        // Replicas without latency samples are tried first.
+        let new_replica = Address {
+            id: 3,
+            ..Default::default()
+        };
+        let mut new_replica_picks = 0;
+        for _ in 0..100 {
+            let mut candidates = vec![&replicas[1], &new_replica, &replicas[0]];
+            fastest_last(&mut candidates, &mut rng);
+            if candidates.last().unwrap().id == 3 {
+                new_replica_picks += 1;
+            }
+        }
+        assert!(new_replica_picks > 90, "{}", new_replica_picks);
+    }
+
+    #[test]
+    fn test_ban_error_threshold() {
+        let pool = pool_with_settings(PoolSettings {
+            ban_error_threshold: 3,
+            ..Default::default()
+        });
+        let flapping = Address::default();
+        let broken = Address {
+            id: 1,
+            ..Default::default()
+        };
+
+        // Fails twice, then succeeds: never banned.
+        pool.ban(&flapping, BanReason::FailedCheckout, None);
+        pool.ban(&flapping, BanReason::FailedHealthCheck, None);
+        assert!(!pool.is_banned(&flapping));
+        pool.decay_ban_errors(&flapping);
+        pool.ban(&flapping, BanReason::FailedCheckout, None);
+        assert!(!pool.is_banned(&flapping));
+
+        // Fails three times in a row: banned.
+        pool.ban(&broken, BanReason::FailedCheckout, None);
+        pool.ban(&broken, BanReason::FailedCheckout, None);
+        assert!(!pool.is_banned(&broken));
+        pool.ban(&broken, BanReason::FailedHealthCheck, None);
+        assert!(pool.is_banned(&broken));
+
+        // Admin bans are not subject to the threshold.
+        pool.ban(&flapping, BanReason::AdminBan(60), None);
+        assert!(pool.is_banned(&flapping));
+    }
+
+    #[test]
+    fn test_ban_error_window() {
+        let pool = pool_with_settings(PoolSettings {
+            ban_error_threshold: 2,
+            ban_error_window: 10,
+            ..Default::default()
+        });
+        let address = Address::default();
+        let now = chrono::offset::Utc::now().naive_utc();
+
+        assert!(!pool.ban_error_threshold_reached(&address, now));
+        // The first error is out of the window by now.
+        assert!(!pool.ban_error_threshold_reached(&address, now + chrono::Duration::seconds(11)));
+        assert!(pool.ban_error_threshold_reached(&address, now + chrono::Duration::seconds(12)));
+    }
+
+    #[test]
+    fn test_persist_restore_bans() {
+        let replica = |id, host: &str| Address {
+            id,
+            host: host.to_string(),
+            ..Default::default()
+        };
+        let pool = ConnectionPool {
+            addresses: Arc::new(vec![vec![
+                replica(0, "replica-1"),
+                replica(1, "replica-2"),
+                replica(2, "replica-3"),
+            ]]),
+            ..pool_with_settings(PoolSettings::default())
+        };
+
+        pool.ban(&replica(0, "replica-1"), BanReason::FailedHealthCheck, None);
+        pool.ban(&replica(1, "replica-2"), BanReason::AdminBan(600), None);
+        pool.ban(&replica(2, "replica-3"), BanReason::StatementTimeout, None);
+
+        let mut bans = pool.persist();
+        bans.sort_by(|a, b| a.host.cmp(&b.host));
+        assert_eq!(bans.len(), 3);
+        assert_eq!(bans[1].reason, BanReason::AdminBan(600));
+        assert_eq!(bans[1].duration, 600);
+
+        // Expired while PgCat was down.
+        bans[2].timestamp -= 3600;
+
+        let bans: Vec<PersistedBan> =
+            serde_json::from_str(&serde_json::to_string(&bans).unwrap()).unwrap();
+
+        // New config: ids changed and replica-2 is gone.
+        let restored_pool = ConnectionPool {
+            addresses: Arc::new(vec![vec![
+                replica(5, "replica-1"),
+                replica(6, "replica-3"),
+                replica(7, "replica-4"),
+            ]]),
+            ..pool_with_settings(PoolSettings::default())
+        };
+
+        assert_eq!(restored_pool.restore(&bans), 1);
+        assert!(restored_pool.is_banned(&replica(5, "replica-1")));
+        assert!(!restored_pool.is_banned(&replica(6, "replica-3")));
+        assert!(!restored_pool.is_banned(&replica(7, "replica-4")));
+        assert_eq!(restored_pool.persist()[0].timestamp, bans[0].timestamp);
+    }
+
+    #[test]
+    fn test_ban_backoff_reset() {
+        let pool = pool_with_settings(PoolSettings {
+            ban_time: 10,
+            ban_backoff_base: 2,
+            ..Default::default()
+        });
+        let address = Address::default();
+        let now = chrono::offset::Utc::now().naive_utc().timestamp();
+
+        let reason = BanReason::FailedHealthCheck;
+
+        assert_eq!(pool.backoff_ban_time(&address, &reason, now), 10);
+        assert_eq!(pool.backoff_ban_time(&address, &reason, now + 15), 20);
+
+        // Healthy for longer than the last ban window (20s) after it expired.
+        assert_eq!(
+            pool.backoff_ban_time(&address, &reason, now + 15 + 20 + 21),
+            10
+        );
+        assert_eq!(address.consecutive_bans(), 1);
+    }
+
+    #[test]
+    fn test_ban_times_per_reason() {
+        let pool = pool_with_settings(PoolSettings {
+            ban_time: 30,
+            ban_times: BTreeMap::from([
+                ("StatementTimeout".to_string(), 5),
+                ("FailedHealthCheck".to_string(), 60),
+            ]),
+            ..Default::default()
+        });
+        let timed_out = Address {
+            host: "replica-1".into(),
+            ..Default::default()
+        };
+        let unhealthy = Address {
+            host: "replica-2".into(),
+            ..Default::default()
+        };
+        let failed_checkout = Address {
+            host: "replica-3".into(),
+            ..Default::default()
+        };
+
+        pool.ban(&timed_out, BanReason::StatementTimeout, None);
+        pool.ban(&unhealthy, BanReason::FailedHealthCheck, None);
+        pool.ban(&failed_checkout, BanReason::FailedCheckout, None);
+        assert_eq!(ban_duration(&pool, &timed_out), 5);
+        assert_eq!(ban_duration(&pool, &unhealthy), 60);
+        assert_eq!(ban_duration(&pool, &failed_checkout), 30);
+
+        // Move all bans back in time.
+        let age_bans = |seconds: i64| {
+            for (_, timestamp, _) in pool.banlist.write()[0].values_mut() {
+                *timestamp -= chrono::Duration::seconds(seconds);
+            }
+        };
+
+        age_bans(10);
+        assert_eq!(pool.expired_bans(), vec![timed_out.clone()]);
+
+        age_bans(25);
+        let mut expired = pool.expired_bans();
+        expired.sort_by(|a, b| a.host.cmp(&b.host));
+        assert_eq!(expired, vec![timed_out.clone(), failed_checkout.clone()]);
+
+        age_bans(30);
+        assert_eq!(pool.expired_bans().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_get_pinned_unavailable() {
+        let replicas = (0..2)
+            .map(|i| Address {
+                host: format!("replica-{}", i),
+                replica_number: i,
+                address_index: i,
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let pool = ConnectionPool {
+            addresses: Arc::new(vec![replicas.clone()]),
+            ..ConnectionPool::with_banlist(1)
+        };
+        let stats = ClientStats::default();
+
+        // The other replica is fine, but the session has to stay on this one.
+        pool.ban(&replicas[0], BanReason::FailedHealthCheck, None);
+        assert_eq!(
+            pool.get_pinned(&replicas[0], &stats, None).await.err(),
+            Some(Error::PinnedServerUnavailable(
+                "pool_name_shard_0_replica_0 is banned or down".into()
+            ))
+        );
+
+        // Removed by a config reload.
+        let removed = Address {
+            host: "replica-2".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            pool.get_pinned(&removed, &stats, None).await.err(),
+            Some(Error::PinnedServerUnavailable(
+                "pool_name_shard_0_replica_0 is not in the pool anymore".into()
+            ))
+        );
+    }
+
+    fn parse_message(name: &str, query: &str) -> Parse {
+        let mut message = BytesMut::new();
+        message.put_u8(b'P');
+        message.put_i32(4 + name.len() as i32 + 1 + query.len() as i32 + 1 + 2);
+        message.put_slice(name.as_bytes());
+        message.put_u8(0);
+        message.put_slice(query.as_bytes());
+        message.put_u8(0);
+        message.put_i16(0);
+        (&message).try_into().unwrap()
+    }
+
+    #[test]
+    fn test_prepared_statement_cache_shared_by_clients() {
+        let pool = ConnectionPool {
+            prepared_statement_cache: Some(Arc::new(Mutex::new(PreparedStatementCache::new(10)))),
+            ..ConnectionPool::with_banlist(1)
+        };
+
+        // Two clients preparing the same query with their own names.
+        let first = parse_message("client_1_statement", "SELECT $1::int");
+        let second = parse_message("s1", "SELECT $1::int");
+        let other = parse_message("s2", "SELECT $1::bigint");
+
+        let first = pool
+            .register_parse_to_cache(first.get_hash(), &first)
+            .unwrap();
+        let second = pool
+            .register_parse_to_cache(second.get_hash(), &second)
+            .unwrap();
+        let other = pool
+            .register_parse_to_cache(other.get_hash(), &other)
+            .unwrap();
+
+        // Same server side statement, each server connection only parses it once.
+        assert_eq!(first.name, second.name);
+        assert!(first.name.starts_with("PGCAT_"));
+        assert_ne!(first.name, other.name);
+    }
+
+    #[tokio::test]
+    async fn test_replication_connection_to_primary() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::TcpListener;
+
+        let replica = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let primary = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = |listener: &TcpListener, role| Address {
+            host: "127.0.0.1".into(),
+            port: listener.local_addr().unwrap().port(),
+            database: "shard0".into(),
+            role,
+            ..Default::default()
+        };
+        let addresses = vec![
+            address(&replica, Role::Replica),
+            address(&primary, Role::Primary),
+        ];
+
+        let (startup_sender, startup_receiver) = tokio::sync::oneshot::channel();
+        tokio::task::spawn(async move {
+            let (mut stream, _) = primary.accept().await.unwrap();
+            let len = stream.read_i32().await.unwrap();
+            let mut startup = vec![0u8; len as usize - 4];
+            stream.read_exact(&mut startup).await.unwrap();
+            startup_sender.send(startup).unwrap();
+
+            let mut response = BytesMut::new();
+            response.put_u8(b'R');
+            response.put_i32(8);
+            response.put_i32(0);
+            response.put_u8(b'K');
+            response.put_i32(12);
+            response.put_i32(1);
+            response.put_i32(2);
+            response.put_u8(b'Z');
+            response.put_i32(5);
+            response.put_u8(b'I');
+            stream.write_all(&response).await.unwrap();
+
+            // Keep the connection open.
+            let _ = stream.read(&mut [0u8; 1]).await;
+        });
+
+        let mut settings = PoolSettings::default();
+        settings.user.username = "replicator".into();
+        let pool = ConnectionPool {
+            addresses: Arc::new(vec![addresses]),
+            ..pool_with_settings(settings)
+        };
+
+        let server = pool
+            .replication_connection(0, "database", ClientServerMap::default())
+            .await
+            .unwrap();
+        assert_eq!(server.address().role, Role::Primary);
+
+        let startup = String::from_utf8_lossy(&startup_receiver.await.unwrap()[4..]).to_string();
+        assert!(startup.contains("user\0replicator\0"));
+        assert!(startup.contains("database\0shard0\0"));
+        assert!(startup.contains("replication\0database\0"));
+
+        // Nothing connected to the replica.
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(100), replica.accept())
+                .await
+                .is_err()
+        );
+
+        // Shards without a primary can't replicate.
+        let pool = ConnectionPool {
+            addresses: Arc::new(vec![vec![address(&replica, Role::Replica)]]),
+            ..pool_with_settings(PoolSettings::default())
+        };
+        assert!(pool
+            .replication_connection(0, "database", ClientServerMap::default())
+            .await
+            .is_err());
+    }
+}
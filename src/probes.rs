@@ -0,0 +1,169 @@
+//! Liveness and readiness probes over HTTP, e.g. for Kubernetes.
+use http_body_util::Full;
+use hyper::body;
+use hyper::body::Bytes;
+use hyper::server::conn::http1;
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::TokioIo;
+use log::{error, info};
+use std::net::SocketAddr;
+use tokio::net::TcpListener;
+
+use crate::config::{reload_failed, Role};
+use crate::pool::{get_all_pools, PoolMap};
+
+/// Why PgCat can't serve clients, if it can't: the config didn't load or no pool has a server
+/// that isn't banned.
+pub fn not_ready_reason(pools: &PoolMap, reload_failed: bool) -> Option<String> {
+    if reload_failed {
+        return Some("the last config reload failed".into());
+    }
+
+    let usable = pools.values().any(|pool| {
+        (0..pool.shards()).any(|shard| {
+            (0..pool.servers(shard)).any(|server| {
+                let address = pool.address(shard, server);
+                address.role != Role::Mirror && !pool.is_banned(address)
+            })
+        })
+    });
+
+    match usable {
+        true => None,
+        false => Some("no pool has a server that isn't banned".into()),
+    }
+}
+
+/// The response to `GET /healthz` or `GET /readyz`, if the request is one of them.
+pub fn probe<B>(request: &Request<B>) -> Option<Result<Response<Full<Bytes>>, hyper::http::Error>> {
+    probe_response(request.method(), request.uri().path(), || {
+        not_ready_reason(&get_all_pools(), reload_failed())
+    })
+}
+
+fn probe_response(
+    method: &Method,
+    path: &str,
+    not_ready_reason: impl FnOnce() -> Option<String>,
+) -> Option<Result<Response<Full<Bytes>>, hyper::http::Error>> {
+    match (method, path) {
+        // The process answers, it's alive.
+        (&Method::GET, "/healthz") => Some(Response::builder().body("ok\n".into())),
+
+        (&Method::GET, "/readyz") => Some(match not_ready_reason() {
+            None => Response::builder().body("ok\n".into()),
+            Some(reason) => Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(format!("{}\n", reason).into()),
+        }),
+
+        _ => None,
+    }
+}
+
+async fn probes(
+    request: Request<body::Incoming>,
+) -> Result<Response<Full<Bytes>>, hyper::http::Error> {
+    match probe(&request) {
+        Some(response) => response,
+        None => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body("".into()),
+    }
+}
+
+pub async fn start_probe_server(http_addr: SocketAddr) {
+    let listener = match TcpListener::bind(http_addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind probe server to HTTP address: {}.", e);
+            return;
+        }
+    };
+    info!(
+        "Exposing probes on http://{}/healthz and http://{}/readyz.",
+        http_addr, http_addr
+    );
+    loop {
+        let stream = match listener.accept().await {
+            Ok((stream, _)) => stream,
+            Err(e) => {
+                error!("Error accepting connection: {}", e);
+                continue;
+            }
+        };
+        let io = TokioIo::new(stream);
+
+        tokio::task::spawn(async move {
+            if let Err(err) = http1::Builder::new()
+                .serve_connection(io, service_fn(probes))
+                .await
+            {
+                error!("Error serving HTTP connection for probes: {:?}", err);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Address;
+    use crate::pool::{BanReason, ConnectionPool, PoolIdentifier};
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let replica = Address::default();
+        let other_replica = Address {
+            address_index: 1,
+            ..Default::default()
+        };
+        let pool =
+            ConnectionPool::with_addresses(vec![vec![replica.clone(), other_replica.clone()]]);
+        let pools = PoolMap::from([(PoolIdentifier::new("db", "user"), pool.clone())]);
+
+        assert_eq!(not_ready_reason(&pools, false), None);
+        assert_eq!(
+            not_ready_reason(&pools, true),
+            Some("the last config reload failed".into())
+        );
+
+        // One server left is enough.
+        pool.ban(&replica, BanReason::FailedHealthCheck, None);
+        assert_eq!(not_ready_reason(&pools, false), None);
+
+        pool.ban(&other_replica, BanReason::FailedHealthCheck, None);
+        assert_eq!(
+            not_ready_reason(&pools, false),
+            Some("no pool has a server that isn't banned".into())
+        );
+
+        // Nor without any pool.
+        assert!(not_ready_reason(&PoolMap::new(), false).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_probe_endpoints() {
+        let address = Address::default();
+        let pool = ConnectionPool::with_addresses(vec![vec![address.clone()]]);
+        let pools = PoolMap::from([(PoolIdentifier::new("db", "user"), pool.clone())]);
+        let status = |method: Method, path: &str| {
+            probe_response(&method, path, || not_ready_reason(&pools, false))
+                .map(|response| response.unwrap().status())
+        };
+
+        assert_eq!(status(Method::GET, "/healthz"), Some(StatusCode::OK));
+        assert_eq!(status(Method::GET, "/readyz"), Some(StatusCode::OK));
+        assert_eq!(status(Method::POST, "/readyz"), None);
+        assert_eq!(status(Method::GET, "/metrics"), None);
+
+        // Banned, it's still alive but not ready.
+        pool.ban(&address, BanReason::FailedHealthCheck, None);
+        assert_eq!(status(Method::GET, "/healthz"), Some(StatusCode::OK));
+        assert_eq!(
+            status(Method::GET, "/readyz"),
+            Some(StatusCode::SERVICE_UNAVAILABLE)
+        );
+    }
+}
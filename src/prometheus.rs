@@ -14,10 +14,12 @@ use std::net::SocketAddr;
 use std::sync::atomic::Ordering;
 use tokio::net::TcpListener;
 
-use crate::config::Address;
-use crate::pool::{get_all_pools, PoolIdentifier};
-use crate::stats::get_server_stats;
+use crate::client_connections::client_connections;
+use crate::config::{get_config, Address};
+use crate::pool::{get_all_pools, PoolIdentifier, PoolMap};
+use crate::probes::probe;
 use crate::stats::pool::PoolStats;
+use crate::stats::{get_ban_counts, get_mirror_drop_counts, get_server_stats};
 
 struct MetricHelpType {
     help: &'static str,
@@ -100,6 +102,50 @@ static METRIC_HELP_AND_TYPES_LOOKUP: phf::Map<&'static str, MetricHelpType> = ph
         help: "Average of total_wait_time every 15 seconds",
         ty: "gauge",
     },
+    "stats_p50_query_time" => MetricHelpType {
+        help: "Estimated median query time, from the query time histogram",
+        ty: "gauge",
+    },
+    "stats_p90_query_time" => MetricHelpType {
+        help: "Estimated 90th percentile query time, from the query time histogram",
+        ty: "gauge",
+    },
+    "stats_p99_query_time" => MetricHelpType {
+        help: "Estimated 99th percentile query time, from the query time histogram",
+        ty: "gauge",
+    },
+    "stats_pool_size" => MetricHelpType {
+        help: "Number of connections that can be checked out from the server, changes when the pool is autoscaled",
+        ty: "gauge",
+    },
+    "stats_total_select_count" => MetricHelpType {
+        help: "Number of SELECT queries, when the query parser is enabled",
+        ty: "counter",
+    },
+    "stats_total_insert_count" => MetricHelpType {
+        help: "Number of INSERT queries, when the query parser is enabled",
+        ty: "counter",
+    },
+    "stats_total_update_count" => MetricHelpType {
+        help: "Number of UPDATE queries, when the query parser is enabled",
+        ty: "counter",
+    },
+    "stats_total_delete_count" => MetricHelpType {
+        help: "Number of DELETE queries, when the query parser is enabled",
+        ty: "counter",
+    },
+    "stats_total_ddl_count" => MetricHelpType {
+        help: "Number of schema changing queries, e.g. CREATE TABLE, when the query parser is enabled",
+        ty: "counter",
+    },
+    "stats_total_other_count" => MetricHelpType {
+        help: "Number of other queries, e.g. SET or BEGIN, when the query parser is enabled",
+        ty: "counter",
+    },
+    "stats_settings_resets_total" => MetricHelpType {
+        help: "Number of server connections returned with settings changed by a client, which were reset",
+        ty: "counter",
+    },
     "pools_maxwait_us" => MetricHelpType {
         help: "The time a client waited for a server connection in microseconds",
         ty: "gauge",
@@ -108,6 +154,18 @@ static METRIC_HELP_AND_TYPES_LOOKUP: phf::Map<&'static str, MetricHelpType> = ph
         help: "The time a client waited for a server connection in seconds",
         ty: "gauge",
     },
+    "pools_query_time" => MetricHelpType {
+        help: "Time taken for queries to execute in milliseconds",
+        ty: "histogram",
+    },
+    "waiting_clients" => MetricHelpType {
+        help: "How many clients are waiting to check out a server connection",
+        ty: "gauge",
+    },
+    "checkout_wait_seconds" => MetricHelpType {
+        help: "Time clients waited to check out a server connection in seconds",
+        ty: "histogram",
+    },
     "pools_cl_waiting" => MetricHelpType {
         help: "How many clients are waiting for a connection from the pool",
         ty: "gauge",
@@ -136,6 +194,38 @@ static METRIC_HELP_AND_TYPES_LOOKUP: phf::Map<&'static str, MetricHelpType> = ph
         help: "How many server connections are currently waiting on a health check to succeed",
         ty: "gauge",
     },
+    "pools_prepared_cache_hits_total" => MetricHelpType {
+        help: "Number of prepared statements parsed by clients that were already in the pool's cache",
+        ty: "counter",
+    },
+    "pools_prepared_cache_misses_total" => MetricHelpType {
+        help: "Number of prepared statements parsed by clients that weren't in the pool's cache",
+        ty: "counter",
+    },
+    "pools_prepared_cache_evictions_total" => MetricHelpType {
+        help: "Number of prepared statements removed from the pool's cache to make room for others",
+        ty: "counter",
+    },
+    "pools_prepared_cache_size" => MetricHelpType {
+        help: "Number of prepared statements in the pool's cache",
+        ty: "gauge",
+    },
+    "pools_cl_connections" => MetricHelpType {
+        help: "How many clients are logged in to the pool, across its users",
+        ty: "gauge",
+    },
+    "pools_max_client_connections" => MetricHelpType {
+        help: "How many clients can be logged in to the pool, 0 if unlimited",
+        ty: "gauge",
+    },
+    "client_connections" => MetricHelpType {
+        help: "How many clients are logged in to the pools",
+        ty: "gauge",
+    },
+    "max_client_connections" => MetricHelpType {
+        help: "How many clients can be logged in to the pools, 0 if unlimited",
+        ty: "gauge",
+    },
     "servers_bytes_received" => MetricHelpType {
         help: "Volume in bytes of network traffic received by server",
         ty: "counter",
@@ -180,6 +270,26 @@ static METRIC_HELP_AND_TYPES_LOOKUP: phf::Map<&'static str, MetricHelpType> = ph
         help: "0 if server is not paused, 1 if server is paused",
         ty: "gauge",
     },
+    "address_errors_total" => MetricHelpType {
+        help: "Number of errors counted towards banning the address",
+        ty: "counter",
+    },
+    "address_error_rate" => MetricHelpType {
+        help: "Errors per second counted towards banning the address, over about the last minute",
+        ty: "gauge",
+    },
+    "bans_total" => MetricHelpType {
+        help: "Number of times an address was banned, by reason",
+        ty: "counter",
+    },
+    "mirror_dropped_messages_total" => MetricHelpType {
+        help: "Number of messages not sent to a mirror because its queue was full",
+        ty: "counter",
+    },
+    "shards_banned_count" => MetricHelpType {
+        help: "Number of banned addresses in the shard",
+        ty: "gauge",
+    },
     "databases_pool_size" => MetricHelpType {
         help: "Maximum number of server connections",
         ty: "gauge",
@@ -289,6 +399,90 @@ impl<Value: fmt::Display> PrometheusMetric<Value> {
         Self::from_name(&format!("pools_{}", name), value, labels)
     }
 
+    /// The `_bucket`, `_sum` and `_count` series of a histogram of the pool.
+    fn from_pool_histogram(
+        pool_id: &PoolIdentifier,
+        name: &str,
+        bounds: &[f64],
+        buckets: &[u64],
+        sum: f64,
+    ) -> Option<Vec<PrometheusMetric<f64>>> {
+        let mut labels = HashMap::new();
+        labels.insert("pool", pool_id.db.clone());
+        labels.insert("user", pool_id.user.clone());
+
+        let series = |suffix: &str, value: f64, labels: HashMap<&'static str, String>| {
+            Self::from_name(name, value, labels).map(|mut metric| {
+                metric.name = format!("{}_{}", name, suffix);
+                metric
+            })
+        };
+
+        let mut metrics = Vec::new();
+        let mut cumulative = 0;
+        for (index, samples) in buckets.iter().enumerate() {
+            cumulative += samples;
+            let mut labels = labels.clone();
+            labels.insert(
+                "le",
+                match bounds.get(index) {
+                    Some(bound) => bound.to_string(),
+                    None => "+Inf".to_string(),
+                },
+            );
+            metrics.push(series("bucket", cumulative as f64, labels)?);
+        }
+        metrics.push(series("sum", sum, labels.clone())?);
+        metrics.push(series("count", cumulative as f64, labels)?);
+
+        Some(metrics)
+    }
+
+    fn from_address_errors<V: fmt::Display>(
+        address: &Address,
+        name: &str,
+        value: V,
+    ) -> Option<PrometheusMetric<V>> {
+        let mut labels = HashMap::new();
+        labels.insert("host", address.host.clone());
+        labels.insert("port", address.port.to_string());
+        labels.insert("shard", address.shard.to_string());
+        labels.insert("role", address.role.to_string());
+        labels.insert("pool", address.pool_name.clone());
+        labels.insert("username", address.username.clone());
+
+        Self::from_name(&format!("address_{}", name), value, labels)
+    }
+
+    fn from_ban(reason: &str, address: &str, value: u64) -> Option<PrometheusMetric<u64>> {
+        let mut labels = HashMap::new();
+        labels.insert("reason", reason.to_string());
+        labels.insert("address", address.to_string());
+
+        Self::from_name("bans_total", value, labels)
+    }
+
+    fn from_mirror(address: &str, value: u64) -> Option<PrometheusMetric<u64>> {
+        let mut labels = HashMap::new();
+        labels.insert("address", address.to_string());
+
+        Self::from_name("mirror_dropped_messages_total", value, labels)
+    }
+
+    fn from_shard(
+        pool_id: &PoolIdentifier,
+        shard: usize,
+        name: &str,
+        value: u64,
+    ) -> Option<PrometheusMetric<u64>> {
+        let mut labels = HashMap::new();
+        labels.insert("pool", pool_id.db.clone());
+        labels.insert("user", pool_id.user.clone());
+        labels.insert("shard", shard.to_string());
+
+        Self::from_name(&format!("shards_{}", name), value, labels)
+    }
+
     fn get_header(&self) -> String {
         format!(
             "\n# HELP {name} {help}\n# TYPE {name} {ty}",
@@ -307,17 +501,27 @@ async fn prometheus_stats(
             let mut lines = Vec::new();
             push_address_stats(&mut lines);
             push_pool_stats(&mut lines);
+            push_pool_histograms(&mut lines, &get_all_pools());
+            push_checkout_stats(&mut lines, &get_all_pools());
+            push_prepared_statement_cache_stats(&mut lines, &get_all_pools());
             push_server_stats(&mut lines);
             push_database_stats(&mut lines);
+            push_ban_stats(&mut lines, &get_all_pools());
+            push_address_error_stats(&mut lines, &all_addresses(&get_all_pools()));
+            push_mirror_stats(&mut lines);
+            push_client_connection_stats(&mut lines);
             lines.push("".to_string()); // Ensure to end the stats with a line terminator as required by the specification.
 
             Response::builder()
                 .header("content-type", "text/plain; version=0.0.4")
                 .body(lines.join("\n").into())
         }
-        _ => Response::builder()
-            .status(StatusCode::NOT_FOUND)
-            .body("".into()),
+        _ => match probe(&request) {
+            Some(response) => response,
+            None => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body("".into()),
+        },
     }
 }
 
@@ -329,7 +533,14 @@ fn push_address_stats(lines: &mut Vec<String>) {
             for server in 0..pool.servers(shard) {
                 let address = pool.address(shard, server);
                 let stats = &*address.stats;
-                for (key, value) in stats.clone() {
+                let pool_size = ("pool_size".to_string(), stats.pool_size());
+                let settings_resets =
+                    ("settings_resets_total".to_string(), stats.settings_resets());
+                for (key, value) in stats
+                    .clone()
+                    .into_iter()
+                    .chain([pool_size, settings_resets])
+                {
                     if let Some(prometheus_metric) =
                         PrometheusMetric::<u64>::from_address(address, &key, value)
                     {
@@ -382,6 +593,54 @@ fn push_pool_stats(lines: &mut Vec<String>) {
     }
 }
 
+// Adds the query time histograms of the pools, summed over their servers.
+fn push_pool_histograms(lines: &mut Vec<String>, pools: &PoolMap) {
+    let mut metrics = Vec::new();
+
+    for (pool_id, pool) in pools {
+        let mut bounds: &[u64] = &[];
+        let mut buckets: Vec<u64> = Vec::new();
+        let mut sum = 0.0;
+
+        for shard in 0..pool.shards() {
+            for server in 0..pool.servers(shard) {
+                let histogram = pool.address(shard, server).stats.query_time_histogram();
+                bounds = histogram.bounds();
+                buckets.resize(bounds.len() + 1, 0);
+                for (total, samples) in buckets.iter_mut().zip(histogram.buckets()) {
+                    *total += samples;
+                }
+                sum += histogram.sum();
+            }
+        }
+
+        if buckets.is_empty() {
+            continue;
+        }
+
+        let bounds = bounds.iter().map(|bound| *bound as f64).collect::<Vec<_>>();
+        match PrometheusMetric::<f64>::from_pool_histogram(
+            pool_id,
+            "pools_query_time",
+            &bounds,
+            &buckets,
+            sum,
+        ) {
+            Some(histogram) => metrics.extend(histogram),
+            None => debug!("Metric query_time not implemented for ({})", pool_id),
+        }
+    }
+
+    // The header is for the histogram, not each of its series.
+    let header = PrometheusMetric::<f64>::from_name("pools_query_time", 0.0, HashMap::new());
+    if let (false, Some(header)) = (metrics.is_empty(), header) {
+        lines.push(header.get_header());
+        for metric in metrics {
+            lines.push(metric.to_string());
+        }
+    }
+}
+
 // Adds relevant metrics shown in a SHOW DATABASES admin command.
 fn push_database_stats(lines: &mut Vec<String>) {
     let mut grouped_metrics: HashMap<String, Vec<PrometheusMetric<u32>>> = HashMap::new();
@@ -495,6 +754,206 @@ fn push_server_stats(lines: &mut Vec<String>) {
     }
 }
 
+// Adds ban counters by reason and the number of banned addresses per shard.
+fn push_ban_stats(lines: &mut Vec<String>, pools: &PoolMap) {
+    let mut ban_counts: Vec<_> = get_ban_counts().into_iter().collect();
+    ban_counts.sort();
+    let metrics: Vec<_> = ban_counts
+        .iter()
+        .filter_map(|((reason, address), count)| {
+            PrometheusMetric::<u64>::from_ban(reason, address, *count)
+        })
+        .collect();
+    if !metrics.is_empty() {
+        lines.push(metrics[0].get_header());
+        for metric in metrics {
+            lines.push(metric.to_string());
+        }
+    }
+
+    let mut metrics = Vec::new();
+    for (pool_id, pool) in pools {
+        for (shard, count) in pool.banned_count_per_shard().into_iter().enumerate() {
+            if let Some(metric) =
+                PrometheusMetric::<u64>::from_shard(pool_id, shard, "banned_count", count)
+            {
+                metrics.push(metric);
+            }
+        }
+    }
+    if !metrics.is_empty() {
+        lines.push(metrics[0].get_header());
+        for metric in metrics {
+            lines.push(metric.to_string());
+        }
+    }
+}
+
+// Adds the clients waiting to check out a connection, and how long they waited, per pool.
+fn push_checkout_stats(lines: &mut Vec<String>, pools: &PoolMap) {
+    let mut waiting = Vec::new();
+    let mut waits = Vec::new();
+
+    for (pool_id, pool) in pools {
+        let mut labels = HashMap::new();
+        labels.insert("pool", pool_id.db.clone());
+        labels.insert("user", pool_id.user.clone());
+        waiting.extend(PrometheusMetric::<u64>::from_name(
+            "waiting_clients",
+            pool.waiting_clients(),
+            labels,
+        ));
+
+        // Recorded in milliseconds.
+        let histogram = pool.checkout_wait_histogram();
+        let bounds = histogram
+            .bounds()
+            .iter()
+            .map(|bound| *bound as f64 / 1_000.0)
+            .collect::<Vec<_>>();
+        waits.extend(
+            PrometheusMetric::<f64>::from_pool_histogram(
+                pool_id,
+                "checkout_wait_seconds",
+                &bounds,
+                &histogram.buckets(),
+                histogram.sum() / 1_000.0,
+            )
+            .into_iter()
+            .flatten(),
+        );
+    }
+
+    if !waiting.is_empty() {
+        lines.push(waiting[0].get_header());
+        lines.extend(waiting.iter().map(|metric| metric.to_string()));
+    }
+
+    // The header is for the histogram, not each of its series.
+    let header = PrometheusMetric::<f64>::from_name("checkout_wait_seconds", 0.0, HashMap::new());
+    if let (false, Some(header)) = (waits.is_empty(), header) {
+        lines.push(header.get_header());
+        lines.extend(waits.iter().map(|metric| metric.to_string()));
+    }
+}
+
+// Adds the hits, misses and evictions of the prepared statement caches of the pools.
+fn push_prepared_statement_cache_stats(lines: &mut Vec<String>, pools: &PoolMap) {
+    let names = [
+        "prepared_cache_hits_total",
+        "prepared_cache_misses_total",
+        "prepared_cache_evictions_total",
+        "prepared_cache_size",
+    ];
+
+    let mut pools: Vec<_> = pools
+        .iter()
+        .filter_map(|(pool_id, pool)| {
+            pool.prepared_statement_cache_stats().map(|stats| {
+                (
+                    pool_id,
+                    [stats.hits, stats.misses, stats.evictions, stats.size],
+                )
+            })
+        })
+        .collect();
+    pools.sort_by_key(|(pool_id, _)| (pool_id.db.clone(), pool_id.user.clone()));
+
+    for (index, name) in names.iter().enumerate() {
+        let metrics: Vec<_> = pools
+            .iter()
+            .filter_map(|(pool_id, values)| {
+                PrometheusMetric::<u64>::from_pool((*pool_id).clone(), name, values[index])
+            })
+            .collect();
+        if !metrics.is_empty() {
+            lines.push(metrics[0].get_header());
+            lines.extend(metrics.iter().map(|metric| metric.to_string()));
+        }
+    }
+}
+
+// Adds the errors counted towards banning each address, and their recent rate.
+fn push_address_error_stats(lines: &mut Vec<String>, addresses: &[Address]) {
+    let errors: Vec<_> = addresses
+        .iter()
+        .filter_map(|address| {
+            PrometheusMetric::<u64>::from_address_errors(
+                address,
+                "errors_total",
+                address.stats.connection_errors(),
+            )
+        })
+        .collect();
+    let rates: Vec<_> = addresses
+        .iter()
+        .filter_map(|address| {
+            PrometheusMetric::<f64>::from_address_errors(
+                address,
+                "error_rate",
+                address.stats.connection_error_rate(),
+            )
+        })
+        .collect();
+
+    if !errors.is_empty() {
+        lines.push(errors[0].get_header());
+        lines.extend(errors.iter().map(|metric| metric.to_string()));
+    }
+    if !rates.is_empty() {
+        lines.push(rates[0].get_header());
+        lines.extend(rates.iter().map(|metric| metric.to_string()));
+    }
+}
+
+fn all_addresses(pools: &PoolMap) -> Vec<Address> {
+    let mut addresses = Vec::new();
+    for pool in pools.values() {
+        for shard in 0..pool.shards() {
+            for server in 0..pool.servers(shard) {
+                addresses.push(pool.address(shard, server).clone());
+            }
+        }
+    }
+    addresses
+}
+
+// Adds the number of messages dropped per mirror.
+fn push_mirror_stats(lines: &mut Vec<String>) {
+    let mut drop_counts: Vec<_> = get_mirror_drop_counts().into_iter().collect();
+    drop_counts.sort();
+    let metrics: Vec<_> = drop_counts
+        .iter()
+        .filter_map(|(address, count)| PrometheusMetric::<u64>::from_mirror(address, *count))
+        .collect();
+    if !metrics.is_empty() {
+        lines.push(metrics[0].get_header());
+        for metric in metrics {
+            lines.push(metric.to_string());
+        }
+    }
+}
+
+// Adds the number of clients logged in, and how many can be.
+fn push_client_connection_stats(lines: &mut Vec<String>) {
+    let metrics = [
+        ("client_connections", client_connections()),
+        (
+            "max_client_connections",
+            get_config()
+                .general
+                .max_client_connections
+                .unwrap_or_default(),
+        ),
+    ];
+    for (name, value) in metrics {
+        if let Some(metric) = PrometheusMetric::<usize>::from_name(name, value, HashMap::new()) {
+            lines.push(metric.get_header());
+            lines.push(metric.to_string());
+        }
+    }
+}
+
 pub async fn start_metric_server(http_addr: SocketAddr) {
     let listener = TcpListener::bind(http_addr);
     let listener = match listener.await {
@@ -528,3 +987,202 @@ pub async fn start_metric_server(http_addr: SocketAddr) {
         });
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pool::{BanReason, ConnectionPool, PreparedStatementCache};
+    use crate::stats::get_reporter;
+    use parking_lot::Mutex;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_ban_stats() {
+        let pool = ConnectionPool::with_banlist(2);
+        let health_check = Address {
+            host: "ban-stats-1".into(),
+            ..Default::default()
+        };
+        let timeout = Address {
+            host: "ban-stats-2".into(),
+            shard: 1,
+            ..Default::default()
+        };
+        pool.ban(&health_check, BanReason::FailedHealthCheck, None);
+        pool.ban(&timeout, BanReason::StatementTimeout, None);
+
+        let mut pools = PoolMap::new();
+        pools.insert(PoolIdentifier::new("db", "user"), pool.clone());
+
+        let mut lines = Vec::new();
+        push_ban_stats(&mut lines, &pools);
+        assert!(lines.contains(
+            &"pgcat_bans_total{address=\"ban-stats-1:5432\",reason=\"FailedHealthCheck\"} 1"
+                .to_string()
+        ));
+        assert!(lines.contains(
+            &"pgcat_bans_total{address=\"ban-stats-2:5432\",reason=\"StatementTimeout\"} 1"
+                .to_string()
+        ));
+        assert!(lines.contains(
+            &"pgcat_shards_banned_count{pool=\"db\",shard=\"0\",user=\"user\"} 1".to_string()
+        ));
+        assert!(lines.contains(
+            &"pgcat_shards_banned_count{pool=\"db\",shard=\"1\",user=\"user\"} 1".to_string()
+        ));
+
+        pool.unban(&timeout);
+
+        let mut lines = Vec::new();
+        push_ban_stats(&mut lines, &pools);
+        assert!(lines.contains(
+            &"pgcat_bans_total{address=\"ban-stats-2:5432\",reason=\"StatementTimeout\"} 1"
+                .to_string()
+        ));
+        assert!(lines.contains(
+            &"pgcat_shards_banned_count{pool=\"db\",shard=\"1\",user=\"user\"} 0".to_string()
+        ));
+    }
+
+    #[test]
+    fn test_address_error_stats() {
+        let pool = ConnectionPool::with_banlist(1);
+        let address = Address {
+            host: "address-errors".into(),
+            pool_name: "db".into(),
+            username: "user".into(),
+            ..Default::default()
+        };
+
+        // Errors are counted with ban reasons like failed health checks, and never reset.
+        pool.ban(&address, BanReason::FailedHealthCheck, None);
+        pool.ban(&address, BanReason::MessageSendFailed, None);
+        address.reset_error_count();
+
+        let mut lines = Vec::new();
+        push_address_error_stats(&mut lines, &[address]);
+        assert!(lines.contains(
+            &"pgcat_address_errors_total{host=\"address-errors\",pool=\"db\",port=\"5432\",role=\"replica\",shard=\"0\",username=\"user\"} 2"
+                .to_string()
+        ));
+        assert!(lines.contains(
+            &"\n# HELP pgcat_address_error_rate Errors per second counted towards banning the address, over about the last minute\n# TYPE pgcat_address_error_rate gauge"
+                .to_string()
+        ));
+
+        let rate = lines
+            .iter()
+            .find(|line| line.starts_with("pgcat_address_error_rate{"))
+            .and_then(|line| line.rsplit(' ').next())
+            .map(|rate| rate.parse::<f64>().unwrap())
+            .unwrap();
+        assert!(rate > 0.03 && rate <= 2.0 / 60.0);
+    }
+
+    #[test]
+    fn test_mirror_stats() {
+        get_reporter().mirror_drop("mirror-stats:5432".into());
+        get_reporter().mirror_drop("mirror-stats:5432".into());
+
+        let mut lines = Vec::new();
+        push_mirror_stats(&mut lines);
+        assert!(lines.contains(
+            &"pgcat_mirror_dropped_messages_total{address=\"mirror-stats:5432\"} 2".to_string()
+        ));
+    }
+
+    #[test]
+    fn test_client_connection_stats() {
+        let mut lines = Vec::new();
+        push_client_connection_stats(&mut lines);
+        assert!(lines[0].ends_with("# TYPE pgcat_client_connections gauge"));
+        assert!(lines.contains(&"pgcat_max_client_connections{} 0".to_string()));
+    }
+
+    #[test]
+    fn test_checkout_stats() {
+        let pools = PoolMap::from([(
+            PoolIdentifier::new("db", "user"),
+            ConnectionPool::with_banlist(1),
+        )]);
+
+        let mut lines = Vec::new();
+        push_checkout_stats(&mut lines, &pools);
+
+        assert!(lines.contains(
+            &"\n# HELP pgcat_waiting_clients How many clients are waiting to check out a server connection\n# TYPE pgcat_waiting_clients gauge"
+                .to_string()
+        ));
+        assert!(lines.contains(&"pgcat_waiting_clients{pool=\"db\",user=\"user\"} 0".to_string()));
+        assert!(lines.contains(
+            &"pgcat_checkout_wait_seconds_bucket{le=\"0.001\",pool=\"db\",user=\"user\"} 0"
+                .to_string()
+        ));
+        assert!(lines.contains(
+            &"pgcat_checkout_wait_seconds_bucket{le=\"+Inf\",pool=\"db\",user=\"user\"} 0"
+                .to_string()
+        ));
+        assert!(lines.contains(
+            &"pgcat_checkout_wait_seconds_count{pool=\"db\",user=\"user\"} 0".to_string()
+        ));
+    }
+
+    #[test]
+    fn test_prepared_statement_cache_stats() {
+        let mut pool = ConnectionPool::with_banlist(1);
+        pool.prepared_statement_cache = Some(Arc::new(Mutex::new(PreparedStatementCache::new(10))));
+        let pools = PoolMap::from([
+            (PoolIdentifier::new("db", "user"), pool),
+            (
+                PoolIdentifier::new("uncached", "user"),
+                ConnectionPool::with_banlist(1),
+            ),
+        ]);
+
+        let mut lines = Vec::new();
+        push_prepared_statement_cache_stats(&mut lines, &pools);
+
+        assert!(lines.contains(
+            &"\n# HELP pgcat_pools_prepared_cache_hits_total Number of prepared statements parsed by clients that were already in the pool's cache\n# TYPE pgcat_pools_prepared_cache_hits_total counter"
+                .to_string()
+        ));
+        assert!(lines.contains(
+            &"pgcat_pools_prepared_cache_misses_total{pool=\"db\",user=\"user\"} 0".to_string()
+        ));
+        assert!(lines
+            .contains(&"pgcat_pools_prepared_cache_size{pool=\"db\",user=\"user\"} 0".to_string()));
+        assert!(!lines.iter().any(|line| line.contains("uncached")));
+    }
+
+    #[test]
+    fn test_pool_histogram() {
+        let histogram = crate::stats::Histogram::new(&[1, 5]);
+        histogram.record(std::time::Duration::from_micros(500));
+        histogram.record(std::time::Duration::from_millis(3));
+        histogram.record(std::time::Duration::from_millis(3));
+        histogram.record(std::time::Duration::from_millis(20));
+
+        let metrics = PrometheusMetric::<f64>::from_pool_histogram(
+            &PoolIdentifier::new("db", "user"),
+            "pools_query_time",
+            &[1.0, 5.0],
+            &histogram.buckets(),
+            histogram.sum(),
+        )
+        .unwrap()
+        .iter()
+        .map(|metric| metric.to_string())
+        .collect::<Vec<_>>();
+
+        assert_eq!(
+            metrics,
+            vec![
+                "pgcat_pools_query_time_bucket{le=\"1\",pool=\"db\",user=\"user\"} 1",
+                "pgcat_pools_query_time_bucket{le=\"5\",pool=\"db\",user=\"user\"} 3",
+                "pgcat_pools_query_time_bucket{le=\"+Inf\",pool=\"db\",user=\"user\"} 4",
+                "pgcat_pools_query_time_sum{pool=\"db\",user=\"user\"} 26.5",
+                "pgcat_pools_query_time_count{pool=\"db\",user=\"user\"} 4",
+            ]
+        );
+    }
+}
@@ -0,0 +1,303 @@
+//! PROXY protocol v1 and v2 headers, sent by TCP load balancers before the Postgres startup
+//! with the address of the client they connected us to.
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>.
+use bytes::{Buf, BytesMut};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::net::TcpStream;
+
+use crate::config::Network;
+use crate::errors::Error;
+
+/// Signature starting v2 headers.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Longest v1 header, `\r\n` included.
+const V1_MAX_LENGTH: usize = 107;
+
+/// Whether a connection starting with this byte sends a PROXY header. Startup messages
+/// start with their length, so with a 0.
+pub fn starts_header(first_byte: u8) -> bool {
+    first_byte == b'P' || first_byte == V2_SIGNATURE[0]
+}
+
+/// Address of the client of a connection from a load balancer in the networks, read from
+/// its PROXY header. Connections without one are from the client itself, but a header
+/// from anywhere else is refused: it could be anyone impersonating a client.
+pub async fn client_addr(
+    stream: &mut TcpStream,
+    peer_addr: SocketAddr,
+    networks: &[Network],
+) -> Result<SocketAddr, Error> {
+    let mut first_byte = [0u8; 1];
+    match stream.peek(&mut first_byte).await {
+        Ok(1) if starts_header(first_byte[0]) => (),
+        _ => return Ok(peer_addr),
+    }
+
+    if !networks
+        .iter()
+        .any(|network| network.contains(peer_addr.ip()))
+    {
+        return Err(Error::ClientError(format!(
+            "PROXY protocol header from {}, which isn't in proxy_protocol_networks",
+            peer_addr.ip()
+        )));
+    }
+
+    Ok(read_header(stream).await?.unwrap_or(peer_addr))
+}
+
+/// Read a v1 or v2 header. The source address is given for proxied TCP connections only,
+/// not for health checks of the load balancer or other protocols.
+pub async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>, Error>
+where
+    S: AsyncRead + std::marker::Unpin,
+{
+    let first_byte = read_u8(stream).await?;
+
+    if first_byte == b'P' {
+        read_v1(stream).await
+    } else {
+        read_v2(stream).await
+    }
+}
+
+/// `PROXY TCP4 <source> <destination> <source port> <destination port>\r\n`,
+/// the `P` already read.
+async fn read_v1<S>(stream: &mut S) -> Result<Option<SocketAddr>, Error>
+where
+    S: AsyncRead + std::marker::Unpin,
+{
+    let mut line = vec![b'P'];
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LENGTH {
+            return Err(bad_header("v1 header too long"));
+        }
+        line.push(read_u8(stream).await?);
+    }
+
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| bad_header("v1 header isn't ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, source_port, _] => {
+            let ip = source
+                .parse::<IpAddr>()
+                .map_err(|_| bad_header("invalid v1 source address"))?;
+            let port = source_port
+                .parse::<u16>()
+                .map_err(|_| bad_header("invalid v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(bad_header("invalid v1 header")),
+    }
+}
+
+/// Binary header after its signature, the first byte of which is already read.
+async fn read_v2<S>(stream: &mut S) -> Result<Option<SocketAddr>, Error>
+where
+    S: AsyncRead + std::marker::Unpin,
+{
+    let mut header = [0u8; 15];
+    read_exact(stream, &mut header).await?;
+
+    if header[..11] != V2_SIGNATURE[1..] {
+        return Err(bad_header("invalid v2 signature"));
+    }
+
+    let version_command = header[11];
+    let family = header[12];
+    let len = u16::from_be_bytes([header[13], header[14]]) as usize;
+
+    if version_command >> 4 != 2 {
+        return Err(bad_header("unsupported v2 version"));
+    }
+
+    let mut addresses = vec![0u8; len];
+    read_exact(stream, &mut addresses).await?;
+    let mut addresses = BytesMut::from(&addresses[..]);
+
+    match (version_command & 0x0f, family) {
+        // LOCAL, e.g. a health check from the load balancer itself.
+        (0x00, _) => Ok(None),
+
+        // PROXY over TCP, over IPv4 then IPv6.
+        (0x01, 0x11) if len >= 12 => {
+            let source = Ipv4Addr::from(addresses.get_u32());
+            addresses.advance(4);
+            Ok(Some(SocketAddr::new(source.into(), addresses.get_u16())))
+        }
+        (0x01, 0x21) if len >= 36 => {
+            let source = Ipv6Addr::from(addresses.get_u128());
+            addresses.advance(16);
+            Ok(Some(SocketAddr::new(source.into(), addresses.get_u16())))
+        }
+        (0x01, 0x11 | 0x21) => Err(bad_header("v2 addresses too short")),
+
+        // UDP, Unix sockets or unspecified.
+        (0x01, _) => Ok(None),
+
+        _ => Err(bad_header("unsupported v2 command")),
+    }
+}
+
+async fn read_u8<S>(stream: &mut S) -> Result<u8, Error>
+where
+    S: AsyncRead + std::marker::Unpin,
+{
+    stream
+        .read_u8()
+        .await
+        .map_err(|err| Error::SocketError(format!("Error reading PROXY header: {}", err)))
+}
+
+async fn read_exact<S>(stream: &mut S, buf: &mut [u8]) -> Result<(), Error>
+where
+    S: AsyncRead + std::marker::Unpin,
+{
+    stream
+        .read_exact(buf)
+        .await
+        .map(|_| ())
+        .map_err(|err| Error::SocketError(format!("Error reading PROXY header: {}", err)))
+}
+
+fn bad_header(reason: &str) -> Error {
+    Error::ProtocolSyncError(format!("Bad PROXY protocol header: {}", reason))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bytes::BufMut;
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> BytesMut {
+        let mut header = BytesMut::from(&V2_SIGNATURE[..]);
+        header.put_u8(0x20 | command);
+        header.put_u8(family);
+        header.put_u16(addresses.len() as u16);
+        header.put_slice(addresses);
+        header
+    }
+
+    #[tokio::test]
+    async fn test_read_v2_header() {
+        let mut addresses = BytesMut::new();
+        addresses.put_slice(&[203, 0, 113, 7]);
+        addresses.put_slice(&[10, 0, 0, 1]);
+        addresses.put_u16(54321);
+        addresses.put_u16(6432);
+
+        // The startup message that follows isn't read.
+        let mut stream = v2_header(0x01, 0x11, &addresses);
+        stream.put_i32(8);
+        let mut stream = &stream[..];
+        assert!(starts_header(stream[0]));
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("203.0.113.7:54321".parse().unwrap())
+        );
+        assert_eq!(stream, &8i32.to_be_bytes()[..]);
+
+        let mut addresses = BytesMut::new();
+        addresses.put_u128(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 0x42).into());
+        addresses.put_u128(Ipv6Addr::LOCALHOST.into());
+        addresses.put_u16(40000);
+        addresses.put_u16(6432);
+        // TLVs after the addresses are skipped.
+        addresses.put_slice(&[0x04, 0x00, 0x01, 0x00]);
+        assert_eq!(
+            read_header(&mut &v2_header(0x01, 0x21, &addresses)[..])
+                .await
+                .unwrap(),
+            Some("[2001:db8::42]:40000".parse().unwrap())
+        );
+
+        // Health check from the load balancer.
+        assert_eq!(
+            read_header(&mut &v2_header(0x00, 0x00, &[])[..])
+                .await
+                .unwrap(),
+            None
+        );
+
+        assert!(read_header(&mut &v2_header(0x01, 0x11, &[1, 2, 3])[..])
+            .await
+            .is_err());
+        assert!(!starts_header(0));
+    }
+
+    #[tokio::test]
+    async fn test_read_v1_header() {
+        assert_eq!(
+            read_header(&mut &b"PROXY TCP4 203.0.113.7 10.0.0.1 54321 6432\r\n"[..])
+                .await
+                .unwrap(),
+            Some("203.0.113.7:54321".parse().unwrap())
+        );
+        assert_eq!(
+            read_header(&mut &b"PROXY UNKNOWN\r\n"[..]).await.unwrap(),
+            None
+        );
+        assert!(read_header(&mut &b"PROXY TCP4 nope 10.0.0.1 1 2\r\n"[..])
+            .await
+            .is_err());
+        assert!(read_header(&mut &[b'P'; 200][..]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_addr() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut addresses = BytesMut::new();
+        addresses.put_slice(&[203, 0, 113, 7]);
+        addresses.put_slice(&[10, 0, 0, 1]);
+        addresses.put_u16(54321);
+        addresses.put_u16(6432);
+        let header = v2_header(0x01, 0x11, &addresses);
+
+        let connect = |bytes: Vec<u8>| async move {
+            let mut client = TcpStream::connect(addr).await.unwrap();
+            tokio::io::AsyncWriteExt::write_all(&mut client, &bytes)
+                .await
+                .unwrap();
+            client
+        };
+
+        let trusted: Network = "127.0.0.0/8".parse().unwrap();
+        let untrusted: Network = "10.0.0.0/8".parse().unwrap();
+
+        // From the load balancer.
+        let _client = connect(header.to_vec()).await;
+        let (mut stream, peer_addr) = listener.accept().await.unwrap();
+        assert_eq!(
+            client_addr(&mut stream, peer_addr, &[trusted])
+                .await
+                .unwrap(),
+            "203.0.113.7:54321".parse().unwrap()
+        );
+
+        // Directly from the client.
+        let _client = connect(8i32.to_be_bytes().to_vec()).await;
+        let (mut stream, peer_addr) = listener.accept().await.unwrap();
+        assert_eq!(
+            client_addr(&mut stream, peer_addr, &[trusted])
+                .await
+                .unwrap(),
+            peer_addr
+        );
+        assert_eq!(stream.read_i32().await.unwrap(), 8);
+
+        // Not from the load balancer.
+        let _client = connect(header.to_vec()).await;
+        let (mut stream, peer_addr) = listener.accept().await.unwrap();
+        assert!(matches!(
+            client_addr(&mut stream, peer_addr, &[untrusted]).await,
+            Err(Error::ClientError(_))
+        ));
+    }
+}
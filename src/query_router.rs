@@ -1,30 +1,34 @@
 /// Route queries automatically based on explicitly requested
 /// or implied query characteristics.
 use bytes::{Buf, BytesMut};
-use log::{debug, error};
-use once_cell::sync::OnceCell;
+use log::{debug, error, warn};
+use once_cell::sync::{Lazy, OnceCell};
 use regex::{Regex, RegexSet};
 use sqlparser::ast::Statement::{Delete, Insert, Query, StartTransaction, Update};
 use sqlparser::ast::{
-    Assignment, BinaryOperator, Expr, Ident, JoinConstraint, JoinOperator, SetExpr, Statement,
-    TableFactor, TableWithJoins, Value,
+    Assignment, BinaryOperator, Expr, FromTable, Ident, JoinConstraint, JoinOperator, SetExpr,
+    Statement, TableFactor, TableWithJoins, Value,
 };
 use sqlparser::dialect::PostgreSqlDialect;
 use sqlparser::parser::Parser;
 
-use crate::config::Role;
+use crate::config::{Role, StatementType};
 use crate::errors::Error;
 use crate::messages::BytesMutReader;
-use crate::plugins::{Intercept, Plugin, PluginOutput, QueryLogger, TableAccess};
+use crate::plugins::{
+    Intercept, Plugin, PluginOutput, QueryFilter, QueryLogger, QueryRewrite, RateLimit,
+    TableAccess, Wasm,
+};
 use crate::pool::PoolSettings;
 use crate::sharding::Sharder;
 
+use std::borrow::Cow;
 use std::collections::BTreeSet;
 use std::io::Cursor;
 use std::{cmp, mem};
 
 /// Regexes used to parse custom commands.
-const CUSTOM_SQL_REGEXES: [&str; 7] = [
+const CUSTOM_SQL_REGEXES: [&str; 11] = [
     r"(?i)^ *SET SHARDING KEY TO '?([0-9]+)'? *;? *$",
     r"(?i)^ *SET SHARD TO '?([0-9]+|ANY)'? *;? *$",
     r"(?i)^ *SHOW SHARD *;? *$",
@@ -32,6 +36,10 @@ const CUSTOM_SQL_REGEXES: [&str; 7] = [
     r"(?i)^ *SHOW SERVER ROLE *;? *$",
     r"(?i)^ *SET PRIMARY READS TO '?(on|off|default)'? *;? *$",
     r"(?i)^ *SHOW PRIMARY READS *;? *$",
+    r"(?i)^ *SET PGCAT\.STICKY TO '?(on|off|default)'? *;? *$",
+    r"(?i)^ *SHOW PGCAT\.STICKY *;? *$",
+    r"(?i)^ *SET PGCAT\.ROUTE TO '?(primary|replica|auto)'? *;? *$",
+    r"(?i)^ *SHOW PGCAT\.ROUTE *;? *$",
 ];
 
 /// Custom commands.
@@ -44,6 +52,10 @@ pub enum Command {
     ShowServerRole,
     SetPrimaryReads,
     ShowPrimaryReads,
+    SetSticky,
+    ShowSticky,
+    SetRoute,
+    ShowRoute,
 }
 
 #[derive(PartialEq, Debug)]
@@ -66,6 +78,10 @@ static CUSTOM_SQL_REGEX_SET: OnceCell<RegexSet> = OnceCell::new();
 // Get the value inside the custom command.
 static CUSTOM_SQL_REGEX_LIST: OnceCell<Vec<Regex>> = OnceCell::new();
 
+/// Comment overriding the checkout timeout of a single query, e.g. `/* pgcat_checkout_timeout: 1000 */`.
+static CHECKOUT_TIMEOUT_REGEX: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"/\*\s*pgcat_checkout_timeout:\s*([^*\s]*)\s*\*/").unwrap());
+
 /// The query router.
 pub struct QueryRouter {
     /// Which shard we should be talking to right now.
@@ -80,11 +96,23 @@ pub struct QueryRouter {
     /// Include the primary into the replica pool for reads.
     primary_reads_enabled: Option<bool>,
 
+    /// Stay on the servers used so far, overrides the pool's sticky_sessions.
+    sticky: Option<bool>,
+
+    /// Client's application_name from the startup parameters.
+    application_name: String,
+
     /// Pool configuration.
     pool_settings: PoolSettings,
 
     // Placeholders from prepared statement.
     placeholders: Vec<i16>,
+
+    /// Checkout timeout (ms) requested by the last query, overrides the pool's connect_timeout.
+    checkout_timeout: Option<u64>,
+
+    /// Whether the messages since the last `take_mirror` can be sent to the mirrors.
+    mirror: Option<bool>,
 }
 
 impl QueryRouter {
@@ -122,8 +150,12 @@ impl QueryRouter {
             active_role: None,
             query_parser_enabled: None,
             primary_reads_enabled: None,
+            sticky: None,
+            application_name: String::new(),
             pool_settings: PoolSettings::default(),
             placeholders: Vec::new(),
+            checkout_timeout: None,
+            mirror: None,
         }
     }
 
@@ -136,6 +168,66 @@ impl QueryRouter {
         &self.pool_settings
     }
 
+    /// Beginning of a Parse or Query message where comments with routing hints are looked for.
+    fn initial_segment<'a>(&self, message_buffer: &'a BytesMut, len: usize) -> Cow<'a, str> {
+        // Check only the first block of bytes configured by the pool settings
+        let seg = cmp::min(len - 5, self.pool_settings.regex_search_limit);
+
+        let query_start_index = mem::size_of::<u8>() + mem::size_of::<i32>();
+
+        String::from_utf8_lossy(&message_buffer[query_start_index..query_start_index + seg])
+    }
+
+    /// Checkout timeout from a `pgcat_checkout_timeout` comment, invalid values are ignored.
+    fn parse_checkout_timeout(initial_segment: &str) -> Option<u64> {
+        if !initial_segment.contains("pgcat_checkout_timeout") {
+            return None;
+        }
+
+        let value = CHECKOUT_TIMEOUT_REGEX
+            .captures(initial_segment)
+            .and_then(|cap| cap.get(1))?
+            .as_str();
+
+        match value.parse::<u64>() {
+            Ok(timeout) if timeout > 0 => {
+                debug!("Setting checkout timeout to {}ms", timeout);
+                Some(timeout)
+            }
+            _ => {
+                warn!(
+                    "Invalid pgcat_checkout_timeout {:?}, using the pool's connect_timeout",
+                    value
+                );
+                None
+            }
+        }
+    }
+
+    /// Checkout timeout (ms) requested by the current query, if any.
+    pub fn checkout_timeout(&self) -> Option<u64> {
+        self.checkout_timeout
+    }
+
+    /// Whether the message is a `SET pgcat.route` or `SHOW pgcat.route` query,
+    /// the custom commands handled while the client has a server too.
+    pub fn is_route_command(message_buffer: &BytesMut) -> bool {
+        if message_buffer.first() != Some(&b'Q') {
+            return false;
+        }
+
+        let mut message_cursor = Cursor::new(message_buffer);
+        message_cursor.advance(mem::size_of::<u8>() + mem::size_of::<i32>());
+
+        match (message_cursor.read_string(), CUSTOM_SQL_REGEX_SET.get()) {
+            (Ok(query), Some(regex_set)) => {
+                let matches = regex_set.matches(&query);
+                matches.matched(9) || matches.matched(10)
+            }
+            _ => false,
+        }
+    }
+
     /// Try to parse a command and execute it.
     pub fn try_execute_command(&mut self, message_buffer: &BytesMut) -> Option<(Command, String)> {
         let mut message_cursor = Cursor::new(message_buffer);
@@ -146,19 +238,18 @@ impl QueryRouter {
         let comment_shard_routing_enabled = self.pool_settings.shard_id_regex.is_some()
             || self.pool_settings.sharding_key_regex.is_some();
 
+        // The checkout timeout comment only applies to the query it's in.
+        if code == 'P' || code == 'Q' {
+            self.checkout_timeout =
+                Self::parse_checkout_timeout(&self.initial_segment(message_buffer, len));
+        }
+
         // Check for any sharding regex matches in any queries
         if comment_shard_routing_enabled {
             match code {
                 // For Parse and Query messages peek to see if they specify a shard_id as a comment early in the statement
                 'P' | 'Q' => {
-                    // Check only the first block of bytes configured by the pool settings
-                    let seg = cmp::min(len - 5, self.pool_settings.regex_search_limit);
-
-                    let query_start_index = mem::size_of::<u8>() + mem::size_of::<i32>();
-
-                    let initial_segment = String::from_utf8_lossy(
-                        &message_buffer[query_start_index..query_start_index + seg],
-                    );
+                    let initial_segment = self.initial_segment(message_buffer, len);
 
                     // Check for a shard_id included in the query
                     if let Some(shard_id_regex) = &self.pool_settings.shard_id_regex {
@@ -200,15 +291,9 @@ impl QueryRouter {
 
         let query = message_cursor.read_string().unwrap();
 
-        let regex_set = match CUSTOM_SQL_REGEX_SET.get() {
-            Some(regex_set) => regex_set,
-            None => return None,
-        };
+        let regex_set = CUSTOM_SQL_REGEX_SET.get()?;
 
-        let regex_list = match CUSTOM_SQL_REGEX_LIST.get() {
-            Some(regex_list) => regex_list,
-            None => return None,
-        };
+        let regex_list = CUSTOM_SQL_REGEX_LIST.get()?;
 
         let matches: Vec<_> = regex_set.matches(&query).into_iter().collect();
 
@@ -227,6 +312,10 @@ impl QueryRouter {
             4 => Command::ShowServerRole,
             5 => Command::SetPrimaryReads,
             6 => Command::ShowPrimaryReads,
+            7 => Command::SetSticky,
+            8 => Command::ShowSticky,
+            9 => Command::SetRoute,
+            10 => Command::ShowRoute,
             _ => unreachable!(),
         };
 
@@ -234,7 +323,9 @@ impl QueryRouter {
             Command::SetShardingKey
             | Command::SetShard
             | Command::SetServerRole
-            | Command::SetPrimaryReads => {
+            | Command::SetPrimaryReads
+            | Command::SetSticky
+            | Command::SetRoute => {
                 // Capture value. I know this re-runs the regex engine, but I haven't
                 // figured out a better way just yet. I think I can write a single Regex
                 // that matches all 5 custom SQL patterns, but maybe that's not very legible?
@@ -252,7 +343,7 @@ impl QueryRouter {
             Command::ShowShard => self
                 .shard()
                 .map_or_else(|| "unset".to_string(), |x| x.to_string()),
-            Command::ShowServerRole => match self.active_role {
+            Command::ShowServerRole | Command::ShowRoute => match self.active_role {
                 Some(Role::Primary) => Role::Primary.to_string(),
                 Some(Role::Replica) => Role::Replica.to_string(),
                 Some(Role::Mirror) => Role::Mirror.to_string(),
@@ -269,6 +360,11 @@ impl QueryRouter {
                 true => String::from("on"),
                 false => String::from("off"),
             },
+
+            Command::ShowSticky => match self.sticky() {
+                true => String::from("on"),
+                false => String::from("off"),
+            },
         };
 
         match command {
@@ -287,7 +383,8 @@ impl QueryRouter {
                 };
             }
 
-            Command::SetServerRole => {
+            // pgcat.route is the same without `any` and `default`.
+            Command::SetServerRole | Command::SetRoute => {
                 self.active_role = match value.to_ascii_lowercase().as_ref() {
                     "primary" => {
                         self.query_parser_enabled = Some(false);
@@ -310,7 +407,7 @@ impl QueryRouter {
                     }
 
                     "default" => {
-                        self.active_role = self.pool_settings.default_role;
+                        self.active_role = self.default_role();
                         self.query_parser_enabled = None;
                         self.active_role
                     }
@@ -332,6 +429,15 @@ impl QueryRouter {
                 }
             }
 
+            Command::SetSticky => {
+                debug!("Setting sticky sessions to {}", value);
+                self.sticky = match value.to_ascii_lowercase().as_ref() {
+                    "on" => Some(true),
+                    "off" => Some(false),
+                    _ => None,
+                };
+            }
+
             _ => (),
         }
 
@@ -386,20 +492,184 @@ impl QueryRouter {
         }
     }
 
-    /// Determines if a query is a mutation or not.
+    /// Determines if a query is a mutation or not, including
+    /// data-modifying WITH queries, e.g. `WITH t AS (UPDATE ...) SELECT ...`.
     fn is_mutation_query(q: &sqlparser::ast::Query) -> bool {
-        use sqlparser::ast::*;
+        let modifying_cte = q.with.as_ref().is_some_and(|with| {
+            with.cte_tables
+                .iter()
+                .any(|cte| Self::is_mutation_query(&cte.query))
+        });
+
+        modifying_cte || Self::is_mutation_set_expr(&q.body)
+    }
 
-        match q.body.as_ref() {
+    fn is_mutation_set_expr(body: &SetExpr) -> bool {
+        match body {
             SetExpr::Insert(_) => true,
             SetExpr::Update(_) => true,
             SetExpr::Query(q) => Self::is_mutation_query(q),
+            SetExpr::SetOperation { left, right, .. } => {
+                Self::is_mutation_set_expr(left) || Self::is_mutation_set_expr(right)
+            }
             _ => false,
         }
     }
 
+    /// Kind of statement, as used to filter the queries sent to the mirrors.
+    pub fn statement_type(statement: &Statement) -> StatementType {
+        match statement {
+            Query(query) => {
+                if !query.locks.is_empty() || Self::is_mutation_query(query) {
+                    StatementType::Write
+                } else {
+                    StatementType::Read
+                }
+            }
+
+            Statement::Copy { to: true, .. } => StatementType::Read,
+
+            Insert(_)
+            | Update { .. }
+            | Delete(_)
+            | Statement::Merge { .. }
+            | Statement::Copy { .. }
+            | Statement::Truncate { .. } => StatementType::Write,
+
+            Statement::CreateView { .. }
+            | Statement::CreateTable(_)
+            | Statement::CreateIndex(_)
+            | Statement::CreateRole { .. }
+            | Statement::CreatePolicy { .. }
+            | Statement::CreateExtension { .. }
+            | Statement::CreateSchema { .. }
+            | Statement::CreateDatabase { .. }
+            | Statement::CreateFunction { .. }
+            | Statement::CreateTrigger { .. }
+            | Statement::CreateProcedure { .. }
+            | Statement::CreateSequence { .. }
+            | Statement::CreateType { .. }
+            | Statement::AlterTable { .. }
+            | Statement::AlterIndex { .. }
+            | Statement::AlterView { .. }
+            | Statement::AlterRole { .. }
+            | Statement::AlterPolicy { .. }
+            | Statement::Drop { .. }
+            | Statement::DropFunction { .. }
+            | Statement::DropProcedure { .. }
+            | Statement::DropPolicy { .. }
+            | Statement::DropTrigger { .. }
+            | Statement::Comment { .. }
+            | Statement::Grant { .. }
+            | Statement::Revoke { .. } => StatementType::Ddl,
+
+            _ => StatementType::Other,
+        }
+    }
+
+    /// Whether the pool only sends some of the queries to the mirrors.
+    fn mirror_filtered(&self) -> bool {
+        self.pool_settings.mirror_statement_types.is_some()
+            || !self.pool_settings.mirror_include.is_empty()
+            || !self.pool_settings.mirror_exclude.is_empty()
+    }
+
+    /// Check a Query or Parse message against the pool's mirror filters.
+    /// The messages are only mirrored if all the queries since the last
+    /// `take_mirror` pass them. `ast` is None if the query wasn't parsed.
+    pub fn filter_mirror(&mut self, message: &BytesMut, ast: Option<&[Statement]>) {
+        if !self.mirror_filtered() {
+            return;
+        }
+
+        let mirrored = self.is_mirrored(message, ast);
+        if !mirrored {
+            debug!("Query not sent to the mirrors");
+        }
+
+        self.mirror = Some(self.mirror.unwrap_or(true) && mirrored);
+    }
+
+    fn is_mirrored(&self, message: &BytesMut, ast: Option<&[Statement]>) -> bool {
+        let query = match Self::query_text(message) {
+            Some(query) => query,
+            None => return false,
+        };
+
+        let settings = &self.pool_settings;
+
+        if !settings.mirror_include.is_empty()
+            && !settings
+                .mirror_include
+                .iter()
+                .any(|regex| regex.is_match(&query))
+        {
+            return false;
+        }
+
+        if settings
+            .mirror_exclude
+            .iter()
+            .any(|regex| regex.is_match(&query))
+        {
+            return false;
+        }
+
+        match (&settings.mirror_statement_types, ast) {
+            (None, _) => true,
+            // We don't know what the query does.
+            (Some(_), None) => false,
+            (Some(statement_types), Some(ast)) => ast
+                .iter()
+                .all(|statement| statement_types.contains(&Self::statement_type(statement))),
+        }
+    }
+
+    /// Query string of a Query or Parse message.
+    pub fn query_text(message: &BytesMut) -> Option<String> {
+        let mut message_cursor = Cursor::new(message);
+
+        match message_cursor.get_u8() as char {
+            'Q' => {
+                message_cursor.get_i32();
+                message_cursor.read_string().ok()
+            }
+            'P' => {
+                message_cursor.get_i32();
+                message_cursor.read_string().ok()?;
+                message_cursor.read_string().ok()
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the messages sent to the server since the last call go to the mirrors.
+    pub fn take_mirror(&mut self) -> bool {
+        match self.mirror.take() {
+            Some(mirror) => mirror,
+            // Nothing was checked, e.g. a prepared statement executed again.
+            None => !self.mirror_filtered(),
+        }
+    }
+
+    /// The query couldn't be parsed, we don't know if it's a read:
+    /// send it to the primary. sqlparser doesn't support everything Postgres does,
+    /// e.g. `WITH t AS (DELETE ...) SELECT ...`.
+    pub fn infer_unparsed(&mut self) {
+        if self.pool_settings.query_parser_read_write_splitting {
+            debug!("Query could not be parsed, using the primary");
+            self.active_role = Some(Role::Primary);
+        }
+    }
+
     /// Try to infer which server to connect to based on the contents of the query.
     pub fn infer(&mut self, ast: &Vec<sqlparser::ast::Statement>) -> Result<(), Error> {
+        // Doesn't depend on read/write splitting.
+        if let Some(shard) = self.infer_shard_from_table_rules(ast) {
+            debug!("Table rule using shard: {}", shard);
+            self.set_shard(Some(shard));
+        }
+
         if !self.pool_settings.query_parser_read_write_splitting {
             return Ok(()); // Nothing to do
         }
@@ -425,18 +695,14 @@ impl QueryRouter {
 
                 // Likely a read-only query
                 Query(query) => {
-                    match &self.pool_settings.automatic_sharding_key {
-                        Some(_) => {
-                            // TODO: if we have multiple queries in the same message,
-                            // we can either split them and execute them individually
-                            // or discard shard selection. If they point to the same shard though,
-                            // we can let them through as-is.
-                            // This is basically building a database now :)
-                            let inferred_shard = self.infer_shard(query);
-                            self.handle_inferred_shard(inferred_shard, &mut prev_inferred_shard)?;
-                        }
-
-                        None => (),
+                    if self.pool_settings.automatic_sharding_key.is_some() {
+                        // TODO: if we have multiple queries in the same message,
+                        // we can either split them and execute them individually
+                        // or discard shard selection. If they point to the same shard though,
+                        // we can let them through as-is.
+                        // This is basically building a database now :)
+                        let inferred_shard = self.infer_shard(query);
+                        self.handle_inferred_shard(inferred_shard, &mut prev_inferred_shard)?;
                     };
 
                     let has_locks = !query.locks.is_empty();
@@ -446,26 +712,25 @@ impl QueryRouter {
                         self.active_role = Some(Role::Primary);
                     } else if !visited_write_statement {
                         // If we already visited a write statement, we should be going to the primary.
-                        self.active_role = match self.primary_reads_enabled() {
-                            false => Some(Role::Replica), // If primary should not be receiving reads, use a replica.
-                            true => None,                 // Any server role is fine in this case.
+                        self.active_role = match self.application_name_role() {
+                            Some(role) => role,
+                            None => match self.primary_reads_enabled() {
+                                false => Some(Role::Replica), // If primary should not be receiving reads, use a replica.
+                                true => None, // Any server role is fine in this case.
+                            },
                         }
                     }
                 }
 
                 // Likely a write
                 _ => {
-                    match &self.pool_settings.automatic_sharding_key {
-                        Some(_) => {
-                            // TODO: similar to the above, if we have multiple queries in the
-                            // same message, we can either split them and execute them individually
-                            // or discard shard selection. If they point to the same shard though,
-                            // we can let them through as-is.
-                            let inferred_shard = self.infer_shard_on_write(q)?;
-                            self.handle_inferred_shard(inferred_shard, &mut prev_inferred_shard)?;
-                        }
-
-                        None => (),
+                    if self.pool_settings.automatic_sharding_key.is_some() {
+                        // TODO: similar to the above, if we have multiple queries in the
+                        // same message, we can either split them and execute them individually
+                        // or discard shard selection. If they point to the same shard though,
+                        // we can let them through as-is.
+                        let inferred_shard = self.infer_shard_on_write(q)?;
+                        self.handle_inferred_shard(inferred_shard, &mut prev_inferred_shard)?;
                     };
                     visited_write_statement = true;
                     self.active_role = Some(Role::Primary);
@@ -476,6 +741,88 @@ impl QueryRouter {
         Ok(())
     }
 
+    /// The shard given by the first table rule matching the main table of the query,
+    /// e.g. the table inserted into or the first table selected from.
+    fn infer_shard_from_table_rules(&self, ast: &[Statement]) -> Option<usize> {
+        if self.pool_settings.table_shard_rules.is_empty() {
+            return None;
+        }
+
+        let table = ast.iter().find_map(Self::target_table)?;
+
+        for (regex, rule) in &self.pool_settings.table_shard_rules {
+            let captures = match regex.captures(&table) {
+                Some(captures) => captures,
+                None => continue,
+            };
+
+            let mut value = String::new();
+            let shard = match (&rule.shard, &rule.sharding_key) {
+                (Some(shard), _) => {
+                    captures.expand(shard, &mut value);
+                    value.parse::<usize>().ok()
+                }
+                (None, Some(sharding_key)) => {
+                    captures.expand(sharding_key, &mut value);
+                    value.parse::<i64>().ok().map(|key| {
+                        Sharder::new(
+                            self.pool_settings.shards,
+                            self.pool_settings.sharding_function.clone(),
+                            self.pool_settings.virtual_nodes,
+                        )
+                        .shard(key)
+                    })
+                }
+                (None, None) => None,
+            };
+
+            return match shard {
+                Some(shard) if shard < self.pool_settings.shards => Some(shard),
+                _ => {
+                    warn!(
+                        "Table rule {} gave an invalid shard {:?} for table {}",
+                        rule.pattern, value, table
+                    );
+                    None
+                }
+            };
+        }
+
+        None
+    }
+
+    /// Name of the table a statement is on, without the schema.
+    fn target_table(statement: &Statement) -> Option<String> {
+        let relation = |table: Option<&TableWithJoins>| match table {
+            Some(TableWithJoins {
+                relation: TableFactor::Table { name, .. },
+                ..
+            }) => name.0.last().map(|ident| ident.value.clone()),
+            _ => None,
+        };
+
+        match statement {
+            Insert(insert) => insert.table_name.0.last().map(|ident| ident.value.clone()),
+            Update { table, .. } => relation(Some(table)),
+            Delete(delete) => match &delete.from {
+                FromTable::WithFromKeyword(tables) | FromTable::WithoutKeyword(tables) => {
+                    relation(tables.first())
+                }
+            },
+            Query(query) => {
+                let mut query = query;
+                loop {
+                    match &*query.body {
+                        SetExpr::Query(inner) => query = inner,
+                        SetExpr::Select(select) => return relation(select.from.first()),
+                        _ => return None,
+                    }
+                }
+            }
+            _ => None,
+        }
+    }
+
     fn handle_inferred_shard(
         &mut self,
         inferred_shard: Option<usize>,
@@ -594,12 +941,8 @@ impl QueryRouter {
     }
 
     fn process_selection(selection: &Option<Expr>, exprs: &mut Vec<Expr>) {
-        match selection {
-            Some(selection) => {
-                exprs.push(selection.clone());
-            }
-
-            None => (),
+        if let Some(selection) = selection {
+            exprs.push(selection.clone());
         };
     }
 
@@ -681,7 +1024,8 @@ impl QueryRouter {
 
         let sharder = Sharder::new(
             self.pool_settings.shards,
-            self.pool_settings.sharding_function,
+            self.pool_settings.sharding_function.clone(),
+            self.pool_settings.virtual_nodes,
         );
 
         let mut shards = BTreeSet::new();
@@ -804,7 +1148,7 @@ impl QueryRouter {
                     == a.target
                         .to_string()
                         .split('.')
-                        .last()
+                        .next_back()
                         .unwrap()
                         .to_lowercase()
             {
@@ -841,45 +1185,43 @@ impl QueryRouter {
         if let Expr::BinaryOp { left, op, right } = expr {
             match &**left {
                 Expr::BinaryOp { .. } => result.extend(self.selection_parser(left, table_names)),
-                Expr::Identifier(ident) => {
+                Expr::Identifier(ident) if ident.value.to_lowercase() == sharding_key[1].value => {
                     // Only if we're dealing with only one table
                     // and there is no ambiguity
-                    if ident.value.to_lowercase() == sharding_key[1].value {
-                        // Sharding key is unique enough, don't worry about
-                        // table names.
-                        if &sharding_key[0].value == "*" {
-                            found = true;
-                        } else if table_names.len() == 1 {
-                            let table = &table_names[0];
-
-                            if table.len() == 1 {
-                                // Table is not fully qualified, e.g.
-                                //      SELECT * FROM t WHERE sharding_key = 5
-                                // Make sure the table name from the sharding key matches
-                                // the table name from the query.
-                                found = sharding_key[0].value == table[0].value.to_lowercase();
-                            } else if table.len() == 2 {
-                                // Table name is fully qualified with the schema: e.g.
-                                //      SELECT * FROM public.t WHERE sharding_key = 5
-                                // Ignore the schema (TODO: at some point, we want schema support)
-                                // and use the table name only.
-                                found = sharding_key[0].value == table[1].value.to_lowercase();
-                            } else {
-                                debug!("Got table name with more than two idents, which is not possible");
-                            }
+                    // Sharding key is unique enough, don't worry about
+                    // table names.
+                    if &sharding_key[0].value == "*" {
+                        found = true;
+                    } else if table_names.len() == 1 {
+                        let table = &table_names[0];
+
+                        if table.len() == 1 {
+                            // Table is not fully qualified, e.g.
+                            //      SELECT * FROM t WHERE sharding_key = 5
+                            // Make sure the table name from the sharding key matches
+                            // the table name from the query.
+                            found = sharding_key[0].value == table[0].value.to_lowercase();
+                        } else if table.len() == 2 {
+                            // Table name is fully qualified with the schema: e.g.
+                            //      SELECT * FROM public.t WHERE sharding_key = 5
+                            // Ignore the schema (TODO: at some point, we want schema support)
+                            // and use the table name only.
+                            found = sharding_key[0].value == table[1].value.to_lowercase();
+                        } else {
+                            debug!(
+                                "Got table name with more than two idents, which is not possible"
+                            );
                         }
                     }
                 }
 
-                Expr::CompoundIdentifier(idents) => {
+                // TODO: key can have schema as well, e.g. public.data.id (len == 3)
+                Expr::CompoundIdentifier(idents) if idents.len() == 2 => {
                     // The key is fully qualified in the query,
                     // it will exist or Postgres will throw an error.
-                    if idents.len() == 2 {
-                        found = (&sharding_key[0].value == "*"
-                            || sharding_key[0].value == idents[0].value.to_lowercase())
-                            && sharding_key[1].value == idents[1].value.to_lowercase();
-                    }
-                    // TODO: key can have schema as well, e.g. public.data.id (len == 3)
+                    found = (&sharding_key[0].value == "*"
+                        || sharding_key[0].value == idents[0].value.to_lowercase())
+                        && sharding_key[1].value == idents[1].value.to_lowercase();
                 }
                 _ => (),
             };
@@ -897,15 +1239,13 @@ impl QueryRouter {
 
             match &**right {
                 Expr::BinaryOp { .. } => result.extend(self.selection_parser(right, table_names)),
-                Expr::Value(Value::Number(value, ..)) => {
-                    if found {
-                        match value.parse::<i64>() {
-                            Ok(value) => result.push(ShardingKey::Value(value)),
-                            Err(_) => {
-                                debug!("Sharding key was not an integer: {}", value);
-                            }
-                        };
-                    }
+                Expr::Value(Value::Number(value, ..)) if found => {
+                    match value.parse::<i64>() {
+                        Ok(value) => result.push(ShardingKey::Value(value)),
+                        Err(_) => {
+                            debug!("Sharding key was not an integer: {}", value);
+                        }
+                    };
                 }
 
                 Expr::Value(Value::Placeholder(placeholder)) => {
@@ -948,7 +1288,8 @@ impl QueryRouter {
 
         let sharder = Sharder::new(
             self.pool_settings.shards,
-            self.pool_settings.sharding_function,
+            self.pool_settings.sharding_function.clone(),
+            self.pool_settings.virtual_nodes,
         );
 
         // Look for sharding keys in either the join condition
@@ -979,7 +1320,7 @@ impl QueryRouter {
                 None
             }
 
-            1 => Some(shards.into_iter().last().unwrap()),
+            1 => Some(shards.into_iter().next_back().unwrap()),
 
             // TODO: support querying multiple shards (some day...)
             _ => {
@@ -1006,6 +1347,17 @@ impl QueryRouter {
             let _ = query_logger.run(self, ast).await;
         }
 
+        if let Some(ref rate_limit) = plugins.rate_limit {
+            let mut rate_limit = RateLimit {
+                enabled: rate_limit.enabled,
+                config: rate_limit,
+            };
+
+            if let Ok(PluginOutput::Deny(error)) = rate_limit.run(self, ast).await {
+                return Ok(PluginOutput::Deny(error));
+            }
+        }
+
         if let Some(ref intercept) = plugins.intercept {
             let mut intercept = Intercept {
                 enabled: intercept.enabled,
@@ -1032,22 +1384,91 @@ impl QueryRouter {
             }
         }
 
+        if let Some(ref query_filter) = plugins.query_filter {
+            let mut query_filter = QueryFilter {
+                enabled: query_filter.enabled,
+                config: query_filter,
+            };
+
+            if let Ok(PluginOutput::Deny(error)) = query_filter.run(self, ast).await {
+                return Ok(PluginOutput::Deny(error));
+            }
+        }
+
+        if let Some(ref wasm) = plugins.wasm {
+            let mut wasm = Wasm {
+                enabled: wasm.enabled,
+                config: wasm,
+            };
+
+            match wasm.run(self, ast).await {
+                Ok(PluginOutput::Allow) | Err(_) => (),
+                result => return result,
+            }
+        }
+
         Ok(PluginOutput::Allow)
     }
 
+    /// Rewrite the statements of a simple query, after the other plugins
+    /// allowed the statements the client sent.
+    pub async fn rewrite_query(&self, ast: &Vec<Statement>) -> Result<PluginOutput, Error> {
+        match self
+            .pool_settings
+            .plugins
+            .as_ref()
+            .and_then(|plugins| plugins.query_rewrite.as_ref())
+        {
+            Some(query_rewrite) => {
+                let mut query_rewrite = QueryRewrite {
+                    enabled: query_rewrite.enabled,
+                };
+
+                query_rewrite.run(self, ast).await
+            }
+            None => Ok(PluginOutput::Allow),
+        }
+    }
+
     fn set_sharding_key(&mut self, sharding_key: i64) -> Option<usize> {
         let sharder = Sharder::new(
             self.pool_settings.shards,
-            self.pool_settings.sharding_function,
+            self.pool_settings.sharding_function.clone(),
+            self.pool_settings.virtual_nodes,
         );
         let shard = sharder.shard(sharding_key);
         self.set_shard(Some(shard));
         self.active_shard
     }
 
-    /// Set active_role as the default_role specified in the pool.
+    /// Set active_role as the default_role specified in the pool,
+    /// or the role of the client's application_name.
     pub fn set_default_role(&mut self) {
-        self.active_role = self.pool_settings.default_role;
+        self.active_role = self.default_role();
+    }
+
+    fn default_role(&self) -> Option<Role> {
+        match self.application_name_role() {
+            Some(role) => role,
+            None => self.pool_settings.default_role,
+        }
+    }
+
+    /// Role of the first application_name rule matching the client, if any.
+    fn application_name_role(&self) -> Option<Option<Role>> {
+        self.pool_settings
+            .application_name_rules
+            .iter()
+            .find(|(regex, _)| regex.is_match(&self.application_name))
+            .map(|(_, role)| *role)
+    }
+
+    pub fn set_application_name(&mut self, application_name: &str) {
+        self.application_name = application_name.to_string();
+    }
+
+    pub fn application_name(&self) -> &str {
+        &self.application_name
     }
 
     /// Get the current desired server role we should be talking to.
@@ -1091,6 +1512,14 @@ impl QueryRouter {
             Some(value) => value,
         }
     }
+
+    /// Whether the client stays on the servers it used so far.
+    pub fn sticky(&self) -> bool {
+        match self.sticky {
+            None => self.pool_settings.sticky_sessions,
+            Some(value) => value,
+        }
+    }
 }
 
 impl Default for QueryRouter {
@@ -1102,10 +1531,11 @@ impl Default for QueryRouter {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::config::PoolMode;
+    use crate::config::{PoolMode, SlowQueryLogFormat};
     use crate::messages::simple_query;
     use crate::sharding::ShardingFunction;
     use bytes::BufMut;
+    use std::collections::BTreeMap;
 
     #[test]
     fn test_defaults() {
@@ -1135,6 +1565,71 @@ mod test {
         assert_eq!(qr.role(), Some(Role::Primary));
     }
 
+    #[test]
+    fn test_infer_cte() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.query_parser_read_write_splitting = true;
+        qr.pool_settings.query_parser_enabled = true;
+        qr.pool_settings.primary_reads_enabled = false;
+
+        let reads = [
+            "WITH t AS (SELECT * FROM items), u AS (SELECT * FROM t) SELECT * FROM u",
+            "WITH RECURSIVE t(n) AS (SELECT 1 UNION ALL SELECT n + 1 FROM t WHERE n < 5) SELECT * FROM t",
+            "WITH t AS (SELECT * FROM items) SELECT * FROM t UNION SELECT * FROM prices",
+        ];
+
+        for query in reads {
+            assert!(qr.infer(&qr.parse(&simple_query(query)).unwrap()).is_ok());
+            assert_eq!(qr.role(), Some(Role::Replica), "{}", query);
+        }
+
+        let writes = [
+            // The final statement is a SELECT but the CTEs write.
+            "WITH t AS (INSERT INTO items (id) VALUES (1) RETURNING *) SELECT * FROM t",
+            "WITH t AS (SELECT 1), u AS (UPDATE items SET name = 'a' RETURNING *) SELECT * FROM u",
+            "WITH t AS (WITH u AS (INSERT INTO items (id) VALUES (1) RETURNING id) SELECT id FROM u) SELECT * FROM t",
+            "WITH t AS (SELECT * FROM items) INSERT INTO prices SELECT * FROM t",
+        ];
+
+        for query in writes {
+            qr.active_role = Some(Role::Replica);
+            assert!(qr.infer(&qr.parse(&simple_query(query)).unwrap()).is_ok());
+            assert_eq!(qr.role(), Some(Role::Primary), "{}", query);
+        }
+
+        // DELETE in a CTE is not supported by the parser.
+        let query =
+            simple_query("WITH t AS (DELETE FROM items WHERE id = 1 RETURNING *) SELECT * FROM t");
+        qr.active_role = Some(Role::Replica);
+        assert!(qr.parse(&query).is_err());
+        qr.infer_unparsed();
+        assert_eq!(qr.role(), Some(Role::Primary));
+    }
+
+    #[test]
+    fn test_infer_multiple_statements() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.query_parser_read_write_splitting = true;
+        qr.pool_settings.query_parser_enabled = true;
+        qr.pool_settings.primary_reads_enabled = false;
+
+        let query = simple_query("SELECT * FROM items; WITH t AS (SELECT 1) SELECT * FROM t");
+        assert!(qr.infer(&qr.parse(&query).unwrap()).is_ok());
+        assert_eq!(qr.role(), Some(Role::Replica));
+
+        // Any write sends the whole batch to the primary, wherever it is.
+        for query in [
+            "SELECT * FROM items; UPDATE items SET name = 'a'; SELECT 1",
+            "DELETE FROM items; SELECT * FROM items",
+            "SELECT 1; WITH t AS (INSERT INTO items (id) VALUES (1) RETURNING *) SELECT * FROM t",
+        ] {
+            assert!(qr.infer(&qr.parse(&simple_query(query)).unwrap()).is_ok());
+            assert_eq!(qr.role(), Some(Role::Primary), "{}", query);
+        }
+    }
+
     #[test]
     fn test_infer_replica() {
         QueryRouter::setup();
@@ -1390,6 +1885,82 @@ mod test {
                 ))
             );
         }
+
+        let sticky = ["on", "off", "default"];
+        let sticky_enabled = ["on", "off", "off"];
+
+        for (idx, sticky) in sticky.iter().enumerate() {
+            assert_eq!(
+                qr.try_execute_command(&simple_query(&format!("SET pgcat.sticky TO {}", sticky))),
+                Some((Command::SetSticky, String::from(*sticky)))
+            );
+            assert_eq!(
+                qr.try_execute_command(&simple_query("SHOW pgcat.sticky")),
+                Some((Command::ShowSticky, String::from(sticky_enabled[idx])))
+            );
+        }
+    }
+
+    #[test]
+    fn test_set_route() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.query_parser_enabled = true;
+        qr.pool_settings.query_parser_read_write_splitting = true;
+        qr.try_execute_command(&simple_query("SET PRIMARY READS TO off"));
+
+        let select = simple_query("SELECT * FROM items WHERE id = 5");
+        let update = simple_query("UPDATE items SET name = 'pumpkin' WHERE id = 5");
+
+        assert!(QueryRouter::is_route_command(&simple_query(
+            "SET pgcat.route TO 'primary'"
+        )));
+        assert!(QueryRouter::is_route_command(&simple_query(
+            "show PGCAT.ROUTE;"
+        )));
+        assert!(!QueryRouter::is_route_command(&simple_query(
+            "SET SERVER ROLE TO 'primary'"
+        )));
+        assert!(!QueryRouter::is_route_command(&select));
+
+        // Reads go to the primary too, the queries aren't parsed.
+        assert_eq!(
+            qr.try_execute_command(&simple_query("SET pgcat.route TO 'primary'")),
+            Some((Command::SetRoute, String::from("primary")))
+        );
+        assert!(!qr.query_parser_enabled());
+        assert_eq!(qr.role(), Some(Role::Primary));
+        assert_eq!(
+            qr.try_execute_command(&simple_query("SHOW pgcat.route")),
+            Some((Command::ShowRoute, String::from("primary")))
+        );
+
+        assert_eq!(
+            qr.try_execute_command(&simple_query("SET pgcat.route TO replica")),
+            Some((Command::SetRoute, String::from("replica")))
+        );
+        assert!(!qr.query_parser_enabled());
+        assert_eq!(qr.role(), Some(Role::Replica));
+
+        // Back to the query parser deciding.
+        assert_eq!(
+            qr.try_execute_command(&simple_query("SET pgcat.route TO 'auto'")),
+            Some((Command::SetRoute, String::from("auto")))
+        );
+        assert!(qr.query_parser_enabled());
+        assert_eq!(
+            qr.try_execute_command(&simple_query("SHOW pgcat.route")),
+            Some((Command::ShowRoute, String::from("auto")))
+        );
+        assert!(qr.infer(&qr.parse(&select).unwrap()).is_ok());
+        assert_eq!(qr.role(), Some(Role::Replica));
+        assert!(qr.infer(&qr.parse(&update).unwrap()).is_ok());
+        assert_eq!(qr.role(), Some(Role::Primary));
+
+        assert_eq!(
+            qr.try_execute_command(&simple_query("SET pgcat.route TO 'any'")),
+            None
+        );
     }
 
     #[test]
@@ -1448,13 +2019,48 @@ mod test {
             query_parser_max_length: None,
             query_parser_read_write_splitting: true,
             primary_reads_enabled: false,
+            sticky_sessions: false,
             sharding_function: ShardingFunction::PgBigintHash,
+            virtual_nodes: PoolSettings::default().virtual_nodes,
             automatic_sharding_key: Some(String::from("test.id")),
             healthcheck_delay: PoolSettings::default().healthcheck_delay,
             healthcheck_timeout: PoolSettings::default().healthcheck_timeout,
+            healthcheck_query: PoolSettings::default().healthcheck_query,
+            max_replica_lag: PoolSettings::default().max_replica_lag,
+            replica_lag_query: PoolSettings::default().replica_lag_query,
+            connect_timeout: PoolSettings::default().connect_timeout,
+            idle_in_transaction_timeout: PoolSettings::default().idle_in_transaction_timeout,
+            client_idle_timeout: PoolSettings::default().client_idle_timeout,
+            max_backend_connections: PoolSettings::default().max_backend_connections,
+            warmup_queries: PoolSettings::default().warmup_queries,
+            warmup_connections: PoolSettings::default().warmup_connections,
+            sticky_parameters: Vec::new(),
+            max_sticky_pools: 100,
             ban_time: PoolSettings::default().ban_time,
+            ban_time_jitter: PoolSettings::default().ban_time_jitter,
+            ban_times: PoolSettings::default().ban_times,
+            ban_backoff_base: PoolSettings::default().ban_backoff_base,
+            max_ban_time: PoolSettings::default().max_ban_time,
+            statement_timeout_soft_ban: PoolSettings::default().statement_timeout_soft_ban,
+            query_timeout: PoolSettings::default().query_timeout,
+            query_timeout_ban: PoolSettings::default().query_timeout_ban,
+            verify_before_unban: PoolSettings::default().verify_before_unban,
+            on_all_replicas_banned: PoolSettings::default().on_all_replicas_banned,
+            checkout_retries: None,
+            latency_ewma_decay: PoolSettings::default().latency_ewma_decay,
+            pause_timeout: 0,
+            log_min_duration_ms: None,
+            slow_query_log_format: SlowQueryLogFormat::default(),
+            application_name_format: None,
+            ban_error_threshold: PoolSettings::default().ban_error_threshold,
+            ban_error_window: PoolSettings::default().ban_error_window,
             sharding_key_regex: None,
             shard_id_regex: None,
+            table_shard_rules: Vec::new(),
+            application_name_rules: Vec::new(),
+            mirror_statement_types: None,
+            mirror_include: Vec::new(),
+            mirror_exclude: Vec::new(),
             default_shard: crate::config::DefaultShard::Shard(0),
             regex_search_limit: 1000,
             auth_query: None,
@@ -1462,6 +2068,10 @@ mod test {
             auth_query_user: None,
             db: "test".to_string(),
             plugins: None,
+            query_filter_rules: Vec::new(),
+            query_cache_patterns: Vec::new(),
+            query_rewrite_rules: Vec::new(),
+            wasm_module: None,
         };
         let mut qr = QueryRouter::new();
         assert_eq!(qr.active_role, None);
@@ -1526,13 +2136,48 @@ mod test {
             query_parser_max_length: None,
             query_parser_read_write_splitting: true,
             primary_reads_enabled: false,
+            sticky_sessions: false,
             sharding_function: ShardingFunction::PgBigintHash,
+            virtual_nodes: PoolSettings::default().virtual_nodes,
             automatic_sharding_key: None,
             healthcheck_delay: PoolSettings::default().healthcheck_delay,
             healthcheck_timeout: PoolSettings::default().healthcheck_timeout,
+            healthcheck_query: PoolSettings::default().healthcheck_query,
+            max_replica_lag: PoolSettings::default().max_replica_lag,
+            replica_lag_query: PoolSettings::default().replica_lag_query,
+            connect_timeout: PoolSettings::default().connect_timeout,
+            idle_in_transaction_timeout: PoolSettings::default().idle_in_transaction_timeout,
+            client_idle_timeout: PoolSettings::default().client_idle_timeout,
+            max_backend_connections: PoolSettings::default().max_backend_connections,
+            warmup_queries: PoolSettings::default().warmup_queries,
+            warmup_connections: PoolSettings::default().warmup_connections,
+            sticky_parameters: Vec::new(),
+            max_sticky_pools: 100,
             ban_time: PoolSettings::default().ban_time,
+            ban_time_jitter: PoolSettings::default().ban_time_jitter,
+            ban_times: PoolSettings::default().ban_times,
+            ban_backoff_base: PoolSettings::default().ban_backoff_base,
+            max_ban_time: PoolSettings::default().max_ban_time,
+            statement_timeout_soft_ban: PoolSettings::default().statement_timeout_soft_ban,
+            query_timeout: PoolSettings::default().query_timeout,
+            query_timeout_ban: PoolSettings::default().query_timeout_ban,
+            verify_before_unban: PoolSettings::default().verify_before_unban,
+            on_all_replicas_banned: PoolSettings::default().on_all_replicas_banned,
+            checkout_retries: None,
+            latency_ewma_decay: PoolSettings::default().latency_ewma_decay,
+            pause_timeout: 0,
+            log_min_duration_ms: None,
+            slow_query_log_format: SlowQueryLogFormat::default(),
+            application_name_format: None,
+            ban_error_threshold: PoolSettings::default().ban_error_threshold,
+            ban_error_window: PoolSettings::default().ban_error_window,
             sharding_key_regex: Some(Regex::new(r"/\* sharding_key: (\d+) \*/").unwrap()),
             shard_id_regex: Some(Regex::new(r"/\* shard_id: (\d+) \*/").unwrap()),
+            table_shard_rules: Vec::new(),
+            application_name_rules: Vec::new(),
+            mirror_statement_types: None,
+            mirror_include: Vec::new(),
+            mirror_exclude: Vec::new(),
             default_shard: crate::config::DefaultShard::Shard(0),
             regex_search_limit: 1000,
             auth_query: None,
@@ -1540,6 +2185,10 @@ mod test {
             auth_query_user: None,
             db: "test".to_string(),
             plugins: None,
+            query_filter_rules: Vec::new(),
+            query_cache_patterns: Vec::new(),
+            query_rewrite_rules: Vec::new(),
+            wasm_module: None,
         };
 
         let mut qr = QueryRouter::new();
@@ -1877,6 +2526,38 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_checkout_timeout_comment() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+        assert_eq!(qr.checkout_timeout(), None);
+
+        let query = simple_query("/* pgcat_checkout_timeout: 1000 */ SELECT 1");
+        assert!(qr.try_execute_command(&query).is_none());
+        assert_eq!(qr.checkout_timeout(), Some(1000));
+
+        // Only applies to the query it's in.
+        assert!(qr.try_execute_command(&simple_query("SELECT 1")).is_none());
+        assert_eq!(qr.checkout_timeout(), None);
+
+        let mut parse = BytesMut::from(&b"P"[..]);
+        let body = b"\0/*pgcat_checkout_timeout:250*/ SELECT $1\0\0\0";
+        parse.put_i32(body.len() as i32 + 4);
+        parse.put_slice(body);
+        assert!(qr.try_execute_command(&parse).is_none());
+        assert_eq!(qr.checkout_timeout(), Some(250));
+
+        // Invalid values fall back to the pool's timeout.
+        for query in [
+            "/* pgcat_checkout_timeout: soon */ SELECT 1",
+            "/* pgcat_checkout_timeout: -5 */ SELECT 1",
+            "/* pgcat_checkout_timeout: 0 */ SELECT 1",
+        ] {
+            assert!(qr.try_execute_command(&simple_query(query)).is_none());
+            assert_eq!(qr.checkout_timeout(), None);
+        }
+    }
+
     #[test]
     fn test_prepared_statements() {
         let stmt = "SELECT * FROM data WHERE id = $1";
@@ -1918,6 +2599,12 @@ mod test {
             intercept: None,
             query_logger: None,
             prewarmer: None,
+            rate_limit: None,
+            query_filter: None,
+            query_cache: None,
+            audit_log: None,
+            query_rewrite: None,
+            wasm: None,
         };
 
         QueryRouter::setup();
@@ -1942,6 +2629,188 @@ mod test {
         );
     }
 
+    #[tokio::test]
+    async fn test_rate_limit_plugin() {
+        use crate::config::{Plugins, RateLimit};
+        let rate_limit = RateLimit {
+            enabled: true,
+            queries_per_second: 2,
+            burst: None,
+            users: BTreeMap::from([("limited_user".to_string(), 1)]),
+        };
+        let plugins = Plugins {
+            rate_limit: Some(rate_limit),
+            ..Default::default()
+        };
+
+        QueryRouter::setup();
+        let mut pool_settings = PoolSettings {
+            query_parser_enabled: true,
+            plugins: Some(plugins),
+            db: "rate_limit_db".into(),
+            ..Default::default()
+        };
+        pool_settings.user.username = "user".into();
+        let mut qr = QueryRouter::new();
+        qr.update_pool_settings(&pool_settings);
+
+        let query = simple_query("SELECT 1");
+        let ast = qr.parse(&query).unwrap();
+
+        // The burst is used up, the next token comes in 500ms.
+        assert_eq!(qr.execute_plugins(&ast).await, Ok(PluginOutput::Allow));
+        assert_eq!(qr.execute_plugins(&ast).await, Ok(PluginOutput::Allow));
+        assert_eq!(
+            qr.execute_plugins(&ast).await,
+            Ok(PluginOutput::Deny(
+                "rate limit exceeded: user \"user\" is limited to 2 queries per second".to_string()
+            ))
+        );
+
+        // Other users have their own bucket and rate.
+        pool_settings.user.username = "limited_user".into();
+        qr.update_pool_settings(&pool_settings);
+        assert_eq!(qr.execute_plugins(&ast).await, Ok(PluginOutput::Allow));
+        assert!(matches!(
+            qr.execute_plugins(&ast).await,
+            Ok(PluginOutput::Deny(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_query_filter_plugin() {
+        use crate::config::{
+            Plugins, QueryFilter, QueryFilterAction, QueryFilterRule, StatementType,
+        };
+        let query_filter = QueryFilter {
+            enabled: true,
+            rules: vec![
+                QueryFilterRule {
+                    action: QueryFilterAction::Deny,
+                    pattern: Some("(?i)^TRUNCATE ".into()),
+                    statement_types: None,
+                    message: Some("TRUNCATE is not allowed here".into()),
+                },
+                QueryFilterRule {
+                    action: QueryFilterAction::Allow,
+                    pattern: None,
+                    statement_types: Some(vec![StatementType::Read]),
+                    message: None,
+                },
+            ],
+            default_action: QueryFilterAction::Deny,
+        };
+
+        QueryRouter::setup();
+        let pool_settings = PoolSettings {
+            query_parser_enabled: true,
+            query_filter_rules: query_filter.compiled_rules(),
+            plugins: Some(Plugins {
+                query_filter: Some(query_filter),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut qr = QueryRouter::new();
+        qr.update_pool_settings(&pool_settings);
+
+        let run = |query: &str| {
+            let ast = qr.parse(&simple_query(query)).unwrap();
+            let qr = &qr;
+            async move { qr.execute_plugins(&ast).await }
+        };
+
+        assert_eq!(
+            run("SELECT * FROM users WHERE id = 1").await,
+            Ok(PluginOutput::Allow)
+        );
+        assert_eq!(
+            run("DROP TABLE users").await,
+            Ok(PluginOutput::Deny(
+                "statement not allowed: DROP TABLE users".into()
+            ))
+        );
+        assert_eq!(
+            run("truncate users").await,
+            Ok(PluginOutput::Deny("TRUNCATE is not allowed here".into()))
+        );
+
+        // Every statement of the query is checked.
+        assert!(matches!(
+            run("SELECT 1; DELETE FROM users").await,
+            Ok(PluginOutput::Deny(_))
+        ));
+        assert!(matches!(
+            run("SELECT * FROM users FOR UPDATE").await,
+            Ok(PluginOutput::Deny(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_query_rewrite_plugin() {
+        use crate::config::{Plugins, QueryRewrite, QueryRewriteRule};
+        let query_rewrite = QueryRewrite {
+            enabled: true,
+            rules: vec![
+                QueryRewriteRule {
+                    pattern: r"\blegacy_users\b".into(),
+                    rewrite: "users_view".into(),
+                },
+                QueryRewriteRule {
+                    pattern: r"^SELECT (.*) FROM broken$".into(),
+                    rewrite: "SELECT $1 FROM".into(),
+                },
+            ],
+        };
+
+        QueryRouter::setup();
+        let pool_settings = PoolSettings {
+            query_parser_enabled: true,
+            query_rewrite_rules: query_rewrite.compiled_rules(),
+            plugins: Some(Plugins {
+                query_rewrite: Some(query_rewrite),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let mut qr = QueryRouter::new();
+        qr.update_pool_settings(&pool_settings);
+
+        let run = |query: &str| {
+            let ast = qr.parse(&simple_query(query)).unwrap();
+            let qr = &qr;
+            async move { qr.rewrite_query(&ast).await }
+        };
+
+        match run("select * from legacy_users where id = 1; select 1").await {
+            Ok(PluginOutput::Overwrite(ast)) => assert_eq!(
+                crate::plugins::QueryRewrite::query(&ast),
+                "SELECT * FROM users_view WHERE id = 1; SELECT 1"
+            ),
+            result => panic!("not rewritten: {:?}", result),
+        };
+        assert_eq!(
+            run("SELECT * FROM legacy_users_archive").await,
+            Ok(PluginOutput::Allow)
+        );
+
+        // The result of a rewrite has to parse.
+        assert!(matches!(
+            run("SELECT id FROM broken").await,
+            Ok(PluginOutput::Deny(error)) if error.starts_with("rewritten query is not valid SQL: ")
+        ));
+
+        // The other plugins don't rewrite.
+        assert_eq!(
+            qr.execute_plugins(
+                &qr.parse(&simple_query("SELECT * FROM legacy_users"))
+                    .unwrap()
+            )
+            .await,
+            Ok(PluginOutput::Allow)
+        );
+    }
+
     #[tokio::test]
     async fn test_plugins_disabled_by_defaault() {
         QueryRouter::setup();
@@ -1954,4 +2823,221 @@ mod test {
 
         assert_eq!(res, Ok(PluginOutput::Allow));
     }
+
+    #[test]
+    fn test_custom_sharding_function() {
+        use crate::sharding::{register_sharding_function, CustomShardingFunction};
+
+        /// Ten consecutive keys per shard.
+        struct Buckets;
+
+        impl CustomShardingFunction for Buckets {
+            fn shard(&self, key: i64, shards: usize) -> usize {
+                (key / 10) as usize % shards
+            }
+        }
+
+        register_sharding_function("buckets", std::sync::Arc::new(Buckets));
+
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.shards = 4;
+        qr.pool_settings.sharding_function = ShardingFunction::Custom("buckets".into());
+        qr.pool_settings.sharding_key_regex =
+            Some(Regex::new(r"/\* sharding_key: (\d+) \*/").unwrap());
+
+        let query = simple_query("SET SHARDING KEY TO 13");
+        assert_eq!(
+            qr.try_execute_command(&query),
+            Some((Command::SetShardingKey, String::from("1")))
+        );
+        assert_eq!(qr.shard(), Some(1));
+
+        let query = simple_query("/* sharding_key: 35 */ SELECT 1");
+        assert_eq!(qr.try_execute_command(&query), None);
+        assert_eq!(qr.shard(), Some(3));
+    }
+
+    #[test]
+    fn test_table_shard_rules() {
+        use crate::config::TableShardRule;
+
+        let rule = |pattern: &str, shard: Option<&str>, sharding_key: Option<&str>| {
+            let rule = TableShardRule {
+                pattern: pattern.into(),
+                shard: shard.map(String::from),
+                sharding_key: sharding_key.map(String::from),
+            };
+            assert!(rule.validate().is_ok());
+            (Regex::new(pattern).unwrap(), rule)
+        };
+
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+        qr.pool_settings.shards = 3;
+        qr.pool_settings.query_parser_enabled = true;
+        qr.pool_settings.table_shard_rules = vec![
+            rule(r"^tenant_(\d+)_", None, Some("$1")),
+            rule(r"^region_(?P<region>\d)_", Some("${region}"), None),
+        ];
+
+        // The shard of sharding key 13.
+        let shard = Sharder::new(3, ShardingFunction::PgBigintHash, 0).shard(13);
+
+        let query = simple_query("INSERT INTO tenant_13_events (id) VALUES (1)");
+        assert!(qr.infer(&qr.parse(&query).unwrap()).is_ok());
+        assert_eq!(qr.shard(), Some(shard));
+
+        let query =
+            simple_query("SELECT * FROM public.region_2_users u JOIN tenant_13_events e ON true");
+        assert!(qr.infer(&qr.parse(&query).unwrap()).is_ok());
+        assert_eq!(qr.shard(), Some(2));
+
+        let query = simple_query("UPDATE region_1_users SET name = 'a'");
+        assert!(qr.infer(&qr.parse(&query).unwrap()).is_ok());
+        assert_eq!(qr.shard(), Some(1));
+
+        // Unmatched, the shard stays the same.
+        let query = simple_query("SELECT * FROM users WHERE id = 5");
+        assert!(qr.infer(&qr.parse(&query).unwrap()).is_ok());
+        assert_eq!(qr.shard(), Some(1));
+
+        // Out of range.
+        let query = simple_query("DELETE FROM region_7_users");
+        assert!(qr.infer(&qr.parse(&query).unwrap()).is_ok());
+        assert_eq!(qr.shard(), Some(1));
+
+        let invalid = TableShardRule {
+            pattern: "^tenant_".into(),
+            shard: Some("1".into()),
+            sharding_key: Some("1".into()),
+        };
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_application_name_rules() {
+        use crate::config::ApplicationNameRule;
+
+        let rule = |pattern: &str, role: &str| {
+            let rule = ApplicationNameRule {
+                pattern: pattern.into(),
+                role: role.into(),
+            };
+            assert!(rule.validate().is_ok());
+            (Regex::new(pattern).unwrap(), rule.server_role().unwrap())
+        };
+
+        QueryRouter::setup();
+        let pool_settings = PoolSettings {
+            query_parser_enabled: true,
+            query_parser_read_write_splitting: true,
+            application_name_rules: vec![rule("_readonly$", "replica"), rule(".*", "primary")],
+            ..Default::default()
+        };
+
+        let mut readonly = QueryRouter::new();
+        readonly.update_pool_settings(&pool_settings);
+        readonly.set_application_name("billing_readonly");
+        readonly.set_default_role();
+        assert_eq!(readonly.role(), Some(Role::Replica));
+
+        let mut other = QueryRouter::new();
+        other.update_pool_settings(&pool_settings);
+        other.set_application_name("billing");
+        other.set_default_role();
+        assert_eq!(other.role(), Some(Role::Primary));
+
+        // Reads follow the application_name, writes still go to the primary.
+        let select = simple_query("SELECT * FROM invoices");
+        let insert = simple_query("INSERT INTO invoices (id) VALUES (1)");
+
+        assert!(readonly.infer(&readonly.parse(&select).unwrap()).is_ok());
+        assert_eq!(readonly.role(), Some(Role::Replica));
+        assert!(readonly.infer(&readonly.parse(&insert).unwrap()).is_ok());
+        assert_eq!(readonly.role(), Some(Role::Primary));
+
+        assert!(other.infer(&other.parse(&select).unwrap()).is_ok());
+        assert_eq!(other.role(), Some(Role::Primary));
+
+        // SET SERVER ROLE TO 'default' goes back to the application_name's role.
+        readonly.try_execute_command(&simple_query("SET SERVER ROLE TO 'primary'"));
+        assert_eq!(readonly.role(), Some(Role::Primary));
+        readonly.try_execute_command(&simple_query("SET SERVER ROLE TO 'default'"));
+        assert_eq!(readonly.role(), Some(Role::Replica));
+
+        let invalid = ApplicationNameRule {
+            pattern: "_readonly$".into(),
+            role: "mirror".into(),
+        };
+        assert!(invalid.validate().is_err());
+    }
+
+    #[test]
+    fn test_mirror_statement_types() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+        qr.update_pool_settings(&PoolSettings {
+            query_parser_enabled: true,
+            mirror_statement_types: Some(vec![StatementType::Read]),
+            ..Default::default()
+        });
+
+        let mut mirrored = |query: &str| {
+            let message = simple_query(query);
+            let ast = qr.parse(&message).ok();
+            qr.filter_mirror(&message, ast.as_deref());
+            qr.take_mirror()
+        };
+
+        assert!(mirrored("SELECT * FROM users"));
+        assert!(!mirrored("INSERT INTO users (id) VALUES (1)"));
+        assert!(!mirrored("SELECT * FROM users FOR UPDATE"));
+        assert!(!mirrored(
+            "WITH t AS (UPDATE users SET id = 2 RETURNING id) SELECT id FROM t"
+        ));
+        assert!(!mirrored("CREATE TABLE users (id BIGINT)"));
+        assert!(!mirrored("SELECT 1; DELETE FROM users"));
+        assert!(!mirrored("SELECT 1 FROM users WHERE"));
+
+        // Nothing was checked since the last query.
+        assert!(!qr.take_mirror());
+    }
+
+    #[test]
+    fn test_mirror_regexes() {
+        QueryRouter::setup();
+        let mut qr = QueryRouter::new();
+        qr.update_pool_settings(&PoolSettings {
+            mirror_include: vec![Regex::new("(?i)^select").unwrap()],
+            mirror_exclude: vec![Regex::new("pg_catalog").unwrap()],
+            ..Default::default()
+        });
+
+        let select = simple_query("SELECT * FROM users");
+        qr.filter_mirror(&select, None);
+        assert!(qr.take_mirror());
+
+        let catalog = simple_query("SELECT * FROM pg_catalog.pg_class");
+        qr.filter_mirror(&catalog, None);
+        assert!(!qr.take_mirror());
+
+        // Every query of an extended protocol batch must pass.
+        let mut parse = BytesMut::new();
+        let query = b"UPDATE users SET id = 2\0";
+        parse.put_u8(b'P');
+        parse.put_i32(4 + 1 + query.len() as i32 + 2);
+        parse.put_u8(0);
+        parse.put_slice(query);
+        parse.put_i16(0);
+        qr.filter_mirror(&select, None);
+        qr.filter_mirror(&parse, None);
+        assert!(!qr.take_mirror());
+
+        // No filters, everything is mirrored.
+        qr.update_pool_settings(&PoolSettings::default());
+        qr.filter_mirror(&parse, None);
+        assert!(qr.take_mirror());
+        assert!(qr.take_mirror());
+    }
 }
@@ -28,14 +28,94 @@ fn normalize(pass: &[u8]) -> Vec<u8> {
     }
 }
 
+/// SCRAM secret, as Postgres stores it in `pg_authid`:
+/// `SCRAM-SHA-256$<iterations>:<salt>$<StoredKey>:<ServerKey>`, base64 encoded.
+/// It's enough to authenticate clients, but not to log in to the server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScramSecret {
+    pub iterations: u32,
+    pub salt: Vec<u8>,
+    pub stored_key: [u8; 32],
+    pub server_key: [u8; 32],
+}
+
+impl ScramSecret {
+    /// Same secret as Postgres generates for the password.
+    pub fn from_password(password: &str, salt: &[u8], iterations: u32) -> ScramSecret {
+        let salted_password = ScramSha256::hi(&normalize(password.as_bytes()), salt, iterations);
+
+        ScramSecret {
+            iterations,
+            salt: salt.to_vec(),
+            stored_key: Sha256::digest(hmac(&salted_password, b"Client Key")).into(),
+            server_key: hmac(&salted_password, b"Server Key"),
+        }
+    }
+
+    /// Parse the secret obtained with auth_query, None if it isn't a SCRAM secret.
+    pub fn parse(secret: &str) -> Option<ScramSecret> {
+        let (iterations_and_salt, keys) = secret.strip_prefix("SCRAM-SHA-256$")?.split_once('$')?;
+        let (iterations, salt) = iterations_and_salt.split_once(':')?;
+        let (stored_key, server_key) = keys.split_once(':')?;
+
+        let key = |key: &str| -> Option<[u8; 32]> {
+            general_purpose::STANDARD.decode(key).ok()?.try_into().ok()
+        };
+
+        Some(ScramSecret {
+            iterations: iterations
+                .parse()
+                .ok()
+                .filter(|iterations| *iterations > 0)?,
+            salt: general_purpose::STANDARD.decode(salt).ok()?,
+            stored_key: key(stored_key)?,
+            server_key: key(server_key)?,
+        })
+    }
+}
+
+impl std::fmt::Display for ScramSecret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "SCRAM-SHA-256${}:{}${}:{}",
+            self.iterations,
+            general_purpose::STANDARD.encode(&self.salt),
+            general_purpose::STANDARD.encode(self.stored_key),
+            general_purpose::STANDARD.encode(self.server_key)
+        )
+    }
+}
+
+fn hmac(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let mut hmac =
+        Hmac::<Sha256>::new_from_slice(key).expect("HMAC is able to accept all key sizes");
+    hmac.update(message);
+    hmac.finalize().into_bytes().into()
+}
+
+/// What we log in to the server with.
+enum Credentials {
+    Password(String),
+
+    /// ClientKey recovered from the proof of a client authenticated with the secret,
+    /// and the ServerKey of the secret.
+    Keys {
+        client_key: [u8; 32],
+        server_key: [u8; 32],
+    },
+}
+
 /// Keep the SASL state through the exchange.
 /// It takes 3 messages to complete the authentication.
 pub struct ScramSha256 {
-    password: String,
-    salted_password: [u8; 32],
+    credentials: Credentials,
+    server_key: [u8; 32],
     auth_message: String,
     message: BytesMut,
     nonce: String,
+    gs2_header: String,
+    channel_binding: Vec<u8>,
 }
 
 impl ScramSha256 {
@@ -56,19 +136,40 @@ impl ScramSha256 {
         Self::from_nonce(password, &nonce)
     }
 
+    /// Log in without the password, with the keys of a client that was authenticated
+    /// with the SCRAM secret of the user, see [`ScramSha256Server::client_key`].
+    pub fn from_keys(client_key: [u8; 32], server_key: [u8; 32]) -> ScramSha256 {
+        let mut scram = Self::new("");
+        scram.credentials = Credentials::Keys {
+            client_key,
+            server_key,
+        };
+        scram
+    }
+
     /// Used for testing.
     pub fn from_nonce(password: &str, nonce: &str) -> ScramSha256 {
         let message = BytesMut::from(format!("{}n=,r={}", "n,,", nonce).as_bytes());
 
         ScramSha256 {
-            password: password.to_string(),
+            credentials: Credentials::Password(password.to_string()),
             nonce: String::from(nonce),
             message,
-            salted_password: [0u8; 32],
+            server_key: [0u8; 32],
             auth_message: String::new(),
+            gs2_header: String::from("n,,"),
+            channel_binding: Vec::new(),
         }
     }
 
+    /// Use SCRAM-SHA-256-PLUS with the tls-server-end-point channel binding data.
+    pub fn with_channel_binding(mut self, channel_binding: &[u8]) -> ScramSha256 {
+        self.gs2_header = format!("p={},,", TLS_SERVER_END_POINT);
+        self.channel_binding = channel_binding.to_vec();
+        self.message = BytesMut::from(format!("{}n=,r={}", self.gs2_header, self.nonce).as_bytes());
+        self
+    }
+
     /// Get the current state of the SASL authentication.
     pub fn message(&mut self) -> BytesMut {
         self.message.clone()
@@ -87,30 +188,35 @@ impl ScramSha256 {
             Err(_) => return Err(Error::ProtocolSyncError("SCRAM".to_string())),
         };
 
-        let salted_password = Self::hi(
-            &normalize(self.password.as_bytes()),
-            &salt,
-            server_message.iterations,
-        );
+        let client_key = match &self.credentials {
+            Credentials::Password(password) => {
+                let salted_password = Self::hi(
+                    &normalize(password.as_bytes()),
+                    &salt,
+                    server_message.iterations,
+                );
 
-        // Save for verification of final server message.
-        self.salted_password = salted_password;
+                // Save for verification of final server message.
+                self.server_key = hmac(&salted_password, b"Server Key");
 
-        let mut hmac = match Hmac::<Sha256>::new_from_slice(&salted_password) {
-            Ok(hmac) => hmac,
-            Err(_) => return Err(Error::ServerError),
+                hmac(&salted_password, b"Client Key")
+            }
+            Credentials::Keys {
+                client_key,
+                server_key,
+            } => {
+                self.server_key = *server_key;
+                *client_key
+            }
         };
 
-        hmac.update(b"Client Key");
-
-        let client_key = hmac.finalize().into_bytes();
-
         let mut hash = Sha256::default();
         hash.update(client_key.as_slice());
 
         let stored_key = hash.finalize_fixed();
         let mut cbind_input = vec![];
-        cbind_input.extend("n,,".as_bytes());
+        cbind_input.extend(self.gs2_header.as_bytes());
+        cbind_input.extend(&self.channel_binding);
 
         let cbind_input = general_purpose::STANDARD.encode(&cbind_input);
 
@@ -153,7 +259,7 @@ impl ScramSha256 {
         match write!(
             &mut self.message,
             ",p={}",
-            general_purpose::STANDARD.encode(&*client_proof)
+            general_purpose::STANDARD.encode(client_proof)
         ) {
             Ok(_) => (),
             Err(_) => return Err(Error::ServerError),
@@ -171,14 +277,7 @@ impl ScramSha256 {
             Err(_) => return Err(Error::ProtocolSyncError("SCRAM".to_string())),
         };
 
-        let mut hmac = match Hmac::<Sha256>::new_from_slice(&self.salted_password) {
-            Ok(hmac) => hmac,
-            Err(_) => return Err(Error::ServerError),
-        };
-        hmac.update(b"Server Key");
-        let server_key = hmac.finalize().into_bytes();
-
-        let mut hmac = match Hmac::<Sha256>::new_from_slice(&server_key) {
+        let mut hmac = match Hmac::<Sha256>::new_from_slice(&self.server_key) {
             Ok(hmac) => hmac,
             Err(_) => return Err(Error::ServerError),
         };
@@ -214,6 +313,194 @@ impl ScramSha256 {
     }
 }
 
+/// Server side of the exchange, authenticating clients with their password from the config,
+/// or with the SCRAM secret obtained with auth_query.
+pub struct ScramSha256Server {
+    secret: ScramSecret,
+
+    /// tls-server-end-point data of the client's TLS connection, if any.
+    channel_binding: Option<Vec<u8>>,
+
+    gs2_header: String,
+    client_first_bare: String,
+    server_first: String,
+    nonce: String,
+
+    /// Recovered from the client's proof once it's verified.
+    client_key: Option<[u8; 32]>,
+}
+
+impl ScramSha256Server {
+    pub fn new(password: &str, channel_binding: Option<Vec<u8>>) -> ScramSha256Server {
+        let salt: [u8; 16] = rand::thread_rng().gen();
+
+        Self::from_secret(
+            ScramSecret::from_password(password, &salt, SCRAM_ITERATIONS),
+            channel_binding,
+        )
+    }
+
+    pub fn from_secret(secret: ScramSecret, channel_binding: Option<Vec<u8>>) -> ScramSha256Server {
+        ScramSha256Server {
+            secret,
+            channel_binding,
+            gs2_header: String::new(),
+            client_first_bare: String::new(),
+            server_first: String::new(),
+            nonce: String::new(),
+            client_key: None,
+        }
+    }
+
+    /// ClientKey of the authenticated client, to log in to the server with
+    /// when we only have the secret.
+    pub fn client_key(&self) -> Option<[u8; 32]> {
+        self.client_key
+    }
+
+    /// Mechanisms to offer the client, channel binding only over TLS.
+    pub fn mechanisms(&self) -> Vec<&'static str> {
+        match self.channel_binding {
+            Some(_) => vec![SCRAM_SHA_256_PLUS, SCRAM_SHA_256],
+            None => vec![SCRAM_SHA_256],
+        }
+    }
+
+    /// Handle the client-first-message, returns the server-first-message.
+    pub fn first(&mut self, mechanism: &str, message: &[u8]) -> Result<String, Error> {
+        let invalid = |reason: &str| Error::AuthError(format!("SCRAM: {}", reason));
+
+        let message = std::str::from_utf8(message).map_err(|_| invalid("invalid message"))?;
+        let mut parts = message.splitn(3, ',');
+        let (flag, authzid, bare) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(flag), Some(authzid), Some(bare)) => (flag, authzid, bare),
+            _ => return Err(invalid("invalid client-first-message")),
+        };
+
+        let plus = mechanism == SCRAM_SHA_256_PLUS;
+        if !plus && mechanism != SCRAM_SHA_256 {
+            return Err(invalid("unsupported mechanism"));
+        }
+
+        match flag {
+            "n" if !plus => (),
+            // The client supports channel binding but thinks we don't.
+            "y" if !plus => {
+                if self.channel_binding.is_some() {
+                    return Err(invalid("channel binding was offered, possible downgrade"));
+                }
+            }
+            _ if plus && flag == format!("p={}", TLS_SERVER_END_POINT) => {
+                if self.channel_binding.is_none() {
+                    return Err(invalid("channel binding requires TLS"));
+                }
+            }
+            _ => return Err(invalid("unexpected channel binding flag")),
+        }
+
+        if !authzid.is_empty() {
+            return Err(invalid("authorization identities are not supported"));
+        }
+
+        // The username from the startup message is used.
+        let client_nonce = bare
+            .split(',')
+            .find_map(|attribute| attribute.strip_prefix("r="))
+            .filter(|nonce| !nonce.is_empty())
+            .ok_or_else(|| invalid("no nonce"))?;
+        if bare.starts_with("m=") {
+            return Err(invalid("unsupported extension"));
+        }
+
+        let mut rng = rand::thread_rng();
+        let server_nonce = (0..NONCE_LENGTH)
+            .map(|_| {
+                let mut v = rng.gen_range(0x21u8..0x7e);
+                if v == 0x2c {
+                    v = 0x7e
+                }
+                v as char
+            })
+            .collect::<String>();
+
+        self.gs2_header = format!("{},{},", flag, authzid);
+        self.client_first_bare = bare.to_string();
+        self.nonce = format!("{}{}", client_nonce, server_nonce);
+        self.server_first = format!(
+            "r={},s={},i={}",
+            self.nonce,
+            general_purpose::STANDARD.encode(&self.secret.salt),
+            self.secret.iterations
+        );
+
+        Ok(self.server_first.clone())
+    }
+
+    /// Verify the client-final-message, returns the server-final-message.
+    pub fn finish(&mut self, message: &[u8]) -> Result<String, Error> {
+        let invalid = |reason: &str| Error::AuthError(format!("SCRAM: {}", reason));
+
+        let message = std::str::from_utf8(message).map_err(|_| invalid("invalid message"))?;
+        let (without_proof, proof) = message
+            .rsplit_once(",p=")
+            .ok_or_else(|| invalid("no proof"))?;
+        let proof = general_purpose::STANDARD
+            .decode(proof)
+            .map_err(|_| invalid("invalid proof"))?;
+
+        let mut attributes = without_proof.split(',');
+
+        let cbind_input = attributes
+            .next()
+            .and_then(|attribute| attribute.strip_prefix("c="))
+            .and_then(|input| general_purpose::STANDARD.decode(input).ok())
+            .ok_or_else(|| invalid("invalid channel binding"))?;
+        let mut expected = self.gs2_header.as_bytes().to_vec();
+        if self.gs2_header.starts_with("p=") {
+            expected.extend(self.channel_binding.as_deref().unwrap_or_default());
+        }
+        if cbind_input != expected {
+            return Err(invalid("channel binding doesn't match"));
+        }
+
+        if attributes
+            .next()
+            .and_then(|attribute| attribute.strip_prefix("r="))
+            != Some(self.nonce.as_str())
+        {
+            return Err(invalid("nonce doesn't match"));
+        }
+
+        let auth_message = format!(
+            "{},{},{}",
+            self.client_first_bare, self.server_first, without_proof
+        );
+
+        let client_signature = hmac(&self.secret.stored_key, auth_message.as_bytes());
+
+        if proof.len() != client_signature.len() {
+            return Err(invalid("invalid proof"));
+        }
+
+        // The client key recovered from the proof must hash to the stored key.
+        let mut recovered_key = [0u8; 32];
+        for ((key, proof), signature) in recovered_key.iter_mut().zip(proof).zip(client_signature) {
+            *key = proof ^ signature;
+        }
+        if Sha256::digest(recovered_key).as_slice() != self.secret.stored_key {
+            return Err(Error::AuthError("SCRAM: invalid password".into()));
+        }
+        self.client_key = Some(recovered_key);
+
+        let server_signature = hmac(&self.secret.server_key, auth_message.as_bytes());
+
+        Ok(format!(
+            "v={}",
+            general_purpose::STANDARD.encode(server_signature)
+        ))
+    }
+}
+
 /// Parse the server challenge.
 struct Message {
     nonce: String,
@@ -233,9 +520,15 @@ impl Message {
             return Err(Error::ProtocolSyncError("SCRAM".to_string()));
         }
 
-        let nonce = str::replace(&parts[0], "r=", "");
-        let salt = str::replace(&parts[1], "s=", "");
-        let iterations = match str::replace(&parts[2], "i=", "").parse::<u32>() {
+        // The nonce can contain "r=", only strip the attribute name.
+        let attribute = |part: &str, name: &str| match part.strip_prefix(name) {
+            Some(value) => Ok(value.to_string()),
+            None => Err(Error::ProtocolSyncError("SCRAM".to_string())),
+        };
+
+        let nonce = attribute(&parts[0], "r=")?;
+        let salt = attribute(&parts[1], "s=")?;
+        let iterations = match attribute(&parts[2], "i=")?.parse::<u32>() {
             Ok(iterations) => iterations,
             Err(_) => return Err(Error::ProtocolSyncError("SCRAM".to_string())),
         };
@@ -322,4 +615,107 @@ mod test {
             .finish(&BytesMut::from(server_final.as_bytes()))
             .unwrap();
     }
+
+    /// Run the exchange between our client and server, returns the server's result.
+    fn server_exchange(
+        client: &mut ScramSha256,
+        server: &mut ScramSha256Server,
+        mechanism: &str,
+    ) -> Result<(), Error> {
+        let client_first = client.message();
+        let server_first = server.first(mechanism, &client_first)?;
+        let client_final = client.update(&BytesMut::from(server_first.as_bytes()))?;
+        let server_final = server.finish(&client_final)?;
+        client.finish(&BytesMut::from(server_final.as_bytes()))
+    }
+
+    #[test]
+    fn server_exchange_without_channel_binding() {
+        let mut server = ScramSha256Server::new("secret", None);
+        assert_eq!(server.mechanisms(), vec![SCRAM_SHA_256]);
+
+        let mut client = ScramSha256::new("secret");
+        assert!(server_exchange(&mut client, &mut server, SCRAM_SHA_256).is_ok());
+
+        let mut server = ScramSha256Server::new("secret", None);
+        let mut client = ScramSha256::new("wrong");
+        assert!(server_exchange(&mut client, &mut server, SCRAM_SHA_256).is_err());
+
+        // Channel binding is never offered without TLS.
+        let mut server = ScramSha256Server::new("secret", None);
+        let mut client = ScramSha256::new("secret").with_channel_binding(b"data");
+        assert!(server_exchange(&mut client, &mut server, SCRAM_SHA_256_PLUS).is_err());
+    }
+
+    #[test]
+    fn server_exchange_with_channel_binding() {
+        let mut server = ScramSha256Server::new("secret", Some(b"data".to_vec()));
+        assert_eq!(server.mechanisms(), vec![SCRAM_SHA_256_PLUS, SCRAM_SHA_256]);
+
+        let mut client = ScramSha256::new("secret").with_channel_binding(b"data");
+        assert!(server_exchange(&mut client, &mut server, SCRAM_SHA_256_PLUS).is_ok());
+
+        // Different TLS connection, e.g. a man in the middle.
+        let mut server = ScramSha256Server::new("secret", Some(b"data".to_vec()));
+        let mut client = ScramSha256::new("secret").with_channel_binding(b"other");
+        assert!(server_exchange(&mut client, &mut server, SCRAM_SHA_256_PLUS).is_err());
+
+        // Clients not using channel binding are still accepted.
+        let mut server = ScramSha256Server::new("secret", Some(b"data".to_vec()));
+        let mut client = ScramSha256::new("secret");
+        assert!(server_exchange(&mut client, &mut server, SCRAM_SHA_256).is_ok());
+
+        // Unless they think the server doesn't support it.
+        let mut server = ScramSha256Server::new("secret", Some(b"data".to_vec()));
+        assert!(server.first(SCRAM_SHA_256, b"y,,n=,r=abcdef").is_err());
+        let mut server = ScramSha256Server::new("secret", None);
+        assert!(server.first(SCRAM_SHA_256, b"y,,n=,r=abcdef").is_ok());
+    }
+
+    #[test]
+    fn parse_secret() {
+        // Generated by Postgres for the password "foobar".
+        let stored = "SCRAM-SHA-256$4096:PWJOqN5vQtj/VlC8Tcm7AA==$XDM+4ht9iITxnDGNGQ1RIsRHyt7ntDj06kmVniqJMJA=:K/HLWnkfFQHWGXFTfHIrWc9rRd+5mJ5HoI9qA3zxAyg=";
+
+        let secret = ScramSecret::parse(stored).unwrap();
+        assert_eq!(secret.iterations, 4096);
+        assert_eq!(
+            secret,
+            ScramSecret::from_password("foobar", &secret.salt, 4096)
+        );
+        assert_eq!(secret.to_string(), stored);
+
+        assert!(ScramSecret::parse("md5a3556571e93b0d20722ba62be61e8c2d").is_none());
+        assert!(
+            ScramSecret::parse("SCRAM-SHA-256$4096:PWJOqN5vQtj/VlC8Tcm7AA==$bad:bad").is_none()
+        );
+        assert!(ScramSecret::parse(&stored.replace("4096", "0")).is_none());
+    }
+
+    #[test]
+    fn secret_exchange() {
+        let salt: [u8; 16] = rand::thread_rng().gen();
+        let secret = ScramSecret::from_password("secret", &salt, SCRAM_ITERATIONS);
+
+        // The client is authenticated with the secret only.
+        let mut server = ScramSha256Server::from_secret(secret.clone(), None);
+        let mut client = ScramSha256::new("secret");
+        assert!(server_exchange(&mut client, &mut server, SCRAM_SHA_256).is_ok());
+        let client_key = server.client_key().unwrap();
+
+        // Its key doesn't work with another secret of the same password, the salt differs.
+        let mut backend = ScramSha256Server::new("secret", None);
+        let mut proxied = ScramSha256::from_keys(client_key, secret.server_key);
+        assert!(server_exchange(&mut proxied, &mut backend, SCRAM_SHA_256).is_err());
+
+        // It logs in to the server the secret was obtained from.
+        let mut backend = ScramSha256Server::from_secret(secret.clone(), None);
+        let mut proxied = ScramSha256::from_keys(client_key, secret.server_key);
+        assert!(server_exchange(&mut proxied, &mut backend, SCRAM_SHA_256).is_ok());
+
+        let mut server = ScramSha256Server::from_secret(secret, None);
+        let mut client = ScramSha256::new("wrong");
+        assert!(server_exchange(&mut client, &mut server, SCRAM_SHA_256).is_err());
+        assert!(server.client_key().is_none());
+    }
 }
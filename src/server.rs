@@ -9,29 +9,33 @@ use parking_lot::{Mutex, RwLock};
 use postgres_protocol::message;
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::mem;
-use std::net::IpAddr;
-use std::num::NonZeroUsize;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, BufStream};
-use tokio::net::TcpStream;
+use tokio::net::{lookup_host, TcpStream};
+use tokio::sync::OwnedSemaphorePermit;
+use tokio::task::JoinSet;
 use tokio_rustls::rustls::{OwnedTrustAnchor, RootCertStore};
 use tokio_rustls::{client::TlsStream, TlsConnector};
 
+use crate::auth_passthrough::scram_client_key;
 use crate::config::{get_config, Address, User};
 use crate::constants::*;
-use crate::dns_cache::{AddrSet, CACHED_RESOLVER};
+use crate::dns_cache::CACHED_RESOLVER;
 use crate::errors::{Error, ServerIdentifier};
 use crate::messages::BytesMutReader;
 use crate::messages::*;
 use crate::mirrors::MirroringManager;
 use crate::pool::ClientServerMap;
-use crate::scram::ScramSha256;
+use crate::scram::{ScramSecret, ScramSha256};
 use crate::stats::ServerStats;
+use crate::tls::{cipher_suites, protocol_versions};
 use std::io::Write;
 
 use pin_project::pin_project;
 
+#[allow(clippy::large_enum_variant)]
 #[pin_project(project = SteamInnerProj)]
 pub enum StreamInner {
     Plain {
@@ -114,6 +118,10 @@ struct CleanupState {
 
     /// If server connection requires DEALLOCATE ALL before checkin because of prepare statement
     needs_cleanup_prepare: bool,
+
+    /// If the settings of the server connection may differ from the ones it started with
+    /// before checkin because of a set statement in a transaction
+    needs_settings_check: bool,
 }
 
 impl CleanupState {
@@ -121,6 +129,7 @@ impl CleanupState {
         CleanupState {
             needs_cleanup_set: false,
             needs_cleanup_prepare: false,
+            needs_settings_check: false,
         }
     }
 
@@ -136,6 +145,7 @@ impl CleanupState {
     fn reset(&mut self) {
         self.needs_cleanup_set = false;
         self.needs_cleanup_prepare = false;
+        self.needs_settings_check = false;
     }
 }
 
@@ -143,8 +153,8 @@ impl std::fmt::Display for CleanupState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "SET: {}, PREPARE: {}",
-            self.needs_cleanup_set, self.needs_cleanup_prepare
+            "SET: {}, PREPARE: {}, SETTINGS CHECK: {}",
+            self.needs_cleanup_set, self.needs_cleanup_prepare, self.needs_settings_check
         )
     }
 }
@@ -159,6 +169,24 @@ static TRACKED_PARAMETERS: Lazy<HashSet<String>> = Lazy::new(|| {
     set
 });
 
+/// Sets the settings that differ from their session defaults (the server config and the
+/// startup parameters) back to them, returning their names. `SET LOCAL`s are gone by then,
+/// so what's left are the `SET`s of a transaction. The tracked parameters are synced at checkout.
+static RESET_DRIFTED_SETTINGS: Lazy<String> = Lazy::new(|| {
+    let mut tracked: Vec<_> = TRACKED_PARAMETERS
+        .iter()
+        .map(|name| format!("'{}'", name))
+        .collect();
+    tracked.sort();
+
+    format!(
+        "SELECT name, set_config(name, reset_val, false) FROM pg_settings \
+         WHERE source = 'session' AND setting IS DISTINCT FROM reset_val \
+         AND name NOT LIKE 'transaction\\_%' AND name NOT IN ({})",
+        tracked.join(", ")
+    )
+});
+
 #[derive(Debug, Clone)]
 pub struct ServerParameters {
     parameters: HashMap<String, String>,
@@ -234,6 +262,23 @@ impl ServerParameters {
         self.parameters.get("application_name").unwrap()
     }
 
+    // Parameters with a different value than what the client was told, except the
+    // tracked ones, those are set on the server instead, see `Server::sync_parameters`.
+    fn reported_diff(&self, client_parameters: &ServerParameters) -> Vec<(String, String)> {
+        let mut diff = self
+            .parameters
+            .iter()
+            .filter(|(key, value)| {
+                !TRACKED_PARAMETERS.contains(*key)
+                    && client_parameters.parameters.get(*key) != Some(value)
+            })
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>();
+
+        diff.sort();
+        diff
+    }
+
     fn add_parameter_message(key: &str, value: &str, buffer: &mut BytesMut) {
         buffer.put_u8(b'S');
 
@@ -309,27 +354,148 @@ pub struct Server {
     /// Application name using the server at the moment.
     application_name: String,
 
+    /// The `application_name` was set for the client using the server, set it back when
+    /// the server is returned to the pool.
+    reset_application_name: bool,
+
     /// Last time that a successful server send or response happened
     last_activity: SystemTime,
 
+    /// Close the connection when it's returned to the pool after this long.
+    max_lifetime: Option<chrono::Duration>,
+
+    /// Held while the connection is checked out from an autoscaled pool,
+    /// or one with `max_backend_connections`.
+    checkout_permits: Vec<OwnedSemaphorePermit>,
+
+    /// Held while the connection is open, when the connections to the server are shared
+    /// with the sticky parameter sub-pools.
+    connection_permit: Option<OwnedSemaphorePermit>,
+
+    /// Close the connection when it's returned to the pool, to give its permit to another pool.
+    close_on_checkin: bool,
+
     mirror_manager: Option<MirroringManager>,
 
-    /// Associated addresses used
-    addr_set: Option<AddrSet>,
+    /// Copy the messages sent to the server to the mirrors, see `set_mirroring`.
+    mirroring: bool,
+
+    /// Rows returned or affected by the commands completed since `take_rows`.
+    rows: u64,
+
+    /// SQLSTATE of the last error since `take_error_code`.
+    error_code: Option<String>,
+
+    /// Address the host resolved to when connecting, with the DNS cache.
+    resolved_address: Option<IpAddr>,
+
+    /// Address of the server this connection is made to, where its queries are canceled.
+    connected_address: Option<IpAddr>,
 
     /// Should clean up dirty connections?
     cleanup_connections: bool,
 
+    /// Query run every time the connection is returned to the pool.
+    reset_query: Option<String>,
+
     /// Log client parameter status changes
     log_client_parameter_status_changes: bool,
 
     /// Prepared statements
     prepared_statement_cache: Option<LruCache<String, ()>>,
 
+    /// How many prepared statements are kept on the server, the least recently used ones
+    /// are closed to make room.
+    max_prepared_statements: usize,
+
+    /// Prepared statements used by the extended protocol messages of the client that aren't
+    /// sent yet, which can't be closed before.
+    prepared_statements_in_use: HashSet<String>,
+
     /// Prepared statement being currently registered on the server.
     registering_prepared_statement: VecDeque<String>,
 }
 
+/// Head start of a connection attempt before the next address is tried too.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connect to the first of the addresses to accept the connection, happy eyeballs style
+/// (RFC 8305): the addresses are tried in turns by family, starting with the family of the first
+/// one, and each attempt gets a head start of `attempt_delay` before the next one starts,
+/// unless it fails sooner. A stalled IPv6 path doesn't hold up the IPv4 addresses.
+/// The attempts still running are canceled once one of them connects.
+pub async fn connect(
+    addresses: &[SocketAddr],
+    attempt_delay: Duration,
+) -> std::io::Result<TcpStream> {
+    let (first, second): (Vec<SocketAddr>, Vec<SocketAddr>) = match addresses.first() {
+        Some(first) => addresses
+            .iter()
+            .partition(|address| address.is_ipv6() == first.is_ipv6()),
+        None => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "no address to connect to",
+            ))
+        }
+    };
+
+    // Interleave the address families.
+    let mut first = first.into_iter();
+    let mut second = second.into_iter();
+    let mut pending = VecDeque::new();
+    loop {
+        match (first.next(), second.next()) {
+            (None, None) => break,
+            (a, b) => pending.extend(a.into_iter().chain(b)),
+        }
+    }
+
+    // Dropping the set aborts the attempts left.
+    let mut attempts = JoinSet::new();
+    let mut last_error = None;
+
+    loop {
+        if attempts.is_empty() {
+            match pending.pop_front() {
+                Some(address) => {
+                    attempts.spawn(TcpStream::connect(address));
+                }
+                None => {
+                    return Err(
+                        last_error.unwrap_or_else(|| std::io::Error::other("could not connect"))
+                    )
+                }
+            }
+        }
+
+        tokio::select! {
+            result = attempts.join_next() => match result {
+                Some(Ok(Ok(stream))) => return Ok(stream),
+                Some(Ok(Err(err))) => {
+                    debug!("Connection attempt failed: {}", err);
+                    last_error = Some(err);
+
+                    // Don't wait for the head start to end.
+                    if let Some(address) = pending.pop_front() {
+                        attempts.spawn(TcpStream::connect(address));
+                    }
+                }
+                Some(Err(err)) => {
+                    last_error = Some(std::io::Error::other(err));
+                }
+                None => (),
+            },
+
+            _ = tokio::time::sleep(attempt_delay), if !pending.is_empty() => {
+                if let Some(address) = pending.pop_front() {
+                    attempts.spawn(TcpStream::connect(address));
+                }
+            }
+        }
+    }
+}
+
 impl Server {
     /// Pretend to be the Postgres client and connect to the server given host, port and credentials.
     /// Perform the authentication and return the server in a ready for query state.
@@ -344,36 +510,61 @@ impl Server {
         cleanup_connections: bool,
         log_client_parameter_status_changes: bool,
         prepared_statement_cache_size: usize,
+        replication: Option<&str>,
     ) -> Result<Server, Error> {
         let cached_resolver = CACHED_RESOLVER.load();
-        let mut addr_set: Option<AddrSet> = None;
+        let mut addresses: Vec<SocketAddr> = Vec::new();
 
         // If we are caching addresses and hostname is not an IP
         if cached_resolver.enabled() && address.host.parse::<IpAddr>().is_err() {
             debug!("Resolving {}", &address.host);
-            addr_set = match cached_resolver.lookup_ip(&address.host).await {
+            match cached_resolver.resolve(&address.host).await {
                 Ok(ok) => {
                     debug!("Obtained: {:?}", ok);
-                    Some(ok)
+                    addresses = ok
+                        .into_iter()
+                        .map(|ip| SocketAddr::new(ip, address.port))
+                        .collect();
                 }
                 Err(err) => {
                     warn!("Error trying to resolve {}, ({:?})", &address.host, err);
-                    None
                 }
             }
         };
 
-        let mut stream =
-            match TcpStream::connect(&format!("{}:{}", &address.host, address.port)).await {
-                Ok(stream) => stream,
+        let cached = !addresses.is_empty();
+
+        if !cached {
+            addresses = match lookup_host((address.host.as_str(), address.port)).await {
+                Ok(addresses) => addresses.collect(),
                 Err(err) => {
-                    error!("Could not connect to server: {}", err);
+                    error!("Could not resolve server: {}", err);
                     return Err(Error::SocketError(format!(
                         "Could not connect to server: {}",
                         err
                     )));
                 }
             };
+        }
+
+        let mut stream = match connect(&addresses, CONNECTION_ATTEMPT_DELAY).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!("Could not connect to server: {}", err);
+                return Err(Error::SocketError(format!(
+                    "Could not connect to server: {}",
+                    err
+                )));
+            }
+        };
+
+        let connected_address = stream.peer_addr().ok().map(|address| address.ip());
+
+        // Connections to an address the host no longer resolves to are closed.
+        let resolved_address = match cached {
+            true => connected_address,
+            false => None,
+        };
 
         // TCP timeouts.
         configure_socket(&stream);
@@ -411,7 +602,10 @@ impl Server {
                     );
 
                     let mut tls_config = rustls::ClientConfig::builder()
-                        .with_safe_defaults()
+                        .with_cipher_suites(&cipher_suites(&config.general)?)
+                        .with_safe_default_kx_groups()
+                        .with_protocol_versions(protocol_versions(&config.general))
+                        .map_err(|err| Error::SocketError(format!("Server TLS error: {:?}", err)))?
                         .with_root_certificates(root_store)
                         .with_no_client_auth();
 
@@ -466,7 +660,14 @@ impl Server {
             None => user.password.as_ref(),
         };
 
-        startup(&mut stream, username, database).await?;
+        startup(
+            &mut stream,
+            username,
+            database,
+            replication,
+            &user.server_settings,
+        )
+        .await?;
 
         let mut process_id: i32 = 0;
         let mut secret_key: i32 = 0;
@@ -566,11 +767,21 @@ impl Server {
                         AUTHENTICATION_SUCCESSFUL => (),
 
                         SASL => {
+                            // Using auth passthrough with a SCRAM secret, we can log in with
+                            // the key of a client authenticated with it.
+                            if scram.is_none() {
+                                scram = (*auth_hash.read()).as_ref().and_then(|hash| {
+                                    let secret = ScramSecret::parse(hash)?;
+                                    let client_key = scram_client_key(hash)?;
+                                    Some(ScramSha256::from_keys(client_key, secret.server_key))
+                                });
+                            }
+
                             if scram.is_none() {
                                 return Err(Error::ServerAuthError(
                                     "SASL auth required and no password specified. \
-                                    Auth passthrough (auth_query) method is currently \
-                                    unsupported for SASL auth"
+                                    Auth passthrough (auth_query) needs a SCRAM secret \
+                                    and a client authenticated with it first"
                                         .into(),
                                     server_identifier,
                                 ));
@@ -707,9 +918,11 @@ impl Server {
 
                         // An error message will be present.
                         _ => {
-                            let mut error = vec![0u8; len as usize];
+                            // The code of the first field was just read.
+                            let mut error = vec![0u8; len as usize - 4];
+                            error[0] = error_code;
 
-                            match stream.read_exact(&mut error).await {
+                            match stream.read_exact(&mut error[1..]).await {
                                 Ok(_) => (),
                                 Err(_) => {
                                     return Err(Error::ServerStartupError(
@@ -727,6 +940,11 @@ impl Server {
                             };
                             trace!("error fields: {}", &fields);
                             error!("server error: {}: {}", fields.severity, fields.message);
+
+                            // Overloaded, not down.
+                            if fields.code == TOO_MANY_CONNECTIONS {
+                                return Err(Error::ServerTooManyConnections);
+                            }
                         }
                     };
 
@@ -757,6 +975,45 @@ impl Server {
                     server_parameters.set_param(key, value, true);
                 }
 
+                // NegotiateProtocolVersion: the server doesn't support everything we asked for.
+                // We only ask for 3.0 without options, so there's nothing to fall back from.
+                'v' => {
+                    // Minor version and option count, at least.
+                    if len < 12 {
+                        return Err(Error::ServerStartupError(
+                            "negotiate protocol version message".into(),
+                            server_identifier,
+                        ));
+                    }
+
+                    let mut bytes = BytesMut::with_capacity(len as usize - 4);
+                    bytes.resize(len as usize - mem::size_of::<i32>(), b'0');
+
+                    match stream.read_exact(&mut bytes[..]).await {
+                        Ok(_) => (),
+                        Err(_) => {
+                            return Err(Error::ServerStartupError(
+                                "negotiate protocol version message".into(),
+                                server_identifier,
+                            ))
+                        }
+                    };
+
+                    let minor_version = bytes.get_i32();
+                    // Every option takes at least its null terminator.
+                    let count = usize::try_from(bytes.get_i32())
+                        .unwrap_or(0)
+                        .min(bytes.len());
+                    let options = (0..count)
+                        .filter_map(|_| bytes.read_string().ok())
+                        .collect::<Vec<_>>();
+
+                    debug!(
+                        "Server {:?} supports protocol up to 3.{}, unsupported options: {:?}",
+                        address, minor_version, options
+                    );
+                }
+
                 // BackendKeyData
                 'K' => {
                     // The frontend must save these values if it wishes to be able to issue CancelRequest messages later.
@@ -809,11 +1066,18 @@ impl Server {
                         bad: false,
                         cleanup_state: CleanupState::new(),
                         client_server_map,
-                        addr_set,
+                        resolved_address,
+                        connected_address,
                         connected_at: chrono::offset::Utc::now().naive_utc(),
                         stats,
                         application_name: "pgcat".to_string(),
+                        reset_application_name: false,
                         last_activity: SystemTime::now(),
+                        max_lifetime: None,
+                        reset_query: None,
+                        checkout_permits: Vec::new(),
+                        connection_permit: None,
+                        close_on_checkin: false,
                         mirror_manager: match address.mirrors.len() {
                             0 => None,
                             _ => Some(MirroringManager::from_addresses(
@@ -822,14 +1086,17 @@ impl Server {
                                 address.mirrors.clone(),
                             )),
                         },
+                        mirroring: true,
+                        rows: 0,
+                        error_code: None,
                         cleanup_connections,
                         log_client_parameter_status_changes,
                         prepared_statement_cache: match prepared_statement_cache_size {
                             0 => None,
-                            _ => Some(LruCache::new(
-                                NonZeroUsize::new(prepared_statement_cache_size).unwrap(),
-                            )),
+                            _ => Some(LruCache::unbounded()),
                         },
+                        max_prepared_statements: prepared_statement_cache_size,
+                        prepared_statements_in_use: HashSet::new(),
                         registering_prepared_statement: VecDeque::new(),
                     };
 
@@ -857,7 +1124,7 @@ impl Server {
         process_id: i32,
         secret_key: i32,
     ) -> Result<(), Error> {
-        let mut stream = match TcpStream::connect(&format!("{}:{}", host, port)).await {
+        let mut stream = match TcpStream::connect((host, port)).await {
             Ok(stream) => stream,
             Err(err) => {
                 error!("Could not connect to server: {}", err);
@@ -879,7 +1146,9 @@ impl Server {
 
     /// Send messages to the server from the client.
     pub async fn send(&mut self, messages: &BytesMut) -> Result<(), Error> {
-        self.mirror_send(messages);
+        if self.mirroring {
+            self.mirror_send(messages);
+        }
         self.stats().data_sent(messages.len());
 
         match write_all_flush(&mut self.stream, messages).await {
@@ -969,6 +1238,8 @@ impl Server {
                         self.in_copy_mode = false;
                     }
 
+                    self.error_code = PgErrorMsg::parse(&message).ok().map(|error| error.code);
+
                     // Remove the prepared statement from the cache, it has a syntax error or something else bad happened.
                     if let Some(prepared_stmt_name) =
                         self.registering_prepared_statement.pop_front()
@@ -1009,16 +1280,25 @@ impl Server {
 
                     match message.read_string() {
                         Ok(command) => {
+                            // E.g. `SELECT 5` or `INSERT 0 5`.
+                            if let Some(Ok(rows)) =
+                                command.rsplit(' ').next().map(str::parse::<u64>)
+                            {
+                                self.rows += rows;
+                            }
+
                             // Non-exhaustive list of commands that are likely to change session variables/resources
                             // which can leak between clients. This is a best effort to block bad clients
                             // from poisoning a transaction-mode pool by setting inappropriate session variables
                             match command.as_str() {
                                 "SET" => {
-                                    // We don't detect set statements in transactions
-                                    // No great way to differentiate between set and set local
-                                    // As a result, we will miss cases when set statements are used in transactions
-                                    // This will reduce amount of reset statements sent
-                                    if !self.in_transaction {
+                                    // No great way to differentiate between set and set local in transactions,
+                                    // so instead of a RESET ALL, the settings that changed are looked up
+                                    // and reset at checkin. There are none if it was a set local.
+                                    if self.in_transaction {
+                                        debug!("Server connection marked for settings check");
+                                        self.cleanup_state.needs_settings_check = true;
+                                    } else {
                                         debug!("Server connection marked for clean up");
                                         self.cleanup_state.needs_cleanup_set = true;
                                     }
@@ -1042,14 +1322,25 @@ impl Server {
                     let key = message.read_string().unwrap();
                     let value = message.read_string().unwrap();
 
+                    // Everything the server reports is forwarded to the client, keep track
+                    // of all of it, not just the parameters we sync between server connections.
                     if let Some(client_server_parameters) = client_server_parameters.as_mut() {
-                        client_server_parameters.set_param(key.clone(), value.clone(), false);
+                        client_server_parameters.set_param(key.clone(), value.clone(), true);
                         if self.log_client_parameter_status_changes {
                             info!("Client parameter status change: {} = {}", key, value)
                         }
+
+                        // Changed by the client, e.g. `SET search_path` in a transaction. We can't
+                        // set it back for the next client like the tracked parameters, reset it at checkin.
+                        if !TRACKED_PARAMETERS.contains(&key)
+                            && self.server_parameters.parameters.get(&key) != Some(&value)
+                        {
+                            debug!("Server connection marked for clean up, {} changed", key);
+                            self.cleanup_state.needs_cleanup_set = true;
+                        }
                     }
 
-                    self.server_parameters.set_param(key, value, false);
+                    self.server_parameters.set_param(key, value, true);
                 }
 
                 // DataRow
@@ -1077,11 +1368,9 @@ impl Server {
                 }
 
                 // CopyData
-                'd' => {
+                'd' if self.buffer.len() >= 8196 => {
                     // Don't flush yet, buffer until we reach limit
-                    if self.buffer.len() >= 8196 {
-                        break;
-                    }
+                    break;
                 }
 
                 // CopyDone
@@ -1132,26 +1421,41 @@ impl Server {
         has_it
     }
 
-    fn add_prepared_statement_to_cache(&mut self, name: &str) -> Option<String> {
+    /// Add the statement to the cache, returning the ones to evict to make room for it.
+    fn add_prepared_statement_to_cache(&mut self, name: &str) -> Vec<String> {
         let cache = match &mut self.prepared_statement_cache {
             Some(cache) => cache,
-            None => return None,
+            None => return Vec::new(),
         };
 
         self.stats.prepared_cache_add();
+        cache.push(name.to_string(), ());
+
+        // The least recently used first. When the others are all in use, the cache stays
+        // over its size until the client's messages using them are sent.
+        let evicted: Vec<String> = cache
+            .iter()
+            .rev()
+            .map(|(name, _)| name)
+            .filter(|name| !self.prepared_statements_in_use.contains(*name))
+            .take(cache.len().saturating_sub(self.max_prepared_statements))
+            .cloned()
+            .collect();
+
+        for evicted_name in &evicted {
+            debug!(
+                "Evicted prepared statement {} from cache, replaced with {}",
+                evicted_name, name
+            );
+        }
 
-        // If we evict something, we need to close it on the server
-        if let Some((evicted_name, _)) = cache.push(name.to_string(), ()) {
-            if evicted_name != name {
-                debug!(
-                    "Evicted prepared statement {} from cache, replaced with {}",
-                    evicted_name, name
-                );
-                return Some(evicted_name);
-            }
-        };
+        evicted
+    }
 
-        None
+    /// The client's extended protocol messages were sent, the statements they used
+    /// can be evicted again.
+    pub fn release_prepared_statements(&mut self) {
+        self.prepared_statements_in_use.clear();
     }
 
     fn remove_prepared_statement_from_cache(&mut self, name: &str) {
@@ -1169,6 +1473,8 @@ impl Server {
         parse: &Parse,
         should_send_parse_to_server: bool,
     ) -> Result<(), Error> {
+        self.prepared_statements_in_use.insert(parse.name.clone());
+
         if !self.has_prepared_statement(&parse.name) {
             self.registering_prepared_statement
                 .push_back(parse.name.clone());
@@ -1182,11 +1488,11 @@ impl Server {
 
             // If we evict something, we need to close it on the server
             // We do this by adding it to the messages we're sending to the server before the sync
-            if let Some(evicted_name) = self.add_prepared_statement_to_cache(&parse.name) {
+            for evicted_name in self.add_prepared_statement_to_cache(&parse.name) {
                 self.remove_prepared_statement_from_cache(&evicted_name);
                 let close_bytes: BytesMut = Close::new(&evicted_name).try_into()?;
                 bytes.extend_from_slice(&close_bytes);
-            };
+            }
 
             // If we have a parse or close we need to send to the server, send them and sync
             if !bytes.is_empty() {
@@ -1220,6 +1526,12 @@ impl Server {
         self.in_transaction
     }
 
+    /// The client changed the session of the server connection, e.g. with `SET` or `PREPARE`,
+    /// so it couldn't carry on with another one.
+    pub fn has_session_state(&self) -> bool {
+        self.cleanup_state.needs_cleanup() || self.cleanup_state.needs_settings_check
+    }
+
     /// Currently copying data from client to server or vice-versa.
     pub fn in_copy_mode(&self) -> bool {
         self.in_copy_mode
@@ -1239,12 +1551,12 @@ impl Server {
         };
         let cached_resolver = CACHED_RESOLVER.load();
         if cached_resolver.enabled() {
-            if let Some(addr_set) = &self.addr_set {
-                if cached_resolver.has_changed(self.address.host.as_str(), addr_set) {
+            if let Some(resolved_address) = &self.resolved_address {
+                if !cached_resolver.resolves_to(self.address.host.as_str(), resolved_address) {
                     warn!(
-                        "DNS changed for {}, it was {:?}. Dropping server connection.",
+                        "DNS changed for {}, it no longer resolves to {}. Dropping server connection.",
                         self.address.host.as_str(),
-                        addr_set
+                        resolved_address
                     );
                     return true;
                 }
@@ -1268,7 +1580,7 @@ impl Server {
         let mut query = String::from("");
 
         for (key, value) in parameter_diff {
-            query.push_str(&format!("SET {} TO '{}';", key, value));
+            query.push_str(&format!("SET {} TO '{}';", key, value.replace('\'', "''")));
         }
 
         let res = self.query(&query).await;
@@ -1278,6 +1590,96 @@ impl Server {
         res
     }
 
+    /// Sync the parameters of the client, with this `application_name` instead of its own if
+    /// given, e.g. with the identity of the client. It's set back to `pgcat` at checkin.
+    pub async fn sync_client_parameters(
+        &mut self,
+        parameters: &ServerParameters,
+        application_name: Option<&str>,
+    ) -> Result<(), Error> {
+        let application_name = match application_name {
+            Some(application_name) => application_name,
+            None => return self.sync_parameters(parameters).await,
+        };
+
+        let mut parameters = parameters.clone();
+        parameters.set_param(
+            "application_name".to_string(),
+            application_name.to_string(),
+            false,
+        );
+        self.reset_application_name = true;
+
+        self.sync_parameters(&parameters).await
+    }
+
+    /// ParameterStatus messages for the parameters of this server the client doesn't know about,
+    /// e.g. `in_hot_standby` when it moves from the primary to a replica. Updates what the client knows.
+    pub fn parameter_status_changes(&self, client_parameters: &mut ServerParameters) -> BytesMut {
+        let mut bytes = BytesMut::new();
+
+        for (key, value) in self.server_parameters.reported_diff(client_parameters) {
+            ServerParameters::add_parameter_message(&key, &value, &mut bytes);
+            client_parameters.set_param(key, value, true);
+        }
+
+        bytes
+    }
+
+    /// Copy everything between the client and the server until either of them
+    /// disconnects. Used by replication connections, their CopyBoth stream
+    /// isn't made of queries and responses.
+    pub async fn relay<R, W>(
+        &mut self,
+        client_read: &mut R,
+        client_write: &mut W,
+    ) -> Result<(), Error>
+    where
+        R: tokio::io::AsyncRead + std::marker::Unpin,
+        W: tokio::io::AsyncWrite + std::marker::Unpin,
+    {
+        let mut client_buffer = vec![0u8; 8196];
+        let mut server_buffer = vec![0u8; 8196];
+
+        loop {
+            tokio::select! {
+                read = client_read.read(&mut client_buffer) => {
+                    let len = read.map_err(|err| {
+                        Error::SocketError(format!("Error reading from client - Error: {:?}", err))
+                    })?;
+
+                    if len == 0 {
+                        return Ok(());
+                    }
+
+                    self.stats.data_sent(len);
+                    write_all_flush(&mut self.stream, &client_buffer[..len]).await?;
+                }
+
+                read = self.stream.read(&mut server_buffer) => {
+                    let len = match read {
+                        Ok(len) => len,
+                        Err(err) => {
+                            self.bad = true;
+                            return Err(Error::SocketError(format!(
+                                "Error reading from server - Error: {:?}",
+                                err
+                            )));
+                        }
+                    };
+
+                    if len == 0 {
+                        self.bad = true;
+                        return Ok(());
+                    }
+
+                    self.stats.data_received(len);
+                    write_all_flush(client_write, &server_buffer[..len]).await?;
+                }
+            }
+        }
+    }
+
     /// Indicate that this server connection cannot be re-used and must be discarded.
     pub fn mark_bad(&mut self, reason: &str) {
         error!("Server {:?} marked bad, reason: {}", self.address, reason);
@@ -1286,18 +1688,28 @@ impl Server {
 
     /// Claim this server as mine for the purposes of query cancellation.
     pub fn claim(&mut self, process_id: i32, secret_key: i32) {
+        let (host, port, server_process_id, server_secret_key) = self.cancel_key();
         let mut guard = self.client_server_map.lock();
         guard.insert(
             (process_id, secret_key),
-            (
-                self.process_id,
-                self.secret_key,
-                self.address.host.clone(),
-                self.address.port,
-            ),
+            (server_process_id, server_secret_key, host, port),
         );
     }
 
+    /// Host, port, process id and secret key to cancel the query running on this connection.
+    /// The host is the address connected to: a hostname can resolve to other servers.
+    pub fn cancel_key(&self) -> (String, u16, i32, i32) {
+        (
+            match self.connected_address {
+                Some(address) => address.to_string(),
+                None => self.address.host.clone(),
+            },
+            self.address.port,
+            self.process_id,
+            self.secret_key,
+        )
+    }
+
     /// Execute an arbitrary query against the server.
     /// It will use the simple query protocol.
     /// Result will not be returned, so this is useful for things like `SET` or `ROLLBACK`.
@@ -1319,9 +1731,88 @@ impl Server {
         Ok(())
     }
 
+    /// Run the query, failing if the server returns an error.
+    pub async fn checked_query(&mut self, query: &str) -> Result<(), Error> {
+        self.error_code = None;
+        self.query(query).await?;
+
+        match self.take_error_code() {
+            Some(code) => {
+                warn!(
+                    "Query `{}` failed on server {} with SQLSTATE {}",
+                    query, self.address, code
+                );
+                Err(Error::ServerError)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Run a query returning a single value: the first column of the first row,
+    /// None if it's NULL or there are no rows.
+    pub async fn query_value(&mut self, query: &str) -> Result<Option<String>, Error> {
+        Ok(self.query_column(query).await?.into_iter().next().flatten())
+    }
+
+    /// Run a query returning the first column of every row, None for NULLs.
+    pub async fn query_column(&mut self, query: &str) -> Result<Vec<Option<String>>, Error> {
+        self.error_code = None;
+        self.send(&simple_query(query)).await?;
+
+        let mut values = Vec::new();
+
+        loop {
+            let mut response = self.recv(None).await?;
+
+            while response.len() >= mem::size_of::<u8>() + mem::size_of::<i32>() {
+                let code = response.get_u8();
+                let len = response.get_i32() as usize;
+                if len < mem::size_of::<i32>() || response.len() < len - mem::size_of::<i32>() {
+                    return Err(Error::ServerMessageParserError("truncated message".into()));
+                }
+                let mut message = response.split_to(len - mem::size_of::<i32>());
+
+                // DataRow
+                if code == b'D' && message.len() >= 6 && message.get_i16() > 0 {
+                    let value_len = message.get_i32();
+                    values.push(if value_len >= 0 && message.len() >= value_len as usize {
+                        Some(String::from_utf8_lossy(&message[..value_len as usize]).to_string())
+                    } else {
+                        None
+                    });
+                }
+            }
+
+            if !self.data_available {
+                break;
+            }
+        }
+
+        match self.take_error_code() {
+            Some(code) => {
+                warn!(
+                    "Query `{}` failed on server {} with SQLSTATE {}",
+                    query, self.address, code
+                );
+                Err(Error::ServerError)
+            }
+            None => Ok(values),
+        }
+    }
+
     /// Perform any necessary cleanup before putting the server
     /// connection back in the pool
     pub async fn checkin_cleanup(&mut self) -> Result<(), Error> {
+        // Client disconnected in the middle of a COPY, the server is still waiting for
+        // or sending data and the next client would get it. Close the connection instead.
+        if self.in_copy_mode() {
+            self.mark_bad("returned while still in copy mode");
+            return Ok(());
+        }
+
+        // The client may have left before sending them.
+        self.release_prepared_statements();
+
         // Client disconnected with an open transaction on the server connection.
         // Pgbouncer behavior is to close the server connection but that can cause
         // server connection thrashing if clients repeatedly do this.
@@ -1353,11 +1844,59 @@ impl Server {
             };
 
             self.query(&reset_string).await?;
-            self.cleanup_state.reset();
+
+            if self.cleanup_state.needs_cleanup_set {
+                self.address.stats.settings_reset();
+            }
         }
 
-        if self.in_copy_mode() {
-            warn!(target: "pgcat::server::cleanup", "Server returned while still in copy-mode");
+        // A RESET ALL took care of them already.
+        if self.cleanup_state.needs_settings_check
+            && !self.cleanup_state.needs_cleanup_set
+            && self.cleanup_connections
+        {
+            self.reset_drifted_settings().await?;
+        }
+
+        // Idle connections shouldn't look like they're used by the last client.
+        if mem::take(&mut self.reset_application_name) && !self.is_bad() {
+            self.query("SET application_name TO 'pgcat'").await?;
+        }
+
+        self.cleanup_state.reset();
+
+        // Can't run in a transaction the rollback didn't end, e.g. DISCARD ALL.
+        if let Some(reset_query) = self.reset_query.clone() {
+            if !self.in_transaction() && !self.is_bad() {
+                if let Err(err) = self.checked_query(&reset_query).await {
+                    self.mark_bad(&format!("server reset query failed: {:?}", err));
+                    return Ok(());
+                }
+
+                // It may have deallocated them.
+                if let Some(cache) = &mut self.prepared_statement_cache {
+                    cache.clear();
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reset the settings changed by a client to the session defaults, falling back
+    /// to a RESET ALL if they can't be looked up.
+    async fn reset_drifted_settings(&mut self) -> Result<(), Error> {
+        match self.query_column(&RESET_DRIFTED_SETTINGS).await {
+            Ok(names) if names.is_empty() => (),
+            Ok(names) => {
+                info!(target: "pgcat::server::cleanup", "Server returned with settings changed in a transaction, reset {:?} for application {}", names.into_iter().flatten().collect::<Vec<_>>(), self.application_name);
+                self.address.stats.settings_reset();
+            }
+            Err(Error::ServerError) => {
+                self.query("RESET ALL").await?;
+                self.address.stats.settings_reset();
+            }
+            Err(err) => return Err(err),
         }
 
         Ok(())
@@ -1379,6 +1918,50 @@ impl Server {
         self.last_activity
     }
 
+    /// Set the query run every time the connection is returned to the pool.
+    pub fn set_reset_query(&mut self, reset_query: Option<String>) {
+        self.reset_query = reset_query;
+    }
+
+    /// Set how long this connection can live for, counting from when it connected.
+    pub fn set_max_lifetime(&mut self, max_lifetime: std::time::Duration) {
+        self.max_lifetime = chrono::Duration::from_std(max_lifetime).ok();
+    }
+
+    /// The connection lived longer than its max lifetime and should be replaced.
+    pub fn is_expired(&self) -> bool {
+        match self.max_lifetime {
+            Some(max_lifetime) => {
+                chrono::offset::Utc::now().naive_utc() - self.connected_at >= max_lifetime
+            }
+            None => false,
+        }
+    }
+
+    /// Keep the permit until the connection is returned to the pool.
+    pub fn add_checkout_permit(&mut self, permit: OwnedSemaphorePermit) {
+        self.checkout_permits.push(permit);
+    }
+
+    /// The connection was returned to the pool, let someone else check one out.
+    pub fn release_checkout_permits(&mut self) {
+        self.checkout_permits.clear();
+    }
+
+    /// Keep the permit until the connection is closed.
+    pub fn set_connection_permit(&mut self, permit: OwnedSemaphorePermit) {
+        self.connection_permit = Some(permit);
+    }
+
+    /// Another pool needs the permit of the connection, close it once it's returned.
+    pub fn close_on_checkin(&mut self) {
+        self.close_on_checkin = true;
+    }
+
+    pub fn closing_on_checkin(&self) -> bool {
+        self.close_on_checkin
+    }
+
     // Marks a connection as needing cleanup at checkin
     pub fn mark_dirty(&mut self) {
         self.cleanup_state.set_true();
@@ -1390,6 +1973,27 @@ impl Server {
         }
     }
 
+    /// Whether the messages sent from now on are copied to the mirrors,
+    /// the client filters the queries it mirrors.
+    pub fn set_mirroring(&mut self, mirroring: bool) {
+        self.mirroring = mirroring;
+    }
+
+    /// Rows returned or affected by the commands completed since the last call.
+    pub fn take_rows(&mut self) -> u64 {
+        std::mem::take(&mut self.rows)
+    }
+
+    /// Rows returned or affected by the commands completed since `take_rows`.
+    pub fn rows(&self) -> u64 {
+        self.rows
+    }
+
+    /// SQLSTATE of the last error returned since the last call.
+    pub fn take_error_code(&mut self) -> Option<String> {
+        self.error_code.take()
+    }
+
     pub fn mirror_disconnect(&mut self) {
         if let Some(manager) = self.mirror_manager.as_mut() {
             manager.disconnect()
@@ -1416,6 +2020,7 @@ impl Server {
             true,
             false,
             0,
+            None,
         )
         .await?;
         debug!("Connected!, sending query.");
@@ -1527,11 +2132,613 @@ impl Drop for Server {
             "Server connection closed"
         };
 
-        info!(
-            "{} {:?}, session duration: {}",
-            message,
-            self.address,
-            crate::format_duration(&duration)
+        tracing::info!(
+            pool = %self.address.pool_name,
+            user = %self.address.username,
+            address = %format_args!("{}:{}", self.address.host, self.address.port),
+            role = %self.address.role,
+            application_name = %self.application_name,
+            session_duration = %crate::format_duration(&duration),
+            "{}",
+            message
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::BTreeMap;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    fn message(code: u8, body: &[u8]) -> BytesMut {
+        let mut message = BytesMut::new();
+        message.put_u8(code);
+        message.put_i32(body.len() as i32 + 4);
+        message.put_slice(body);
+        message
+    }
+
+    fn parameter_status(key: &str, value: &str) -> BytesMut {
+        let mut bytes = BytesMut::new();
+        ServerParameters::add_parameter_message(key, value, &mut bytes);
+        bytes
+    }
+
+    /// Server answering the startup with `startup_response`, then every query with `query_response`.
+    async fn mock_server(startup_response: BytesMut, query_response: BytesMut) -> Address {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        tokio::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let len = stream.read_i32().await.unwrap();
+            let mut startup = vec![0u8; len as usize - 4];
+            stream.read_exact(&mut startup).await.unwrap();
+            assert_eq!(&startup[..4], &PROTOCOL_VERSION_NUMBER.to_be_bytes());
+
+            let mut response = startup_response;
+            response.put(message(b'R', &0i32.to_be_bytes()));
+            response.put(parameter_status("server_version", "16.4"));
+            response.put(message(b'K', &[0, 0, 0, 1, 0, 0, 0, 2]));
+            response.put(message(b'Z', b"I"));
+            stream.write_all(&response).await.unwrap();
+
+            while let Ok(message) = read_message(&mut stream).await {
+                if message[0] == b'Q' {
+                    stream.write_all(&query_response).await.unwrap();
+                }
+            }
+        });
+
+        Address {
+            host: "127.0.0.1".into(),
+            port,
+            ..Default::default()
+        }
+    }
+
+    async fn connect(address: &Address) -> Server {
+        try_connect(address).await.unwrap()
+    }
+
+    async fn try_connect(address: &Address) -> Result<Server, Error> {
+        let user = User {
+            username: "postgres".into(),
+            password: Some("secret".into()),
+            ..Default::default()
+        };
+        let stats = Arc::new(ServerStats::new(
+            address.clone(),
+            tokio::time::Instant::now(),
+        ));
+
+        Server::startup(
+            address,
+            &user,
+            "postgres",
+            Arc::new(Mutex::new(HashMap::new())),
+            stats,
+            Arc::new(RwLock::new(None)),
+            true,
+            false,
+            0,
+            None,
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_server_settings() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = Address {
+            host: "127.0.0.1".into(),
+            port: listener.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+
+        // Answers `SHOW <name>` with the parameter of the startup message, like Postgres for a fresh session.
+        tokio::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let len = stream.read_i32().await.unwrap();
+            let mut startup = BytesMut::zeroed(len as usize - 4);
+            stream.read_exact(&mut startup).await.unwrap();
+            let parameters = parse_startup(startup.split_off(4)).unwrap();
+
+            let mut response = message(b'R', &0i32.to_be_bytes());
+            response.put(message(b'Z', b"I"));
+            stream.write_all(&response).await.unwrap();
+
+            while let Ok(mut message) = read_message(&mut stream).await {
+                let query = String::from_utf8_lossy(&message.split_off(5)).to_string();
+                let name = query.trim_end_matches('\0').trim_start_matches("SHOW ");
+                let mut response = data_row(&vec![parameters[name].clone()]);
+                response.put(command_complete("SHOW"));
+                response.put(ready_for_query(false));
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+
+        let user = User {
+            server_settings: BTreeMap::from([
+                ("statement_timeout".into(), "5s".into()),
+                ("search_path".into(), "tenant_1, public".into()),
+            ]),
+            ..Default::default()
+        };
+        let mut server = Server::startup(
+            &address,
+            &user,
+            "postgres",
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(ServerStats::default()),
+            Arc::new(RwLock::new(None)),
+            true,
+            false,
+            0,
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            server.query_value("SHOW statement_timeout").await.unwrap(),
+            Some("5s".into())
+        );
+        assert_eq!(
+            server.query_value("SHOW search_path").await.unwrap(),
+            Some("tenant_1, public".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_client_application_name() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = Address {
+            host: "127.0.0.1".into(),
+            port: listener.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+
+        // Keeps the application_name set, and reports it like Postgres.
+        tokio::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let len = stream.read_i32().await.unwrap();
+            let mut startup = vec![0u8; len as usize - 4];
+            stream.read_exact(&mut startup).await.unwrap();
+
+            let mut application_name = "pgcat".to_string();
+            let mut response = message(b'R', &0i32.to_be_bytes());
+            response.put(parameter_status("application_name", &application_name));
+            response.put(message(b'Z', b"I"));
+            stream.write_all(&response).await.unwrap();
+
+            while let Ok(mut message) = read_message(&mut stream).await {
+                let query = String::from_utf8_lossy(&message.split_off(5)).to_string();
+                let query = query.trim_end_matches('\0');
+
+                let mut response = BytesMut::new();
+                if let Some(value) = query.strip_prefix("SET application_name TO '") {
+                    application_name = value.trim_end_matches(';').trim_end_matches('\'').into();
+                    response.put(parameter_status("application_name", &application_name));
+                    response.put(command_complete("SET"));
+                } else if query == "SHOW application_name" {
+                    response.put(data_row(&vec![application_name.clone()]));
+                    response.put(command_complete("SHOW"));
+                } else {
+                    panic!("unexpected query {}", query);
+                }
+                response.put(ready_for_query(false));
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+
+        let mut server = connect(&address).await;
+        let mut parameters = ServerParameters::new();
+        parameters.set_param("application_name".into(), "web".into(), true);
+
+        // While the client uses it.
+        server
+            .sync_client_parameters(&parameters, Some("pgcat:web:0x00000007"))
+            .await
+            .unwrap();
+        assert_eq!(
+            server.query_value("SHOW application_name").await.unwrap(),
+            Some("pgcat:web:0x00000007".into())
+        );
+
+        // Idle in the pool.
+        server.checkin_cleanup().await.unwrap();
+        assert_eq!(
+            server.query_value("SHOW application_name").await.unwrap(),
+            Some("pgcat".into())
         );
+        assert!(!server.cleanup_state.needs_cleanup());
+
+        // Without a format, the client's own.
+        server
+            .sync_client_parameters(&parameters, None)
+            .await
+            .unwrap();
+        server.checkin_cleanup().await.unwrap();
+        assert_eq!(
+            server.query_value("SHOW application_name").await.unwrap(),
+            Some("web".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reset_settings_changed_in_transaction() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = Address {
+            host: "127.0.0.1".into(),
+            port: listener.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+
+        // One statement_timeout setting, with the queries of a transaction setting it.
+        tokio::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let len = stream.read_i32().await.unwrap();
+            let mut startup = vec![0u8; len as usize - 4];
+            stream.read_exact(&mut startup).await.unwrap();
+
+            let mut response = message(b'R', &0i32.to_be_bytes());
+            response.put(message(b'Z', b"I"));
+            stream.write_all(&response).await.unwrap();
+
+            let mut statement_timeout = "0".to_string();
+            let mut in_transaction = false;
+
+            while let Ok(mut message) = read_message(&mut stream).await {
+                let query = String::from_utf8_lossy(&message.split_off(5)).to_string();
+                let query = query.trim_end_matches('\0');
+
+                let mut response = BytesMut::new();
+                if query == "BEGIN" || query == "COMMIT" {
+                    in_transaction = query == "BEGIN";
+                    response.put(command_complete(query));
+                } else if let Some(value) = query.strip_prefix("SET statement_timeout TO ") {
+                    statement_timeout = value.into();
+                    response.put(command_complete("SET"));
+                } else if query.starts_with("SET LOCAL ") {
+                    response.put(command_complete("SET"));
+                } else if query == "SHOW statement_timeout" {
+                    response.put(data_row(&vec![statement_timeout.clone()]));
+                    response.put(command_complete("SHOW"));
+                } else if query == *RESET_DRIFTED_SETTINGS {
+                    if statement_timeout != "0" {
+                        statement_timeout = "0".into();
+                        response.put(data_row(&vec!["statement_timeout".into(), "0".into()]));
+                    }
+                    response.put(command_complete("SELECT"));
+                } else {
+                    panic!("unexpected query {}", query);
+                }
+                response.put(ready_for_query(in_transaction));
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+
+        let mut server = connect(&address).await;
+
+        // A set local leaves nothing behind.
+        server.query("BEGIN").await.unwrap();
+        server
+            .query("SET LOCAL statement_timeout TO 1s")
+            .await
+            .unwrap();
+        server.query("COMMIT").await.unwrap();
+        assert!(server.cleanup_state.needs_settings_check);
+        server.checkin_cleanup().await.unwrap();
+        assert_eq!(address.stats.settings_resets(), 0);
+
+        server.query("BEGIN").await.unwrap();
+        server.query("SET statement_timeout TO 1s").await.unwrap();
+        server.query("COMMIT").await.unwrap();
+        assert!(!server.cleanup_state.needs_cleanup_set);
+        assert!(server.cleanup_state.needs_settings_check);
+
+        // The next client sees the setting the connection started with.
+        server.checkin_cleanup().await.unwrap();
+        assert_eq!(
+            server.query_value("SHOW statement_timeout").await.unwrap(),
+            Some("0".into())
+        );
+        assert_eq!(address.stats.settings_resets(), 1);
+        assert!(!server.cleanup_state.needs_settings_check);
+
+        // Nothing changed, nothing to check.
+        server.checkin_cleanup().await.unwrap();
+        assert_eq!(address.stats.settings_resets(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_startup_negotiate_protocol_version() {
+        // Only 3.0 and no _pq_.compression.
+        let mut negotiate = BytesMut::new();
+        negotiate.put_i32(0);
+        negotiate.put_i32(1);
+        negotiate.put_slice(b"_pq_.compression\0");
+
+        let address = mock_server(message(b'v', &negotiate), BytesMut::new()).await;
+        let server = connect(&address).await;
+
+        assert!(!server.is_bad());
+        assert_eq!(
+            server.server_parameters.parameters.get("server_version"),
+            Some(&"16.4".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_startup_negotiate_protocol_version_invalid() {
+        // No option count.
+        let address = mock_server(message(b'v', &0i32.to_be_bytes()), BytesMut::new()).await;
+        assert!(matches!(
+            try_connect(&address).await,
+            Err(Error::ServerStartupError(..))
+        ));
+
+        // More options than there are bytes for.
+        let mut negotiate = BytesMut::new();
+        negotiate.put_i32(0);
+        negotiate.put_i32(i32::MAX);
+        let address = mock_server(message(b'v', &negotiate), BytesMut::new()).await;
+        assert!(!connect(&address).await.is_bad());
+    }
+
+    #[tokio::test]
+    async fn test_parameter_status_forwarded() {
+        let mut query_response = parameter_status("search_path", "tenant_1");
+        query_response.put(message(b'C', b"SET\0"));
+        query_response.put(message(b'Z', b"I"));
+
+        let address = mock_server(parameter_status("in_hot_standby", "on"), query_response).await;
+        let mut server = connect(&address).await;
+
+        // The client was told about another server.
+        let mut client_parameters = ServerParameters::new();
+        client_parameters.set_param("in_hot_standby".into(), "off".into(), true);
+        client_parameters.set_param("server_version".into(), "16.4".into(), true);
+
+        let changes = server.parameter_status_changes(&mut client_parameters);
+        assert_eq!(changes, parameter_status("in_hot_standby", "on"));
+        assert!(server
+            .parameter_status_changes(&mut client_parameters)
+            .is_empty());
+
+        // Reported during a query, the client gets it with the response.
+        server
+            .send(&simple_query("SET search_path TO tenant_1"))
+            .await
+            .unwrap();
+        let response = server.recv(Some(&mut client_parameters)).await.unwrap();
+        assert!(response.starts_with(&parameter_status("search_path", "tenant_1")));
+        assert_eq!(
+            client_parameters.parameters.get("search_path"),
+            Some(&"tenant_1".to_string())
+        );
+
+        // Not leaked to the next client.
+        assert!(server.cleanup_state.needs_cleanup_set);
+    }
+
+    #[tokio::test]
+    async fn test_relay() {
+        let mut query_response = message(b'W', &[0, 0, 0]);
+        query_response.put(message(b'd', b"wal"));
+
+        let address = mock_server(BytesMut::new(), query_response.clone()).await;
+        let mut server = connect(&address).await;
+
+        let (client, mut client_side) = tokio::io::duplex(1024);
+        let (mut client_read, mut client_write) = tokio::io::split(client);
+
+        let relay = tokio::task::spawn(async move {
+            let result = server.relay(&mut client_read, &mut client_write).await;
+            (result, server)
+        });
+
+        client_side
+            .write_all(&simple_query("START_REPLICATION 0/0"))
+            .await
+            .unwrap();
+        let mut response = vec![0u8; query_response.len()];
+        client_side.read_exact(&mut response).await.unwrap();
+        assert_eq!(&response[..], &query_response[..]);
+
+        // The client going away ends it.
+        drop(client_side);
+        let (result, server) = relay.await.unwrap();
+        assert!(result.is_ok());
+        assert!(
+            server
+                .stats()
+                .bytes_sent
+                .load(std::sync::atomic::Ordering::Relaxed)
+                > 0
+        );
+    }
+
+    #[tokio::test]
+    async fn test_happy_eyeballs() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::task::spawn(async move { while listener.accept().await.is_ok() {} });
+
+        // The discard prefix, blackholed or unreachable.
+        let ipv6: SocketAddr = format!("[100::1]:{}", port).parse().unwrap();
+        let ipv4: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+
+        let start = std::time::Instant::now();
+        let stream = super::connect(&[ipv6, ipv6, ipv4], Duration::from_millis(50))
+            .await
+            .unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), ipv4);
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Every attempt failed.
+        let closed = {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            listener.local_addr().unwrap()
+        };
+        assert!(super::connect(&[closed], Duration::from_millis(50))
+            .await
+            .is_err());
+        assert!(super::connect(&[], Duration::from_millis(50))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cancel_request() {
+        let address = mock_server(BytesMut::new(), BytesMut::new()).await;
+        let mut server = connect(&address).await;
+
+        // Cancel requests of the client are sent to the server it's using, with the server's key.
+        server.claim(7, 8);
+        let (process_id, secret_key, host, port) = server
+            .client_server_map
+            .lock()
+            .get(&(7, 8))
+            .cloned()
+            .unwrap();
+        assert_eq!((process_id, secret_key), (1, 2));
+        assert_eq!((host.as_str(), port), ("127.0.0.1", address.port));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        Server::cancel(&host, port, process_id, secret_key)
+            .await
+            .unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = [0u8; 16];
+        stream.read_exact(&mut request).await.unwrap();
+        let mut expected = BytesMut::new();
+        expected.put_i32(16);
+        expected.put_i32(CANCEL_REQUEST_CODE);
+        expected.put_i32(1);
+        expected.put_i32(2);
+        assert_eq!(&request[..], &expected[..]);
+    }
+
+    #[tokio::test]
+    async fn test_prepared_statement_eviction() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = Address {
+            host: "127.0.0.1".into(),
+            port: listener.local_addr().unwrap().port(),
+            ..Default::default()
+        };
+        let (closed_tx, mut closed_rx) = tokio::sync::mpsc::unbounded_channel();
+
+        // Parses and closes statements, reporting the closed ones.
+        tokio::task::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+
+            let len = stream.read_i32().await.unwrap();
+            let mut startup = vec![0u8; len as usize - 4];
+            stream.read_exact(&mut startup).await.unwrap();
+
+            let mut response = message(b'R', &0i32.to_be_bytes());
+            response.put(message(b'Z', b"I"));
+            stream.write_all(&response).await.unwrap();
+
+            let mut response = BytesMut::new();
+            while let Ok(message) = read_message(&mut stream).await {
+                match message[0] {
+                    b'P' => response.put(parse_complete()),
+                    b'C' => {
+                        let name = String::from_utf8_lossy(&message[6..message.len() - 1]);
+                        closed_tx.send(name.to_string()).unwrap();
+                        response.put(close_complete());
+                    }
+                    b'S' => {
+                        response.put(ready_for_query(false));
+                        stream.write_all(&response.split()).await.unwrap();
+                    }
+                    _ => (),
+                }
+            }
+        });
+
+        let mut server = Server::startup(
+            &address,
+            &User::default(),
+            "postgres",
+            Arc::new(Mutex::new(HashMap::new())),
+            Arc::new(ServerStats::default()),
+            Arc::new(RwLock::new(None)),
+            true,
+            false,
+            2,
+            None,
+        )
+        .await
+        .unwrap();
+
+        let parse = |name: &str| {
+            let query = "SELECT 1";
+            let mut message = BytesMut::new();
+            message.put_u8(b'P');
+            message.put_i32(4 + name.len() as i32 + 1 + query.len() as i32 + 1 + 2);
+            message.put_slice(name.as_bytes());
+            message.put_u8(0);
+            message.put_slice(query.as_bytes());
+            message.put_u8(0);
+            message.put_i16(0);
+            Parse::try_from(&message).unwrap()
+        };
+        let mut closed = || {
+            let mut names = Vec::new();
+            while let Ok(name) = closed_rx.try_recv() {
+                names.push(name);
+            }
+            names
+        };
+
+        for name in ["a", "b"] {
+            server
+                .register_prepared_statement(&parse(name), true)
+                .await
+                .unwrap();
+            server.release_prepared_statements();
+        }
+        assert!(server.has_prepared_statement("a"));
+
+        // The least recently used one makes room.
+        server
+            .register_prepared_statement(&parse("c"), true)
+            .await
+            .unwrap();
+        assert_eq!(closed(), vec!["b".to_string()]);
+        assert!(!server.has_prepared_statement("b"));
+
+        // Not the ones used by the messages of the client, until they're sent.
+        server
+            .register_prepared_statement(&parse("a"), true)
+            .await
+            .unwrap();
+        server
+            .register_prepared_statement(&parse("d"), true)
+            .await
+            .unwrap();
+        assert!(closed().is_empty());
+        server.release_prepared_statements();
+
+        server
+            .register_prepared_statement(&parse("e"), true)
+            .await
+            .unwrap();
+        assert_eq!(closed(), vec!["c".to_string(), "a".to_string()]);
+        assert!(server.has_prepared_statement("d"));
+        assert!(server.has_prepared_statement("e"));
     }
 }
@@ -1,17 +1,52 @@
-use serde_derive::{Deserialize, Serialize};
+use log::error;
+use once_cell::sync::Lazy;
+use parking_lot::RwLock;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 /// Implements various sharding functions.
 use sha1::{Digest, Sha1};
+use std::collections::HashMap;
+use std::sync::Arc;
 
 /// See: <https://github.com/postgres/postgres/blob/27b77ecf9f4d5be211900eda54d8155ada50d696/src/include/catalog/partition.h#L20>.
 const PARTITION_HASH_SEED: u64 = 0x7A5B22367996DCFD;
 
 /// The sharding functions we support.
-#[derive(Debug, PartialEq, Copy, Clone, Serialize, Deserialize, Hash, std::cmp::Eq)]
+#[derive(Debug, PartialEq, Clone, Hash, std::cmp::Eq)]
 pub enum ShardingFunction {
-    #[serde(alias = "pg_bigint_hash", alias = "PgBigintHash")]
     PgBigintHash,
-    #[serde(alias = "sha1", alias = "Sha1")]
     Sha1,
+    Consistent,
+    /// A function registered with `register_sharding_function`, `custom:<name>` in the config.
+    Custom(String),
+}
+
+impl Serialize for ShardingFunction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for ShardingFunction {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+
+        if let Some(name) = s.strip_prefix("custom:") {
+            return Ok(ShardingFunction::Custom(name.to_string()));
+        }
+
+        match s.as_str() {
+            "pg_bigint_hash" | "PgBigintHash" => Ok(ShardingFunction::PgBigintHash),
+            "sha1" | "Sha1" => Ok(ShardingFunction::Sha1),
+            "consistent" | "Consistent" => Ok(ShardingFunction::Consistent),
+            _ => Err(serde::de::Error::custom(format!(
+                "invalid sharding_function {}",
+                s
+            ))),
+        }
+    }
 }
 
 impl std::fmt::Display for ShardingFunction {
@@ -19,10 +54,47 @@ impl std::fmt::Display for ShardingFunction {
         match self {
             ShardingFunction::PgBigintHash => write!(f, "pg_bigint_hash"),
             ShardingFunction::Sha1 => write!(f, "sha1"),
+            ShardingFunction::Consistent => write!(f, "consistent"),
+            ShardingFunction::Custom(name) => write!(f, "custom:{}", name),
         }
     }
 }
 
+/// A sharding function implemented outside of PgCat, for keys the built-in hashes
+/// can't express, e.g. a tenant id with its region encoded in the upper bits.
+/// Register it with `register_sharding_function` before the config is loaded, and use it
+/// with `sharding_function = "custom:<name>"`. It replaces the hash step only: the key
+/// still comes from `SET SHARDING KEY TO`, `sharding_key_regex` or the `automatic_sharding_key`.
+pub trait CustomShardingFunction: Send + Sync {
+    /// The shard for the key, lower than `shards`.
+    fn shard(&self, key: i64, shards: usize) -> usize;
+}
+
+/// Custom sharding functions, by name.
+static CUSTOM_SHARDING_FUNCTIONS: Lazy<RwLock<HashMap<String, Arc<dyn CustomShardingFunction>>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
+/// Make a custom sharding function available as `custom:<name>`, replaces
+/// the function registered with the same name, if any.
+pub fn register_sharding_function(name: &str, function: Arc<dyn CustomShardingFunction>) {
+    CUSTOM_SHARDING_FUNCTIONS
+        .write()
+        .insert(name.to_string(), function);
+}
+
+/// Whether a custom sharding function is registered with that name.
+pub fn is_sharding_function_registered(name: &str) -> bool {
+    CUSTOM_SHARDING_FUNCTIONS.read().contains_key(name)
+}
+
+/// Points on the hash ring, sorted, with the shard that owns the keys up to each one.
+type Ring = Arc<Vec<(u64, usize)>>;
+
+/// Rings only depend on the number of shards and virtual nodes,
+/// so they're built once and shared by all sharders.
+static RINGS: Lazy<RwLock<HashMap<(usize, usize), Ring>>> =
+    Lazy::new(|| RwLock::new(HashMap::new()));
+
 /// The sharder.
 pub struct Sharder {
     /// Number of shards in the cluster.
@@ -30,14 +102,37 @@ pub struct Sharder {
 
     /// The sharding function in use.
     sharding_function: ShardingFunction,
+
+    /// Hash ring of the consistent sharding function.
+    ring: Option<Ring>,
+
+    /// The custom sharding function, if registered.
+    custom: Option<Arc<dyn CustomShardingFunction>>,
 }
 
 impl Sharder {
-    /// Create new instance of the sharder.
-    pub fn new(shards: usize, sharding_function: ShardingFunction) -> Sharder {
+    /// Create new instance of the sharder. Each shard gets `virtual_nodes`
+    /// points on the hash ring of the consistent sharding function.
+    pub fn new(
+        shards: usize,
+        sharding_function: ShardingFunction,
+        virtual_nodes: usize,
+    ) -> Sharder {
+        let ring = match sharding_function {
+            ShardingFunction::Consistent => Some(Self::ring(shards, virtual_nodes)),
+            _ => None,
+        };
+
+        let custom = match &sharding_function {
+            ShardingFunction::Custom(name) => CUSTOM_SHARDING_FUNCTIONS.read().get(name).cloned(),
+            _ => None,
+        };
+
         Sharder {
             shards,
             sharding_function,
+            ring,
+            custom,
         }
     }
 
@@ -46,7 +141,65 @@ impl Sharder {
         match self.sharding_function {
             ShardingFunction::PgBigintHash => self.pg_bigint_hash(key),
             ShardingFunction::Sha1 => self.sha1(key),
+            ShardingFunction::Consistent => self.consistent(key),
+            ShardingFunction::Custom(_) => self.custom(key),
+        }
+    }
+
+    fn custom(&self, key: i64) -> usize {
+        match &self.custom {
+            // Don't trust it to stay in range.
+            Some(custom) => custom.shard(key, self.shards) % self.shards.max(1),
+            None => {
+                error!(
+                    "Sharding function {} is not registered, using shard 0",
+                    self.sharding_function
+                );
+                0
+            }
+        }
+    }
+
+    /// The shard owning the first point on the ring at or after the key's hash.
+    /// Adding a shard only takes over the keys right before its own points,
+    /// about 1/n of them, the other keys stay where they are.
+    fn consistent(&self, key: i64) -> usize {
+        let ring = match &self.ring {
+            Some(ring) if !ring.is_empty() => ring,
+            _ => return 0,
+        };
+
+        let hash = Self::sha1_u64(key.to_string().as_bytes());
+
+        match ring.partition_point(|(point, _)| *point < hash) {
+            index if index == ring.len() => ring[0].1,
+            index => ring[index].1,
+        }
+    }
+
+    fn ring(shards: usize, virtual_nodes: usize) -> Ring {
+        if let Some(ring) = RINGS.read().get(&(shards, virtual_nodes)) {
+            return ring.clone();
         }
+
+        let mut ring = Vec::with_capacity(shards * virtual_nodes);
+        for shard in 0..shards {
+            for node in 0..virtual_nodes {
+                let point = Self::sha1_u64(format!("shard-{}-{}", shard, node).as_bytes());
+                ring.push((point, shard));
+            }
+        }
+        ring.sort_unstable();
+
+        let ring = Arc::new(ring);
+        RINGS.write().insert((shards, virtual_nodes), ring.clone());
+        ring
+    }
+
+    /// First 8 bytes of the SHA1 hash.
+    fn sha1_u64(bytes: &[u8]) -> u64 {
+        let result = Sha1::digest(bytes);
+        u64::from_be_bytes(result[..8].try_into().unwrap())
     }
 
     /// Hash function used by Postgres to determine which partition
@@ -79,7 +232,7 @@ impl Sharder {
 
     #[inline]
     fn rot(x: u32, k: u32) -> u32 {
-        (x << k) | (x >> (32 - k))
+        x.rotate_left(k)
     }
 
     #[inline]
@@ -166,7 +319,7 @@ mod test {
     // confirming that we implemented Postgres BIGINT hashing correctly.
     #[test]
     fn test_pg_bigint_hash() {
-        let sharder = Sharder::new(5, ShardingFunction::PgBigintHash);
+        let sharder = Sharder::new(5, ShardingFunction::PgBigintHash, 0);
 
         let shard_0 = vec![1, 4, 5, 14, 19, 39, 40, 46, 47, 53];
 
@@ -201,7 +354,7 @@ mod test {
 
     #[test]
     fn test_sha1_hash() {
-        let sharder = Sharder::new(12, ShardingFunction::Sha1);
+        let sharder = Sharder::new(12, ShardingFunction::Sha1, 0);
         let ids = [
             0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19,
         ];
@@ -213,4 +366,92 @@ mod test {
             assert_eq!(sharder.shard(*id), shards[i]);
         }
     }
+
+    #[test]
+    fn test_consistent_hash() {
+        let keys = 0..10_000;
+        let four = Sharder::new(4, ShardingFunction::Consistent, 256);
+        let five = Sharder::new(5, ShardingFunction::Consistent, 256);
+
+        let mut moved = 0;
+        let mut counts = [0; 5];
+        for key in keys.clone() {
+            let (before, after) = (four.shard(key), five.shard(key));
+            counts[after] += 1;
+
+            if before != after {
+                // Keys only move to the new shard.
+                assert_eq!(after, 4);
+                moved += 1;
+            }
+        }
+
+        // About a fifth of the keys moved, instead of 4/5 with modulo.
+        assert!((1_500..2_500).contains(&moved), "{} keys moved", moved);
+
+        // Keys are spread evenly enough.
+        for count in counts {
+            assert!((1_500..2_500).contains(&count), "{:?}", counts);
+        }
+
+        // Removing the shard moves them back.
+        for key in keys {
+            assert_eq!(
+                Sharder::new(4, ShardingFunction::Consistent, 256).shard(key),
+                four.shard(key)
+            );
+        }
+    }
+
+    /// Tenant ids with the region in the upper 16 bits, one region per shard.
+    struct TenantRegion;
+
+    impl CustomShardingFunction for TenantRegion {
+        fn shard(&self, key: i64, _shards: usize) -> usize {
+            (key >> 48) as usize
+        }
+    }
+
+    #[test]
+    fn test_custom_sharding_function() {
+        register_sharding_function("tenant_region", Arc::new(TenantRegion));
+        assert!(is_sharding_function_registered("tenant_region"));
+
+        let sharder = Sharder::new(3, ShardingFunction::Custom("tenant_region".into()), 0);
+        assert_eq!(sharder.shard(1234), 0);
+        assert_eq!(sharder.shard((1 << 48) | 1234), 1);
+        assert_eq!(sharder.shard((2 << 48) | 1234), 2);
+        // Out of range.
+        assert_eq!(sharder.shard((4 << 48) | 1234), 1);
+
+        let sharder = Sharder::new(3, ShardingFunction::Custom("missing".into()), 0);
+        assert_eq!(sharder.shard((2 << 48) | 1234), 0);
+    }
+
+    #[test]
+    fn test_sharding_function_config() {
+        #[derive(Deserialize)]
+        struct Config {
+            sharding_function: ShardingFunction,
+        }
+        let parse = |value: &str| {
+            toml::from_str::<Config>(&format!("sharding_function = \"{}\"", value))
+                .map(|config| config.sharding_function)
+        };
+
+        assert_eq!(
+            parse("pg_bigint_hash").unwrap(),
+            ShardingFunction::PgBigintHash
+        );
+        assert_eq!(parse("Sha1").unwrap(), ShardingFunction::Sha1);
+        assert_eq!(
+            parse("custom:tenant_region").unwrap(),
+            ShardingFunction::Custom("tenant_region".into())
+        );
+        assert!(parse("md5").is_err());
+        assert_eq!(
+            ShardingFunction::Custom("tenant_region".into()).to_string(),
+            "custom:tenant_region"
+        );
+    }
 }
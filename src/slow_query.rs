@@ -0,0 +1,199 @@
+//! Log of the queries taking longer than `log_min_duration_ms`.
+use log::warn;
+use serde_json::json;
+use sqlparser::dialect::PostgreSqlDialect;
+use sqlparser::tokenizer::{Token, Tokenizer};
+use std::time::Duration;
+
+use crate::config::{Address, SlowQueryLogFormat};
+
+/// A query that took at least `log_min_duration_ms` to complete.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlowQuery {
+    /// The query with its literals replaced, see [`normalize`].
+    pub query: String,
+    pub duration: Duration,
+    pub pool: String,
+    pub user: String,
+    pub address: String,
+}
+
+impl SlowQuery {
+    /// The entry of the query in the slow query log, if it took at least
+    /// `log_min_duration_ms`. The query is only looked up in that case.
+    pub fn new(
+        log_min_duration_ms: Option<u64>,
+        duration: Duration,
+        query: impl FnOnce() -> Option<String>,
+        address: &Address,
+    ) -> Option<SlowQuery> {
+        if duration < Duration::from_millis(log_min_duration_ms?) {
+            return None;
+        }
+
+        Some(SlowQuery {
+            query: match query() {
+                Some(query) => normalize(&query),
+                None => "<unknown>".into(),
+            },
+            duration,
+            pool: address.pool_name.clone(),
+            user: address.username.clone(),
+            address: format!("{}:{}", address.host, address.port),
+        })
+    }
+
+    fn duration_ms(&self) -> f64 {
+        self.duration.as_micros() as f64 / 1_000.0
+    }
+
+    pub fn format(&self, format: SlowQueryLogFormat) -> String {
+        match format {
+            SlowQueryLogFormat::Text => format!(
+                "duration_ms={:.3} pool={} user={} address={} query={:?}",
+                self.duration_ms(),
+                self.pool,
+                self.user,
+                self.address,
+                self.query
+            ),
+            SlowQueryLogFormat::Json => json!({
+                "duration_ms": self.duration_ms(),
+                "pool": self.pool,
+                "user": self.user,
+                "address": self.address,
+                "query": self.query,
+            })
+            .to_string(),
+        }
+    }
+
+    pub fn log(&self, format: SlowQueryLogFormat) {
+        // JSON logs have the entry's fields next to the message already.
+        if crate::logger::json() {
+            tracing::warn!(
+                target: "pgcat::slow_query",
+                duration_ms = self.duration_ms(),
+                pool = %self.pool,
+                user = %self.user,
+                address = %self.address,
+                query = %self.query,
+                "Slow query"
+            );
+        } else {
+            warn!(target: "pgcat::slow_query", "Slow query: {}", self.format(format));
+        }
+    }
+}
+
+/// Replace the literals of the query with `?` so no data ends up in the logs,
+/// and drop the comments and extra whitespace.
+pub fn normalize(query: &str) -> String {
+    let dialect = PostgreSqlDialect {};
+
+    let tokens = match Tokenizer::new(&dialect, query).tokenize() {
+        Ok(tokens) => tokens,
+        // We can't tell the literals apart, log nothing of the query.
+        Err(_) => return "<unparsable>".into(),
+    };
+
+    let mut normalized = String::with_capacity(query.len());
+    let mut space = false;
+
+    for token in tokens {
+        // Comments included.
+        if let Token::Whitespace(_) = token {
+            space = true;
+            continue;
+        }
+
+        if space && !normalized.is_empty() {
+            normalized.push(' ');
+        }
+        space = false;
+
+        match token {
+            Token::Number(_, _)
+            | Token::SingleQuotedString(_)
+            | Token::TripleSingleQuotedString(_)
+            | Token::TripleDoubleQuotedString(_)
+            | Token::DollarQuotedString(_)
+            | Token::SingleQuotedByteStringLiteral(_)
+            | Token::DoubleQuotedByteStringLiteral(_)
+            | Token::TripleSingleQuotedByteStringLiteral(_)
+            | Token::TripleDoubleQuotedByteStringLiteral(_)
+            | Token::SingleQuotedRawStringLiteral(_)
+            | Token::DoubleQuotedRawStringLiteral(_)
+            | Token::TripleSingleQuotedRawStringLiteral(_)
+            | Token::TripleDoubleQuotedRawStringLiteral(_)
+            | Token::NationalStringLiteral(_)
+            | Token::EscapedStringLiteral(_)
+            | Token::UnicodeStringLiteral(_)
+            | Token::HexStringLiteral(_) => normalized.push('?'),
+            // Everything else, including the double quoted identifiers.
+            token => normalized.push_str(&token.to_string()),
+        }
+    }
+
+    normalized
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize("SELECT * FROM users WHERE email = 'a@b.c' AND id IN (1, 2.5)"),
+            "SELECT * FROM users WHERE email = ? AND id IN (?, ?)"
+        );
+        assert_eq!(
+            normalize("/* traceparent='00-1' */ select\n\t\"Name\" -- the name\nfrom t where x = $1 and y = E'\\n' and z = $$secret$$"),
+            "select \"Name\" from t where x = $1 and y = ? and z = ?"
+        );
+        assert_eq!(normalize("select 'unterminated"), "<unparsable>");
+    }
+
+    #[test]
+    fn test_slow_query() {
+        let address = Address {
+            host: "127.0.0.1".into(),
+            port: 5432,
+            pool_name: "pool".into(),
+            username: "user".into(),
+            ..Default::default()
+        };
+        let duration = Duration::from_micros(1_500);
+
+        assert_eq!(
+            SlowQuery::new(None, duration, || Some("select 1".into()), &address),
+            None
+        );
+        assert_eq!(
+            SlowQuery::new(Some(2), duration, || Some("select 1".into()), &address),
+            None
+        );
+
+        // Everything is logged with a threshold of 0.
+        let entry = SlowQuery::new(
+            Some(0),
+            duration,
+            || Some("select 'secret'".into()),
+            &address,
+        )
+        .unwrap();
+        assert_eq!(
+            entry.format(SlowQueryLogFormat::Text),
+            "duration_ms=1.500 pool=pool user=user address=127.0.0.1:5432 query=\"select ?\""
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&entry.format(SlowQueryLogFormat::Json)).unwrap();
+        assert_eq!(json["duration_ms"], 1.5);
+        assert_eq!(json["pool"], "pool");
+        assert_eq!(json["user"], "user");
+        assert_eq!(json["address"], "127.0.0.1:5432");
+        assert_eq!(json["query"], "select ?");
+    }
+}
@@ -11,15 +11,21 @@ use std::sync::Arc;
 // Structs that hold stats for different resources
 pub mod address;
 pub mod client;
+pub mod histogram;
 pub mod pool;
 pub mod server;
+pub mod statements;
 pub use address::AddressStats;
 pub use client::{ClientState, ClientStats};
+pub use histogram::Histogram;
 pub use server::{ServerState, ServerStats};
+pub use statements::{StatementCounts, StatementKind};
 
 /// Convenience types for various stats
 type ClientStatesLookup = HashMap<i32, Arc<ClientStats>>;
 type ServerStatesLookup = HashMap<i32, Arc<ServerStats>>;
+type BanCountsLookup = HashMap<(String, String), u64>;
+type MirrorDropCountsLookup = HashMap<String, u64>;
 
 /// Stats for individual client connections
 /// Used in SHOW CLIENTS.
@@ -31,6 +37,16 @@ static CLIENT_STATS: Lazy<Arc<RwLock<ClientStatesLookup>>> =
 static SERVER_STATS: Lazy<Arc<RwLock<ServerStatesLookup>>> =
     Lazy::new(|| Arc::new(RwLock::new(ServerStatesLookup::default())));
 
+/// Number of bans per (reason, address), since startup.
+/// Exported to Prometheus.
+static BAN_COUNTS: Lazy<Arc<RwLock<BanCountsLookup>>> =
+    Lazy::new(|| Arc::new(RwLock::new(BanCountsLookup::default())));
+
+/// Number of messages dropped per mirror address, since startup.
+/// Exported to Prometheus.
+static MIRROR_DROP_COUNTS: Lazy<Arc<RwLock<MirrorDropCountsLookup>>> =
+    Lazy::new(|| Arc::new(RwLock::new(MirrorDropCountsLookup::default())));
+
 /// The statistics reporter. An instance is given to each possible source of statistics,
 /// e.g. client stats, server stats, connection pool stats.
 pub static REPORTER: Lazy<ArcSwap<Reporter>> =
@@ -72,6 +88,19 @@ impl Reporter {
     fn server_disconnecting(&self, server_id: i32) {
         SERVER_STATS.write().remove(&server_id);
     }
+
+    /// Reports an address (host:port) was banned for the given reason.
+    pub fn ban(&self, reason: &str, address: String) {
+        *BAN_COUNTS
+            .write()
+            .entry((reason.to_string(), address))
+            .or_default() += 1;
+    }
+
+    /// Reports a message for a mirror (host:port) was dropped, its queue was full.
+    pub fn mirror_drop(&self, address: String) {
+        *MIRROR_DROP_COUNTS.write().entry(address).or_default() += 1;
+    }
 }
 
 /// The statistics collector which used for calculating averages
@@ -124,6 +153,16 @@ pub fn get_server_stats() -> ServerStatesLookup {
     SERVER_STATS.read().clone()
 }
 
+/// Get a snapshot of the number of bans per (reason, address).
+pub fn get_ban_counts() -> BanCountsLookup {
+    BAN_COUNTS.read().clone()
+}
+
+/// Get a snapshot of the number of dropped messages per mirror address.
+pub fn get_mirror_drop_counts() -> MirrorDropCountsLookup {
+    MIRROR_DROP_COUNTS.read().clone()
+}
+
 /// Get the statistics reporter used to update stats across the pools/clients.
 pub fn get_reporter() -> Reporter {
     (*(*REPORTER.load())).clone()
@@ -1,5 +1,8 @@
+use super::{Histogram, StatementCounts, StatementKind};
+use parking_lot::Mutex;
 use std::sync::atomic::*;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, Clone, Default)]
 struct AddressStatFields {
@@ -24,6 +27,55 @@ pub struct AddressStats {
 
     // Determines if the averages have been updated since the last time they were reported
     pub averages_updated: Arc<AtomicBool>,
+
+    // Exponentially weighted moving average of the query latency in microseconds (f64 bits),
+    // 0 until the first sample
+    latency_ewma: Arc<AtomicU64>,
+
+    // Number of connections that can be checked out from this server
+    pool_size: Arc<AtomicU64>,
+
+    // Query times, in the buckets configured for the pool
+    query_time_histogram: Arc<Histogram>,
+
+    // Errors counted to decide bans (see `Address::increment_error_count`), since startup
+    connection_errors: Arc<AtomicU64>,
+
+    // Recent rate of these errors
+    connection_error_rate: Arc<Mutex<DecayingRate>>,
+
+    // Server connections returned with settings changed by a client, which had to be reset
+    settings_resets: Arc<AtomicU64>,
+
+    // Queries by kind of statement, when the query parser is enabled
+    statement_counts: Arc<StatementCounts>,
+}
+
+/// Events per second, decaying exponentially so about the last minute counts.
+#[derive(Debug, Default)]
+struct DecayingRate {
+    rate: f64,
+    updated_at: Option<Instant>,
+}
+
+impl DecayingRate {
+    // Seconds for the rate to decay to 1/e without events.
+    const WINDOW: f64 = 60.0;
+
+    fn record(&mut self, now: Instant) {
+        self.rate = self.at(now) + 1.0 / Self::WINDOW;
+        self.updated_at = Some(now);
+    }
+
+    fn at(&self, now: Instant) -> f64 {
+        match self.updated_at {
+            Some(updated_at) => {
+                let elapsed = now.saturating_duration_since(updated_at).as_secs_f64();
+                self.rate * (-elapsed / Self::WINDOW).exp()
+            }
+            None => 0.0,
+        }
+    }
 }
 
 impl IntoIterator for AddressStats {
@@ -31,7 +83,7 @@ impl IntoIterator for AddressStats {
     type IntoIter = std::vec::IntoIter<Self::Item>;
 
     fn into_iter(self) -> Self::IntoIter {
-        vec![
+        let mut stats = vec![
             (
                 "total_xact_count".to_string(),
                 self.total.xact_count.load(Ordering::Relaxed),
@@ -96,12 +148,31 @@ impl IntoIterator for AddressStats {
                 "avg_wait_time".to_string(),
                 self.averages.wait_time.load(Ordering::Relaxed),
             ),
-        ]
-        .into_iter()
+            ("p50_query_time".to_string(), self.query_time_quantile(0.5)),
+            ("p90_query_time".to_string(), self.query_time_quantile(0.9)),
+            ("p99_query_time".to_string(), self.query_time_quantile(0.99)),
+        ];
+
+        stats.extend(StatementKind::ALL.map(|kind| {
+            (
+                format!("total_{}_count", kind.name()),
+                self.statement_counts.get(kind),
+            )
+        }));
+
+        stats.into_iter()
     }
 }
 
 impl AddressStats {
+    /// Stats with the query times sorted into the given buckets (ms).
+    pub fn new(query_time_buckets: &[u64]) -> Self {
+        AddressStats {
+            query_time_histogram: Arc::new(Histogram::new(query_time_buckets)),
+            ..Default::default()
+        }
+    }
+
     pub fn xact_count_add(&self) {
         self.total.xact_count.fetch_add(1, Ordering::Relaxed);
         self.current.xact_count.fetch_add(1, Ordering::Relaxed);
@@ -131,9 +202,23 @@ impl AddressStats {
         self.current.xact_time.fetch_add(time, Ordering::Relaxed);
     }
 
-    pub fn query_time_add(&self, time: u64) {
+    pub fn query_time_add(&self, duration: Duration) {
+        let time = duration.as_millis() as u64;
         self.total.query_time.fetch_add(time, Ordering::Relaxed);
         self.current.query_time.fetch_add(time, Ordering::Relaxed);
+        self.query_time_histogram.record(duration);
+    }
+
+    pub fn query_time_histogram(&self) -> &Histogram {
+        &self.query_time_histogram
+    }
+
+    /// Estimated query time quantile (ms), 0 until a query ran.
+    fn query_time_quantile(&self, quantile: f64) -> u64 {
+        self.query_time_histogram
+            .quantile(quantile)
+            .map(|time| time.round() as u64)
+            .unwrap_or(0)
     }
 
     pub fn wait_time_add(&self, time: u64) {
@@ -141,11 +226,74 @@ impl AddressStats {
         self.current.wait_time.fetch_add(time, Ordering::Relaxed);
     }
 
+    /// Add a latency sample (microseconds) to the moving average, keeping `decay` of the previous average.
+    pub fn latency_ewma_add(&self, microseconds: f64, decay: f64) {
+        let _ = self
+            .latency_ewma
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |bits| {
+                let average = f64::from_bits(bits);
+                let average = if bits == 0 {
+                    microseconds
+                } else {
+                    decay * average + (1.0 - decay) * microseconds
+                };
+                // Keep 0 for "no samples"
+                Some(average.max(f64::MIN_POSITIVE).to_bits())
+            });
+    }
+
+    /// Average query latency in microseconds, if any query ran yet.
+    pub fn latency_ewma(&self) -> Option<f64> {
+        match self.latency_ewma.load(Ordering::Relaxed) {
+            0 => None,
+            bits => Some(f64::from_bits(bits)),
+        }
+    }
+
+    pub fn set_pool_size(&self, pool_size: u64) {
+        self.pool_size.store(pool_size, Ordering::Relaxed);
+    }
+
+    /// Current pool size, changes over time when the pool is autoscaled.
+    pub fn pool_size(&self) -> u64 {
+        self.pool_size.load(Ordering::Relaxed)
+    }
+
     pub fn error(&self) {
         self.total.errors.fetch_add(1, Ordering::Relaxed);
         self.current.errors.fetch_add(1, Ordering::Relaxed);
     }
 
+    pub fn connection_error(&self) {
+        self.connection_errors.fetch_add(1, Ordering::Relaxed);
+        self.connection_error_rate.lock().record(Instant::now());
+    }
+
+    /// A server connection was returned with settings changed by a client and had to be reset.
+    pub fn settings_reset(&self) {
+        self.settings_resets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A query with a statement of this kind was sent to the server.
+    pub fn statement_add(&self, kind: StatementKind) {
+        self.statement_counts.add(kind);
+    }
+
+    /// Resets of the settings of the server connections, since startup.
+    pub fn settings_resets(&self) -> u64 {
+        self.settings_resets.load(Ordering::Relaxed)
+    }
+
+    /// Errors counted to decide bans, since startup.
+    pub fn connection_errors(&self) -> u64 {
+        self.connection_errors.load(Ordering::Relaxed)
+    }
+
+    /// Recent errors per second counted to decide bans, over about the last minute.
+    pub fn connection_error_rate(&self) -> f64 {
+        self.connection_error_rate.lock().at(Instant::now())
+    }
+
     pub fn update_averages(&self) {
         let stat_period_per_second = crate::stats::STAT_PERIOD / 1_000;
 
@@ -156,13 +304,12 @@ impl AddressStats {
             current_xact_count / stat_period_per_second,
             Ordering::Relaxed,
         );
-        if current_xact_count == 0 {
-            self.averages.xact_time.store(0, Ordering::Relaxed);
-        } else {
-            self.averages
-                .xact_time
-                .store(current_xact_time / current_xact_count, Ordering::Relaxed);
-        }
+        self.averages.xact_time.store(
+            current_xact_time
+                .checked_div(current_xact_count)
+                .unwrap_or(0),
+            Ordering::Relaxed,
+        );
 
         // query_count
         let current_query_count = self.current.query_count.load(Ordering::Relaxed);
@@ -171,13 +318,12 @@ impl AddressStats {
             current_query_count / stat_period_per_second,
             Ordering::Relaxed,
         );
-        if current_query_count == 0 {
-            self.averages.query_time.store(0, Ordering::Relaxed);
-        } else {
-            self.averages
-                .query_time
-                .store(current_query_time / current_query_count, Ordering::Relaxed);
-        }
+        self.averages.query_time.store(
+            current_query_time
+                .checked_div(current_query_count)
+                .unwrap_or(0),
+            Ordering::Relaxed,
+        );
 
         // bytes_received
         let current_bytes_received = self.current.bytes_received.load(Ordering::Relaxed);
@@ -224,3 +370,26 @@ impl AddressStats {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decaying_rate() {
+        let start = Instant::now();
+        let mut rate = DecayingRate::default();
+        assert_eq!(rate.at(start), 0.0);
+
+        // 60 errors in a second is about one per second over the last minute.
+        for _ in 0..60 {
+            rate.record(start);
+        }
+        assert!((rate.at(start) - 1.0).abs() < 1e-9);
+
+        let later = start + Duration::from_secs(60);
+        assert!((rate.at(later) - (-1.0f64).exp()).abs() < 1e-9);
+        rate.record(later);
+        assert!((rate.at(later) - (-1.0f64).exp() - 1.0 / 60.0).abs() < 1e-9);
+    }
+}
@@ -1,7 +1,10 @@
-use super::{get_reporter, Reporter};
+use super::{get_reporter, Reporter, StatementCounts, StatementKind};
+use crate::config::Address;
 use atomic_enum::atomic_enum;
+use parking_lot::RwLock;
 use std::sync::atomic::*;
 use std::sync::Arc;
+use tokio::sync::Notify;
 use tokio::time::Instant;
 /// The various states that a client can be in
 #[atomic_enum]
@@ -57,6 +60,15 @@ pub struct ClientStats {
 
     /// Number of errors made by this client
     pub error_count: Arc<AtomicU64>,
+
+    /// Number of queries executed by this client, by kind of statement
+    pub statement_counts: Arc<StatementCounts>,
+
+    /// Server (host:port) currently assigned to the client, if any
+    pub server_address: Arc<RwLock<Option<String>>>,
+
+    /// Notified when the client is killed by an admin
+    terminate: Arc<Notify>,
 }
 
 impl Default for ClientStats {
@@ -74,6 +86,9 @@ impl Default for ClientStats {
             transaction_count: Arc::new(AtomicU64::new(0)),
             query_count: Arc::new(AtomicU64::new(0)),
             error_count: Arc::new(AtomicU64::new(0)),
+            statement_counts: Arc::new(StatementCounts::default()),
+            server_address: Arc::new(RwLock::new(None)),
+            terminate: Arc::new(Notify::new()),
             reporter: get_reporter(),
         }
     }
@@ -103,6 +118,17 @@ impl ClientStats {
         self.reporter.client_disconnecting(self.client_id);
     }
 
+    /// Asks the client to close its connection, e.g. with the KILL CLIENT admin command.
+    /// The client notices the next time it waits for a message.
+    pub fn terminate(&self) {
+        self.terminate.notify_one();
+    }
+
+    /// Completes once the client was asked to close its connection.
+    pub async fn terminated(&self) {
+        self.terminate.notified().await
+    }
+
     /// Register a client with the stats system. The stats system uses client_id
     /// to track and aggregate statistics from all source that relate to that client
     pub fn register(&self, stats: Arc<ClientStats>) {
@@ -113,6 +139,7 @@ impl ClientStats {
     /// Reports a client is done querying the server and is no longer assigned a server connection
     pub fn idle(&self) {
         self.state.store(ClientState::Idle, Ordering::Relaxed);
+        *self.server_address.write() = None;
     }
 
     /// Reports a client is waiting for a connection
@@ -124,13 +151,15 @@ impl ClientStats {
     }
 
     /// Reports a client is done waiting for a connection and is about to query the server.
-    pub fn active(&self) {
+    pub fn active(&self, address: &Address) {
         self.state.store(ClientState::Active, Ordering::Relaxed);
+        *self.server_address.write() = Some(format!("{}:{}", address.host, address.port));
     }
 
     /// Reports a client has failed to obtain a connection from a connection pool
     pub fn checkout_error(&self) {
         self.state.store(ClientState::Idle, Ordering::Relaxed);
+        *self.server_address.write() = None;
         self.update_wait_times();
     }
 
@@ -143,6 +172,7 @@ impl ClientStats {
     /// Reports a client has had the server assigned to it be banned
     pub fn ban_error(&self) {
         self.state.store(ClientState::Idle, Ordering::Relaxed);
+        *self.server_address.write() = None;
         self.error_count.fetch_add(1, Ordering::Relaxed);
     }
 
@@ -173,6 +203,11 @@ impl ClientStats {
         self.query_count.fetch_add(1, Ordering::Relaxed);
     }
 
+    /// Report a query of this kind of statement executed by the client
+    pub fn statement(&self, kind: StatementKind) {
+        self.statement_counts.add(kind);
+    }
+
     /// Report a transaction executed by a client a server
     /// we report each individual queries outside a transaction as a transaction
     /// We only count the initial BEGIN as a transaction, all queries within do not
@@ -201,4 +236,8 @@ impl ClientStats {
     pub fn pool_name(&self) -> String {
         self.pool_name.clone()
     }
+
+    pub fn server_address(&self) -> Option<String> {
+        self.server_address.read().clone()
+    }
 }
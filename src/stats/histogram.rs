@@ -0,0 +1,158 @@
+use std::sync::atomic::*;
+use std::time::Duration;
+
+/// Upper bounds of the query time buckets (ms) unless configured otherwise.
+pub const DEFAULT_QUERY_TIME_BUCKETS: [u64; 12] =
+    [1, 5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10000];
+
+/// Query times sorted into buckets, from which quantiles can be estimated.
+/// Recording a query is a couple of atomic increments, nothing is allocated.
+#[derive(Debug)]
+pub struct Histogram {
+    /// Upper bounds of the buckets (ms), in increasing order.
+    bounds: Vec<u64>,
+
+    /// Number of samples in each bucket, the last one is for samples above all the bounds.
+    buckets: Vec<AtomicU64>,
+
+    count: AtomicU64,
+
+    /// Sum of the samples (microseconds).
+    sum: AtomicU64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram::new(&DEFAULT_QUERY_TIME_BUCKETS)
+    }
+}
+
+impl Histogram {
+    pub fn new(bounds: &[u64]) -> Self {
+        Histogram {
+            bounds: bounds.to_vec(),
+            buckets: (0..=bounds.len()).map(|_| AtomicU64::new(0)).collect(),
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+        }
+    }
+
+    pub fn record(&self, duration: Duration) {
+        let microseconds = duration.as_micros() as u64;
+        let bucket = self
+            .bounds
+            .partition_point(|bound| bound.saturating_mul(1_000) < microseconds);
+
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(microseconds, Ordering::Relaxed);
+    }
+
+    pub fn bounds(&self) -> &[u64] {
+        &self.bounds
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Sum of the samples in milliseconds.
+    pub fn sum(&self) -> f64 {
+        self.sum.load(Ordering::Relaxed) as f64 / 1_000.0
+    }
+
+    /// Number of samples in each bucket, including the one above all the bounds.
+    pub fn buckets(&self) -> Vec<u64> {
+        self.buckets
+            .iter()
+            .map(|bucket| bucket.load(Ordering::Relaxed))
+            .collect()
+    }
+
+    /// Estimate the `quantile` (e.g. 0.99) in milliseconds, the same way
+    /// Prometheus' `histogram_quantile` does: samples are assumed to be spread
+    /// evenly within their bucket. Samples above all the bounds are estimated
+    /// at the highest bound.
+    pub fn quantile(&self, quantile: f64) -> Option<f64> {
+        quantile_of(&self.bounds, &self.buckets(), quantile)
+    }
+}
+
+/// Estimate a quantile from the bucket counts of a histogram, see [`Histogram::quantile`].
+pub fn quantile_of(bounds: &[u64], buckets: &[u64], quantile: f64) -> Option<f64> {
+    let count: u64 = buckets.iter().sum();
+    if count == 0 {
+        return None;
+    }
+
+    let rank = quantile.clamp(0.0, 1.0) * count as f64;
+    let mut below = 0;
+
+    for (index, samples) in buckets.iter().enumerate() {
+        let cumulative = below + samples;
+
+        if cumulative as f64 >= rank && *samples > 0 {
+            let upper = match bounds.get(index) {
+                Some(upper) => *upper as f64,
+                None => return bounds.last().map(|bound| *bound as f64),
+            };
+            let lower = match index {
+                0 => 0.0,
+                index => bounds[index - 1] as f64,
+            };
+
+            return Some(lower + (upper - lower) * (rank - below as f64) / *samples as f64);
+        }
+
+        below = cumulative;
+    }
+
+    bounds.last().map(|bound| *bound as f64)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(histogram: &Histogram, microseconds: u64, times: usize) {
+        for _ in 0..times {
+            histogram.record(Duration::from_micros(microseconds));
+        }
+    }
+
+    #[test]
+    fn test_histogram() {
+        let histogram = Histogram::new(&[1, 5, 10, 50, 100]);
+        assert_eq!(histogram.quantile(0.5), None);
+
+        record(&histogram, 500, 50);
+        record(&histogram, 3_000, 40);
+        record(&histogram, 40_000, 9);
+        record(&histogram, 200_000, 1);
+
+        assert_eq!(histogram.buckets(), vec![50, 40, 0, 9, 0, 1]);
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.sum(), 25.0 + 120.0 + 360.0 + 200.0);
+
+        assert_eq!(histogram.quantile(0.25), Some(0.5));
+        assert_eq!(histogram.quantile(0.5), Some(1.0));
+        assert_eq!(histogram.quantile(0.7), Some(3.0));
+        assert_eq!(histogram.quantile(0.9), Some(5.0));
+        assert_eq!(histogram.quantile(0.99), Some(50.0));
+        // In the bucket above all the bounds.
+        assert_eq!(histogram.quantile(0.999), Some(100.0));
+    }
+
+    #[test]
+    fn test_histogram_bounds() {
+        let histogram = Histogram::new(&[1, 5]);
+
+        // Upper bounds are inclusive.
+        histogram.record(Duration::from_millis(1));
+        histogram.record(Duration::from_micros(1_001));
+        histogram.record(Duration::from_millis(5));
+        histogram.record(Duration::from_secs(60));
+
+        assert_eq!(histogram.buckets(), vec![1, 2, 1]);
+    }
+}
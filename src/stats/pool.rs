@@ -5,6 +5,8 @@ use crate::{config::PoolMode, messages::DataType, pool::PoolIdentifier};
 use std::collections::HashMap;
 use std::sync::atomic::*;
 
+use crate::client_connections::pool_client_connections;
+use crate::config::get_config;
 use crate::pool::get_all_pools;
 
 #[derive(Debug, Clone)]
@@ -22,11 +24,14 @@ pub struct PoolStats {
     pub sv_tested: u64,
     pub sv_login: u64,
     pub maxwait: u64,
+    /// Clients logged in to the pool, whatever their user.
+    pub cl_connections: u64,
+    /// 0 when the pool has no max_client_connections.
+    pub max_client_connections: u64,
 }
 impl PoolStats {
     pub fn new(identifier: PoolIdentifier, mode: PoolMode) -> Self {
         PoolStats {
-            identifier,
             mode,
             cl_idle: 0,
             cl_active: 0,
@@ -38,6 +43,13 @@ impl PoolStats {
             sv_tested: 0,
             sv_login: 0,
             maxwait: 0,
+            cl_connections: pool_client_connections(&identifier.db) as u64,
+            max_client_connections: get_config()
+                .pools
+                .get(&identifier.db)
+                .and_then(|pool| pool.max_client_connections)
+                .unwrap_or_default() as u64,
+            identifier,
         }
     }
 
@@ -108,6 +120,8 @@ impl PoolStats {
             ("sv_login", DataType::Numeric),
             ("maxwait", DataType::Numeric),
             ("maxwait_us", DataType::Numeric),
+            ("cl_connections", DataType::Numeric),
+            ("max_client_connections", DataType::Numeric),
         ]
     }
 
@@ -127,6 +141,8 @@ impl PoolStats {
             self.sv_login.to_string(),
             (self.maxwait / 1_000_000).to_string(),
             (self.maxwait % 1_000_000).to_string(),
+            self.cl_connections.to_string(),
+            self.max_client_connections.to_string(),
         ]
     }
 }
@@ -148,6 +164,11 @@ impl IntoIterator for PoolStats {
             ("sv_login".to_string(), self.sv_login),
             ("maxwait".to_string(), self.maxwait / 1_000_000),
             ("maxwait_us".to_string(), self.maxwait % 1_000_000),
+            ("cl_connections".to_string(), self.cl_connections),
+            (
+                "max_client_connections".to_string(),
+                self.max_client_connections,
+            ),
         ]
         .into_iter()
     }
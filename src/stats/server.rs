@@ -176,10 +176,10 @@ impl ServerStats {
     }
 
     /// Report a query executed by a client against a server
-    pub fn query(&self, milliseconds: u64, application_name: &str) {
+    pub fn query(&self, duration: std::time::Duration, application_name: &str) {
         self.set_application(application_name.to_string());
         self.address.stats.query_count_add();
-        self.address.stats.query_time_add(milliseconds);
+        self.address.stats.query_time_add(duration);
         self.query_count.fetch_add(1, Ordering::Relaxed);
     }
 
@@ -0,0 +1,102 @@
+use crate::config::StatementType;
+use crate::query_router::QueryRouter;
+use sqlparser::ast::Statement;
+use std::sync::atomic::*;
+
+/// Kind of statement the queries are counted by, in SHOW STATS and SHOW CLIENTS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatementKind {
+    Select,
+    Insert,
+    Update,
+    Delete,
+    Ddl,
+    Other,
+}
+
+impl StatementKind {
+    pub const ALL: [StatementKind; 6] = [
+        StatementKind::Select,
+        StatementKind::Insert,
+        StatementKind::Update,
+        StatementKind::Delete,
+        StatementKind::Ddl,
+        StatementKind::Other,
+    ];
+
+    /// Kind of a statement parsed by the query router.
+    pub fn classify(statement: &Statement) -> StatementKind {
+        match statement {
+            Statement::Query(_) => StatementKind::Select,
+            Statement::Insert(_) => StatementKind::Insert,
+            Statement::Update { .. } => StatementKind::Update,
+            Statement::Delete(_) => StatementKind::Delete,
+            statement => match QueryRouter::statement_type(statement) {
+                StatementType::Ddl => StatementKind::Ddl,
+                _ => StatementKind::Other,
+            },
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            StatementKind::Select => "select",
+            StatementKind::Insert => "insert",
+            StatementKind::Update => "update",
+            StatementKind::Delete => "delete",
+            StatementKind::Ddl => "ddl",
+            StatementKind::Other => "other",
+        }
+    }
+}
+
+/// Number of queries of each kind of statement, since startup.
+#[derive(Debug, Default)]
+pub struct StatementCounts([AtomicU64; StatementKind::ALL.len()]);
+
+impl StatementCounts {
+    pub fn add(&self, kind: StatementKind) {
+        self.0[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn get(&self, kind: StatementKind) -> u64 {
+        self.0[kind as usize].load(Ordering::Relaxed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sqlparser::dialect::PostgreSqlDialect;
+    use sqlparser::parser::Parser;
+
+    #[test]
+    fn test_statement_counts() {
+        let queries = [
+            ("SELECT * FROM users", StatementKind::Select),
+            (
+                "WITH u AS (SELECT 1) SELECT * FROM u",
+                StatementKind::Select,
+            ),
+            ("INSERT INTO users (id) VALUES (1)", StatementKind::Insert),
+            ("UPDATE users SET id = 2", StatementKind::Update),
+            ("DELETE FROM users", StatementKind::Delete),
+            ("CREATE TABLE users (id BIGINT)", StatementKind::Ddl),
+            ("DROP TABLE users", StatementKind::Ddl),
+            ("SET statement_timeout TO 0", StatementKind::Other),
+            ("BEGIN", StatementKind::Other),
+        ];
+
+        let counts = StatementCounts::default();
+        for (query, kind) in queries {
+            let ast = Parser::parse_sql(&PostgreSqlDialect {}, query).unwrap();
+            assert_eq!(StatementKind::classify(&ast[0]), kind, "{}", query);
+            counts.add(kind);
+        }
+
+        assert_eq!(
+            StatementKind::ALL.map(|kind| counts.get(kind)),
+            [2, 1, 1, 1, 2, 2]
+        );
+    }
+}
@@ -0,0 +1,474 @@
+//! Metrics pushed to a StatsD server over UDP, the same ones the Prometheus exporter serves.
+use log::{debug, error, info, warn};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use tokio::net::{lookup_host, UdpSocket};
+
+use crate::config::Address;
+use crate::errors::Error;
+use crate::pool::{get_all_pools, PoolIdentifier, PoolMap};
+use crate::stats::pool::PoolStats;
+use crate::stats::{get_ban_counts, get_mirror_drop_counts};
+
+/// Metrics are packed into datagrams of at most this many bytes,
+/// so they are not fragmented on a typical network.
+const MAX_PACKET_SIZE: usize = 1432;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MetricType {
+    /// Only the increase since the last flush is sent.
+    Counter,
+    Gauge,
+    /// Milliseconds.
+    Timer,
+}
+
+impl fmt::Display for MetricType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MetricType::Counter => write!(f, "c"),
+            MetricType::Gauge => write!(f, "g"),
+            MetricType::Timer => write!(f, "ms"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct StatsdMetric {
+    name: String,
+    ty: MetricType,
+    tags: Vec<(&'static str, String)>,
+    value: u64,
+}
+
+impl StatsdMetric {
+    fn address_tags(address: &Address) -> Vec<(&'static str, String)> {
+        vec![
+            ("pool", address.pool_name.clone()),
+            ("username", address.username.clone()),
+            ("database", address.database.clone()),
+            ("shard", address.shard.to_string()),
+            ("role", address.role.to_string()),
+            ("index", address.address_index.to_string()),
+            ("host", address.host.clone()),
+        ]
+    }
+
+    /// Metrics shown in a SHOW STATS admin command.
+    fn from_address(address: &Address, name: &str, value: u64) -> StatsdMetric {
+        let (ty, value) = if name.starts_with("total_") {
+            (MetricType::Counter, value)
+        } else if name == "avg_wait_time" {
+            // Microseconds.
+            (MetricType::Timer, value / 1_000)
+        } else if name.ends_with("_time") {
+            (MetricType::Timer, value)
+        } else {
+            (MetricType::Gauge, value)
+        };
+
+        StatsdMetric {
+            name: format!("stats.{}", name),
+            ty,
+            tags: Self::address_tags(address),
+            value,
+        }
+    }
+
+    /// Metrics shown in a SHOW DATABASES admin command.
+    fn from_database_info(address: &Address, name: &str, value: u64) -> StatsdMetric {
+        StatsdMetric {
+            name: format!("databases.{}", name),
+            ty: MetricType::Gauge,
+            tags: Self::address_tags(address),
+            value,
+        }
+    }
+
+    /// Metrics shown in a SHOW POOLS admin command.
+    fn from_pool(pool_id: &PoolIdentifier, name: &str, value: u64) -> StatsdMetric {
+        StatsdMetric {
+            name: format!("pools.{}", name),
+            ty: MetricType::Gauge,
+            tags: vec![("pool", pool_id.db.clone()), ("user", pool_id.user.clone())],
+            value,
+        }
+    }
+
+    fn from_shard(pool_id: &PoolIdentifier, shard: usize, name: &str, value: u64) -> StatsdMetric {
+        StatsdMetric {
+            name: format!("shards.{}", name),
+            ty: MetricType::Gauge,
+            tags: vec![
+                ("pool", pool_id.db.clone()),
+                ("user", pool_id.user.clone()),
+                ("shard", shard.to_string()),
+            ],
+            value,
+        }
+    }
+
+    fn from_ban(reason: &str, address: &str, value: u64) -> StatsdMetric {
+        StatsdMetric {
+            name: "bans".into(),
+            ty: MetricType::Counter,
+            tags: vec![("reason", reason.into()), ("address", address.into())],
+            value,
+        }
+    }
+
+    fn from_mirror(address: &str, value: u64) -> StatsdMetric {
+        StatsdMetric {
+            name: "mirror_dropped_messages".into(),
+            ty: MetricType::Counter,
+            tags: vec![("address", address.into())],
+            value,
+        }
+    }
+
+    /// Identifies the series of the metric, to keep track of the counters.
+    fn key(&self) -> String {
+        let tags = self
+            .tags
+            .iter()
+            .map(|(tag, value)| format!("{}:{}", tag, value))
+            .collect::<Vec<_>>()
+            .join(",");
+
+        format!("{}|{}", self.name, tags)
+    }
+
+    /// The metric line, e.g. `pgcat.pools.cl_idle:3|g|#pool:db,user:user` with DogStatsD tags,
+    /// or `pgcat.pools.db.user.cl_idle:3|g` without: the values of the tags go in the name then.
+    fn format(&self, prefix: &str, tags: bool, value: u64) -> String {
+        if tags {
+            let tags = self
+                .tags
+                .iter()
+                .map(|(tag, value)| format!("{}:{}", tag, sanitize_tag(value)))
+                .collect::<Vec<_>>()
+                .join(",");
+
+            format!("{}.{}:{}|{}|#{}", prefix, self.name, value, self.ty, tags)
+        } else {
+            let (group, name) = self.name.split_once('.').unwrap_or(("", &self.name));
+            let mut parts = vec![prefix.to_string()];
+            if !group.is_empty() {
+                parts.push(group.into());
+            }
+            parts.extend(self.tags.iter().map(|(_, value)| sanitize_name(value)));
+            parts.push(name.into());
+
+            format!("{}:{}|{}", parts.join("."), value, self.ty)
+        }
+    }
+}
+
+/// Characters with a meaning in the StatsD protocol can't be in the names.
+fn sanitize_name(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '.' | ':' | '|' | '#' | ',' | '@' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+/// Colons are fine in DogStatsD tag values, e.g. `address:127.0.0.1:5432`.
+fn sanitize_tag(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            '|' | '#' | ',' | '@' => '_',
+            c if c.is_whitespace() => '_',
+            c => c,
+        })
+        .collect()
+}
+
+fn collect_metrics(pools: &PoolMap) -> Vec<StatsdMetric> {
+    let mut metrics = Vec::new();
+
+    for pool in pools.values() {
+        for shard in 0..pool.shards() {
+            for server in 0..pool.servers(shard) {
+                let address = pool.address(shard, server);
+                let stats = &*address.stats;
+                let pool_size = ("pool_size".to_string(), stats.pool_size());
+                for (key, value) in stats.clone().into_iter().chain([pool_size]) {
+                    metrics.push(StatsdMetric::from_address(address, &key, value));
+                }
+
+                let pool_state = pool.pool_state(shard, server);
+                metrics.push(StatsdMetric::from_database_info(
+                    address,
+                    "current_connections",
+                    pool_state.connections as u64,
+                ));
+            }
+        }
+    }
+
+    for (pool_id, stats) in PoolStats::construct_pool_lookup().iter() {
+        for (name, value) in stats.clone() {
+            metrics.push(StatsdMetric::from_pool(pool_id, &name, value));
+        }
+    }
+
+    for (pool_id, pool) in pools {
+        for (shard, count) in pool.banned_count_per_shard().into_iter().enumerate() {
+            metrics.push(StatsdMetric::from_shard(
+                pool_id,
+                shard,
+                "banned_count",
+                count,
+            ));
+        }
+    }
+
+    for ((reason, address), count) in get_ban_counts() {
+        metrics.push(StatsdMetric::from_ban(&reason, &address, count));
+    }
+
+    for (address, count) in get_mirror_drop_counts() {
+        metrics.push(StatsdMetric::from_mirror(&address, count));
+    }
+
+    metrics
+}
+
+/// Pushes the metrics to a StatsD server.
+#[derive(Debug)]
+pub struct StatsdExporter {
+    socket: UdpSocket,
+    prefix: String,
+    tags: bool,
+
+    /// Values of the counters at the last flush.
+    counters: HashMap<String, u64>,
+}
+
+impl StatsdExporter {
+    pub async fn new(address: &str, prefix: &str, tags: bool) -> Result<StatsdExporter, Error> {
+        let server = match lookup_host(address).await.map(|mut addrs| addrs.next()) {
+            Ok(Some(server)) => server,
+            Ok(None) => {
+                return Err(Error::SocketError(format!(
+                    "StatsD server {} not found",
+                    address
+                )))
+            }
+            Err(err) => {
+                return Err(Error::SocketError(format!(
+                    "Could not resolve StatsD server {}: {}",
+                    address, err
+                )))
+            }
+        };
+
+        let local = if server.is_ipv4() {
+            "0.0.0.0:0"
+        } else {
+            "[::]:0"
+        };
+        let socket = match UdpSocket::bind(local).await {
+            Ok(socket) => socket,
+            Err(err) => return Err(Error::SocketError(format!("StatsD socket: {}", err))),
+        };
+
+        if let Err(err) = socket.connect(server).await {
+            return Err(Error::SocketError(format!(
+                "Could not connect to StatsD server {}: {}",
+                address, err
+            )));
+        }
+
+        Ok(StatsdExporter {
+            socket,
+            prefix: prefix.into(),
+            tags,
+            counters: HashMap::new(),
+        })
+    }
+
+    fn lines(&mut self, metrics: Vec<StatsdMetric>) -> Vec<String> {
+        metrics
+            .into_iter()
+            .map(|metric| {
+                let value = match metric.ty {
+                    MetricType::Counter => {
+                        let previous = self.counters.insert(metric.key(), metric.value);
+                        match previous {
+                            // The counter was reset, e.g. its pool was recreated.
+                            Some(previous) if previous > metric.value => metric.value,
+                            Some(previous) => metric.value - previous,
+                            None => metric.value,
+                        }
+                    }
+                    _ => metric.value,
+                };
+
+                metric.format(&self.prefix, self.tags, value)
+            })
+            .collect()
+    }
+
+    /// Send the current metrics of the pools.
+    pub async fn flush(&mut self, pools: &PoolMap) -> Result<(), Error> {
+        let lines = self.lines(collect_metrics(pools));
+
+        let mut packet = String::new();
+        for line in lines {
+            if !packet.is_empty() && packet.len() + line.len() + 1 > MAX_PACKET_SIZE {
+                self.send(&packet).await?;
+                packet.clear();
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(&line);
+        }
+
+        if !packet.is_empty() {
+            self.send(&packet).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn send(&self, packet: &str) -> Result<(), Error> {
+        match self.socket.send(packet.as_bytes()).await {
+            Ok(_) => Ok(()),
+            Err(err) => Err(Error::SocketError(format!(
+                "Could not send metrics to StatsD: {}",
+                err
+            ))),
+        }
+    }
+}
+
+/// Push the metrics to `address` every `interval` ms.
+pub async fn start_statsd_exporter(address: String, interval: u64, prefix: String, tags: bool) {
+    let mut exporter = match StatsdExporter::new(&address, &prefix, tags).await {
+        Ok(exporter) => exporter,
+        Err(err) => {
+            error!("StatsD exporter not started: {:?}", err);
+            return;
+        }
+    };
+
+    info!("Pushing StatsD metrics to {} every {}ms", address, interval);
+
+    let mut interval = tokio::time::interval(Duration::from_millis(interval));
+    loop {
+        interval.tick().await;
+
+        match exporter.flush(&get_all_pools()).await {
+            Ok(()) => debug!("Metrics sent to StatsD"),
+            Err(err) => warn!("{:?}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::Role;
+    use crate::pool::{BanReason, ConnectionPool};
+    use crate::stats::get_reporter;
+
+    #[test]
+    fn test_format() {
+        let address = Address {
+            host: "10.0.0.1".into(),
+            database: "db".into(),
+            pool_name: "pool".into(),
+            username: "user".into(),
+            role: Role::Replica,
+            shard: 1,
+            address_index: 2,
+            ..Default::default()
+        };
+
+        let metric = StatsdMetric::from_address(&address, "avg_query_time", 12);
+        assert_eq!(
+            metric.format("pgcat", true, 12),
+            "pgcat.stats.avg_query_time:12|ms|#pool:pool,username:user,database:db,shard:1,role:replica,index:2,host:10.0.0.1"
+        );
+        assert_eq!(
+            metric.format("pgcat", false, 12),
+            "pgcat.stats.pool.user.db.1.replica.2.10_0_0_1.avg_query_time:12|ms"
+        );
+
+        let metric = StatsdMetric::from_address(&address, "avg_wait_time", 3_000);
+        assert_eq!(metric.ty, MetricType::Timer);
+        assert_eq!(metric.value, 3);
+        assert_eq!(
+            StatsdMetric::from_address(&address, "total_query_count", 1).ty,
+            MetricType::Counter
+        );
+        assert_eq!(
+            StatsdMetric::from_address(&address, "avg_query_count", 1).ty,
+            MetricType::Gauge
+        );
+    }
+
+    #[tokio::test]
+    async fn test_statsd_exporter() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut exporter =
+            StatsdExporter::new(&server.local_addr().unwrap().to_string(), "pgcat", true)
+                .await
+                .unwrap();
+
+        let pool = ConnectionPool::with_banlist(1);
+        let banned = Address {
+            host: "statsd-exporter".into(),
+            ..Default::default()
+        };
+        pool.ban(&banned, BanReason::FailedHealthCheck, None);
+        get_reporter().mirror_drop("statsd-mirror:5432".into());
+
+        let mut pools = PoolMap::new();
+        pools.insert(PoolIdentifier::new("db", "user"), pool);
+
+        let receive = || async {
+            let mut lines = Vec::new();
+            let mut buffer = vec![0; MAX_PACKET_SIZE];
+            // Everything is sent at once, wait for the last of the packets.
+            while let Ok(Ok(len)) =
+                tokio::time::timeout(Duration::from_millis(100), server.recv(&mut buffer)).await
+            {
+                assert!(len <= MAX_PACKET_SIZE);
+                let packet = String::from_utf8(buffer[..len].to_vec()).unwrap();
+                lines.extend(packet.lines().map(String::from));
+            }
+            lines
+        };
+
+        exporter.flush(&pools).await.unwrap();
+        let lines = receive().await;
+        assert!(lines.contains(
+            &"pgcat.bans:1|c|#reason:FailedHealthCheck,address:statsd-exporter:5432".to_string()
+        ));
+        assert!(lines.contains(
+            &"pgcat.mirror_dropped_messages:1|c|#address:statsd-mirror:5432".to_string()
+        ));
+        assert!(
+            lines.contains(&"pgcat.shards.banned_count:1|g|#pool:db,user:user,shard:0".to_string())
+        );
+
+        // Counters only send what was added since the last flush, gauges their value.
+        exporter.flush(&pools).await.unwrap();
+        let lines = receive().await;
+        assert!(lines.contains(
+            &"pgcat.bans:0|c|#reason:FailedHealthCheck,address:statsd-exporter:5432".to_string()
+        ));
+        assert!(
+            lines.contains(&"pgcat.shards.banned_count:1|g|#pool:db,user:user,shard:0".to_string())
+        );
+    }
+}
@@ -1,6 +1,10 @@
 // Stream wrapper.
 
+use arc_swap::ArcSwap;
+use log::{error, info};
+use once_cell::sync::Lazy;
 use rustls_pemfile::{certs, read_one, Item};
+use sha2::{Digest, Sha256, Sha384, Sha512};
 use std::iter;
 use std::path::Path;
 use std::sync::Arc;
@@ -8,13 +12,38 @@ use std::time::SystemTime;
 use tokio_rustls::rustls::{
     self,
     client::{ServerCertVerified, ServerCertVerifier},
-    Certificate, PrivateKey, ServerName,
+    server::{AllowAnyAnonymousOrAuthenticatedClient, AllowAnyAuthenticatedClient},
+    version, Certificate, PrivateKey, RootCertStore, ServerName, SupportedCipherSuite,
+    SupportedProtocolVersion, ALL_CIPHER_SUITES, ALL_VERSIONS,
 };
 use tokio_rustls::TlsAcceptor;
 
-use crate::config::get_config;
+use crate::config::{get_config, ClientCertificateMode, Config, General, TlsVersion};
+use crate::der::element;
 use crate::errors::Error;
 
+/// DER tags used by certificates.
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_SET: u8 = 0x31;
+const TAG_OID: u8 = 0x06;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_VERSION: u8 = 0xa0;
+const TAG_EXTENSIONS: u8 = 0xa3;
+
+/// Subject alternative names we map to users.
+const TAG_RFC822_NAME: u8 = 0x81;
+const TAG_DNS_NAME: u8 = 0x82;
+const TAG_URI: u8 = 0x86;
+
+const OID_COMMON_NAME: &[u8] = &[0x55, 0x04, 0x03];
+const OID_SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+
+/// Certificate signature algorithms hashed with something else than SHA-256.
+const OID_SHA384_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0c];
+const OID_SHA512_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0d];
+const OID_ECDSA_WITH_SHA384: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+const OID_ECDSA_WITH_SHA512: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x04];
+
 // TLS
 pub fn load_certs(path: &Path) -> std::io::Result<Vec<Certificate>> {
     certs(&mut std::io::BufReader::new(std::fs::File::open(path)?))
@@ -36,38 +65,289 @@ pub fn load_keys(path: &Path) -> std::io::Result<Vec<PrivateKey>> {
         .collect()
 }
 
-pub struct Tls {
-    pub acceptor: TlsAcceptor,
+static TLS13_ONLY: &[&SupportedProtocolVersion] = &[&version::TLS13];
+
+/// TLS versions allowed by `tls_min_version`.
+pub fn protocol_versions(general: &General) -> &'static [&'static SupportedProtocolVersion] {
+    match general.tls_min_version {
+        TlsVersion::Tls12 => ALL_VERSIONS,
+        TlsVersion::Tls13 => TLS13_ONLY,
+    }
 }
 
-impl Tls {
-    pub fn new() -> Result<Self, Error> {
-        let config = get_config();
+/// Cipher suites allowed by `tls_cipher_suites`, without those
+/// that can't be used with `tls_min_version` or later.
+pub fn cipher_suites(general: &General) -> Result<Vec<SupportedCipherSuite>, Error> {
+    let suites = match &general.tls_cipher_suites {
+        Some(names) => names
+            .iter()
+            .map(|name| {
+                ALL_CIPHER_SUITES
+                    .iter()
+                    .find(|suite| match suite.suite().as_str() {
+                        Some(suite) => suite.eq_ignore_ascii_case(name),
+                        None => false,
+                    })
+                    .copied()
+                    .ok_or_else(|| {
+                        error!("tls_cipher_suites: unknown cipher suite {}", name);
+                        Error::BadConfig
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => ALL_CIPHER_SUITES.to_vec(),
+    };
 
-        let certs = match load_certs(Path::new(&config.general.tls_certificate.unwrap())) {
-            Ok(certs) => certs,
-            Err(_) => return Err(Error::TlsError),
-        };
+    let versions = protocol_versions(general);
+    let suites = suites
+        .into_iter()
+        .filter(|suite| versions.contains(&suite.version()))
+        .collect::<Vec<_>>();
 
-        let mut keys = match load_keys(Path::new(&config.general.tls_private_key.unwrap())) {
-            Ok(keys) => keys,
-            Err(_) => return Err(Error::TlsError),
-        };
+    if suites.is_empty() {
+        error!(
+            "None of the tls_cipher_suites can be used with tls_min_version {}",
+            general.tls_min_version
+        );
+        return Err(Error::BadConfig);
+    }
 
-        let config = match rustls::ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(certs, keys.remove(0))
-            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))
-        {
-            Ok(c) => c,
-            Err(_) => return Err(Error::TlsError),
-        };
+    Ok(suites)
+}
+
+/// The acceptor for client connections, swapped when the certificate is reloaded.
+/// Not set if TLS is not configured.
+pub static TLS: Lazy<ArcSwap<Option<Tls>>> = Lazy::new(|| ArcSwap::from_pointee(None));
+
+pub struct Tls {
+    pub acceptor: TlsAcceptor,
+
+    /// tls-server-end-point channel binding data of our certificate.
+    pub channel_binding: Vec<u8>,
+}
+
+impl Tls {
+    pub fn from_general(general: &General) -> Result<Self, Error> {
+        // The channel binding is of the certificate the acceptor presents, so
+        // it's computed from the same chain, read once.
+        let certs = server_certs(general)?;
+        let channel_binding = certs.first().map(tls_server_end_point).unwrap_or_default();
+        let config = server_config_with_certs(general, certs)?;
 
         Ok(Tls {
             acceptor: TlsAcceptor::from(Arc::new(config)),
+            channel_binding,
         })
     }
+
+    /// (Re)load the global acceptor from the current configuration.
+    /// Connections already established keep using the certificate they were accepted with.
+    pub fn from_config() {
+        reload(&TLS, &get_config().general);
+    }
+}
+
+/// Load the certificate and key into `tls`, keeping the current ones if they can't be loaded.
+fn reload(tls: &ArcSwap<Option<Tls>>, general: &General) {
+    let path = match &general.tls_certificate {
+        Some(path) => path,
+        None => {
+            tls.store(Arc::new(None));
+            return;
+        }
+    };
+
+    match Tls::from_general(general) {
+        Ok(new) => {
+            info!("Loaded TLS certificate {}", path);
+            tls.store(Arc::new(Some(new)));
+        }
+        Err(err) => match &**tls.load() {
+            Some(_) => error!(
+                "Could not load TLS certificate {}, keeping the current one: {:?}",
+                path, err
+            ),
+            None => error!("Could not load TLS certificate {}: {:?}", path, err),
+        },
+    }
+}
+
+/// What authentication needs to know about a client's TLS connection.
+#[derive(Debug, Clone, Default)]
+pub struct TlsSession {
+    /// Verified by the acceptor if client certificates are enabled.
+    pub client_certificate: Option<Certificate>,
+
+    /// tls-server-end-point channel binding data.
+    pub channel_binding: Vec<u8>,
+}
+
+/// Hash of the certificate for the tls-server-end-point channel binding (RFC 5929):
+/// it uses the hash function of the certificate's signature, or SHA-256 if that's MD5 or SHA-1.
+pub fn tls_server_end_point(cert: &Certificate) -> Vec<u8> {
+    let algorithm = element(&cert.0)
+        .and_then(|(_, cert, _)| element(cert))
+        .and_then(|(_, _tbs, rest)| element(rest))
+        .and_then(|(_, algorithm, _)| element(algorithm))
+        .map(|(_, oid, _)| oid);
+
+    match algorithm {
+        Some(OID_SHA384_WITH_RSA) | Some(OID_ECDSA_WITH_SHA384) => Sha384::digest(&cert.0).to_vec(),
+        Some(OID_SHA512_WITH_RSA) | Some(OID_ECDSA_WITH_SHA512) => Sha512::digest(&cert.0).to_vec(),
+        _ => Sha256::digest(&cert.0).to_vec(),
+    }
+}
+
+/// TLS settings for client connections.
+pub fn server_config(general: &General) -> Result<rustls::ServerConfig, Error> {
+    server_config_with_certs(general, server_certs(general)?)
+}
+
+/// Certificate chain of `tls_certificate`.
+fn server_certs(general: &General) -> Result<Vec<Certificate>, Error> {
+    match &general.tls_certificate {
+        Some(path) => load_certs(Path::new(path)).map_err(|_| Error::TlsError),
+        None => Err(Error::TlsError),
+    }
+}
+
+fn server_config_with_certs(
+    general: &General,
+    certs: Vec<Certificate>,
+) -> Result<rustls::ServerConfig, Error> {
+    let mut keys = match &general.tls_private_key {
+        Some(path) => load_keys(Path::new(path)).map_err(|_| Error::TlsError)?,
+        None => return Err(Error::TlsError),
+    };
+
+    if certs.is_empty() || keys.is_empty() {
+        return Err(Error::TlsError);
+    }
+
+    let builder = rustls::ServerConfig::builder()
+        .with_cipher_suites(&cipher_suites(general)?)
+        .with_safe_default_kx_groups()
+        .with_protocol_versions(protocol_versions(general))
+        .map_err(|_| Error::TlsError)?;
+
+    let builder = match &general.tls_client_ca {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(Path::new(path)).map_err(|_| Error::TlsError)? {
+                roots.add(&cert).map_err(|_| Error::TlsError)?;
+            }
+
+            builder.with_client_cert_verifier(match general.tls_client_certificate_mode {
+                ClientCertificateMode::Optional => {
+                    AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed()
+                }
+                ClientCertificateMode::Required => AllowAnyAuthenticatedClient::new(roots).boxed(),
+            })
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    builder
+        .with_single_cert(certs, keys.remove(0))
+        .map_err(|_| Error::TlsError)
+}
+
+/// Identities of a client certificate: the subject CN, and the DNS, email
+/// and URI subject alternative names.
+pub fn certificate_identities(cert: &Certificate) -> Vec<String> {
+    let mut identities = Vec::new();
+
+    let tbs = match element(&cert.0)
+        .filter(|(tag, _, _)| *tag == TAG_SEQUENCE)
+        .and_then(|(_, cert, _)| element(cert))
+    {
+        Some((TAG_SEQUENCE, tbs, _)) => tbs,
+        _ => return identities,
+    };
+
+    // version, serialNumber, signature, issuer, validity, subject, subjectPublicKeyInfo
+    // and what comes after, i.e. the extensions.
+    let mut fields = Vec::new();
+    let mut rest = tbs;
+    while let Some((tag, content, next)) = element(rest) {
+        fields.push((tag, content));
+        rest = next;
+    }
+    if fields.first().map(|(tag, _)| *tag) == Some(TAG_VERSION) {
+        fields.remove(0);
+    }
+
+    if let Some((TAG_SEQUENCE, subject)) = fields.get(4) {
+        let mut rdns = *subject;
+        while let Some((TAG_SET, rdn, next)) = element(rdns) {
+            if let Some((TAG_SEQUENCE, attribute, _)) = element(rdn) {
+                if let Some((TAG_OID, OID_COMMON_NAME, value)) = element(attribute) {
+                    if let Some((_, value, _)) = element(value) {
+                        identities.push(String::from_utf8_lossy(value).to_string());
+                    }
+                }
+            }
+            rdns = next;
+        }
+    }
+
+    let extensions = fields
+        .iter()
+        .skip(6)
+        .find(|(tag, _)| *tag == TAG_EXTENSIONS)
+        .and_then(|(_, extensions)| element(extensions));
+
+    if let Some((TAG_SEQUENCE, mut extensions, _)) = extensions {
+        while let Some((TAG_SEQUENCE, extension, next)) = element(extensions) {
+            if let Some((TAG_OID, OID_SUBJECT_ALT_NAME, mut rest)) = element(extension) {
+                // Skip the critical flag.
+                while let Some((tag, value, next)) = element(rest) {
+                    if tag == TAG_OCTET_STRING {
+                        if let Some((TAG_SEQUENCE, mut names, _)) = element(value) {
+                            while let Some((tag, name, next)) = element(names) {
+                                if matches!(tag, TAG_RFC822_NAME | TAG_DNS_NAME | TAG_URI) {
+                                    identities.push(String::from_utf8_lossy(name).to_string());
+                                }
+                                names = next;
+                            }
+                        }
+                    }
+                    rest = next;
+                }
+            }
+            extensions = next;
+        }
+    }
+
+    identities
+}
+
+/// Check the client certificate is mapped to the username in `[client_certificate_users]`.
+pub fn authenticate_certificate(
+    config: &Config,
+    cert: Option<&Certificate>,
+    username: &str,
+) -> Result<(), Error> {
+    let cert = match cert {
+        Some(cert) => cert,
+        None => return Err(Error::AuthError("no client certificate".into())),
+    };
+
+    let identities = certificate_identities(cert);
+
+    match identities.iter().any(|identity| {
+        config
+            .client_certificate_users
+            .get(identity)
+            .map(String::as_str)
+            == Some(username)
+    }) {
+        true => Ok(()),
+        false => Err(Error::AuthError(format!(
+            "client certificate {:?} is not mapped to {}",
+            identities, username
+        ))),
+    }
 }
 
 pub struct NoCertificateVerification;
@@ -85,3 +365,344 @@ impl ServerCertVerifier for NoCertificateVerification {
         Ok(ServerCertVerified::assertion())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    fn general(mode: ClientCertificateMode) -> General {
+        General {
+            tls_certificate: Some(".circleci/server.cert".into()),
+            tls_private_key: Some(".circleci/server.key".into()),
+            tls_client_ca: Some("tests/tls/ca.pem".into()),
+            tls_client_certificate_mode: mode,
+            ..Default::default()
+        }
+    }
+
+    fn client_certificate(name: &str) -> Certificate {
+        load_certs(Path::new(&format!("tests/tls/{}.pem", name)))
+            .unwrap()
+            .remove(0)
+    }
+
+    /// Connect with the given test certificate, returns the certificate the server got.
+    async fn connect(general: &General, name: Option<&str>) -> Result<Option<Certificate>, Error> {
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(general)?));
+
+        let builder = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_custom_certificate_verifier(Arc::new(NoCertificateVerification));
+        let config = match name {
+            Some(name) => builder
+                .with_client_auth_cert(
+                    vec![client_certificate(name)],
+                    load_keys(Path::new(&format!("tests/tls/{}.key", name)))
+                        .unwrap()
+                        .remove(0),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        let connector = TlsConnector::from(Arc::new(config));
+
+        let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+
+        let server = async move {
+            let mut stream = acceptor
+                .accept(server_stream)
+                .await
+                .map_err(|_| Error::TlsError)?;
+            let mut byte = [0u8; 1];
+            stream
+                .read_exact(&mut byte)
+                .await
+                .map_err(|_| Error::TlsError)?;
+            Ok(stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first().cloned()))
+        };
+
+        let client = async move {
+            let mut stream = connector
+                .connect(ServerName::try_from("localhost").unwrap(), client_stream)
+                .await?;
+            stream.write_all(b"x").await?;
+            Ok::<_, std::io::Error>(stream)
+        };
+
+        let (server, _client) = tokio::join!(server, client);
+        server
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_required() {
+        let general = general(ClientCertificateMode::Required);
+
+        assert_eq!(
+            connect(&general, Some("client")).await.unwrap(),
+            Some(client_certificate("client"))
+        );
+        assert!(connect(&general, None).await.is_err());
+        assert!(connect(&general, Some("expired")).await.is_err());
+        assert!(connect(&general, Some("untrusted")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_client_certificate_optional() {
+        let general = general(ClientCertificateMode::Optional);
+
+        assert_eq!(
+            connect(&general, Some("client")).await.unwrap(),
+            Some(client_certificate("client"))
+        );
+        assert_eq!(connect(&general, None).await.unwrap(), None);
+
+        // Invalid certificates are still rejected.
+        assert!(connect(&general, Some("expired")).await.is_err());
+        assert!(connect(&general, Some("untrusted")).await.is_err());
+    }
+
+    #[test]
+    fn test_certificate_identities() {
+        assert_eq!(
+            certificate_identities(&client_certificate("client")),
+            vec!["app", "app.example.org", "spiffe://example.org/app"]
+        );
+        assert_eq!(
+            certificate_identities(&client_certificate("untrusted")),
+            vec!["app"]
+        );
+        assert!(certificate_identities(&Certificate(vec![0x30, 0x00])).is_empty());
+    }
+
+    #[test]
+    fn test_authenticate_certificate() {
+        let mut config = Config::default();
+        config
+            .client_certificate_users
+            .insert("spiffe://example.org/app".into(), "app_user".into());
+        let cert = client_certificate("client");
+
+        assert!(authenticate_certificate(&config, Some(&cert), "app_user").is_ok());
+        assert!(authenticate_certificate(&config, Some(&cert), "admin").is_err());
+        assert!(authenticate_certificate(&config, None, "app_user").is_err());
+
+        // The CN is not mapped.
+        let cert = client_certificate("untrusted");
+        assert!(authenticate_certificate(&config, Some(&cert), "app_user").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_channel_binding() {
+        use crate::constants::SCRAM_SHA_256_PLUS;
+        use crate::scram::{ScramSha256, ScramSha256Server};
+        use bytes::BytesMut;
+
+        let general = General {
+            tls_client_ca: None,
+            ..general(ClientCertificateMode::Optional)
+        };
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(&general).unwrap()));
+        let connector = TlsConnector::from(Arc::new(
+            rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+                .with_no_client_auth(),
+        ));
+        let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+
+        let (server, client) = tokio::join!(
+            acceptor.accept(server_stream),
+            connector.connect(ServerName::try_from("localhost").unwrap(), client_stream)
+        );
+        let (_server, client) = (server.unwrap(), client.unwrap());
+
+        // What the client sees of the session.
+        let client_binding =
+            tls_server_end_point(&client.get_ref().1.peer_certificates().unwrap()[0]);
+
+        let server_cert = load_certs(Path::new(".circleci/server.cert"))
+            .unwrap()
+            .remove(0);
+        let server_binding = tls_server_end_point(&server_cert);
+        assert_eq!(server_binding, Sha256::digest(&server_cert.0).to_vec());
+
+        let exchange = |client_binding: &[u8]| {
+            let mut server = ScramSha256Server::new("secret", Some(server_binding.clone()));
+            let mut client = ScramSha256::new("secret").with_channel_binding(client_binding);
+            let server_first = server.first(SCRAM_SHA_256_PLUS, &client.message()).unwrap();
+            let client_final = client
+                .update(&BytesMut::from(server_first.as_bytes()))
+                .unwrap();
+            server.finish(&client_final)
+        };
+
+        assert!(exchange(&client_binding).is_ok());
+
+        // The client is connected to someone else.
+        let other_binding = tls_server_end_point(&client_certificate("client"));
+        assert!(exchange(&other_binding).is_err());
+    }
+
+    /// Which certificate the server presents to a new connection.
+    async fn presented_certificate(tls: &ArcSwap<Option<Tls>>) -> Certificate {
+        let acceptor = tls.load().as_ref().as_ref().unwrap().acceptor.clone();
+        let connector = TlsConnector::from(Arc::new(
+            rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+                .with_no_client_auth(),
+        ));
+        let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+
+        let (server, client) = tokio::join!(
+            acceptor.accept(server_stream),
+            connector.connect(ServerName::try_from("localhost").unwrap(), client_stream)
+        );
+        let (_server, client) = (server.unwrap(), client.unwrap());
+        client.get_ref().1.peer_certificates().unwrap()[0].clone()
+    }
+
+    #[tokio::test]
+    async fn test_reload() {
+        let dir = std::env::temp_dir().join(format!("pgcat-tls-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("server.pem"), dir.join("server.key"));
+        let install = |from_cert: &str, from_key: &str| {
+            std::fs::copy(from_cert, &cert).unwrap();
+            std::fs::copy(from_key, &key).unwrap();
+        };
+
+        let general = General {
+            tls_certificate: Some(cert.to_str().unwrap().into()),
+            tls_private_key: Some(key.to_str().unwrap().into()),
+            ..Default::default()
+        };
+        let tls = ArcSwap::from_pointee(None);
+
+        install(".circleci/server.cert", ".circleci/server.key");
+        reload(&tls, &general);
+        let first = load_certs(Path::new(".circleci/server.cert"))
+            .unwrap()
+            .remove(0);
+        assert_eq!(presented_certificate(&tls).await, first);
+
+        // A connection accepted before the reload keeps its acceptor.
+        let old = tls.load_full();
+
+        install("tests/tls/client.pem", "tests/tls/client.key");
+        reload(&tls, &general);
+        let rotated = client_certificate("client");
+        assert_eq!(presented_certificate(&tls).await, rotated);
+        assert_eq!(
+            old.as_ref().as_ref().unwrap().channel_binding,
+            tls_server_end_point(&first)
+        );
+
+        // A broken certificate doesn't replace the working one.
+        std::fs::write(&cert, "not a certificate").unwrap();
+        reload(&tls, &general);
+        assert_eq!(presented_certificate(&tls).await, rotated);
+
+        // Neither does a missing one.
+        std::fs::remove_file(&key).unwrap();
+        reload(&tls, &general);
+        assert_eq!(presented_certificate(&tls).await, rotated);
+
+        // Not configured anymore.
+        reload(&tls, &General::default());
+        assert!(tls.load().is_none());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Handshake with a client limited to the given versions and suites,
+    /// returns the cipher suite that was negotiated.
+    async fn negotiate(
+        general: &General,
+        versions: &[&'static SupportedProtocolVersion],
+        suites: &[SupportedCipherSuite],
+    ) -> Option<SupportedCipherSuite> {
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(general).unwrap()));
+        let connector = TlsConnector::from(Arc::new(
+            rustls::ClientConfig::builder()
+                .with_cipher_suites(suites)
+                .with_safe_default_kx_groups()
+                .with_protocol_versions(versions)
+                .unwrap()
+                .with_custom_certificate_verifier(Arc::new(NoCertificateVerification))
+                .with_no_client_auth(),
+        ));
+        let (client_stream, server_stream) = tokio::io::duplex(64 * 1024);
+
+        let (server, client) = tokio::join!(
+            acceptor.accept(server_stream),
+            connector.connect(ServerName::try_from("localhost").unwrap(), client_stream)
+        );
+        server.ok()?;
+        client.ok()?.get_ref().1.negotiated_cipher_suite()
+    }
+
+    #[tokio::test]
+    async fn test_min_version() {
+        let tls12 = &[&version::TLS12];
+        let mut general = General {
+            tls_client_ca: None,
+            ..general(ClientCertificateMode::Optional)
+        };
+
+        let suite = negotiate(&general, tls12, ALL_CIPHER_SUITES).await.unwrap();
+        assert_eq!(suite.version(), &version::TLS12);
+
+        general.tls_min_version = TlsVersion::Tls13;
+        assert!(negotiate(&general, tls12, ALL_CIPHER_SUITES)
+            .await
+            .is_none());
+        let suite = negotiate(&general, ALL_VERSIONS, ALL_CIPHER_SUITES)
+            .await
+            .unwrap();
+        assert_eq!(suite.version(), &version::TLS13);
+    }
+
+    #[tokio::test]
+    async fn test_cipher_suites() {
+        let mut general = General {
+            tls_client_ca: None,
+            tls_cipher_suites: Some(vec!["tls13_chacha20_poly1305_sha256".into()]),
+            ..general(ClientCertificateMode::Optional)
+        };
+
+        let suites = cipher_suites(&general).unwrap();
+        assert_eq!(suites.len(), 1);
+        assert_eq!(
+            negotiate(&general, ALL_VERSIONS, ALL_CIPHER_SUITES).await,
+            Some(suites[0])
+        );
+        assert!(negotiate(&general, ALL_VERSIONS, &ALL_CIPHER_SUITES[..1])
+            .await
+            .is_none());
+
+        // The TLS 1.2 suites are dropped with a 1.3 minimum.
+        general.tls_cipher_suites = Some(vec![
+            "TLS13_AES_256_GCM_SHA384".into(),
+            "TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384".into(),
+        ]);
+        assert_eq!(cipher_suites(&general).unwrap().len(), 2);
+        general.tls_min_version = TlsVersion::Tls13;
+        assert_eq!(cipher_suites(&general).unwrap().len(), 1);
+
+        // Nothing left to negotiate with.
+        general.tls_cipher_suites = Some(vec!["TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384".into()]);
+        assert!(cipher_suites(&general).is_err());
+        general.tls_cipher_suites = Some(vec![]);
+        assert!(cipher_suites(&general).is_err());
+
+        general.tls_cipher_suites = Some(vec!["TLS_RSA_WITH_RC4_128_MD5".into()]);
+        assert!(cipher_suites(&general).is_err());
+    }
+}
@@ -0,0 +1,145 @@
+//! Clients connecting over a Unix domain socket, `.s.PGSQL.<port>` in `unix_socket_dir`
+//! like Postgres.
+use log::{debug, error, info, warn};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use tokio::net::UnixListener;
+use tokio::sync::{broadcast, mpsc};
+
+use crate::client::unix_client_entrypoint;
+use crate::config::get_config;
+use crate::errors::Error;
+use crate::pool::ClientServerMap;
+
+/// Path of the socket, the name libpq looks for in its `host` directory.
+pub fn socket_path(dir: &str, port: u16) -> PathBuf {
+    Path::new(dir).join(format!(".s.PGSQL.{}", port))
+}
+
+/// Listen on the socket. A socket file left behind by a PgCat that didn't shut down cleanly
+/// is replaced, but not one another process is listening on.
+pub fn bind(path: &Path) -> Result<UnixListener, Error> {
+    if path.exists() {
+        if std::os::unix::net::UnixStream::connect(path).is_ok() {
+            return Err(Error::SocketError(format!(
+                "{} is already in use",
+                path.display()
+            )));
+        }
+
+        fs::remove_file(path).map_err(|err| {
+            Error::SocketError(format!(
+                "Could not remove stale socket {}: {}",
+                path.display(),
+                err
+            ))
+        })?;
+    }
+
+    let listener = UnixListener::bind(path).map_err(|err| {
+        Error::SocketError(format!("Could not bind to {}: {}", path.display(), err))
+    })?;
+
+    // Anyone can connect, like Postgres does by default: access is controlled with
+    // the permissions of the directory.
+    fs::set_permissions(path, fs::Permissions::from_mode(0o777)).map_err(|err| {
+        Error::SocketError(format!(
+            "Could not set the permissions of {}: {}",
+            path.display(),
+            err
+        ))
+    })?;
+
+    Ok(listener)
+}
+
+/// Remove the socket when shutting down.
+pub fn remove(path: &Path) {
+    match fs::remove_file(path) {
+        Ok(()) => info!("Removed {}", path.display()),
+        Err(err) => warn!("Could not remove {}: {}", path.display(), err),
+    }
+}
+
+/// Accept the clients of the socket until PgCat exits. Only admin clients are accepted
+/// once the graceful shutdown started, like on the TCP port.
+pub async fn accept_clients(
+    listener: UnixListener,
+    client_server_map: ClientServerMap,
+    shutdown: broadcast::Sender<()>,
+    drain: mpsc::Sender<i32>,
+) {
+    let mut shutdown_rx = shutdown.subscribe();
+    let mut admin_only = false;
+
+    loop {
+        tokio::select! {
+            _ = shutdown_rx.recv(), if !admin_only => {
+                admin_only = true;
+            }
+
+            new_client = listener.accept() => {
+                let stream = match new_client {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        error!("{:?}", err);
+                        continue;
+                    }
+                };
+
+                let client_server_map = client_server_map.clone();
+                let shutdown_rx = shutdown.subscribe();
+                let drain = drain.clone();
+
+                tokio::task::spawn(async move {
+                    match unix_client_entrypoint(
+                        stream,
+                        client_server_map,
+                        shutdown_rx,
+                        drain,
+                        admin_only,
+                        get_config().general.log_client_connections,
+                    )
+                    .await
+                    {
+                        Ok(()) => debug!("Unix socket client disconnected"),
+                        Err(Error::ClientBadStartup) => {
+                            debug!("Unix socket client disconnected with error {:?}", Error::ClientBadStartup)
+                        }
+                        Err(err) => warn!("Unix socket client disconnected with error {:?}", err),
+                    }
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_bind() {
+        let dir = std::env::temp_dir().join(format!("pgcat_test_bind_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = socket_path(dir.to_str().unwrap(), 6432);
+        assert_eq!(path, dir.join(".s.PGSQL.6432"));
+
+        let listener = bind(&path).unwrap();
+        assert_eq!(
+            fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+            0o777
+        );
+        assert!(matches!(bind(&path), Err(Error::SocketError(_))));
+
+        // Left behind without anyone listening.
+        drop(listener);
+        assert!(path.exists());
+        let _listener = bind(&path).unwrap();
+
+        remove(&path);
+        assert!(!path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -92,6 +92,23 @@ describe "Auth Query" do
             Helpers::AuthQuery.exec_in_instances(query: "ALTER USER #{pg_user['username']} WITH ENCRYPTED PASSWORD '#{pg_user['password']}';")
           end
         end
+
+        context 'and the servers store SCRAM secrets' do
+          let(:config_user) { { 'username' => 'sharding_user', 'auth_type' => 'scram' } }
+
+          around(:example) do |example|
+            Helpers::AuthQuery.exec_in_instances(query: "SET password_encryption = 'scram-sha-256'; ALTER USER #{pg_user['username']} WITH ENCRYPTED PASSWORD '#{pg_user['password']}';")
+            example.run
+            Helpers::AuthQuery.exec_in_instances(query: "SET password_encryption = 'md5'; ALTER USER #{pg_user['username']} WITH ENCRYPTED PASSWORD '#{pg_user['password']}';")
+          end
+
+          it 'authenticates clients with the secret and logs in to the servers with their key' do
+            conn = PG.connect(processes.pgcat.connection_string("sharded_db", pg_user['username'], pg_user['password']))
+            expect(conn.async_exec("SELECT 1 + 2")).not_to be_nil
+
+            expect { PG.connect(processes.pgcat.connection_string("sharded_db", pg_user['username'], 'wrong')) }.to raise_error(PG::ConnectionBad)
+          end
+        end
       end
 
       context 'with wrong parameters' do
@@ -73,6 +73,61 @@ describe "COPY Handling" do
         ])
       end
     end
+
+    context "with many rows" do
+      it "copies all of them" do
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        conn.copy_data "COPY copy_test_table FROM STDIN CSV" do
+          1000.times { |i| conn.put_copy_data "#{i},data,to,copy\n" }
+        end
+
+        res = conn.async_exec("SELECT count(*) AS total, count(DISTINCT a) AS distinct_a FROM copy_test_table")[0]
+        expect(res).to eq({"total"=>"1000", "distinct_a"=>"1000"})
+      end
+    end
+
+    context "with the extended protocol" do
+      it "finishes the copy" do
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        Timeout.timeout(3) do
+          conn.exec_params("COPY copy_test_table FROM STDIN CSV", [])
+          conn.put_copy_data "some,data,to,copy\n"
+          conn.put_copy_end
+          expect(conn.get_last_result.cmd_tuples).to eq(1)
+        end
+
+        expect(conn.async_exec("SELECT count(*) FROM copy_test_table")[0]["count"]).to eq("1")
+      end
+    end
+
+    context "when the client aborts" do
+      it "rolls back the copy and keeps the connection usable" do
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        expect {
+          conn.copy_data "COPY copy_test_table FROM STDIN CSV" do
+            conn.put_copy_data "some,data,to,copy\n"
+            raise "client gave up"
+          end
+        }.to raise_error(RuntimeError, "client gave up")
+
+        expect(conn.async_exec("SELECT count(*) FROM copy_test_table")[0]["count"]).to eq("0")
+      end
+    end
+
+    context "when the client disconnects" do
+      let(:processes) { Helpers::Pgcat.single_instance_setup("sharded_db", 1) }
+
+      it "does not give the server in copy mode to the next client" do
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        conn.async_exec("COPY copy_test_table FROM STDIN CSV")
+        conn.put_copy_data "some,data,to,copy\n"
+        conn.close
+
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        expect(conn.async_exec("SELECT 1")[0]["?column?"]).to eq("1")
+        expect(conn.async_exec("SELECT count(*) FROM copy_test_table")[0]["count"]).to eq("0")
+      end
+    end
   end
 
   describe "COPY TO" do
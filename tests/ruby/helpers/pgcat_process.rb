@@ -3,6 +3,7 @@ require 'json'
 require 'tempfile'
 require 'fileutils'
 require 'securerandom'
+require 'timeout'
 
 class ConfigReloadFailed < StandardError; end
 class PgcatProcess
@@ -112,6 +113,22 @@ class PgcatProcess
     @pid = nil
   end
 
+  # Start a graceful shutdown, see wait_until_exited
+  def terminate
+    Process.kill("TERM", @pid)
+  end
+
+  def running?
+    return false unless @pid
+
+    Process.wait(@pid, Process::WNOHANG).nil?
+  end
+
+  def wait_until_exited(timeout = 10)
+    Timeout.timeout(timeout) { Process.wait(@pid) }
+    @pid = nil
+  end
+
   def shutdown
     stop
     File.delete(@config_filename) if File.exist?(@config_filename)
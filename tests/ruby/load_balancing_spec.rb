@@ -91,6 +91,60 @@ describe "Random Load Balancing" do
       expect(failed_count).to eq(0)
     end
   end
+
+  context "when all replicas are down and read_failover_to_primary is enabled" do
+    let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5, "transaction", "random", "debug", {"default_role" => "replica", "read_failover_to_primary" => true}) }
+
+    it "sends reads to the primary instead of unbanning the replicas" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      failed_count = 0
+      number_of_replicas = processes[:replicas].length
+
+      processes[:replicas].each(&:take_down)
+
+      # Replicas get banned as they fail
+      (number_of_replicas + 1).times do
+        conn.async_exec("SELECT 1 + 2")
+      rescue
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      end
+
+      processes.primary.reset_stats
+
+      QUERY_COUNT.times do
+        conn.async_exec("SELECT 1 + 2")
+      rescue
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        failed_count += 1
+      end
+
+      expect(failed_count).to eq(0)
+      expect(processes.primary.count_select_1_plus_2).to eq(QUERY_COUNT)
+    end
+  end
+
+  context "when all replicas are down and on_all_replicas_banned is error" do
+    let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5, "transaction", "random", "debug", {"default_role" => "replica", "on_all_replicas_banned" => "error"}) }
+
+    it "fails the reads instead of unbanning the replicas" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      number_of_replicas = processes[:replicas].length
+
+      processes[:replicas].each(&:take_down)
+
+      # Replicas get banned as they fail
+      (number_of_replicas + 1).times do
+        conn.async_exec("SELECT 1 + 2")
+      rescue
+        conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      end
+
+      processes.primary.reset_stats
+
+      expect { conn.async_exec("SELECT 1 + 2") }.to raise_error(PG::Error, /all replicas are banned/)
+      expect(processes.primary.count_select_1_plus_2).to eq(0)
+    end
+  end
 end
 
 describe "Least Outstanding Queries Load Balancing" do
@@ -196,3 +250,65 @@ describe "Least Outstanding Queries Load Balancing" do
     end
   end
 end
+
+describe "Sticky sessions" do
+  let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5) }
+  after do
+    processes.all_databases.map(&:reset)
+    processes.pgcat.shutdown
+  end
+
+  it "sends all queries of a sticky session to the same instance" do
+    conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+    conn.async_exec("SET pgcat.sticky TO on")
+    expect(conn.async_exec("SHOW pgcat.sticky")[0]["pgcat.sticky"]).to eq("on")
+
+    QUERY_COUNT.times { conn.async_exec("SELECT 1 + 2") }
+
+    counts = processes.all_databases.map(&:count_select_1_plus_2)
+    expect(counts.sort).to eq([0] * (counts.count - 1) + [QUERY_COUNT])
+  end
+
+  it "returns an error when the pinned instance is down" do
+    conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+    conn.async_exec("SET SERVER ROLE TO 'replica'")
+    conn.async_exec("SET pgcat.sticky TO on")
+    conn.async_exec("SELECT 1 + 2")
+
+    pinned = processes.replicas.find { |replica| replica.count_select_1_plus_2 == 1 }
+    pinned.take_down do
+      expect { conn.async_exec("SELECT 1 + 2") }.to raise_error(PG::Error, /pinned server/)
+    end
+
+    # Turning it off lets the session use the other replicas.
+    conn.async_exec("SET pgcat.sticky TO off")
+    pinned.take_down do
+      expect { conn.async_exec("SELECT 1 + 2") }.not_to raise_error
+    end
+  end
+end
+
+describe "Route override" do
+  let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5) }
+  after do
+    processes.all_databases.map(&:reset)
+    processes.pgcat.shutdown
+  end
+
+  it "keeps the server of a transaction when the route changes" do
+    conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+    conn.async_exec("SET pgcat.route TO 'primary'")
+    expect(conn.async_exec("SHOW pgcat.route")[0]["pgcat.route"]).to eq("primary")
+
+    conn.async_exec("BEGIN")
+    conn.async_exec("SELECT 1 + 2")
+    conn.async_exec("SET pgcat.route TO 'replica'")
+    conn.async_exec("SELECT 1 + 2")
+    conn.async_exec("COMMIT")
+    expect(processes.primary.count_select_1_plus_2).to eq(2)
+
+    conn.async_exec("SELECT 1 + 2")
+    expect(processes.primary.count_select_1_plus_2).to eq(2)
+    expect(processes.replicas.sum(&:count_select_1_plus_2)).to eq(1)
+  end
+end
@@ -71,6 +71,33 @@ describe "Query Mirroing" do
     end
   end
 
+  context "with sample rates" do
+    let(:unsampled_pg) { PgInstance.new(7432, "sharding_user", "sharding_user", "shard1") }
+
+    before do
+      new_configs = processes.pgcat.current_config
+      new_configs["pools"]["sharded_db"]["shards"]["0"]["mirrors"] = [
+        [mirror_host, mirror_pg.port.to_i, 0, 1.0],
+        [mirror_host, unsampled_pg.port.to_i, 0, 0.0],
+      ]
+      processes.pgcat.update_config(new_configs)
+      processes.pgcat.reload_config
+    end
+
+    after do
+      unsampled_pg.reset
+    end
+
+    it "only mirrors to the sampled mirror" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      5.times { conn.async_exec("SELECT 1 + 2") }
+      sleep 0.5
+      expect(processes.all_databases.first.count_select_1_plus_2).to eq(5)
+      expect(mirror_pg.count_select_1_plus_2).to be > 0
+      expect(unsampled_pg.count_select_1_plus_2).to eq(0)
+    end
+  end
+
   context "when a mirror is down" do
     let(:mirror_host) { "badhost" }
 
@@ -1,5 +1,6 @@
 # frozen_string_literal: true
 require_relative 'spec_helper'
+require 'tmpdir'
 
 describe "Miscellaneous" do
   let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5) }
@@ -57,6 +58,48 @@ describe "Miscellaneous" do
     end
   end
 
+  context "when reloading a single pool using RELOAD <pool>" do
+    it "applies the changes to that pool only" do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["other_db"] = Marshal.load(Marshal.dump(current_configs["pools"]["sharded_db"]))
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      server_count = lambda do |pool|
+        admin_conn.async_exec("SHOW DATABASES").count { |db| db["name"].start_with?("#{pool}_shard_") }
+      end
+      replica_count = current_configs["pools"]["sharded_db"]["shards"]["0"]["servers"].count
+      expect(server_count.call("sharded_db")).to eq(replica_count)
+      expect(server_count.call("other_db")).to eq(replica_count)
+
+      # A transaction on the other pool keeps its server connection through the reload.
+      other_conn = PG::connect(processes.pgcat.connection_string("other_db", "sharding_user"))
+      other_conn.async_exec("BEGIN")
+      backend_pid = other_conn.async_exec("SELECT pg_backend_pid()")[0]["pg_backend_pid"]
+
+      current_configs["pools"]["sharded_db"]["shards"]["0"]["servers"].pop
+      current_configs["pools"]["other_db"]["shards"]["0"]["servers"].pop
+      processes.pgcat.update_config(current_configs)
+      admin_conn.async_exec("RELOAD sharded_db")
+
+      expect(server_count.call("sharded_db")).to eq(replica_count - 1)
+      expect(server_count.call("other_db")).to eq(replica_count)
+
+      expect(other_conn.async_exec("SELECT pg_backend_pid()")[0]["pg_backend_pid"]).to eq(backend_pid)
+      other_conn.async_exec("COMMIT")
+
+      expect { admin_conn.async_exec("RELOAD missing_db") }.to raise_error(PG::SystemError, /no pool missing_db/)
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      admin_conn.async_exec("RELOAD")
+      expect(server_count.call("other_db")).to eq(replica_count - 1)
+
+      other_conn.close
+      admin_conn.close
+    end
+  end
+
   describe "TCP Keepalives" do
     # Ideally, we should block TCP traffic to the database using
     # iptables to mimic passive (connection is dropped without a RST packet)
@@ -200,6 +243,29 @@ describe "Miscellaneous" do
     end
   end
 
+  describe "Replication connections" do
+    it "passes them through to the primary" do
+      conn_str = processes.pgcat.connection_string(
+        "sharded_db",
+        "sharding_user",
+        parameters: { "replication" => "database" }
+      )
+
+      # Only the primary can take them.
+      PgInstance.mass_takedown(processes.replicas) do
+        conn = PG::connect(conn_str)
+        result = conn.async_exec("IDENTIFY_SYSTEM")
+        expect(result[0]["dbname"]).to eq("shard0")
+        conn.close
+      end
+
+      # Not taken from the pool, no connections left behind.
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      expect(admin_conn.async_exec("SHOW POOLS")[0]["sv_active"]).to eq("0")
+      admin_conn.close
+    end
+  end
+
   describe "State clearance" do
     context "session mode" do
       let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5, "session") }
@@ -322,7 +388,7 @@ describe "Miscellaneous" do
 
     context "transaction mode with transactions" do
       let(:processes) { Helpers::Pgcat.single_shard_setup("sharded_db", 5, "transaction") }
-      it "Does not clear set statement state when declared in a transaction" do
+      it "Resets only the settings changed in a transaction" do
         10.times do
           conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
           conn.async_exec("SET SERVER ROLE to 'primary'")
@@ -333,6 +399,12 @@ describe "Miscellaneous" do
         end
         expect(processes.primary.count_query("RESET ALL")).to eq(0)
 
+        # The next client doesn't get the setting of the previous one.
+        conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        conn.async_exec("SET SERVER ROLE to 'primary'")
+        expect(conn.async_exec("SHOW statement_timeout")[0]["statement_timeout"]).to eq("0")
+        conn.close
+
         10.times do
           conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
           conn.async_exec("SET SERVER ROLE to 'primary'")
@@ -343,6 +415,25 @@ describe "Miscellaneous" do
         end
         expect(processes.primary.count_query("RESET ALL")).to eq(0)
       end
+
+      it "Reports parameters set in a transaction without leaking them to other clients" do
+        conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        conn.async_exec("SET SERVER ROLE to 'primary'")
+        conn.async_exec("BEGIN")
+        conn.async_exec("SET IntervalStyle TO iso_8601")
+        conn.async_exec("COMMIT")
+
+        # The server's ParameterStatus made it to the client.
+        expect(conn.parameter_status("IntervalStyle")).to eq("iso_8601")
+        conn.close
+
+        10.times do
+          conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+          conn.async_exec("SET SERVER ROLE to 'primary'")
+          expect(conn.async_exec("SHOW IntervalStyle")[0]["IntervalStyle"]).to eq("postgres")
+          conn.close
+        end
+      end
     end
 
     context "server cleanup disabled" do
@@ -420,5 +511,461 @@ describe "Miscellaneous" do
         conn.close
       end
     end
+
+    context "pool idle_in_transaction_timeout set to 500ms" do
+      before do
+        current_configs = processes.pgcat.current_config
+        current_configs["pools"]["sharded_db"]["idle_in_transaction_timeout"] = 500
+
+        processes.pgcat.update_config(current_configs)
+        processes.pgcat.reload_config
+      end
+
+      it "Allows idle clients outside of transactions" do
+        conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        conn.async_exec("SELECT 1")
+        sleep(1)
+        conn.async_exec("SELECT 1")
+        conn.close
+      end
+
+      it "Rolls back the transaction and disconnects the client" do
+        conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        conn.async_exec("SET SERVER ROLE to 'primary'")
+        conn.async_exec("BEGIN")
+        conn.async_exec("CREATE TABLE idle_in_transaction_test (id INT)")
+        sleep(1) # above 500ms
+        expect{ conn.async_exec("COMMIT") }.to raise_error(PG::Error, /idle-in-transaction timeout/)
+
+        # The server connection went back to the pool without the table.
+        conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+        conn.async_exec("SET SERVER ROLE to 'primary'")
+        expect(conn.async_exec("SELECT to_regclass('idle_in_transaction_test')")[0]["to_regclass"]).to be_nil
+        conn.close
+
+        admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+        expect(admin_conn.async_exec("SHOW POOLS").map { |pool| pool["sv_active"] }.uniq).to eq(["0"])
+        admin_conn.close
+      end
+    end
+  end
+
+  describe "Session mode client_idle_timeout" do
+    let(:processes) { Helpers::Pgcat.single_instance_setup("sharded_db", 1, "session") }
+
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["client_idle_timeout"] = 500
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "gives the server of idle clients to waiting clients" do
+      idle_conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      idle_conn.async_exec("SELECT 1")
+
+      # The only server connection is given back once the client is idle for 500ms.
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      expect(conn.async_exec("SELECT 2")[0]["?column?"]).to eq("2")
+      conn.close
+
+      # And the idle client gets one again with its next query.
+      expect(idle_conn.async_exec("SELECT 3")[0]["?column?"]).to eq("3")
+      idle_conn.close
+    end
+
+    it "keeps the server of clients with session state" do
+      idle_conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      idle_conn.async_exec("SET statement_timeout TO 1234")
+      sleep(1)
+
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      expect { conn.async_exec("SELECT 1") }.to raise_error(PG::Error, /could not get connection from the pool/)
+      conn.close
+
+      expect(idle_conn.async_exec("SHOW statement_timeout")[0]["statement_timeout"]).to eq("1234ms")
+      idle_conn.close
+    end
+  end
+
+  describe "Per-user pool size" do
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["default_role"] = "primary"
+      current_configs["pools"]["sharded_db"]["connect_timeout"] = 5000
+      current_configs["pools"]["sharded_db"]["users"]["0"]["pool_size"] = 2
+      current_configs["pools"]["sharded_db"]["users"]["1"] = {
+        "username" => "other_user",
+        "password" => "other_user",
+        "pool_size" => 5,
+        "statement_timeout" => 0,
+      }
+
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "makes a user wait once it holds pool_size connections without affecting other users" do
+      busy = 2.times.map do
+        Thread.new do
+          conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+          conn.async_exec("SELECT pg_sleep(1)")
+          conn.close
+        end
+      end
+      sleep(0.2)
+
+      # Other users have their own pool
+      other_conn = PG::connect(processes.pgcat.connection_string("sharded_db", "other_user"))
+      started = Time.now
+      other_conn.async_exec("SELECT 1")
+      expect(Time.now - started).to be < 0.5
+      other_conn.close
+
+      # The third concurrent checkout waits for one of the two busy connections
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      started = Time.now
+      conn.async_exec("SELECT 1")
+      expect(Time.now - started).to be > 0.5
+      conn.close
+
+      busy.each(&:join)
+    end
+  end
+
+  describe "Checkout timeout comment" do
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["default_role"] = "primary"
+      current_configs["pools"]["sharded_db"]["connect_timeout"] = 5000
+      current_configs["pools"]["sharded_db"]["users"]["0"]["pool_size"] = 1
+
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "fails fast with the timeout requested by the query when the pool is exhausted" do
+      busy = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      busy.async_exec("BEGIN")
+      busy.async_exec("SELECT 1")
+
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      started = Time.now
+      expect {
+        conn.async_exec("/* pgcat_checkout_timeout: 100 */ SELECT 1")
+      }.to raise_error(PG::Error, /CheckoutTimeout/)
+      expect(Time.now - started).to be < 1
+
+      busy.async_exec("COMMIT")
+      busy.close
+
+      # The primary was not banned.
+      expect(conn.async_exec("SELECT 1")[0]["?column?"]).to eq("1")
+      conn.close
+    end
+  end
+
+  describe "Server lifetime" do
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["server_lifetime"] = 1000
+      current_configs["pools"]["sharded_db"]["users"]["0"]["pool_size"] = 1
+
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "replaces server connections older than the lifetime once they are returned" do
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      first_pid = conn.async_exec("SELECT pg_backend_pid()")[0]["pg_backend_pid"]
+      expect(conn.async_exec("SELECT pg_backend_pid()")[0]["pg_backend_pid"]).to eq(first_pid)
+
+      sleep(1.5)
+
+      # The connection is still used for this query, and is recycled after.
+      conn.async_exec("SELECT 1")
+      expect(conn.async_exec("SELECT pg_backend_pid()")[0]["pg_backend_pid"]).not_to eq(first_pid)
+      conn.close
+    end
+  end
+
+  describe "Query timeout" do
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["query_timeout"] = 1000
+      current_configs["pools"]["sharded_db"]["users"]["0"]["pool_size"] = 1
+
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "cancels the queries running longer than the timeout" do
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      pid = conn.async_exec("SELECT pg_backend_pid()")[0]["pg_backend_pid"]
+
+      start = Time.now
+      expect { conn.async_exec("SELECT pg_sleep(5)") }.to raise_error(PG::QueryCanceled)
+      expect(Time.now - start).to be < 2
+
+      # The server connection is still good.
+      expect(conn.async_exec("SELECT pg_backend_pid()")[0]["pg_backend_pid"]).to eq(pid)
+      expect(conn.async_exec("SELECT pg_sleep(0.5), 1 AS one")[0]["one"]).to eq("1")
+      conn.close
+    end
+  end
+
+  describe "Query cancellation" do
+    it "cancels the query the client is running on its server" do
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      other_conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+
+      # The server of the other client isn't canceled.
+      other_query = Thread.new { other_conn.async_exec("SELECT pg_sleep(2), 1 AS one") }
+
+      query = Thread.new { conn.async_exec("SELECT pg_sleep(5)") }
+      sleep(0.5)
+      conn.cancel
+
+      start = Time.now
+      expect { query.join }.to raise_error(PG::QueryCanceled)
+      expect(Time.now - start).to be < 1
+      expect(other_query.value[0]["one"]).to eq("1")
+
+      # Nothing is canceled outside of queries.
+      conn.cancel
+      expect(conn.async_exec("SELECT 1 AS one")[0]["one"]).to eq("1")
+
+      conn.close
+      other_conn.close
+    end
+  end
+
+  describe "Client connection limit" do
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["general"]["max_client_connections"] = 3
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "refuses the clients past max_client_connections at login" do
+      conns = 3.times.map do
+        PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      end
+      conns.each { |conn| conn.async_exec("SELECT 1") }
+
+      expect {
+        PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      }.to raise_error(PG::Error, /no more connections allowed \(max_client_connections = 3\)/)
+
+      # The admin database is still reachable, and shows the count.
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      lists = admin_conn.async_exec("SHOW LISTS").to_a.to_h { |row| [row["list"], row["items"]] }
+      expect(lists["client_connections"]).to eq("3")
+      expect(lists["max_client_connections"]).to eq("3")
+
+      # Room for a new client once one leaves.
+      conns.pop.close
+      sleep(0.1)
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      conn.async_exec("SELECT 1")
+
+      conn.close
+      conns.each(&:close)
+      admin_conn.close
+    end
+  end
+
+  describe "Pool networks" do
+    def use_networks(allowed, denied)
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["allowed_networks"] = allowed
+      current_configs["pools"]["sharded_db"]["denied_networks"] = denied
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "accepts clients from an allowed network" do
+      use_networks(["10.0.0.0/8", "127.0.0.0/8"], ["10.0.1.0/24"])
+
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      expect(conn.async_exec("SELECT 1 AS one")[0]["one"]).to eq("1")
+      conn.close
+    end
+
+    it "rejects clients from a denied network, even if it's allowed" do
+      use_networks(["127.0.0.0/8"], ["127.0.0.1"])
+
+      expect {
+        PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      }.to raise_error(PG::ConnectionBad, /access to database "sharded_db" not allowed from 127.0.0.1/)
+    end
+
+    it "rejects clients from outside the allowed networks" do
+      use_networks(["10.0.0.0/8"], [])
+
+      expect {
+        PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      }.to raise_error(PG::ConnectionBad, /not allowed/)
+    end
+  end
+
+  describe "Sticky parameters" do
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["sticky_parameters"] = ["options"]
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    def connect(search_path)
+      PG::connect(
+        processes.pgcat.connection_string(
+          "sharded_db",
+          "sharding_user",
+          parameters: { "options" => "-csearch_path%3D#{search_path}" }
+        )
+      )
+    end
+
+    it "gives clients with different search_paths their own server connections" do
+      app = connect("app")
+      audit = connect("audit")
+
+      # In transaction mode, every query may get another server connection.
+      5.times do
+        expect(app.async_exec("SHOW search_path")[0]["search_path"]).to eq("app")
+        expect(audit.async_exec("SHOW search_path")[0]["search_path"]).to eq("audit")
+      end
+
+      app.close
+      audit.close
+    end
+  end
+
+  describe "Postgres-style connection logs" do
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["general"]["log_client_connections"] = true
+      current_configs["general"]["log_client_disconnections"] = true
+      current_configs["general"]["log_line_prefix"] = "[%p] %u@%d "
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "logs the session time of a client when it closes" do
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      pid = conn.backend_pid
+      conn.async_exec("SELECT 1")
+      conn.close
+      sleep(0.5)
+
+      expect(processes.pgcat.logs).to match(/connection received: host=127\.0\.0\.1 port=\d+/)
+      expect(processes.pgcat.logs).to match(/\[#{pid}\] sharding_user@sharded_db connection authorized: user=sharding_user database=sharded_db/)
+      expect(processes.pgcat.logs).to match(
+        /\[#{pid}\] sharding_user@sharded_db disconnection: session time: \d+:\d{2}:\d{2}\.\d{3} user=sharding_user database=sharded_db host=127\.0\.0\.1 port=\d+ bytes_received=\d+ bytes_sent=\d+/
+      )
+    end
+  end
+
+  describe "Application name format" do
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["application_name_format"] = "pgcat:{application_name}:{client_id}"
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "shows the client on the server while it runs a query" do
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user", parameters: { "application_name" => "web" }))
+      application_name = conn.async_exec("SELECT application_name FROM pg_stat_activity WHERE pid = pg_backend_pid()")[0]["application_name"]
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      client = admin_conn.async_exec("SHOW CLIENTS").to_a.find { |client| client["application_name"] == "web" }
+      admin_conn.close
+
+      expect(application_name).to eq("pgcat:web:#{client["client_id"]}")
+    ensure
+      conn.close if conn
+    end
+  end
+
+  describe "Read-only replicas" do
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["replica_read_only"] = true
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "rejects the writes routed to a replica" do
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      conn.async_exec("SET SERVER ROLE TO 'replica'")
+      expect { conn.async_exec("CREATE TEMP TABLE read_only_test (id INT)") }.to raise_error(
+        PG::ReadOnlySqlTransaction, /cannot execute CREATE TABLE in a read-only transaction/
+      )
+
+      conn.async_exec("SET SERVER ROLE TO 'primary'")
+      expect { conn.async_exec("CREATE TEMP TABLE read_only_test (id INT)") }.not_to raise_error
+    end
+  end
+
+  describe "Unix socket" do
+    let(:socket_dir) { Dir.mktmpdir }
+
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["general"]["unix_socket_dir"] = socket_dir
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.stop
+      processes.pgcat.start
+      processes.pgcat.wait_until_ready
+    end
+
+    after do
+      FileUtils.remove_entry(socket_dir)
+    end
+
+    it "accepts clients on the socket and removes it on shutdown" do
+      conn = PG::connect(
+        host: socket_dir,
+        port: processes.pgcat.port,
+        dbname: "sharded_db",
+        user: "sharding_user",
+        password: "sharding_user"
+      )
+      expect(conn.async_exec("SELECT 1 + 2")[0]["?column?"]).to eq("3")
+      conn.close
+
+      socket = File.join(socket_dir, ".s.PGSQL.#{processes.pgcat.port}")
+      expect(File.socket?(socket)).to be(true)
+
+      processes.pgcat.terminate
+      processes.pgcat.wait_until_exited
+      expect(File.exist?(socket)).to be(false)
+    end
+  end
+
+  describe "Graceful shutdown on SIGTERM" do
+    it "lets transactions in progress finish before exiting" do
+      conn = PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      conn.async_exec("BEGIN")
+      conn.async_exec("SELECT 1")
+
+      processes.pgcat.terminate
+      sleep(0.5)
+      expect(processes.pgcat.running?).to be(true)
+
+      # New clients are turned away
+      expect {
+        PG::connect(processes.pgcat.connection_string("sharded_db", "sharding_user")).async_exec("SELECT 1")
+      }.to raise_error(PG::Error, /terminating connection due to administrator command/)
+
+      conn.async_exec("SELECT pg_sleep(1)")
+      conn.async_exec("COMMIT")
+
+      processes.pgcat.wait_until_exited
+      expect { conn.async_exec("SELECT 1") }.to raise_error(PG::Error)
+    end
   end
 end
@@ -11,4 +11,128 @@ describe "Plugins" do
       expect(res.values).to eq([["sharded_db", "{public}"]])
     end
   end
+
+  context "query_filter" do
+    before do
+      new_configs = processes.pgcat.current_config
+      new_configs["pools"]["sharded_db"]["plugins"]["query_filter"] = {
+        "enabled" => true,
+        "default_action" => "deny",
+        "rules" => [
+          { "action" => "deny", "pattern" => "(?i)pg_sleep" },
+          { "action" => "allow", "statement_types" => ["read"] },
+        ],
+      }
+      processes.pgcat.update_config(new_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "only lets the allowed statements through" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+
+      expect(conn.async_exec("SELECT 1").values).to eq([["1"]])
+      expect { conn.async_exec("DROP TABLE data") }.to raise_error(PG::Error, /statement not allowed: DROP TABLE data/)
+      expect { conn.async_exec("SELECT pg_sleep(1)") }.to raise_error(PG::Error, /statement not allowed/)
+      expect(conn.async_exec("SELECT 2").values).to eq([["2"]])
+
+      conn.close
+    end
+  end
+
+  context "query_rewrite" do
+    before do
+      new_configs = processes.pgcat.current_config
+      new_configs["pools"]["sharded_db"]["plugins"]["query_rewrite"] = {
+        "enabled" => true,
+        "rules" => [
+          { "pattern" => "\\blegacy_value\\b", "rewrite" => "2" },
+          { "pattern" => "^SELECT broken$", "rewrite" => "SELECT FROM FROM" },
+        ],
+      }
+      processes.pgcat.update_config(new_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "sends the rewritten simple queries" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+
+      expect(conn.async_exec("SELECT legacy_value AS value").values).to eq([["2"]])
+      expect { conn.async_exec("SELECT broken") }.to raise_error(PG::Error, /rewritten query is not valid SQL/)
+
+      # Prepared statements are not rewritten.
+      expect { conn.exec_params("SELECT legacy_value AS value", []) }.to raise_error(PG::UndefinedColumn)
+
+      conn.close
+    end
+  end
+
+  context "query_cache" do
+    before do
+      new_configs = processes.pgcat.current_config
+      new_configs["pools"]["sharded_db"]["plugins"]["query_cache"] = {
+        "enabled" => true,
+        "queries" => ["now\\(\\)"],
+        "ttl" => 5000,
+      }
+      processes.pgcat.update_config(new_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "answers the same query from the cache within the ttl" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      first = conn.async_exec("SELECT now()::text AS now").values
+      conn.close
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      queries_before = admin_conn.async_exec("SHOW STATS").map { |row| row["total_query_count"].to_i }.sum
+      servers_before = admin_conn.async_exec("SHOW SERVERS").ntuples
+
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+      expect(conn.async_exec("select   now()::text as now").values).to eq(first)
+
+      sleep(1.1) # Stats are updated every second
+      expect(admin_conn.async_exec("SHOW STATS").map { |row| row["total_query_count"].to_i }.sum).to eq(queries_before)
+      expect(admin_conn.async_exec("SHOW SERVERS").ntuples).to eq(servers_before)
+
+      # Not in a transaction.
+      conn.async_exec("BEGIN")
+      expect(conn.async_exec("SELECT now()::text AS now").values).not_to eq(first)
+      conn.async_exec("COMMIT")
+
+      conn.close
+      admin_conn.close
+    end
+  end
+
+  context "rate_limit" do
+    before do
+      new_configs = processes.pgcat.current_config
+      new_configs["pools"]["sharded_db"]["plugins"]["rate_limit"] = {
+        "enabled" => true,
+        "queries_per_second" => 5,
+      }
+      processes.pgcat.update_config(new_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "rejects the queries above the limit of the user, over all its connections" do
+      conns = 2.times.map { PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user")) }
+
+      results = 20.times.map do |i|
+        conns[i % 2].async_exec("SELECT 1")
+        :ok
+      rescue PG::Error => e
+        expect(e.message).to include("rate limit exceeded")
+        :rejected
+      end
+
+      expect(results.count(:ok)).to be_between(5, 6)
+      expect(results.count(:rejected)).to be_between(14, 15)
+
+      sleep(1)
+      expect(conns[0].async_exec("SELECT 1").values).to eq([["1"]])
+
+      conns.each(&:close)
+    end
+  end
 end
@@ -133,6 +133,34 @@ describe 'Prepared statements' do
     end
   end
 
+  context 'when max_prepared_statements is smaller than the statement cache' do
+    let(:pool_size) { 1 }
+
+    before do
+      new_configs = processes.pgcat.current_config
+      new_configs["pools"]["sharded_db"]["max_prepared_statements"] = 2
+      processes.pgcat.update_config(new_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "keeps the most recently used statements on the server" do
+      conn = PG.connect(processes.pgcat.connection_string('sharded_db', 'sharding_user'))
+
+      5.times do |i|
+        conn.prepare("statement#{i}", "SELECT '#{i}'")
+        expect(conn.exec_prepared("statement#{i}").getvalue(0, 0)).to eq(i.to_s)
+      end
+
+      statements = conn.exec("SELECT statement FROM pg_prepared_statements ORDER BY statement").column_values(0)
+      expect(statements).to eq(["SELECT '3'", "SELECT '4'"])
+
+      # Evicted from the server, prepared there again.
+      expect(conn.exec_prepared("statement0").getvalue(0, 0)).to eq("0")
+    ensure
+      conn.close if conn
+    end
+  end
+
   context 'when statement cache is larger than set of unqiue statements' do
     let(:pool_size) { 1 }
 
@@ -184,6 +212,70 @@ describe 'Prepared statements' do
       n_statements = conn.exec("SELECT count(*) FROM pg_prepared_statements").getvalue(0, 0).to_i
       expect(n_statements).to eq(1)
     end
+
+    context 'with two server connections' do
+      let(:pool_size) { 2 }
+
+      it "parses the statement only once on each server" do
+        conn1 = PG.connect(processes.pgcat.connection_string('sharded_db', 'sharding_user'))
+        conn2 = PG.connect(processes.pgcat.connection_string('sharded_db', 'sharding_user'))
+
+        3.times do |i|
+          # Both clients hold a server at the same time, so they use different ones.
+          conn1.transaction do
+            conn2.transaction do
+              conn1.prepare("conn1_statement_#{i}", 'SELECT $1::int')
+              conn2.prepare("conn2_statement_#{i}", 'SELECT $1::int')
+              expect(conn1.exec_prepared("conn1_statement_#{i}", [1]).getvalue(0, 0)).to eq("1")
+              expect(conn2.exec_prepared("conn2_statement_#{i}", [2]).getvalue(0, 0)).to eq("2")
+
+              n_statements = conn1.exec("SELECT count(*) FROM pg_prepared_statements").getvalue(0, 0).to_i
+              expect(n_statements).to eq(1)
+            end
+          end
+        end
+
+        admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+        servers = admin_conn.async_exec("SHOW SERVERS").to_a.select do |server|
+          server["prepare_cache_hit"].to_i + server["prepare_cache_miss"].to_i > 0
+        end
+        admin_conn.close
+
+        expect(servers.length).to eq(2)
+        servers.each do |server|
+          expect(server["prepare_cache_miss"].to_i).to eq(1)
+          expect(server["prepare_cache_hit"].to_i).to be > 0
+        end
+      ensure
+        conn1.close if conn1
+        conn2.close if conn2
+      end
+    end
+  end
+
+  context 'when showing the cache' do
+    it "counts one miss then one hit for the same query" do
+      conn1 = PG.connect(processes.pgcat.connection_string('sharded_db', 'sharding_user'))
+      conn2 = PG.connect(processes.pgcat.connection_string('sharded_db', 'sharding_user'))
+
+      conn1.prepare('statement1', 'SELECT $1::bigint')
+      conn1.exec_prepared('statement1', [1])
+      conn2.prepare('statement2', 'SELECT $1::bigint')
+      conn2.exec_prepared('statement2', [2])
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      statements = admin_conn.async_exec("SHOW PREPARED_STATEMENTS").to_a
+      admin_conn.close
+
+      expect(statements.length).to eq(1)
+      expect(statements[0]["database"]).to eq("sharded_db")
+      expect(statements[0]["query"]).to eq("SELECT $1::bigint")
+      expect(statements[0]["name"]).to start_with("PGCAT_")
+      expect(statements[0]["hits"]).to eq("1")
+    ensure
+      conn1.close if conn1
+      conn2.close if conn2
+    end
   end
 
   context 'when reloading config' do
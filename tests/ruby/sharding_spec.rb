@@ -50,6 +50,28 @@ describe "Sharding" do
     end
   end
 
+  describe "queries for all shards" do
+    it "returns the rows of every shard" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+
+      result = conn.async_exec("/* pgcat_shard: all */ SELECT id FROM data")
+      expect(result.ntuples).to eq(18)
+      expect(result.map { |row| row["id"].to_i }.sort).to eq((1..18).to_a)
+      expect(result.cmd_tuples).to eq(18)
+
+      counts = conn.async_exec("/* pgcat_shard: all */ SELECT count(*) FROM data")
+      expect(counts.ntuples).to eq(3)
+      expect(counts.map { |row| row["count"].to_i }.sum).to eq(18)
+    end
+
+    it "returns errors from the shards" do
+      conn = PG.connect(processes.pgcat.connection_string("sharded_db", "sharding_user"))
+
+      expect { conn.async_exec("/* pgcat_shard: all */ SELECT * FROM missing_table") }.to raise_error(PG::UndefinedTable)
+      expect(conn.async_exec("SELECT 1").ntuples).to eq(1)
+    end
+  end
+
   describe "no_shard_specified_behavior config" do
     context "when default shard number is invalid" do
       it "prevents config reload" do
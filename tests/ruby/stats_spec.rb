@@ -1,4 +1,5 @@
 # frozen_string_literal: true
+require 'json'
 require 'open3'
 require_relative 'spec_helper'
 
@@ -32,10 +33,79 @@ describe "Stats" do
         expect(results["total_wait_time"].to_i).to_not eq(0)
         expect(results["avg_wait_time"].to_i).to_not eq(0)
       end
+
+      it "estimates query time quantiles from the histogram buckets" do
+        connections = Array.new(3) { PG::connect("#{pgcat_conn_str}?application_name=one_query") }
+        connections.map { |c| Thread.new { c.async_exec("SELECT pg_sleep(0.25)") } }.each(&:join)
+        connections.map(&:close)
+
+        # Not averaged over a period, no need to wait.
+        admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+        results = admin_conn.async_exec("SHOW STATS")[0]
+        admin_conn.close
+
+        # All in the (250ms, 500ms] bucket.
+        expect(results["p50_query_time"].to_i).to be_between(250, 500)
+        expect(results["p99_query_time"].to_i).to be_between(250, 500)
+      end
+    end
+
+    context "with the query parser enabled" do
+      before do
+        new_configs = processes.pgcat.current_config
+        new_configs["pools"]["sharded_db"]["query_parser_enabled"] = true
+        processes.pgcat.update_config(new_configs)
+        processes.pgcat.reload_config
+      end
+
+      it "counts the queries by kind of statement" do
+        conn = PG::connect("#{pgcat_conn_str}?application_name=statement_kinds")
+        conn.async_exec("CREATE TABLE statement_kinds (id BIGINT)")
+        conn.async_exec("INSERT INTO statement_kinds VALUES (1)")
+        conn.async_exec("UPDATE statement_kinds SET id = 2")
+        conn.async_exec("SELECT * FROM statement_kinds")
+        conn.async_exec("DELETE FROM statement_kinds")
+        conn.async_exec("DROP TABLE statement_kinds")
+        conn.async_exec("SET statement_timeout TO 0")
+
+        admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+        client = admin_conn.async_exec("SHOW CLIENTS").detect { |c| c["application_name"] == "statement_kinds" }
+        stats = admin_conn.async_exec("SHOW STATS")[0]
+        admin_conn.close
+        conn.close
+
+        expect(client.slice("select_count", "insert_count", "update_count", "delete_count", "ddl_count", "other_count"))
+          .to eq({ "select_count" => "1", "insert_count" => "1", "update_count" => "1", "delete_count" => "1", "ddl_count" => "2", "other_count" => "1" })
+        expect(stats["total_select_count"]).to eq("1")
+        expect(stats["total_insert_count"]).to eq("1")
+        expect(stats["total_update_count"]).to eq("1")
+        expect(stats["total_delete_count"]).to eq("1")
+        expect(stats["total_ddl_count"]).to eq("2")
+        expect(stats["total_other_count"]).to eq("1")
+      end
     end
   end
 
   describe "SHOW POOLS" do
+    context "with the JSON suffix" do
+      it "returns the pools as a single JSON document" do
+        admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+        results = admin_conn.async_exec("SHOW POOLS JSON")
+        expect(results.fields).to eq(["json"])
+        expect(results.ntuples).to eq(1)
+
+        pools = JSON.parse(results[0]["json"])
+        expect(pools.count).to eq(admin_conn.async_exec("SHOW POOLS").ntuples)
+        expect(pools[0]["database"]).to eq("sharded_db")
+        expect(pools[0]["user"]).to eq("sharding_user")
+        expect(pools[0]["pool_mode"]).to eq("transaction")
+        expect(pools[0]["cl_waiting"]).to eq(0)
+        expect(pools[0]["sv_idle"]).to be_a(Integer)
+
+        admin_conn.close
+      end
+    end
+
     context "bad credentials" do
       it "does not change any stats" do
         bad_password_url = URI(pgcat_conn_str)
@@ -280,6 +350,50 @@ describe "Stats" do
     end
   end
 
+  describe "Idle server reaping" do
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["pools"]["sharded_db"]["idle_timeout"] = 1000
+      current_configs["pools"]["sharded_db"]["users"]["0"]["min_pool_size"] = 2
+
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "shrinks the pool to min_pool_size but not below" do
+      connections = Array.new(5) { PG::connect(pgcat_conn_str) }
+      connections.map { |c| Thread.new { c.async_exec("SELECT pg_sleep(0.5)") } }.each(&:join)
+      connections.map(&:close)
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      expect(admin_conn.async_exec("SHOW POOLS")[0]["sv_idle"]).to eq("5")
+
+      sleep(2.5)
+      expect(admin_conn.async_exec("SHOW POOLS")[0]["sv_idle"]).to eq("2")
+
+      sleep(2.5)
+      expect(admin_conn.async_exec("SHOW POOLS")[0]["sv_idle"]).to eq("2")
+      admin_conn.close
+    end
+  end
+
+  describe "Prewarm" do
+    before do
+      current_configs = processes.pgcat.current_config
+      current_configs["general"]["prewarm"] = true
+      current_configs["pools"]["sharded_db"]["users"]["0"]["min_pool_size"] = 5
+
+      processes.pgcat.update_config(current_configs)
+      processes.pgcat.reload_config
+    end
+
+    it "opens min_pool_size connections before the first client query" do
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      expect(admin_conn.async_exec("SHOW POOLS")[0]["sv_idle"]).to eq("5")
+      admin_conn.close
+    end
+  end
+
   describe "SHOW CLIENTS" do
     it "reports correct number and application names" do
       conn_str = processes.pgcat.connection_string("sharded_db", "sharding_user")
@@ -366,6 +480,30 @@ describe "Stats" do
         connections.map(&:close)
       end
     end
+
+    it "shows the state, waits and server of the clients" do
+      conn = PG::connect("#{pgcat_conn_str}?application_name=show_clients_app")
+      conn.async_exec("BEGIN")
+      conn.async_exec("SELECT 1")
+
+      admin_conn = PG::connect(processes.pgcat.admin_connection_string)
+      client = admin_conn.async_exec("SHOW CLIENTS").detect { |c| c["application_name"] == "show_clients_app" }
+      expect(client["user"]).to eq("sharding_user")
+      expect(client["state"]).to eq("active")
+      expect(client["wait"]).to eq("0")
+      expect(client["wait_us"]).to eq("0")
+      expect(client["total_wait_us"].to_i).to be >= 0
+      expect(client["server_address"]).to match(/\A.+:\d+\z/)
+
+      conn.async_exec("COMMIT")
+      sleep(0.5)
+      client = admin_conn.async_exec("SHOW CLIENTS").detect { |c| c["application_name"] == "show_clients_app" }
+      expect(client["state"]).to eq("idle")
+      expect(client["server_address"]).to eq("")
+
+      admin_conn.close
+      conn.close
+    end
   end
 
 
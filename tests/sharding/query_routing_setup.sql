@@ -55,7 +55,7 @@ CREATE TABLE data (
 DROP ROLE IF EXISTS sharding_user;
 DROP ROLE IF EXISTS other_user;
 DROP ROLE IF EXISTS simple_user;
-CREATE ROLE sharding_user ENCRYPTED PASSWORD 'sharding_user' LOGIN;
+CREATE ROLE sharding_user ENCRYPTED PASSWORD 'sharding_user' LOGIN REPLICATION;
 CREATE ROLE other_user ENCRYPTED PASSWORD 'other_user' LOGIN;
 CREATE ROLE simple_user ENCRYPTED PASSWORD 'simple_user' LOGIN;
 
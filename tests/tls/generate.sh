@@ -0,0 +1,47 @@
+#!/bin/bash
+# Generates the client certificates used by the tests in src/tls.rs.
+set -e
+cd "$(dirname "$0")"
+TMP=$(mktemp -d)
+trap 'rm -rf "$TMP"' EXIT
+
+NEWKEY="-newkey ec -pkeyopt ec_paramgen_curve:P-256 -nodes"
+
+openssl req -x509 $NEWKEY -keyout "$TMP/ca.key" -out ca.pem -days 36500 -subj "/CN=PgCat Test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign"
+
+cat > "$TMP/client.ext" <<EXT
+basicConstraints=CA:FALSE
+keyUsage=critical,digitalSignature
+extendedKeyUsage=clientAuth
+subjectAltName=DNS:app.example.org,URI:spiffe://example.org/app
+EXT
+
+# Valid client certificate.
+openssl req $NEWKEY -keyout client.key -out "$TMP/client.csr" -subj "/O=PgCat/CN=app"
+openssl x509 -req -in "$TMP/client.csr" -CA ca.pem -CAkey "$TMP/ca.key" -CAcreateserial \
+    -CAserial "$TMP/ca.srl" -out client.pem -days 36500 -extfile "$TMP/client.ext"
+
+# Signed by the CA, but expired.
+mkdir "$TMP/newcerts"
+touch "$TMP/index.txt"
+echo 1000 > "$TMP/serial"
+cat > "$TMP/ca.cnf" <<CNF
+[ca]
+default_ca = test
+[test]
+dir = $TMP
+database = $TMP/index.txt
+new_certs_dir = $TMP/newcerts
+serial = $TMP/serial
+default_md = sha256
+policy = any
+[any]
+commonName = supplied
+CNF
+openssl req $NEWKEY -keyout expired.key -out "$TMP/expired.csr" -subj "/CN=expired"
+openssl ca -batch -config "$TMP/ca.cnf" -cert ca.pem -keyfile "$TMP/ca.key" -in "$TMP/expired.csr" \
+    -out expired.pem -startdate 20200101000000Z -enddate 20210101000000Z -extfile "$TMP/client.ext" -notext
+
+# Self-signed, not trusted by the CA.
+openssl req -x509 $NEWKEY -keyout untrusted.key -out untrusted.pem -days 36500 -subj "/CN=app"
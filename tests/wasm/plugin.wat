@@ -0,0 +1,74 @@
+;; Fixture of the wasm plugin tests.
+;;
+;; Rejects the queries with DROP, rewrites the ones with legacy_users, routes the ones
+;; with shard_me to shard 1, loops forever on spin and grows its memory on grow.
+(module
+  (import "pgcat" "set_output" (func $set_output (param i32 i32)))
+  (import "pgcat" "set_shard" (func $set_shard (param i32)))
+  (import "pgcat" "log" (func $log (param i32 i32)))
+
+  (memory (export "memory") 1)
+
+  (data (i32.const 0) "DROP")
+  (data (i32.const 16) "dropping tables is not allowed")
+  (data (i32.const 64) "legacy_users")
+  (data (i32.const 80) "SELECT * FROM users")
+  (data (i32.const 112) "shard_me")
+  (data (i32.const 128) "spin")
+  (data (i32.const 136) "grow")
+
+  ;; The input is written after the constants.
+  (func (export "alloc") (param $len i32) (result i32)
+    (i32.const 1024))
+
+  ;; Whether [$ptr, $ptr + $len) contains [$needle, $needle + $n).
+  (func $contains (param $ptr i32) (param $len i32) (param $needle i32) (param $n i32) (result i32)
+    (local $i i32)
+    (local $j i32)
+    (block $not_found
+      (loop $next
+        (br_if $not_found
+          (i32.gt_u (i32.add (local.get $i) (local.get $n)) (local.get $len)))
+        (local.set $j (i32.const 0))
+        (block $mismatch
+          (loop $compare
+            (if (i32.eq (local.get $j) (local.get $n))
+              (then (return (i32.const 1))))
+            (br_if $mismatch
+              (i32.ne
+                (i32.load8_u (i32.add (local.get $ptr) (i32.add (local.get $i) (local.get $j))))
+                (i32.load8_u (i32.add (local.get $needle) (local.get $j)))))
+            (local.set $j (i32.add (local.get $j) (i32.const 1)))
+            (br $compare)))
+        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+        (br $next)))
+    (i32.const 0))
+
+  (func (export "intercept") (param $ptr i32) (param $len i32) (result i32)
+    (call $log (local.get $ptr) (local.get $len))
+
+    (if (call $contains (local.get $ptr) (local.get $len) (i32.const 0) (i32.const 4))
+      (then
+        (call $set_output (i32.const 16) (i32.const 30))
+        (return (i32.const 2))))
+
+    (if (call $contains (local.get $ptr) (local.get $len) (i32.const 64) (i32.const 12))
+      (then
+        (call $set_output (i32.const 80) (i32.const 19))
+        (return (i32.const 1))))
+
+    (if (call $contains (local.get $ptr) (local.get $len) (i32.const 112) (i32.const 8))
+      (then
+        (call $set_shard (i32.const 1))
+        (return (i32.const 3))))
+
+    (if (call $contains (local.get $ptr) (local.get $len) (i32.const 128) (i32.const 4))
+      (then (loop $forever (br $forever))))
+
+    (if (call $contains (local.get $ptr) (local.get $len) (i32.const 136) (i32.const 4))
+      (then
+        (if (i32.eq (memory.grow (i32.const 1024)) (i32.const -1))
+          (then unreachable))))
+
+    (i32.const 0))
+)